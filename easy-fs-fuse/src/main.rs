@@ -1,11 +1,23 @@
-use clap::{App, Arg};
-use easy_fs::{BlockDevice, EasyFileSystem, Inode};
+use clap::{App, Arg, ArgMatches, SubCommand};
+use easy_fs::{
+    blocks_needed, BlockDevice, EasyFileSystem, Inode, FEATURE_DIR_INDEX, FEATURE_EXTENTS,
+    FEATURE_LONG_NAMES, MAX_FILE_SIZE,
+};
+#[cfg(test)]
+use easy_fs::{partitions, Ext2FileSystem, Fat32FileSystem, BLOCK_CACHE_SIZE};
 use std::fs::{read_dir, File, OpenOptions};
 use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, Ordering};
 use std::sync::Arc;
 use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+mod mount;
 
 const BLOCK_SZ: usize = 512;
+/// Blocks cached when working on an image, memory is cheap on the host
+const CACHE_BLOCKS: usize = 4096;
 
 struct BlockFile(Mutex<File>);
 
@@ -26,13 +38,115 @@ impl BlockDevice for BlockFile {
     fn handle_irq(&self) {
         unimplemented!();
     }
+    fn read_blocks(&self, block_id: usize, buf: &mut [u8]) {
+        let mut file = self.0.lock().unwrap();
+        file.seek(SeekFrom::Start((block_id * BLOCK_SZ) as u64))
+            .expect("Error when seeking!");
+        file.read_exact(buf).expect("Not complete blocks!");
+    }
+    fn write_blocks(&self, block_id: usize, buf: &[u8]) {
+        let mut file = self.0.lock().unwrap();
+        file.seek(SeekFrom::Start((block_id * BLOCK_SZ) as u64))
+            .expect("Error when seeking!");
+        file.write_all(buf).expect("Not complete blocks!");
+    }
+    fn num_blocks(&self) -> usize {
+        let len = self.0.lock().unwrap().metadata().unwrap().len();
+        len as usize / BLOCK_SZ
+    }
 }
 
-fn main() {
-    easy_fs_pack().expect("Error when packing easy-fs!");
+/// A block file that counts the read requests it serves
+#[cfg(test)]
+struct CountFile {
+    file: BlockFile,
+    reads: AtomicU32,
 }
 
-fn easy_fs_pack() -> std::io::Result<()> {
+#[cfg(test)]
+impl BlockDevice for CountFile {
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) {
+        self.reads.fetch_add(1, Ordering::Relaxed);
+        self.file.read_block(block_id, buf);
+    }
+    fn write_block(&self, block_id: usize, buf: &[u8]) {
+        self.file.write_block(block_id, buf);
+    }
+    fn handle_irq(&self) {
+        unimplemented!();
+    }
+    fn read_blocks(&self, block_id: usize, buf: &mut [u8]) {
+        self.reads.fetch_add(1, Ordering::Relaxed);
+        self.file.read_blocks(block_id, buf);
+    }
+    fn num_blocks(&self) -> usize {
+        self.file.num_blocks()
+    }
+}
+
+/// A block file that loses every write past the first `budget` ones,
+/// as if the machine was turned off
+#[cfg(test)]
+struct CrashFile {
+    file: Mutex<File>,
+    budget: Mutex<usize>,
+}
+
+#[cfg(test)]
+impl BlockDevice for CrashFile {
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) {
+        let mut file = self.file.lock().unwrap();
+        file.seek(SeekFrom::Start((block_id * BLOCK_SZ) as u64))
+            .expect("Error when seeking!");
+        assert_eq!(file.read(buf).unwrap(), BLOCK_SZ, "Not a complete block!");
+    }
+
+    fn write_block(&self, block_id: usize, buf: &[u8]) {
+        let mut budget = self.budget.lock().unwrap();
+        if *budget == 0 {
+            return;
+        }
+        *budget -= 1;
+        let mut file = self.file.lock().unwrap();
+        file.seek(SeekFrom::Start((block_id * BLOCK_SZ) as u64))
+            .expect("Error when seeking!");
+        assert_eq!(file.write(buf).unwrap(), BLOCK_SZ, "Not a complete block!");
+    }
+    fn handle_irq(&self) {
+        unimplemented!();
+    }
+    fn num_blocks(&self) -> usize {
+        let len = self.file.lock().unwrap().metadata().unwrap().len();
+        len as usize / BLOCK_SZ
+    }
+}
+
+fn main() {
+    easy_fs::set_clock(host_clock);
+    let image = || Arg::with_name("image").required(true).help("Image file");
+    let format_args = || {
+        [
+            Arg::with_name("size")
+                .long("size")
+                .takes_value(true)
+                .default_value("16")
+                .help("Image size in MiB"),
+            Arg::with_name("inodes")
+                .long("inodes")
+                .takes_value(true)
+                .default_value("4096")
+                .help("Number of inodes, rounded up to a multiple of 4096"),
+            Arg::with_name("short-names")
+                .long("short-names")
+                .help("Keep names to 27 bytes, for kernels without long name support"),
+            Arg::with_name("no-index")
+                .long("no-index")
+                .help("Search large directories linearly, without a hash index"),
+            Arg::with_name("no-extents")
+                .long("no-extents")
+                .help("Map file blocks with indirect blocks instead of extent trees"),
+        ]
+    };
     let matches = App::new("EasyFileSystem packer")
         .arg(
             Arg::with_name("source")
@@ -48,49 +162,454 @@ fn easy_fs_pack() -> std::io::Result<()> {
                 .takes_value(true)
                 .help("Executable target dir(with backslash)"),
         )
+        .arg(
+            Arg::with_name("root")
+                .short("r")
+                .long("root")
+                .takes_value(true)
+                .help("Host dir whose tree is copied to the root of the image"),
+        )
+        .arg(
+            Arg::with_name("output")
+                .short("o")
+                .long("output")
+                .takes_value(true)
+                .help("Image file, fs.img in the target dir by default"),
+        )
+        .args(&format_args())
+        .subcommand(
+            SubCommand::with_name("check")
+                .about("Verify an image, replaying its journal first")
+                .arg(image())
+                .arg(
+                    Arg::with_name("repair")
+                        .long("repair")
+                        .help("Fix the problems that can be fixed safely"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("mkfs")
+                .about("Create an empty image")
+                .arg(image())
+                .args(&format_args()),
+        )
+        .subcommand(
+            SubCommand::with_name("ls")
+                .about("List a directory")
+                .arg(image())
+                .arg(Arg::with_name("path").default_value("/")),
+        )
+        .subcommand(
+            SubCommand::with_name("tree")
+                .about("Show a directory tree")
+                .arg(image())
+                .arg(Arg::with_name("path").default_value("/")),
+        )
+        .subcommand(
+            SubCommand::with_name("cat")
+                .about("Print a file")
+                .arg(image())
+                .arg(Arg::with_name("path").required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("put")
+                .about("Copy a host file into the image")
+                .arg(image())
+                .arg(Arg::with_name("host").required(true))
+                .arg(Arg::with_name("path").required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("get")
+                .about("Copy a file of the image to the host")
+                .arg(image())
+                .arg(Arg::with_name("path").required(true))
+                .arg(Arg::with_name("host").required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("mkdir")
+                .about("Create a directory")
+                .arg(image())
+                .arg(Arg::with_name("path").required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("rm")
+                .about("Remove a file or an empty directory")
+                .arg(image())
+                .arg(Arg::with_name("path").required(true))
+                .arg(
+                    Arg::with_name("recursive")
+                        .short("r")
+                        .help("Remove directories and their content"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("mount")
+                .about("Serve an image through FUSE until it is unmounted")
+                .arg(image())
+                .arg(Arg::with_name("dir").required(true).help("Mount point")),
+        )
+        .subcommand(
+            SubCommand::with_name("df")
+                .about("Show block and inode usage")
+                .arg(image()),
+        )
         .get_matches();
-    let src_path = matches.value_of("source").unwrap();
-    let target_path = matches.value_of("target").unwrap();
-    println!("src_path = {}\ntarget_path = {}", src_path, target_path);
+    let result = match matches.subcommand() {
+        ("check", Some(matches)) => easy_fs_check(
+            matches.value_of("image").unwrap(),
+            matches.is_present("repair"),
+        )
+        .and_then(|clean| {
+            if clean {
+                Ok(())
+            } else {
+                Err(std::io::Error::other("inconsistent image"))
+            }
+        }),
+        ("mkfs", Some(matches)) => image_format(matches).and_then(|(blocks, inodes, features)| {
+            create_image(matches.value_of("image").unwrap(), blocks, inodes, features).map(|_| ())
+        }),
+        ("mount", Some(matches)) => open_image(matches.value_of("image").unwrap())
+            .and_then(|efs| mount::mount(efs, matches.value_of("dir").unwrap())),
+        (command, Some(matches)) => easy_fs_command(command, matches),
+        _ => easy_fs_pack(&matches),
+    };
+    if let Err(error) = result {
+        eprintln!("{}", error);
+        std::process::exit(1);
+    }
+}
+
+fn host_clock() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as u32)
+        .unwrap_or(0)
+}
+
+/// Blocks, inode bitmap blocks and features of a new image, from the format options
+fn image_format(matches: &ArgMatches) -> std::io::Result<(u32, u32, u32)> {
+    let number = |name: &str| -> std::io::Result<u32> {
+        matches
+            .value_of(name)
+            .unwrap()
+            .parse()
+            .map_err(|_| std::io::Error::other(format!("bad {}", name)))
+    };
+    let total_blocks = number("size")? * 1024 * 1024 / BLOCK_SZ as u32;
+    let inode_bitmap_blocks = number("inodes")?.div_ceil(BLOCK_SZ as u32 * 8).max(1);
+    // the inode area and the journal take some room too
+    if total_blocks < inode_bitmap_blocks * (1 + 1024) + 256 {
+        return Err(std::io::Error::other("image too small for the inodes"));
+    }
+    let mut features = if matches.is_present("short-names") {
+        0
+    } else if matches.is_present("no-index") {
+        FEATURE_LONG_NAMES
+    } else {
+        FEATURE_LONG_NAMES | FEATURE_DIR_INDEX
+    };
+    if !matches.is_present("no-extents") {
+        features |= FEATURE_EXTENTS;
+    }
+    Ok((total_blocks, inode_bitmap_blocks, features))
+}
+
+/// Format a new image at `path`
+fn create_image(
+    path: &str,
+    total_blocks: u32,
+    inode_bitmap_blocks: u32,
+    features: u32,
+) -> std::io::Result<Arc<spin::Mutex<EasyFileSystem>>> {
     let block_file = Arc::new(BlockFile(Mutex::new({
         let f = OpenOptions::new()
             .read(true)
             .write(true)
             .create(true)
-            .open(format!("{}{}", target_path, "fs.img"))?;
-        f.set_len(16 * 2048 * 512).unwrap();
+            .truncate(true)
+            .open(path)?;
+        f.set_len(total_blocks as u64 * BLOCK_SZ as u64)?;
         f
     })));
-    // 16MiB, at most 4095 files
-    let efs = EasyFileSystem::create(block_file, 16 * 2048, 1);
+    Ok(EasyFileSystem::create(
+        block_file,
+        total_blocks,
+        inode_bitmap_blocks,
+        features,
+    ))
+}
+
+fn open_image(path: &str) -> std::io::Result<Arc<spin::Mutex<EasyFileSystem>>> {
+    let block_file = Arc::new(BlockFile(Mutex::new(
+        OpenOptions::new().read(true).write(true).open(path)?,
+    )));
+    Ok(EasyFileSystem::open(block_file, CACHE_BLOCKS))
+}
+
+/// Check an image, return whether it is (or has been repaired to be) consistent
+fn easy_fs_check(image: &str, repair: bool) -> std::io::Result<bool> {
+    let efs = open_image(image)?;
+    let problems = EasyFileSystem::check(&efs);
+    for problem in problems.iter() {
+        println!("{}", problem);
+    }
+    if problems.is_empty() {
+        println!("{}: clean", image);
+        return Ok(true);
+    }
+    if !repair {
+        println!("{}: {} problems", image, problems.len());
+        return Ok(false);
+    }
+    EasyFileSystem::repair(&efs, &problems);
+    let remaining = EasyFileSystem::check(&efs);
+    for problem in remaining.iter() {
+        println!("not repaired: {}", problem);
+    }
+    println!(
+        "{}: {} problems, {} left after repair",
+        image,
+        problems.len(),
+        remaining.len()
+    );
+    Ok(remaining.is_empty())
+}
+
+/// Run a command on the files of an existing image
+fn easy_fs_command(command: &str, matches: &ArgMatches) -> std::io::Result<()> {
+    let efs = open_image(matches.value_of("image").unwrap())?;
+    let root = Arc::new(EasyFileSystem::root_inode(&efs));
+    let path = matches.value_of("path").unwrap_or("/");
+    let find = |path: &str| {
+        root.find(path)
+            .ok_or_else(|| std::io::Error::other(format!("{}: no such file or directory", path)))
+    };
+    let find_file = |path: &str| {
+        let inode = find(path)?;
+        if inode.is_dir() {
+            return Err(std::io::Error::other(format!("{}: is a directory", path)));
+        }
+        Ok(inode)
+    };
+    // parent directory and name of the last component
+    let split = |path: &str| {
+        let (dir, name) = path
+            .trim_end_matches('/')
+            .rsplit_once('/')
+            .unwrap_or(("", path));
+        if name.is_empty() {
+            return Err(std::io::Error::other(format!("{}: bad path", path)));
+        }
+        Ok((find(dir)?, name.to_string()))
+    };
+    match command {
+        "ls" => {
+            let inode = find(path)?;
+            if inode.is_dir() {
+                for name in inode.ls() {
+                    print_stat(&inode.find(&name).unwrap(), &name);
+                }
+            } else {
+                print_stat(&inode, path);
+            }
+        }
+        "tree" => tree(&find(path)?, path, 0),
+        "cat" => std::io::stdout().write_all(&read_all(&find_file(path)?))?,
+        "get" => std::fs::write(
+            matches.value_of("host").unwrap(),
+            read_all(&find_file(path)?),
+        )?,
+        "put" => {
+            let data = std::fs::read(matches.value_of("host").unwrap())?;
+            let (dir, name) = split(path)?;
+            let old = dir.find(&name);
+            if old.as_ref().is_some_and(|inode| inode.is_dir()) {
+                return Err(std::io::Error::other(format!("{}: is a directory", path)));
+            }
+            // the blocks of the file replaced are released before the write,
+            // which cannot be undone if it runs out of blocks halfway
+            let released = old.as_ref().map_or(0, |inode| inode.stat().blocks as usize);
+            if data.len() > MAX_FILE_SIZE
+                || blocks_needed(0, data.len())
+                    > efs.lock().stat().free_data_blocks as usize + released
+            {
+                return Err(std::io::Error::other(format!(
+                    "{}: no room left in the image",
+                    path
+                )));
+            }
+            let inode = match old {
+                Some(inode) => {
+                    inode.clear();
+                    inode
+                }
+                None => dir
+                    .create(&name)
+                    .ok_or_else(|| std::io::Error::other(format!("{}: cannot create", path)))?,
+            };
+            inode.write_at(0, &data);
+        }
+        "mkdir" => {
+            let (dir, name) = split(path)?;
+            dir.create_dir(&name)
+                .ok_or_else(|| std::io::Error::other(format!("{}: cannot create", path)))?;
+        }
+        "rm" => {
+            let (dir, name) = split(path)?;
+            find(path)?;
+            let removed = if matches.is_present("recursive") {
+                remove_all(&dir, &name)
+            } else {
+                dir.unlink(&name)
+            };
+            if !removed {
+                return Err(std::io::Error::other(format!("{}: cannot remove", path)));
+            }
+        }
+        "df" => {
+            let stat = efs.lock().stat();
+            println!("{:<8}{:>10}{:>10}{:>10}", "", "total", "used", "free");
+            println!(
+                "{:<8}{:>10}{:>10}{:>10}",
+                "blocks",
+                stat.data_blocks,
+                stat.data_blocks - stat.free_data_blocks,
+                stat.free_data_blocks
+            );
+            println!(
+                "{:<8}{:>10}{:>10}{:>10}",
+                "inodes",
+                stat.inodes,
+                stat.inodes - stat.free_inodes,
+                stat.free_inodes
+            );
+        }
+        _ => unreachable!(),
+    }
+    Ok(())
+}
+
+fn easy_fs_pack(matches: &ArgMatches) -> std::io::Result<()> {
+    let target_path = matches.value_of("target");
+    let image = match (matches.value_of("output"), target_path) {
+        (Some(output), _) => String::from(output),
+        (None, Some(target_path)) => format!("{}{}", target_path, "fs.img"),
+        _ => return Err(std::io::Error::other("no image to write, give --output")),
+    };
+    // the same input gives the same image, at the time SOURCE_DATE_EPOCH asks for if any
+    let time = std::env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|time| time.parse().ok())
+        .unwrap_or(0);
+    PACK_TIME.store(time, Ordering::Relaxed);
+    easy_fs::set_clock(pack_clock);
+    // 16MiB and 4096 inodes unless told otherwise
+    let (total_blocks, inode_bitmap_blocks, features) = image_format(matches)?;
+    let efs = create_image(&image, total_blocks, inode_bitmap_blocks, features)?;
     let root_inode = Arc::new(EasyFileSystem::root_inode(&efs));
-    let bin_inode = root_inode.create_dir("bin").unwrap();
-    let apps: Vec<_> = read_dir(src_path)
-        .unwrap()
-        .into_iter()
-        .map(|dir_entry| {
-            let mut name_with_ext = dir_entry.unwrap().file_name().into_string().unwrap();
-            name_with_ext.drain(name_with_ext.find('.').unwrap()..name_with_ext.len());
-            name_with_ext
-        })
-        .collect();
-    for app in apps {
-        // load app data from host file system
-        let mut host_file = File::open(format!("{}{}", target_path, app)).unwrap();
-        let mut all_data: Vec<u8> = Vec::new();
-        host_file.read_to_end(&mut all_data).unwrap();
-        // create a file in easy-fs
-        let inode = bin_inode.create(app.as_str()).unwrap();
-        // write data to easy-fs
-        inode.write_at(0, all_data.as_slice());
+    if let Some(root_path) = matches.value_of("root") {
+        pack_dir(&efs, &root_inode, Path::new(root_path))?;
+    }
+    if let (Some(src_path), Some(target_path)) = (matches.value_of("source"), target_path) {
+        println!("src_path = {}\ntarget_path = {}", src_path, target_path);
+        let bin_inode = match root_inode.find("bin") {
+            Some(bin_inode) => bin_inode,
+            None => root_inode.create_dir("bin").unwrap(),
+        };
+        // an app is named after its source file, extension excluded
+        let mut apps: Vec<String> = Vec::new();
+        for dir_entry in read_dir(src_path)? {
+            let path = dir_entry?.path();
+            if let Some(app) = path.file_stem().and_then(|stem| stem.to_str()) {
+                apps.push(String::from(app));
+            }
+        }
+        apps.sort();
+        for app in apps {
+            pack_file(&efs, &bin_inode, &app, &Path::new(target_path).join(&app))?;
+        }
     }
-    // list apps
-    // for app in root_inode.ls() {
-    //     println!("{}", app);
-    // }
     Ok(())
 }
 
+/// Time of everything packed, see [`easy_fs_pack`]
+static PACK_TIME: AtomicU32 = AtomicU32::new(0);
+
+fn pack_clock() -> u32 {
+    PACK_TIME.load(Ordering::Relaxed)
+}
+
+/// Mirror the host directory `host_dir` into `dir`, in name order
+fn pack_dir(
+    efs: &Arc<spin::Mutex<EasyFileSystem>>,
+    dir: &Arc<Inode>,
+    host_dir: &Path,
+) -> std::io::Result<()> {
+    let mut paths: Vec<PathBuf> = Vec::new();
+    for dir_entry in read_dir(host_dir)? {
+        paths.push(dir_entry?.path());
+    }
+    paths.sort();
+    for path in paths {
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| {
+                std::io::Error::other(format!("{}: name is not UTF-8", path.display()))
+            })?;
+        let metadata = std::fs::metadata(&path)?;
+        if metadata.is_dir() {
+            let inode = match dir.find(name) {
+                Some(inode) if inode.is_dir() => inode,
+                _ => dir
+                    .create_dir(name)
+                    .ok_or_else(|| cannot_pack(efs, &path))?,
+            };
+            pack_dir(efs, &inode, &path)?;
+        } else if metadata.is_file() {
+            pack_file(efs, dir, name, &path)?;
+        } else {
+            eprintln!(
+                "{}: skipped, neither a file nor a directory",
+                path.display()
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Copy the host file at `host_path` into `dir` as `name`
+fn pack_file(
+    efs: &Arc<spin::Mutex<EasyFileSystem>>,
+    dir: &Arc<Inode>,
+    name: &str,
+    host_path: &Path,
+) -> std::io::Result<()> {
+    let data = std::fs::read(host_path)?;
+    if data.len() > MAX_FILE_SIZE
+        || blocks_needed(0, data.len()) > efs.lock().stat().free_data_blocks as usize
+    {
+        return Err(std::io::Error::other(format!(
+            "{}: no room left in the image",
+            host_path.display()
+        )));
+    }
+    let inode = dir
+        .create(name)
+        .ok_or_else(|| cannot_pack(efs, host_path))?;
+    inode.write_at(0, &data);
+    Ok(())
+}
+
+fn cannot_pack(efs: &Arc<spin::Mutex<EasyFileSystem>>, host_path: &Path) -> std::io::Error {
+    std::io::Error::other(format!(
+        "{}: cannot be packed, its name is taken or longer than {} bytes",
+        host_path.display(),
+        efs.lock().name_length_limit()
+    ))
+}
+
 #[allow(dead_code)]
 fn read_string(file: &Arc<Inode>) -> String {
     let mut read_buffer = [0u8; 512];
@@ -107,7 +626,19 @@ fn read_string(file: &Arc<Inode>) -> String {
     read_str
 }
 
-#[allow(dead_code)]
+fn read_all(file: &Arc<Inode>) -> Vec<u8> {
+    let mut data = vec![0u8; file.size() as usize];
+    let len = file.read_at(0, &mut data);
+    data.truncate(len);
+    data
+}
+
+fn print_stat(inode: &Arc<Inode>, name: &str) {
+    let stat = inode.stat();
+    let type_ = if inode.is_dir() { 'd' } else { '-' };
+    println!("{} {:>5} {:>10} {}", type_, stat.ino, stat.size, name);
+}
+
 fn tree(inode: &Arc<Inode>, name: &str, depth: usize) {
     for _ in 0..depth {
         print!("  ");
@@ -122,6 +653,20 @@ fn tree(inode: &Arc<Inode>, name: &str, depth: usize) {
     }
 }
 
+/// Unlink `name` from `dir`, emptying it first if it is a directory
+fn remove_all(dir: &Arc<Inode>, name: &str) -> bool {
+    if let Some(inode) = dir.find(name) {
+        if inode.is_dir() {
+            for child in inode.ls() {
+                if child != "." && child != ".." {
+                    remove_all(&inode, &child);
+                }
+            }
+        }
+    }
+    dir.unlink(name)
+}
+
 #[test]
 fn efs_test() -> std::io::Result<()> {
     let block_file = Arc::new(BlockFile(Mutex::new({
@@ -129,12 +674,13 @@ fn efs_test() -> std::io::Result<()> {
             .read(true)
             .write(true)
             .create(true)
+            .truncate(true)
             .open("target/fs.img")?;
         f.set_len(8192 * 512).unwrap();
         f
     })));
-    EasyFileSystem::create(block_file.clone(), 4096, 1);
-    let efs = EasyFileSystem::open(block_file.clone());
+    EasyFileSystem::create(block_file.clone(), 4096, 1, 0);
+    let efs = EasyFileSystem::open(block_file.clone(), BLOCK_CACHE_SIZE);
     let root_inode = EasyFileSystem::root_inode(&efs);
     root_inode.create("filea");
     root_inode.create("fileb");
@@ -156,7 +702,7 @@ fn efs_test() -> std::io::Result<()> {
         use rand;
         // random digit
         for _ in 0..len {
-            str.push(char::from('0' as u8 + rand::random::<u8>() % 10));
+            str.push(char::from(b'0' + rand::random::<u8>() % 10));
         }
         filea.write_at(0, str.as_bytes());
         let mut read_buffer = [0u8; 127];
@@ -192,12 +738,13 @@ fn efs_dir_test() -> std::io::Result<()> {
             .read(true)
             .write(true)
             .create(true)
+            .truncate(true)
             .open("target/fs.img")?;
         f.set_len(8192 * 512).unwrap();
         f
     })));
-    EasyFileSystem::create(block_file.clone(), 4096, 1);
-    let efs = EasyFileSystem::open(block_file.clone());
+    EasyFileSystem::create(block_file.clone(), 4096, 1, 0);
+    let efs = EasyFileSystem::open(block_file.clone(), BLOCK_CACHE_SIZE);
     let root = Arc::new(EasyFileSystem::root_inode(&efs));
 
     let root_parent = root.get_parent();
@@ -249,3 +796,1160 @@ fn efs_dir_test() -> std::io::Result<()> {
     assert!(f3.find("whatever").is_none());
     Ok(())
 }
+
+#[test]
+fn efs_stat_test() -> std::io::Result<()> {
+    let block_file = Arc::new(BlockFile(Mutex::new({
+        let f = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open("target/fs.img")?;
+        f.set_len(8192 * 512).unwrap();
+        f
+    })));
+    easy_fs::set_clock(host_clock);
+    let before = host_clock();
+    EasyFileSystem::create(block_file.clone(), 4096, 1, 0);
+    let efs = EasyFileSystem::open(block_file.clone(), BLOCK_CACHE_SIZE);
+    let root = Arc::new(EasyFileSystem::root_inode(&efs));
+
+    let stat = root.stat();
+    assert_eq!(stat.ino, 0);
+    assert_eq!(stat.type_, easy_fs::DiskInodeType::Directory);
+    assert_eq!(stat.nlink, 2);
+
+    let f1 = root.create("f1").unwrap();
+    let stat = f1.stat();
+    assert_eq!(stat.ino, 1);
+    assert_eq!(stat.type_, easy_fs::DiskInodeType::File);
+    assert_eq!((stat.size, stat.blocks, stat.nlink), (0, 0, 1));
+    assert!(stat.mtime >= before && stat.mtime <= host_clock());
+
+    // 30 data blocks: 25 direct, 1 indirect1 and 5 behind it
+    f1.write_at(0, &[1u8; 30 * BLOCK_SZ]);
+    let stat = f1.stat();
+    assert_eq!((stat.size, stat.blocks), (30 * BLOCK_SZ as u32, 31));
+
+    let d1 = root.create_dir("d1").unwrap();
+    d1.create_dir("d2").unwrap();
+    assert!(d1.is_dir() && !f1.is_dir());
+    assert_eq!(root.stat().nlink, 3);
+    assert_eq!(d1.stat().nlink, 3);
+    assert_eq!(d1.stat().ino, 2);
+
+    // an image of the layout before the timestamps is refused
+    let mut image = vec![0u8; 16 * BLOCK_SZ];
+    image[..4].copy_from_slice(&0x3b80_0001u32.to_le_bytes());
+    std::fs::write("target/fs-v1.img", image)?;
+    let old: Arc<dyn BlockDevice> = Arc::new(BlockFile(Mutex::new(
+        OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open("target/fs-v1.img")?,
+    )));
+    assert!(!EasyFileSystem::detect(&old));
+    Ok(())
+}
+
+#[test]
+fn efs_sparse_test() -> std::io::Result<()> {
+    let block_file = Arc::new(BlockFile(Mutex::new({
+        let f = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open("target/fs.img")?;
+        f.set_len(8192 * 512).unwrap();
+        f
+    })));
+    EasyFileSystem::create(block_file.clone(), 4096, 1, 0);
+    let efs = EasyFileSystem::open(block_file.clone(), BLOCK_CACHE_SIZE);
+    let root = Arc::new(EasyFileSystem::root_inode(&efs));
+    let f1 = root.create("f1").unwrap();
+    f1.write_at(0, b"head");
+    // skip past the direct blocks
+    let offset = 30 * BLOCK_SZ + 7;
+    assert_eq!(f1.write_at(offset, b"tail"), 4);
+    assert_eq!(f1.size() as usize, offset + 4);
+    let mut buffer = vec![0xffu8; offset + 4];
+    assert_eq!(f1.read_at(0, &mut buffer), offset + 4);
+    assert_eq!(&buffer[..4], b"head");
+    assert!(buffer[4..offset].iter().all(|b| *b == 0));
+    assert_eq!(&buffer[offset..], b"tail");
+    // writes past the largest file or the free blocks are refused whole
+    assert_eq!(f1.write_at(MAX_FILE_SIZE, b"x"), 0);
+    assert_eq!(f1.write_at(4096 * BLOCK_SZ, b"x"), 0);
+    assert_eq!(f1.size() as usize, offset + 4);
+    assert_eq!(f1.write_at(offset + 4, b"more"), 4);
+    Ok(())
+}
+
+#[test]
+fn efs_truncate_test() -> std::io::Result<()> {
+    let block_file = Arc::new(BlockFile(Mutex::new({
+        let f = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open("target/fs.img")?;
+        f.set_len(8192 * 512).unwrap();
+        f
+    })));
+    // too small to hold two copies of `data` unless truncated blocks are released
+    EasyFileSystem::create(block_file.clone(), 2400, 1, 0);
+    let efs = EasyFileSystem::open(block_file.clone(), BLOCK_CACHE_SIZE);
+    let root = Arc::new(EasyFileSystem::root_inode(&efs));
+    let f1 = root.create("f1").unwrap();
+    let data: Vec<u8> = (0..1000 * BLOCK_SZ).map(|i| (i % 251) as u8).collect();
+    assert_eq!(f1.write_at(0, &data), data.len());
+    // shrink across indirect2, indirect1 and direct blocks
+    for size in [
+        700 * BLOCK_SZ + 3,
+        200 * BLOCK_SZ,
+        100 * BLOCK_SZ + 9,
+        3 * BLOCK_SZ + 1,
+        0,
+    ] {
+        f1.truncate(size as u32);
+        assert_eq!(f1.size() as usize, size);
+        // data blocks plus the indirect1 block and the indirect2 index blocks
+        let data_blocks = size.div_ceil(BLOCK_SZ);
+        let mut blocks = data_blocks;
+        if data_blocks > 25 {
+            blocks += 1;
+        }
+        if data_blocks > 25 + 128 {
+            blocks += 1 + (data_blocks - 25 - 128).div_ceil(128);
+        }
+        assert_eq!(f1.stat().blocks as usize, blocks);
+        let mut buffer = vec![0u8; size];
+        assert_eq!(f1.read_at(0, &mut buffer), size);
+        assert!(buffer == data[..size]);
+    }
+    // released blocks are reused and grown space reads as zeros
+    f1.write_at(0, &data[..BLOCK_SZ + 5]);
+    f1.truncate(5);
+    f1.truncate(2 * BLOCK_SZ as u32);
+    let mut buffer = vec![0xffu8; 2 * BLOCK_SZ];
+    assert_eq!(f1.read_at(0, &mut buffer), 2 * BLOCK_SZ);
+    assert_eq!(&buffer[..5], &data[..5]);
+    assert!(buffer[5..].iter().all(|b| *b == 0));
+    let f2 = root.create("f2").unwrap();
+    assert_eq!(f2.write_at(0, &data), data.len());
+    // growing past the largest file or the free blocks changes nothing
+    assert!(!f1.truncate(MAX_FILE_SIZE as u32 + 1));
+    assert!(!f1.truncate(2400 * BLOCK_SZ as u32));
+    assert_eq!(f1.size() as usize, 2 * BLOCK_SZ);
+    Ok(())
+}
+
+#[test]
+fn efs_crash_test() -> std::io::Result<()> {
+    {
+        let block_file = Arc::new(BlockFile(Mutex::new({
+            let f = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open("target/fs.img")?;
+            f.set_len(2048 * 512).unwrap();
+            f
+        })));
+        EasyFileSystem::create(block_file, 2048, 1, 0);
+    }
+    let image = std::fs::read("target/fs.img")?;
+    // crash after every possible number of writes, until none is lost
+    for budget in 0.. {
+        std::fs::write("target/fs.img", &image)?;
+        let crash_file = Arc::new(CrashFile {
+            file: Mutex::new(
+                OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .open("target/fs.img")?,
+            ),
+            budget: Mutex::new(budget),
+        });
+        let efs = EasyFileSystem::open(crash_file.clone(), BLOCK_CACHE_SIZE);
+        let root = EasyFileSystem::root_inode(&efs);
+        root.create_dir("d").unwrap();
+        root.create("f").unwrap().write_at(0, b"hello");
+        let crashed = *crash_file.budget.lock().unwrap() == 0;
+
+        // recover from what reached the disk
+        let block_file = Arc::new(BlockFile(Mutex::new(
+            OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open("target/fs.img")?,
+        )));
+        let efs = EasyFileSystem::open(block_file, BLOCK_CACHE_SIZE);
+        let root = EasyFileSystem::root_inode(&efs);
+        let names = root.ls();
+        assert!(["d", "f"][..names.len()] == names, "{:?}", names);
+        let mut inos = vec![0];
+        if let Some(d) = root.find("d") {
+            assert_eq!(d.ls(), [".", ".."]);
+            inos.push(d.stat().ino);
+        }
+        if let Some(f) = root.find("f") {
+            let mut buffer = [0u8; 8];
+            let len = f.read_at(0, &mut buffer);
+            assert!(
+                len == 0 || &buffer[..len] == b"hello" || buffer[..len] == [0; 5],
+                "{:?}",
+                &buffer[..len]
+            );
+            inos.push(f.stat().ino);
+        }
+        // the recovered image is still usable and allocates fresh inodes
+        let g = root.create("g").unwrap();
+        g.write_at(0, b"world");
+        assert!(!inos.contains(&g.stat().ino));
+        if let Some(d) = root.find("d") {
+            assert_eq!(d.ls(), [".", ".."]);
+        }
+        if !crashed {
+            assert_eq!(names, ["d", "f"]);
+            break;
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn efs_journal_devices_test() -> std::io::Result<()> {
+    let image = |path: &str| -> std::io::Result<Arc<dyn BlockDevice>> {
+        let f = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        f.set_len(2048 * 512).unwrap();
+        Ok(Arc::new(BlockFile(Mutex::new(f))))
+    };
+    let (a, b) = (image("target/fs-a.img")?, image("target/fs-b.img")?);
+    EasyFileSystem::create(a.clone(), 2048, 1, 0);
+    EasyFileSystem::create(b.clone(), 2048, 1, 0);
+    let efs_a = EasyFileSystem::open(a.clone(), BLOCK_CACHE_SIZE);
+    let efs_b = EasyFileSystem::open(b.clone(), BLOCK_CACHE_SIZE);
+    // a transaction on one device neither blocks nor takes in the other's
+    efs_a.lock().begin();
+    let root_b = EasyFileSystem::root_inode(&efs_b);
+    let file = root_b.create("f").unwrap();
+    assert_eq!(file.write_at(0, b"beside a transaction"), 20);
+    efs_a.lock().commit();
+    drop((file, root_b, efs_b));
+
+    let reopened: Arc<dyn BlockDevice> = Arc::new(BlockFile(Mutex::new(
+        OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open("target/fs-b.img")?,
+    )));
+    let efs_b = EasyFileSystem::open(reopened, BLOCK_CACHE_SIZE);
+    let file = EasyFileSystem::root_inode(&efs_b).find("f").unwrap();
+    assert_eq!(read_all(&file), b"beside a transaction");
+    Ok(())
+}
+
+#[test]
+fn efs_check_test() -> std::io::Result<()> {
+    let open_image = || -> std::io::Result<Arc<BlockFile>> {
+        Ok(Arc::new(BlockFile(Mutex::new(
+            OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open("target/fs.img")?,
+        ))))
+    };
+    let block_file = Arc::new(BlockFile(Mutex::new({
+        let f = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open("target/fs.img")?;
+        f.set_len(4096 * 512).unwrap();
+        f
+    })));
+    EasyFileSystem::create(block_file.clone(), 4096, 1, 0);
+    let efs = EasyFileSystem::open(block_file.clone(), BLOCK_CACHE_SIZE);
+    let root = EasyFileSystem::root_inode(&efs);
+    let f = root.create("f").unwrap();
+    f.write_at(0, &[1u8; 3 * BLOCK_SZ]);
+    let d1 = root.create_dir("d1").unwrap();
+    let d2 = d1.create_dir("d2").unwrap();
+    assert_eq!(EasyFileSystem::check(&efs), []);
+
+    // a second ".." in d2, to the root instead of d1
+    d2.create_dir_link("..", 0);
+    // with 4096 blocks and one inode bitmap block, the inode bitmap is
+    // block 1 and the data bitmap block 1026
+    let mut block = [0u8; BLOCK_SZ];
+    block_file.read_block(1, &mut block);
+    // free the inode of "f", and allocate an unused one
+    block[0] &= !(1 << 1);
+    block[100 / 8] |= 1 << (100 % 8);
+    block_file.write_block(1, &block);
+    block_file.read_block(1026, &mut block);
+    block[200] = 0xff;
+    block_file.write_block(1026, &block);
+
+    // a new device, so that nothing is served from the block cache
+    let efs = EasyFileSystem::open(open_image()?, BLOCK_CACHE_SIZE);
+    let problems = EasyFileSystem::check(&efs);
+    let (d1_ino, d2_ino) = (d1.stat().ino, d2.stat().ino);
+    assert!(problems.contains(&easy_fs::Problem::DanglingEntry {
+        dir: 0,
+        name: String::from("f"),
+        inode: 1,
+    }));
+    assert!(problems.contains(&easy_fs::Problem::BadDotEntry {
+        dir: d2_ino,
+        name: "..",
+        expected: d1_ino,
+    }));
+    assert!(problems.contains(&easy_fs::Problem::LeakedInode {
+        inode: 100,
+        size: 0
+    }));
+    // 3 blocks of "f" and 8 marked by hand
+    let leaked = problems
+        .iter()
+        .filter(|problem| matches!(problem, easy_fs::Problem::LeakedBlock { .. }))
+        .count();
+    assert_eq!(leaked, 3 + 8);
+    assert_eq!(problems.len(), 3 + leaked);
+    assert!(problems.iter().all(|problem| problem.is_repairable()));
+
+    EasyFileSystem::repair(&efs, &problems);
+    assert_eq!(EasyFileSystem::check(&efs), []);
+    let root = EasyFileSystem::root_inode(&efs);
+    assert_eq!(root.ls(), ["d1"]);
+    let d2 = root.find("d1/d2").unwrap();
+    assert_eq!(d2.get_parent().unwrap().stat().ino, d1_ino);
+    Ok(())
+}
+
+#[test]
+fn efs_unlink_test() -> std::io::Result<()> {
+    let block_file = Arc::new(BlockFile(Mutex::new({
+        let f = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open("target/fs_unlink.img")?;
+        f.set_len(4096 * 512).unwrap();
+        f
+    })));
+    EasyFileSystem::create(block_file.clone(), 4096, 1, 0);
+    let efs = EasyFileSystem::open(block_file.clone(), BLOCK_CACHE_SIZE);
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let empty = efs.lock().stat();
+    assert_eq!(empty.inodes - empty.free_inodes, 1);
+
+    let file = root_inode.create("file").unwrap();
+    file.write_at(0, &[7u8; 40 * BLOCK_SZ]);
+    let dir = root_inode.create_dir("dir").unwrap();
+    dir.create("inner").unwrap();
+    let full = efs.lock().stat();
+    assert_eq!(full.free_inodes, empty.free_inodes - 3);
+    assert!(full.free_data_blocks < empty.free_data_blocks - 40);
+
+    // names that never name a removable entry
+    assert!(!root_inode.unlink("."));
+    assert!(!root_inode.unlink("missing"));
+    // a directory goes only once it is empty
+    assert!(!root_inode.unlink("dir"));
+    assert!(dir.unlink("inner"));
+    assert!(dir.find("inner").is_none());
+    assert!(root_inode.unlink("dir"));
+    // a removed directory still in use takes no new entry
+    assert!(dir.create("late").is_none());
+    drop(dir);
+    // an open file keeps its inode and blocks until its last handle goes
+    assert!(root_inode.unlink("file"));
+    assert!(root_inode.ls().is_empty());
+    assert_eq!(file.stat().nlink, 0);
+    let other = root_inode.create("other").unwrap();
+    assert_ne!(other.stat().ino, file.stat().ino);
+    other.write_at(0, &[1u8; BLOCK_SZ]);
+    let mut buf = [0u8; BLOCK_SZ];
+    assert_eq!(file.read_at(39 * BLOCK_SZ, &mut buf), BLOCK_SZ);
+    assert_eq!(buf, [7u8; BLOCK_SZ]);
+    drop(file);
+    drop(other);
+    assert!(root_inode.unlink("other"));
+
+    let stat = efs.lock().stat();
+    assert_eq!(stat.free_inodes, empty.free_inodes);
+    assert_eq!(stat.free_data_blocks, empty.free_data_blocks);
+    assert!(EasyFileSystem::check(&efs).is_empty());
+    Ok(())
+}
+
+#[test]
+fn efs_pack_test() -> std::io::Result<()> {
+    let host_root = Path::new("target/pack_root");
+    let _ = std::fs::remove_dir_all(host_root);
+    std::fs::create_dir_all(host_root.join("etc/conf.d"))?;
+    std::fs::create_dir_all(host_root.join("empty"))?;
+    std::fs::write(host_root.join("etc/motd"), "hello\n")?;
+    std::fs::write(host_root.join("etc/conf.d/a.b.c"), "")?;
+    let blob: Vec<u8> = (0..100 * BLOCK_SZ).map(|i| (i * 7 % 251) as u8).collect();
+    std::fs::write(host_root.join(".blob"), &blob)?;
+
+    easy_fs::set_clock(pack_clock);
+    let mut images = Vec::new();
+    for path in ["target/fs_pack1.img", "target/fs_pack2.img"] {
+        let efs = create_image(path, 4096, 1, FEATURE_LONG_NAMES)?;
+        let root_inode = Arc::new(EasyFileSystem::root_inode(&efs));
+        pack_dir(&efs, &root_inode, host_root)?;
+        assert_eq!(root_inode.ls(), [".blob", "empty", "etc"]);
+        assert_eq!(read_all(&root_inode.find(".blob").unwrap()), blob);
+        assert_eq!(
+            read_string(&root_inode.find("etc/motd").unwrap()),
+            "hello\n"
+        );
+        assert_eq!(root_inode.find("etc/conf.d/a.b.c").unwrap().size(), 0);
+        assert!(root_inode.find("empty").unwrap().is_dir());
+        assert!(EasyFileSystem::check(&efs).is_empty());
+        images.push(std::fs::read(path)?);
+    }
+    easy_fs::set_clock(host_clock);
+    assert!(images[0] == images[1]);
+
+    // names that do not fit are reported, not cut
+    std::fs::write(host_root.join("n".repeat(28)), "")?;
+    let efs = create_image("target/fs_pack1.img", 4096, 1, 0)?;
+    let root_inode = Arc::new(EasyFileSystem::root_inode(&efs));
+    assert!(pack_dir(&efs, &root_inode, host_root).is_err());
+    Ok(())
+}
+
+#[test]
+fn efs_long_name_test() -> std::io::Result<()> {
+    let block_file = Arc::new(BlockFile(Mutex::new({
+        let f = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open("target/fs_long.img")?;
+        f.set_len(4096 * 512).unwrap();
+        f
+    })));
+    EasyFileSystem::create(block_file.clone(), 4096, 1, FEATURE_LONG_NAMES);
+    let efs = EasyFileSystem::open(block_file.clone(), BLOCK_CACHE_SIZE);
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let names: Vec<String> = (1..=255)
+        .step_by(6)
+        .map(|len| format!("{:x<1$}", len % 10, len))
+        .collect();
+    for name in names.iter() {
+        root_inode.create(name).unwrap();
+    }
+    assert!(root_inode.create(&"x".repeat(256)).is_none());
+    let dir = root_inode.create_dir(&"d".repeat(255)).unwrap();
+    assert_eq!(dir.get_name().unwrap(), "d".repeat(255));
+    assert_eq!(root_inode.ls()[..names.len()], names[..]);
+    let size = root_inode.size();
+
+    // holes left by removed entries are reused before the directory grows
+    for name in names.iter().step_by(2) {
+        assert!(root_inode.unlink(name));
+    }
+    assert_eq!(root_inode.size(), size);
+    for name in names.iter().step_by(2) {
+        root_inode.create(name).unwrap();
+    }
+    assert_eq!(root_inode.size(), size);
+    assert!(EasyFileSystem::check(&efs).is_empty());
+
+    // and the directory shrinks when its last entries go
+    drop(dir);
+    assert!(root_inode.unlink(&"d".repeat(255)));
+    for name in names.iter() {
+        assert!(root_inode.unlink(name));
+    }
+    assert_eq!(root_inode.size(), 0);
+    root_inode.create("a").unwrap();
+    assert!(EasyFileSystem::check(&efs).is_empty());
+
+    // the original format keeps its limit
+    EasyFileSystem::create(block_file.clone(), 4096, 1, 0);
+    let efs = EasyFileSystem::open(block_file.clone(), BLOCK_CACHE_SIZE);
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    assert!(root_inode.create(&"x".repeat(28)).is_none());
+    root_inode.create(&"x".repeat(27)).unwrap();
+    assert_eq!(root_inode.ls(), ["x".repeat(27)]);
+    Ok(())
+}
+
+#[test]
+fn efs_index_test() -> std::io::Result<()> {
+    let names: Vec<String> = (0..600).map(|i| format!("bin{}", i)).collect();
+    let mut listings = Vec::new();
+    let mut used_inodes = Vec::new();
+    for (path, features) in [
+        (
+            "target/fs_index.img",
+            FEATURE_LONG_NAMES | FEATURE_DIR_INDEX,
+        ),
+        ("target/fs_linear.img", FEATURE_LONG_NAMES),
+    ] {
+        let efs = create_image(path, 8192, 1, features)?;
+        let root_inode = EasyFileSystem::root_inode(&efs);
+        let dir = root_inode.create_dir("bin").unwrap();
+        for name in names.iter() {
+            dir.create(name).unwrap().write_at(0, name.as_bytes());
+        }
+        // the index is rebuilt as the directory grows
+        for name in names.iter() {
+            assert_eq!(read_string(&dir.find(name).unwrap()), *name);
+            assert!(dir.create(name).is_none());
+        }
+        assert!(dir.find("bin600").is_none());
+        assert_eq!(root_inode.find("bin/..").unwrap().get_name().unwrap(), "/");
+        let stat = efs.lock().stat();
+        used_inodes.push(stat.inodes - stat.free_inodes);
+        assert!(EasyFileSystem::check(&efs).is_empty());
+
+        // removed entries leave the index, and their slots are reused
+        for name in names.iter().step_by(3) {
+            assert!(dir.unlink(name));
+            assert!(dir.find(name).is_none());
+        }
+        for name in names.iter().step_by(6) {
+            dir.create(name).unwrap();
+        }
+        for (i, name) in names.iter().enumerate() {
+            assert_eq!(dir.find(name).is_some(), i % 3 != 0 || i % 6 == 0);
+        }
+        assert!(EasyFileSystem::check(&efs).is_empty());
+        listings.push(dir.ls());
+
+        // the index goes with its directory
+        for name in dir.ls().iter().filter(|name| *name != "." && *name != "..") {
+            assert!(dir.unlink(name));
+        }
+        drop(dir);
+        assert!(root_inode.unlink("bin"));
+        assert!(EasyFileSystem::check(&efs).is_empty());
+        let stat = efs.lock().stat();
+        assert_eq!(stat.free_inodes, stat.inodes - 1);
+    }
+    assert_eq!(listings[0], listings[1]);
+    // the index takes an inode of its own
+    assert_eq!(used_inodes[0], used_inodes[1] + 1);
+    Ok(())
+}
+
+#[test]
+fn efs_cache_test() -> std::io::Result<()> {
+    create_image("target/fs_cache.img", 4096, 1, FEATURE_LONG_NAMES)?;
+    // every open of the image is a device of its own, with blocks of its own in the cache
+    let mut devices: Vec<Arc<BlockFile>> = Vec::new();
+    let mut open_image = |cache_blocks: usize| -> std::io::Result<_> {
+        let block_file = Arc::new(BlockFile(Mutex::new(
+            OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open("target/fs_cache.img")?,
+        )));
+        devices.push(block_file.clone());
+        Ok(EasyFileSystem::open(block_file, cache_blocks))
+    };
+
+    // a single block of cache grows while more are in use, and shrinks back
+    let efs = open_image(1)?;
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let dir = root_inode.create_dir("d").unwrap();
+    let data: Vec<u8> = (0..40 * BLOCK_SZ).map(|i| (i % 251) as u8).collect();
+    for i in 0..40 {
+        let file = dir.create(&format!("f{}", i)).unwrap();
+        file.write_at(0, &data[..i * BLOCK_SZ]);
+    }
+    for i in 0..40 {
+        let file = dir.find(&format!("f{}", i)).unwrap();
+        assert_eq!(read_all(&file), data[..i * BLOCK_SZ]);
+    }
+    assert!(EasyFileSystem::check(&efs).is_empty());
+
+    // blocks modified outside of a transaction reach the image on a background flush,
+    // the cache is large enough for them to stay until then
+    easy_fs::set_clock(pack_clock);
+    PACK_TIME.store(200, Ordering::Relaxed);
+    let efs = open_image(64)?;
+    efs.lock().set_flush_interval(5);
+    efs.lock().sync();
+    let file = EasyFileSystem::root_inode(&efs).find("d/f1").unwrap();
+    file.read_at(0, &mut [0u8; 1]);
+    PACK_TIME.store(204, Ordering::Relaxed);
+    assert!(!EasyFileSystem::flush_if_due(&efs));
+    let on_disk = EasyFileSystem::root_inode(&open_image(64)?);
+    assert_ne!(on_disk.find("d/f1").unwrap().stat().atime, 200);
+    PACK_TIME.store(205, Ordering::Relaxed);
+    assert!(EasyFileSystem::flush_if_due(&efs));
+    let on_disk = EasyFileSystem::root_inode(&open_image(64)?);
+    assert_eq!(on_disk.find("d/f1").unwrap().stat().atime, 200);
+
+    // fsync writes back the inode of a file at once, fdatasync only its content
+    PACK_TIME.store(210, Ordering::Relaxed);
+    file.read_at(0, &mut [0u8; 1]);
+    file.sync(true);
+    let on_disk = EasyFileSystem::root_inode(&open_image(64)?);
+    assert_eq!(on_disk.find("d/f1").unwrap().stat().atime, 200);
+    file.sync(false);
+    let on_disk = EasyFileSystem::root_inode(&open_image(64)?);
+    assert_eq!(on_disk.find("d/f1").unwrap().stat().atime, 210);
+    easy_fs::set_clock(host_clock);
+    Ok(())
+}
+
+#[test]
+fn efs_read_ahead_test() -> std::io::Result<()> {
+    let efs = create_image("target/fs_read_ahead.img", 4096, 1, FEATURE_LONG_NAMES)?;
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let data: Vec<u8> = (0..200 * BLOCK_SZ).map(|i| (i % 253) as u8).collect();
+    root_inode.create("elf").unwrap().write_at(0, &data);
+    efs.lock().sync();
+
+    // a new device, so that nothing is served from the block cache
+    let count_file = Arc::new(CountFile {
+        file: BlockFile(Mutex::new(
+            OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open("target/fs_read_ahead.img")?,
+        )),
+        reads: AtomicU32::new(0),
+    });
+    let efs = EasyFileSystem::open(count_file.clone(), 64);
+    let file = EasyFileSystem::root_inode(&efs).find("elf").unwrap();
+    let reads = count_file.reads.load(Ordering::Relaxed);
+    // block by block, as a loader reading its way through the file
+    let mut buffer = [0u8; BLOCK_SZ];
+    let mut read = Vec::new();
+    while read.len() < data.len() {
+        let len = file.read_at(read.len(), &mut buffer);
+        read.extend_from_slice(&buffer[..len]);
+    }
+    assert_eq!(read, data);
+    let sequential_reads = count_file.reads.load(Ordering::Relaxed) - reads;
+    assert!(sequential_reads <= 20, "{} reads", sequential_reads);
+
+    // and all at once, from another device
+    let count_file = Arc::new(CountFile {
+        file: BlockFile(Mutex::new(
+            OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open("target/fs_read_ahead.img")?,
+        )),
+        reads: AtomicU32::new(0),
+    });
+    let efs = EasyFileSystem::open(count_file.clone(), 64);
+    let file = EasyFileSystem::root_inode(&efs).find("elf").unwrap();
+    let reads = count_file.reads.load(Ordering::Relaxed);
+    assert_eq!(read_all(&file), data);
+    let whole_reads = count_file.reads.load(Ordering::Relaxed) - reads;
+    assert!(whole_reads <= 20, "{} reads", whole_reads);
+    Ok(())
+}
+
+#[test]
+fn efs_extents_test() -> std::io::Result<()> {
+    let efs = create_image(
+        "target/fs_extents.img",
+        16384,
+        1,
+        FEATURE_LONG_NAMES | FEATURE_EXTENTS,
+    )?;
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let free = efs.lock().stat().free_data_blocks;
+    let data: Vec<u8> = (0..8000 * BLOCK_SZ).map(|i| (i % 251) as u8).collect();
+
+    // a large file written in order is a single extent, held in the inode
+    let big = root_inode.create("big").unwrap();
+    big.write_at(0, &data);
+    assert_eq!(big.stat().blocks, 8000);
+    assert_eq!(read_all(&big), data);
+
+    // two files growing in turns get an extent per block, in a deeper tree
+    let a = root_inode.create("a").unwrap();
+    let b = root_inode.create("b").unwrap();
+    for i in 0..600 {
+        a.write_at(i * BLOCK_SZ, &data[i * BLOCK_SZ..(i + 1) * BLOCK_SZ]);
+        b.write_at(i * BLOCK_SZ, &data[(i + 1) * BLOCK_SZ..(i + 2) * BLOCK_SZ]);
+    }
+    assert!(a.stat().blocks > 600);
+    assert_eq!(read_all(&a), data[..600 * BLOCK_SZ]);
+    assert_eq!(read_all(&b), data[BLOCK_SZ..601 * BLOCK_SZ]);
+    assert!(EasyFileSystem::check(&efs).is_empty());
+
+    // shrinking releases the blocks and the nodes left empty
+    for size in [300 * BLOCK_SZ + 7, 5 * BLOCK_SZ, 0] {
+        a.truncate(size as u32);
+        assert_eq!(read_all(&a), data[..size]);
+        assert!(EasyFileSystem::check(&efs).is_empty());
+    }
+    assert_eq!(a.stat().blocks, 0);
+    drop((a, b, big));
+    for name in ["a", "b", "big"] {
+        assert!(root_inode.unlink(name));
+    }
+    assert_eq!(efs.lock().stat().free_data_blocks, free);
+    Ok(())
+}
+
+/// A FAT32 volume of 4096 sectors, one per cluster, built the way
+/// `mkfs.vfat` and a host would leave it
+#[cfg(test)]
+fn fat32_image() -> Vec<u8> {
+    const FAT_START: usize = 32;
+    const FAT_SIZE: usize = 32;
+    const DATA_START: usize = FAT_START + 2 * FAT_SIZE;
+    let mut image = vec![0u8; 4096 * BLOCK_SZ];
+    let boot = &mut image[..BLOCK_SZ];
+    boot[11..13].copy_from_slice(&(BLOCK_SZ as u16).to_le_bytes());
+    boot[13] = 1;
+    boot[14..16].copy_from_slice(&(FAT_START as u16).to_le_bytes());
+    boot[16] = 2;
+    boot[32..36].copy_from_slice(&4096u32.to_le_bytes());
+    boot[36..40].copy_from_slice(&(FAT_SIZE as u32).to_le_bytes());
+    boot[44..48].copy_from_slice(&2u32.to_le_bytes());
+    boot[510..].copy_from_slice(&[0x55, 0xaa]);
+    // the root, a directory, a small file and a fragmented one
+    let chains: [(u32, u32); 10] = [
+        (0, 0x0fff_fff8),
+        (1, 0x0fff_ffff),
+        (2, 0x0fff_ffff),
+        (3, 0x0fff_ffff),
+        (4, 0x0fff_ffff),
+        (5, 7),
+        (7, 6),
+        (6, 9),
+        (9, 0x0fff_ffff),
+        (10, 0x0fff_ffff),
+    ];
+    for (cluster, next) in chains {
+        for fat in 0..2 {
+            let offset = (FAT_START + fat * FAT_SIZE) * BLOCK_SZ + cluster as usize * 4;
+            image[offset..offset + 4].copy_from_slice(&next.to_le_bytes());
+        }
+    }
+    let cluster = |cluster: usize| (DATA_START + cluster - 2) * BLOCK_SZ;
+    let short_entry = |name: &[u8; 11], attr: u8, lower: u8, first: u32, size: u32| {
+        let mut entry = [0u8; 32];
+        entry[..11].copy_from_slice(name);
+        entry[11] = attr;
+        entry[12] = lower;
+        // 2024-03-15 12:34:56
+        let date: u16 = (2024 - 1980) << 9 | 3 << 5 | 15;
+        let time: u16 = 12 << 11 | 34 << 5 | 28;
+        entry[22..24].copy_from_slice(&time.to_le_bytes());
+        entry[24..26].copy_from_slice(&date.to_le_bytes());
+        entry[20..22].copy_from_slice(&((first >> 16) as u16).to_le_bytes());
+        entry[26..28].copy_from_slice(&(first as u16).to_le_bytes());
+        entry[28..32].copy_from_slice(&size.to_le_bytes());
+        entry
+    };
+    let long_entries = |long_name: &str, short_name: &[u8; 11]| {
+        let checksum = short_name
+            .iter()
+            .fold(0u8, |sum, &byte| sum.rotate_right(1).wrapping_add(byte));
+        let mut chars: Vec<u16> = long_name.encode_utf16().chain([0]).collect();
+        chars.resize(chars.len().div_ceil(13) * 13, 0xffff);
+        let parts = chars.len() / 13;
+        (0..parts)
+            .rev()
+            .map(|part| {
+                let mut entry = [0u8; 32];
+                entry[0] = (part as u8 + 1) | if part == parts - 1 { 0x40 } else { 0 };
+                entry[11] = 0x0f;
+                entry[13] = checksum;
+                let offsets = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+                for (c, offset) in chars[part * 13..].iter().zip(offsets) {
+                    entry[offset..offset + 2].copy_from_slice(&c.to_le_bytes());
+                }
+                entry
+            })
+            .collect::<Vec<_>>()
+    };
+    let long_data: Vec<u8> = (0..1636).map(|i| (i % 251) as u8).collect();
+    let mut root: Vec<[u8; 32]> = vec![short_entry(b"TESTVOL    ", 0x08, 0, 0, 0)];
+    root.extend(long_entries("A long file name.txt", b"ALONGF~1TXT"));
+    root.push(short_entry(
+        b"ALONGF~1TXT",
+        0x20,
+        0,
+        5,
+        long_data.len() as u32,
+    ));
+    let mut removed = short_entry(b"GONE    TXT", 0x20, 0, 0, 0);
+    removed[0] = 0xe5;
+    root.push(removed);
+    root.push(short_entry(b"HELLO   TXT", 0x20, 0x18, 4, 13));
+    // a long name whose short entry changed since, which is not used
+    root.extend(long_entries("stale name", b"STALE      "));
+    root.push(short_entry(b"SUBDIR     ", 0x10, 0, 3, 0));
+    for (i, entry) in root.iter().enumerate() {
+        image[cluster(2) + i * 32..][..32].copy_from_slice(entry);
+    }
+    let mut subdir = [
+        short_entry(b".          ", 0x10, 0, 3, 0),
+        short_entry(b"..         ", 0x10, 0, 0, 0),
+        short_entry(b"INNER   TXT", 0x20, 0, 10, 5),
+    ];
+    // 2107-12-31, the last day FAT dates reach
+    let date: u16 = 127 << 9 | 12 << 5 | 31;
+    subdir[2][24..26].copy_from_slice(&date.to_le_bytes());
+    for (i, entry) in subdir.iter().enumerate() {
+        image[cluster(3) + i * 32..][..32].copy_from_slice(entry);
+    }
+    image[cluster(4)..][..13].copy_from_slice(b"Hello, FAT32!");
+    image[cluster(10)..][..5].copy_from_slice(b"inner");
+    for (i, chunk) in long_data.chunks(BLOCK_SZ).enumerate() {
+        let at = cluster([5, 7, 6, 9][i]);
+        image[at..at + chunk.len()].copy_from_slice(chunk);
+    }
+    image
+}
+
+#[test]
+fn fat32_test() -> std::io::Result<()> {
+    let block_file: Arc<dyn BlockDevice> = Arc::new(BlockFile(Mutex::new({
+        let mut f = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open("target/fat32.img")?;
+        f.write_all(&fat32_image())?;
+        f
+    })));
+    assert!(Fat32FileSystem::detect(&block_file));
+    assert!(!EasyFileSystem::detect(&block_file));
+    let fs = Fat32FileSystem::open(block_file.clone());
+    let root = Fat32FileSystem::root_inode(&fs);
+    assert!(root.is_dir());
+    assert_eq!(root.ls(), ["A long file name.txt", "hello.txt", "SUBDIR"]);
+    assert!(root.find("GONE.TXT").is_none());
+
+    let hello = root.find("HELLO.TXT").unwrap();
+    assert!(hello.is_file());
+    let mut buf = [0u8; 64];
+    assert_eq!(hello.read_at(0, &mut buf), 13);
+    assert_eq!(&buf[..13], b"Hello, FAT32!");
+    assert_eq!(hello.read_at(7, &mut buf), 6);
+    assert_eq!(hello.read_at(13, &mut buf), 0);
+    let stat = hello.stat();
+    assert_eq!(stat.size, 13);
+    assert_eq!(stat.mtime, 1710506096);
+
+    // a file whose clusters are out of order
+    let long = root.find("a long FILE name.txt").unwrap();
+    let expected: Vec<u8> = (0..1636).map(|i| (i % 251) as u8).collect();
+    let mut data = vec![0u8; 2000];
+    assert_eq!(long.read_at(0, &mut data), 1636);
+    assert_eq!(data[..1636], expected[..]);
+    assert_eq!(long.read_at(500, &mut data[..600]), 600);
+    assert_eq!(data[..600], expected[500..1100]);
+
+    let subdir = root.find("subdir").unwrap();
+    assert!(subdir.is_dir());
+    assert_eq!(subdir.ls(), ["INNER.TXT"]);
+    let inner = subdir.find("inner.txt").unwrap();
+    assert_eq!(inner.read_at(0, &mut buf), 5);
+    assert_eq!(&buf[..5], b"inner");
+    assert_eq!(inner.stat().mtime, u32::MAX);
+    assert!(subdir.find("..").is_none());
+
+    // and easy-fs is not taken for FAT32
+    EasyFileSystem::create(block_file.clone(), 4096, 1, 0);
+    assert!(!Fat32FileSystem::detect(&block_file));
+    Ok(())
+}
+
+/// Run a tool of e2fsprogs, which the ext2 test needs installed
+#[cfg(test)]
+fn e2fsprogs(tool: &str, args: &[&str]) -> std::process::Output {
+    std::process::Command::new(tool)
+        .args(args)
+        .output()
+        .unwrap_or_else(|error| panic!("{} of e2fsprogs: {}", tool, error))
+}
+
+#[test]
+fn ext2_test() -> std::io::Result<()> {
+    // e2fsck takes inodes freed at a time of 0 for inodes in use
+    easy_fs::set_clock(host_clock);
+    let root_dir = Path::new("target/ext2-root");
+    if root_dir.exists() {
+        std::fs::remove_dir_all(root_dir)?;
+    }
+    std::fs::create_dir_all(root_dir.join("sub"))?;
+    std::fs::write(root_dir.join("hello.txt"), b"Hello, ext2!")?;
+    std::fs::write(root_dir.join("sub/inner.txt"), b"inner")?;
+    // past the single indirect block, with blocks of 1 KiB
+    let big: Vec<u8> = (0..300 * 1024).map(|i| (i % 251) as u8).collect();
+    std::fs::write(root_dir.join("big.bin"), &big)?;
+    let _ = std::fs::remove_file("target/ext2.img");
+    let output = e2fsprogs(
+        "mke2fs",
+        &[
+            "-q",
+            "-t",
+            "ext2",
+            "-b",
+            "1024",
+            "-d",
+            "target/ext2-root",
+            "target/ext2.img",
+            "4M",
+        ],
+    );
+    assert!(output.status.success());
+    let block_file: Arc<dyn BlockDevice> = Arc::new(BlockFile(Mutex::new(
+        OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open("target/ext2.img")?,
+    )));
+    assert!(Ext2FileSystem::detect(&block_file));
+    assert!(!EasyFileSystem::detect(&block_file));
+    assert!(!Fat32FileSystem::detect(&block_file));
+    let fs = Ext2FileSystem::open(block_file.clone(), BLOCK_CACHE_SIZE);
+    let root = Ext2FileSystem::root_inode(&fs);
+    assert!(root.is_dir());
+    let mut names = root.ls();
+    names.sort();
+    assert_eq!(
+        names,
+        [".", "..", "big.bin", "hello.txt", "lost+found", "sub"]
+    );
+
+    let hello = root.find("hello.txt").unwrap();
+    assert!(hello.is_file());
+    let mut buf = [0u8; 64];
+    assert_eq!(hello.read_at(0, &mut buf), 12);
+    assert_eq!(&buf[..12], b"Hello, ext2!");
+    assert_eq!(hello.read_at(12, &mut buf), 0);
+    assert_eq!(hello.stat().nlink, 1);
+    let mut data = vec![0u8; big.len() + 10];
+    let file = root.find("big.bin").unwrap();
+    assert_eq!(file.read_at(0, &mut data), big.len());
+    assert_eq!(data[..big.len()], big[..]);
+    let inner = root.find("sub").unwrap().find("inner.txt").unwrap();
+    assert_eq!(inner.read_at(0, &mut buf), 5);
+    assert_eq!(&buf[..5], b"inner");
+
+    // writes, through the double indirect block
+    let new = root.create("new.bin").unwrap();
+    assert!(root.create("new.bin").is_none());
+    let written: Vec<u8> = (0..600 * 1024).map(|i| (i % 241) as u8).collect();
+    assert_eq!(new.write_at(0, &written), written.len());
+    assert_eq!(new.write_at(10, b"patched"), 7);
+    let mut expected = written.clone();
+    expected[10..17].copy_from_slice(b"patched");
+    let mut data = vec![0u8; expected.len()];
+    assert_eq!(new.read_at(0, &mut data), expected.len());
+    assert_eq!(data, expected);
+
+    // directories, and their link counts
+    let dir = root.create_dir("dir").unwrap();
+    assert_eq!(root.stat().nlink, 5);
+    assert_eq!(dir.ls(), [".", ".."]);
+    for i in 0..100 {
+        dir.create(&format!("file-with-a-long-name-{}", i)).unwrap();
+    }
+    assert!(!root.unlink("dir"));
+    for i in (0..100).step_by(2) {
+        assert!(dir.unlink(&format!("file-with-a-long-name-{}", i)));
+    }
+    assert_eq!(dir.ls().len(), 52);
+    assert!(dir.find("file-with-a-long-name-1").is_some());
+    assert!(dir.find("file-with-a-long-name-2").is_none());
+
+    // the end of a shrunk file reads as zeros once grown again
+    assert!(file.truncate(5000));
+    assert!(file.truncate(9000));
+    assert_eq!(file.size(), 9000);
+    let mut data = vec![0u8; 9000];
+    assert_eq!(file.read_at(0, &mut data), 9000);
+    assert_eq!(data[..5000], big[..5000]);
+    assert!(data[5000..].iter().all(|byte| *byte == 0));
+
+    // an open file keeps its inode and blocks until its last handle goes
+    assert!(root.unlink("hello.txt"));
+    assert!(root.find("hello.txt").is_none());
+    assert_eq!(hello.stat().nlink, 0);
+    assert_eq!(hello.write_at(12, b" Still open."), 12);
+    let other = root.create("other.txt").unwrap();
+    assert_ne!(other.stat().ino, hello.stat().ino);
+    assert_eq!(other.write_at(0, b"other"), 5);
+    assert_eq!(hello.read_at(0, &mut buf), 24);
+    assert_eq!(&buf[..24], b"Hello, ext2! Still open.");
+    drop(hello);
+    drop(other);
+    assert!(root.unlink("other.txt"));
+    // a removed directory still in use takes no new entry
+    let gone = root.create_dir("gone").unwrap();
+    assert!(root.unlink("gone"));
+    assert!(gone.create("late").is_none());
+    assert!(gone.create_dir("late").is_none());
+    drop(gone);
+    root.sync();
+
+    // what was written is what the tools of Linux read
+    let output = e2fsprogs("e2fsck", &["-fn", "target/ext2.img"]);
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stdout)
+    );
+    let output = e2fsprogs("debugfs", &["-R", "cat /new.bin", "target/ext2.img"]);
+    assert_eq!(output.stdout, expected);
+    Ok(())
+}
+
+/// Set entry `slot` of the MBR or EBR at block `block`
+#[cfg(test)]
+fn mbr_entry(image: &mut [u8], block: usize, slot: usize, type_: u8, start: u32, blocks: u32) {
+    let mbr = &mut image[block * BLOCK_SZ..(block + 1) * BLOCK_SZ];
+    let entry = &mut mbr[446 + slot * 16..446 + (slot + 1) * 16];
+    entry[4] = type_;
+    entry[8..12].copy_from_slice(&start.to_le_bytes());
+    entry[12..16].copy_from_slice(&blocks.to_le_bytes());
+    mbr[510..].copy_from_slice(&[0x55, 0xaa]);
+}
+
+#[cfg(test)]
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in bytes {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+/// A GPT disk of `blocks` blocks, with partitions of (slot, first block,
+/// last block)
+#[cfg(test)]
+fn gpt_image(blocks: usize, parts: &[(usize, u64, u64)]) -> Vec<u8> {
+    let mut image = vec![0u8; blocks * BLOCK_SZ];
+    mbr_entry(&mut image, 0, 0, 0xee, 1, blocks as u32 - 1);
+    let mut entries = vec![0u8; 128 * 128];
+    for (slot, first, last) in parts {
+        let entry = &mut entries[slot * 128..(slot + 1) * 128];
+        entry[..16].copy_from_slice(b"type of the part");
+        entry[32..40].copy_from_slice(&first.to_le_bytes());
+        entry[40..48].copy_from_slice(&last.to_le_bytes());
+    }
+    let last = blocks as u64 - 1;
+    // the primary header and entries, then the backup ones at the end
+    for (lba, alternate, entries_lba) in [(1, last, 2), (last, 1, last - 32)] {
+        let mut header = [0u8; 92];
+        header[..8].copy_from_slice(b"EFI PART");
+        header[8..12].copy_from_slice(&0x0001_0000u32.to_le_bytes());
+        header[12..16].copy_from_slice(&92u32.to_le_bytes());
+        header[24..32].copy_from_slice(&lba.to_le_bytes());
+        header[32..40].copy_from_slice(&alternate.to_le_bytes());
+        header[72..80].copy_from_slice(&entries_lba.to_le_bytes());
+        header[80..84].copy_from_slice(&128u32.to_le_bytes());
+        header[84..88].copy_from_slice(&128u32.to_le_bytes());
+        header[88..92].copy_from_slice(&crc32(&entries).to_le_bytes());
+        let crc = crc32(&header);
+        header[16..20].copy_from_slice(&crc.to_le_bytes());
+        let offset = lba as usize * BLOCK_SZ;
+        image[offset..offset + 92].copy_from_slice(&header);
+        let offset = entries_lba as usize * BLOCK_SZ;
+        image[offset..offset + entries.len()].copy_from_slice(&entries);
+    }
+    image
+}
+
+#[test]
+fn partition_test() -> std::io::Result<()> {
+    let open_disk = |path: &str, image: &[u8]| -> std::io::Result<Arc<dyn BlockDevice>> {
+        let mut f = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        f.write_all(image)?;
+        Ok(Arc::new(BlockFile(Mutex::new(f))))
+    };
+    // two primary partitions, the second extended with two logical ones
+    let mut image = vec![0u8; 8192 * BLOCK_SZ];
+    mbr_entry(&mut image, 0, 0, 0x83, 2048, 4096);
+    mbr_entry(&mut image, 0, 1, 0x05, 6144, 2048);
+    mbr_entry(&mut image, 6144, 0, 0x83, 1, 1023);
+    mbr_entry(&mut image, 6144, 1, 0x05, 1024, 1024);
+    mbr_entry(&mut image, 7168, 0, 0x0c, 1, 1023);
+    let disk = open_disk("target/mbr.img", &image)?;
+    let parts = partitions(&disk);
+    let layout: Vec<_> = parts
+        .iter()
+        .map(|part| (part.number(), part.start(), part.num_blocks()))
+        .collect();
+    assert_eq!(layout, [(1, 2048, 4096), (5, 6145, 1023), (6, 7169, 1023)]);
+
+    // a filesystem in a partition starts at its first block
+    let efs = EasyFileSystem::create(parts[0].clone(), 4096, 1, 0);
+    EasyFileSystem::root_inode(&efs)
+        .create("in-part")
+        .unwrap()
+        .write_at(0, b"partitioned");
+    efs.lock().sync();
+    assert!(EasyFileSystem::detect(
+        &(parts[0].clone() as Arc<dyn BlockDevice>)
+    ));
+    assert!(!EasyFileSystem::detect(&disk));
+    let mut from_disk = [0u8; BLOCK_SZ];
+    let mut from_part = [0u8; BLOCK_SZ];
+    disk.read_block(2048, &mut from_disk);
+    parts[0].read_block(0, &mut from_part);
+    assert_eq!(from_disk, from_part);
+    let part = partitions(&disk).remove(0);
+    let efs = EasyFileSystem::open(part, BLOCK_CACHE_SIZE);
+    let file = EasyFileSystem::root_inode(&efs).find("in-part").unwrap();
+    assert_eq!(read_string(&file), "partitioned");
+
+    // neither a filesystem nor the boot sector of FAT32 is a partition table
+    assert!(partitions(&open_disk("target/mbr.img", &vec![0u8; 4096 * BLOCK_SZ])?).is_empty());
+    assert!(partitions(&open_disk("target/mbr.img", &fat32_image())?).is_empty());
+
+    // GPT, with the backup header taken when the primary one is damaged
+    let mut image = gpt_image(8192, &[(0, 2048, 4095), (2, 4096, 8158)]);
+    let disk = open_disk("target/gpt.img", &image)?;
+    let layout: Vec<_> = partitions(&disk)
+        .iter()
+        .map(|part| (part.number(), part.start(), part.num_blocks()))
+        .collect();
+    assert_eq!(layout, [(1, 2048, 2048), (3, 4096, 4063)]);
+    image[BLOCK_SZ + 20] ^= 1;
+    let disk = open_disk("target/gpt.img", &image)?;
+    assert_eq!(partitions(&disk).len(), 2);
+    image[8191 * BLOCK_SZ + 20] ^= 1;
+    let disk = open_disk("target/gpt.img", &image)?;
+    assert!(partitions(&disk).is_empty());
+    Ok(())
+}
@@ -0,0 +1,584 @@
+//! Serve an image to the host kernel through the FUSE protocol on `/dev/fuse`,
+//! so that it can be browsed and edited with the usual tools
+
+use easy_fs::{EasyFileSystem, Inode, MAX_FILE_SIZE};
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::sync::{Arc, Mutex, OnceLock};
+
+const BLOCK_SZ: usize = 512;
+
+const FUSE_KERNEL_VERSION: u32 = 7;
+const FUSE_KERNEL_MINOR_VERSION: u32 = 31;
+/// Node id of the root directory, which is never forgotten
+const FUSE_ROOT_ID: u64 = 1;
+/// Largest write request, the kernel splits bigger writes
+const MAX_WRITE: usize = 128 * 1024;
+/// Seconds the kernel may cache entries and attributes, we are the only writer
+const TTL: u64 = 1;
+/// Seconds between write backs of the blocks modified outside of a transaction
+const FLUSH_INTERVAL: u32 = 5;
+
+const FUSE_LOOKUP: u32 = 1;
+const FUSE_FORGET: u32 = 2;
+const FUSE_GETATTR: u32 = 3;
+const FUSE_SETATTR: u32 = 4;
+const FUSE_MKDIR: u32 = 9;
+const FUSE_UNLINK: u32 = 10;
+const FUSE_RMDIR: u32 = 11;
+const FUSE_RENAME: u32 = 12;
+const FUSE_OPEN: u32 = 14;
+const FUSE_READ: u32 = 15;
+const FUSE_WRITE: u32 = 16;
+const FUSE_STATFS: u32 = 17;
+const FUSE_RELEASE: u32 = 18;
+const FUSE_FSYNC: u32 = 20;
+const FUSE_FLUSH: u32 = 25;
+const FUSE_INIT: u32 = 26;
+const FUSE_OPENDIR: u32 = 27;
+const FUSE_READDIR: u32 = 28;
+const FUSE_RELEASEDIR: u32 = 29;
+const FUSE_FSYNCDIR: u32 = 30;
+const FUSE_CREATE: u32 = 35;
+const FUSE_INTERRUPT: u32 = 36;
+const FUSE_DESTROY: u32 = 38;
+const FUSE_BATCH_FORGET: u32 = 42;
+const FUSE_RENAME2: u32 = 45;
+
+/// `fsync_flags` bit of an fsync request for the file content only
+const FUSE_FSYNC_FDATASYNC: u32 = 1;
+/// `valid` bit of a setattr request changing the size
+const FATTR_SIZE: u32 = 1 << 3;
+
+const DT_DIR: u32 = 4;
+const DT_REG: u32 = 8;
+
+/// Mount point, for the signal handler to unmount
+static MOUNT_POINT: OnceLock<CString> = OnceLock::new();
+
+extern "C" fn unmount(_signal: libc::c_int) {
+    if let Some(dir) = MOUNT_POINT.get() {
+        // the pending read on /dev/fuse then fails and the loop ends
+        unsafe { libc::umount2(dir.as_ptr(), libc::MNT_DETACH) };
+    }
+}
+
+/// Mount `efs` on `dir` and serve requests until it is unmounted
+pub fn mount(efs: Arc<spin::Mutex<EasyFileSystem>>, dir: &str) -> std::io::Result<()> {
+    let mut device = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/fuse")?;
+    let target = CString::new(dir)?;
+    let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+    let options = CString::new(format!(
+        "fd={},rootmode=40000,user_id={},group_id={},default_permissions",
+        device.as_raw_fd(),
+        uid,
+        gid
+    ))?;
+    let result = unsafe {
+        libc::mount(
+            c"easy-fs".as_ptr(),
+            target.as_ptr(),
+            c"fuse".as_ptr(),
+            libc::MS_NOSUID | libc::MS_NODEV,
+            options.as_ptr() as *const libc::c_void,
+        )
+    };
+    if result != 0 {
+        let error = std::io::Error::last_os_error();
+        return Err(std::io::Error::new(
+            error.kind(),
+            format!("cannot mount on {}: {} (root required)", dir, error),
+        ));
+    }
+    MOUNT_POINT.set(target).unwrap();
+    unsafe {
+        libc::signal(libc::SIGINT, unmount as *const () as libc::sighandler_t);
+        libc::signal(libc::SIGTERM, unmount as *const () as libc::sighandler_t);
+    }
+    let name_max = efs.lock().name_length_limit();
+    efs.lock().set_flush_interval(FLUSH_INTERVAL);
+    let fs = Fuse {
+        efs,
+        uid,
+        gid,
+        name_max,
+        generations: Mutex::new(HashMap::new()),
+        nodes: Mutex::new(HashMap::new()),
+    };
+    let mut buffer = vec![0u8; MAX_WRITE + 4096];
+    loop {
+        let len = match device.read(&mut buffer) {
+            Ok(len) => len,
+            Err(error) => match error.raw_os_error() {
+                // unmounted
+                Some(libc::ENODEV) => break,
+                // the request was interrupted before we read it
+                Some(libc::ENOENT) | Some(libc::EINTR) | Some(libc::EAGAIN) => continue,
+                _ => return Err(error),
+            },
+        };
+        let mut request = Request(&buffer[..len]);
+        let _len = request.u32();
+        let opcode = request.u32();
+        let unique = request.u64();
+        let nodeid = request.u64();
+        request.skip(16);
+        if let Some(reply) = fs.handle(opcode, nodeid, request) {
+            send(&mut device, unique, reply);
+        }
+        EasyFileSystem::flush_if_due(&fs.efs);
+    }
+    // files unlinked while the kernel knew them are released with their node
+    fs.nodes.lock().unwrap().clear();
+    // atime updates are written back lazily
+    fs.efs.lock().sync();
+    Ok(())
+}
+
+fn send(device: &mut File, unique: u64, reply: Result<Vec<u8>, i32>) {
+    let (error, body) = match reply {
+        Ok(body) => (0, body),
+        Err(errno) => (-errno, Vec::new()),
+    };
+    let mut out = Reply::new();
+    out.u32((16 + body.len()) as u32).i32(error).u64(unique);
+    out.0.extend_from_slice(&body);
+    // fails only if the request has been interrupted meanwhile
+    let _ = device.write(&out.0);
+}
+
+/// Little endian fields of a request, in order
+struct Request<'a>(&'a [u8]);
+
+impl<'a> Request<'a> {
+    fn take(&mut self, len: usize) -> &'a [u8] {
+        let len = len.min(self.0.len());
+        let (head, tail) = self.0.split_at(len);
+        self.0 = tail;
+        head
+    }
+    fn skip(&mut self, len: usize) {
+        self.take(len);
+    }
+    fn u32(&mut self) -> u32 {
+        let mut bytes = [0u8; 4];
+        let field = self.take(4);
+        bytes[..field.len()].copy_from_slice(field);
+        u32::from_le_bytes(bytes)
+    }
+    fn u64(&mut self) -> u64 {
+        let mut bytes = [0u8; 8];
+        let field = self.take(8);
+        bytes[..field.len()].copy_from_slice(field);
+        u64::from_le_bytes(bytes)
+    }
+    /// A nul terminated name, of at most `limit` bytes
+    fn name(&mut self, limit: usize) -> Result<&'a str, i32> {
+        let end = self.0.iter().position(|&b| b == 0).ok_or(libc::EINVAL)?;
+        let name = std::str::from_utf8(self.take(end)).map_err(|_| libc::EINVAL)?;
+        self.skip(1);
+        if name.len() > limit {
+            return Err(libc::ENAMETOOLONG);
+        }
+        Ok(name)
+    }
+}
+
+/// Little endian fields of a reply, in order
+struct Reply(Vec<u8>);
+
+impl Reply {
+    fn new() -> Self {
+        Self(Vec::new())
+    }
+    fn u32(&mut self, value: u32) -> &mut Self {
+        self.0.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+    fn i32(&mut self, value: i32) -> &mut Self {
+        self.0.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+    fn u64(&mut self, value: u64) -> &mut Self {
+        self.0.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+}
+
+struct Fuse {
+    efs: Arc<spin::Mutex<EasyFileSystem>>,
+    uid: u32,
+    gid: u32,
+    /// longest name of the image
+    name_max: usize,
+    /// Inode ids freed while mounted, and how many times. easy-fs hands a
+    /// freed id to the next file, which gets a new generation.
+    generations: Mutex<HashMap<u32, u64>>,
+    /// Inodes the kernel knows by node id, and its lookup count of each.
+    /// The handle keeps an unlinked inode, and its id, until it is forgotten.
+    nodes: Mutex<HashMap<u64, (Arc<Inode>, u64)>>,
+}
+
+impl Fuse {
+    /// Answer a request, `None` for the ones that get no reply
+    fn handle(
+        &self,
+        opcode: u32,
+        nodeid: u64,
+        mut request: Request,
+    ) -> Option<Result<Vec<u8>, i32>> {
+        let reply = match opcode {
+            FUSE_FORGET => {
+                self.forget(nodeid, request.u64());
+                return None;
+            }
+            FUSE_BATCH_FORGET => {
+                let count = request.u32();
+                request.skip(4);
+                for _ in 0..count {
+                    let nodeid = request.u64();
+                    self.forget(nodeid, request.u64());
+                }
+                return None;
+            }
+            FUSE_INTERRUPT => return None,
+            FUSE_INIT => self.init(request),
+            FUSE_DESTROY | FUSE_RELEASE | FUSE_RELEASEDIR | FUSE_FLUSH => Ok(Vec::new()),
+            // directory changes are committed before they are answered
+            FUSE_FSYNCDIR => Ok(Vec::new()),
+            FUSE_OPEN | FUSE_OPENDIR => Ok(open_out()),
+            FUSE_STATFS => Ok(self.statfs()),
+            // there is no rename in easy-fs, mv copies across filesystems instead
+            FUSE_RENAME | FUSE_RENAME2 => Err(libc::EXDEV),
+            _ => match self.inode(nodeid) {
+                Ok(inode) => match opcode {
+                    FUSE_LOOKUP => request
+                        .name(self.name_max)
+                        .and_then(|name| self.lookup(&inode, name)),
+                    FUSE_GETATTR => Ok(self.attr_out(&inode)),
+                    FUSE_SETATTR => self.setattr(&inode, request),
+                    FUSE_READ => self.read(&inode, request),
+                    FUSE_WRITE => self.write(&inode, request),
+                    FUSE_FSYNC => {
+                        request.skip(8);
+                        inode.sync(request.u32() & FUSE_FSYNC_FDATASYNC != 0);
+                        Ok(Vec::new())
+                    }
+                    FUSE_READDIR => self.readdir(&inode, request),
+                    FUSE_MKDIR => {
+                        request.skip(8);
+                        request
+                            .name(self.name_max)
+                            .and_then(|name| self.mkdir(&inode, name))
+                    }
+                    FUSE_CREATE => {
+                        request.skip(16);
+                        request
+                            .name(self.name_max)
+                            .and_then(|name| self.create(&inode, name))
+                    }
+                    FUSE_UNLINK => request
+                        .name(self.name_max)
+                        .and_then(|name| self.unlink(&inode, name, false)),
+                    FUSE_RMDIR => request
+                        .name(self.name_max)
+                        .and_then(|name| self.unlink(&inode, name, true)),
+                    _ => Err(libc::ENOSYS),
+                },
+                Err(errno) => Err(errno),
+            },
+        };
+        Some(reply)
+    }
+
+    fn init(&self, mut request: Request) -> Result<Vec<u8>, i32> {
+        let major = request.u32();
+        let minor = request.u32();
+        let max_readahead = request.u32();
+        if major != FUSE_KERNEL_VERSION {
+            return Err(libc::EPROTO);
+        }
+        let mut out = Reply::new();
+        out.u32(FUSE_KERNEL_VERSION)
+            .u32(FUSE_KERNEL_MINOR_VERSION.min(minor))
+            .u32(max_readahead)
+            // no optional feature
+            .u32(0)
+            // max_background and congestion_threshold
+            .u32(0)
+            .u32(MAX_WRITE as u32)
+            // time granularity in ns
+            .u32(1_000_000_000);
+        // max_pages, map_alignment, flags2 and unused fields
+        out.0.resize(64, 0);
+        // kernels before 7.23 expect the short version
+        if minor < 23 {
+            out.0.truncate(24);
+        }
+        Ok(out.0)
+    }
+
+    /// FUSE node ids are easy-fs inode ids plus one, the root being 1.
+    /// Other nodes are those of a lookup the kernel has not forgotten yet.
+    fn inode(&self, nodeid: u64) -> Result<Arc<Inode>, i32> {
+        if nodeid == FUSE_ROOT_ID {
+            return Ok(Arc::new(EasyFileSystem::root_inode(&self.efs)));
+        }
+        match self.nodes.lock().unwrap().get(&nodeid) {
+            Some((inode, _)) => Ok(Arc::clone(inode)),
+            None => Err(libc::ESTALE),
+        }
+    }
+
+    /// Drop `nlookup` lookups of a node, and its inode with the last one
+    fn forget(&self, nodeid: u64, nlookup: u64) {
+        let mut nodes = self.nodes.lock().unwrap();
+        if let Some((_, count)) = nodes.get_mut(&nodeid) {
+            *count = count.saturating_sub(nlookup);
+            if *count == 0 {
+                nodes.remove(&nodeid);
+            }
+        }
+    }
+
+    fn attr(&self, inode: &Inode, out: &mut Reply) {
+        let stat = inode.stat();
+        let mode = if inode.is_dir() {
+            libc::S_IFDIR | 0o755
+        } else {
+            libc::S_IFREG | 0o644
+        };
+        out.u64(stat.ino as u64 + 1)
+            .u64(stat.size as u64)
+            .u64(stat.blocks as u64)
+            .u64(stat.atime as u64)
+            .u64(stat.mtime as u64)
+            .u64(stat.ctime as u64)
+            .u32(0)
+            .u32(0)
+            .u32(0)
+            .u32(mode)
+            .u32(stat.nlink)
+            .u32(self.uid)
+            .u32(self.gid)
+            // rdev, blksize and flags
+            .u32(0)
+            .u32(BLOCK_SZ as u32)
+            .u32(0);
+    }
+
+    fn attr_out(&self, inode: &Inode) -> Vec<u8> {
+        let mut out = Reply::new();
+        out.u64(TTL).u32(0).u32(0);
+        self.attr(inode, &mut out);
+        out.0
+    }
+
+    /// Reply with a node, which counts as a lookup of it
+    fn entry_out(&self, inode: Arc<Inode>) -> Vec<u8> {
+        let mut out = Reply::new();
+        let ino = inode.stat().ino;
+        let nodeid = ino as u64 + 1;
+        let generation = self.generations.lock().unwrap().get(&ino).copied();
+        out.u64(nodeid)
+            .u64(generation.unwrap_or(0))
+            .u64(TTL)
+            .u64(TTL)
+            .u32(0)
+            .u32(0);
+        self.attr(&inode, &mut out);
+        if nodeid != FUSE_ROOT_ID {
+            self.nodes
+                .lock()
+                .unwrap()
+                .entry(nodeid)
+                .or_insert((inode, 0))
+                .1 += 1;
+        }
+        out.0
+    }
+
+    fn statfs(&self) -> Vec<u8> {
+        let stat = self.efs.lock().stat();
+        let mut out = Reply::new();
+        out.u64(stat.data_blocks as u64)
+            .u64(stat.free_data_blocks as u64)
+            .u64(stat.free_data_blocks as u64)
+            .u64(stat.inodes as u64)
+            .u64(stat.free_inodes as u64)
+            .u32(BLOCK_SZ as u32)
+            .u32(self.name_max as u32)
+            .u32(BLOCK_SZ as u32);
+        out.0.resize(80, 0);
+        out.0
+    }
+
+    /// Fail early when growing a file from `old_size` to `new_size` may
+    /// run out of blocks, easy-fs cannot undo a half done allocation
+    fn reserve(&self, old_size: usize, new_size: usize) -> Result<(), i32> {
+        if new_size > MAX_FILE_SIZE {
+            return Err(libc::EFBIG);
+        }
+        let needed = easy_fs::blocks_needed(old_size, new_size);
+        if needed > self.efs.lock().stat().free_data_blocks as usize {
+            return Err(libc::ENOSPC);
+        }
+        Ok(())
+    }
+
+    fn lookup(&self, dir: &Inode, name: &str) -> Result<Vec<u8>, i32> {
+        if !dir.is_dir() {
+            return Err(libc::ENOTDIR);
+        }
+        let inode = dir.find(name).ok_or(libc::ENOENT)?;
+        Ok(self.entry_out(inode))
+    }
+
+    fn setattr(&self, inode: &Inode, mut request: Request) -> Result<Vec<u8>, i32> {
+        let valid = request.u32();
+        request.skip(12);
+        let size = request.u64();
+        // mode, owners and times are not kept, only the size can change
+        if valid & FATTR_SIZE != 0 {
+            if inode.is_dir() {
+                return Err(libc::EISDIR);
+            }
+            self.reserve(inode.size() as usize, size as usize)?;
+            inode.truncate(size as u32);
+        }
+        Ok(self.attr_out(inode))
+    }
+
+    fn read(&self, inode: &Inode, mut request: Request) -> Result<Vec<u8>, i32> {
+        request.skip(8);
+        let offset = request.u64() as usize;
+        let size = request.u32() as usize;
+        if inode.is_dir() {
+            return Err(libc::EISDIR);
+        }
+        let mut data = vec![0u8; size.min((inode.size() as usize).saturating_sub(offset))];
+        let len = inode.read_at(offset, &mut data);
+        data.truncate(len);
+        Ok(data)
+    }
+
+    fn write(&self, inode: &Inode, mut request: Request) -> Result<Vec<u8>, i32> {
+        request.skip(8);
+        let offset = request.u64() as usize;
+        let size = request.u32() as usize;
+        request.skip(20);
+        let data = request.take(size);
+        if inode.is_dir() {
+            return Err(libc::EISDIR);
+        }
+        self.reserve(inode.size() as usize, offset + data.len())?;
+        let len = inode.write_at(offset, data);
+        let mut out = Reply::new();
+        out.u32(len as u32).u32(0);
+        Ok(out.0)
+    }
+
+    fn readdir(&self, dir: &Inode, mut request: Request) -> Result<Vec<u8>, i32> {
+        request.skip(8);
+        let offset = request.u64() as usize;
+        let size = request.u32() as usize;
+        if !dir.is_dir() {
+            return Err(libc::ENOTDIR);
+        }
+        let ino = dir.stat().ino as u64 + 1;
+        // the root has no "." and ".." entries on disk
+        let parent = dir
+            .find("..")
+            .map_or(ino, |parent| parent.stat().ino as u64 + 1);
+        let mut entries = vec![
+            (String::from("."), ino, DT_DIR),
+            (String::from(".."), parent, DT_DIR),
+        ];
+        for name in dir.ls() {
+            if name == "." || name == ".." {
+                continue;
+            }
+            if let Some(inode) = dir.find(&name) {
+                let type_ = if inode.is_dir() { DT_DIR } else { DT_REG };
+                entries.push((name, inode.stat().ino as u64 + 1, type_));
+            }
+        }
+        let mut out = Reply::new();
+        for (i, (name, ino, type_)) in entries.into_iter().enumerate().skip(offset) {
+            let len = (24 + name.len()).next_multiple_of(8);
+            if out.0.len() + len > size {
+                break;
+            }
+            out.u64(ino)
+                .u64(i as u64 + 1)
+                .u32(name.len() as u32)
+                .u32(type_);
+            out.0.extend_from_slice(name.as_bytes());
+            out.0.resize(out.0.len().next_multiple_of(8), 0);
+        }
+        Ok(out.0)
+    }
+
+    /// Check that `name` can be added to `dir`
+    fn can_create(&self, dir: &Inode, name: &str) -> Result<(), i32> {
+        if !dir.is_dir() {
+            return Err(libc::ENOTDIR);
+        }
+        if dir.find(name).is_some() {
+            return Err(libc::EEXIST);
+        }
+        let stat = self.efs.lock().stat();
+        // the entry may take a new block of the directory, which may need
+        // an index block, and a new directory needs one for "." and ".."
+        if stat.free_inodes == 0 || stat.free_data_blocks < 3 {
+            return Err(libc::ENOSPC);
+        }
+        Ok(())
+    }
+
+    fn mkdir(&self, dir: &Inode, name: &str) -> Result<Vec<u8>, i32> {
+        self.can_create(dir, name)?;
+        let inode = dir.create_dir(name).ok_or(libc::EINVAL)?;
+        Ok(self.entry_out(inode))
+    }
+
+    fn create(&self, dir: &Inode, name: &str) -> Result<Vec<u8>, i32> {
+        self.can_create(dir, name)?;
+        let inode = dir.create(name).ok_or(libc::EINVAL)?;
+        let mut out = self.entry_out(inode);
+        out.extend_from_slice(&open_out());
+        Ok(out)
+    }
+
+    fn unlink(&self, dir: &Inode, name: &str, is_dir: bool) -> Result<Vec<u8>, i32> {
+        let inode = dir.find(name).ok_or(libc::ENOENT)?;
+        let ino = inode.stat().ino;
+        match (is_dir, inode.is_dir()) {
+            (false, true) => return Err(libc::EISDIR),
+            (true, false) => return Err(libc::ENOTDIR),
+            _ => {}
+        }
+        if !dir.unlink(name) {
+            return Err(if is_dir {
+                libc::ENOTEMPTY
+            } else {
+                libc::EINVAL
+            });
+        }
+        *self.generations.lock().unwrap().entry(ino).or_insert(0) += 1;
+        Ok(Vec::new())
+    }
+}
+
+/// Reply to an open, no file handle is needed
+fn open_out() -> Vec<u8> {
+    let mut out = Reply::new();
+    out.u64(0).u32(0).u32(0);
+    out.0
+}
@@ -23,27 +23,80 @@ impl Bitmap {
 
     pub fn alloc(&self, block_device: &Arc<dyn BlockDevice>) -> Option<usize> {
         for block_id in 0..self.blocks {
-            let pos = get_block_cache(block_id + self.start_block_id, Arc::clone(block_device))
+            let block_cache =
+                get_block_cache(block_id + self.start_block_id, Arc::clone(block_device));
+            let mut block_cache = block_cache.lock();
+            // look for a free bit first, full blocks are left unmodified
+            let free = block_cache.read(0, |bitmap_block: &BitmapBlock| {
+                bitmap_block
+                    .iter()
+                    .enumerate()
+                    .find(|(_, bits64)| **bits64 != u64::MAX)
+                    .map(|(bits64_pos, bits64)| (bits64_pos, bits64.trailing_ones() as usize))
+            });
+            if let Some((bits64_pos, inner_pos)) = free {
+                // modify cache
+                block_cache.modify(0, |bitmap_block: &mut BitmapBlock| {
+                    bitmap_block[bits64_pos] |= 1u64 << inner_pos;
+                });
+                return Some(block_id * BLOCK_BITS + bits64_pos * 64 + inner_pos);
+            }
+        }
+        None
+    }
+
+    /// Allocate up to `count` consecutive bits, from the first free bit at
+    /// or after `goal`, or else from the first free bit. Return the first
+    /// bit allocated and the number of bits.
+    pub fn alloc_run(
+        &self,
+        block_device: &Arc<dyn BlockDevice>,
+        goal: usize,
+        count: usize,
+    ) -> Option<(usize, usize)> {
+        let start = self
+            .find_free(block_device, goal)
+            .or_else(|| self.find_free(block_device, 0))?;
+        let mut len = 0;
+        while len < count && start + len < self.maximum() {
+            let (block_pos, bits64_pos, inner_pos) = decomposition(start + len);
+            let taken = get_block_cache(block_pos + self.start_block_id, Arc::clone(block_device))
                 .lock()
                 .modify(0, |bitmap_block: &mut BitmapBlock| {
-                    if let Some((bits64_pos, inner_pos)) = bitmap_block
-                        .iter()
-                        .enumerate()
-                        .find(|(_, bits64)| **bits64 != u64::MAX)
-                        .map(|(bits64_pos, bits64)| (bits64_pos, bits64.trailing_ones() as usize))
-                    {
-                        // modify cache
-                        bitmap_block[bits64_pos] |= 1u64 << inner_pos;
-                        Some(block_id * BLOCK_BITS + bits64_pos * 64 + inner_pos as usize)
-                    } else {
-                        None
-                    }
+                    let free = bitmap_block[bits64_pos] & (1u64 << inner_pos) == 0;
+                    bitmap_block[bits64_pos] |= 1u64 << inner_pos;
+                    free
                 });
-            if pos.is_some() {
-                return pos;
+            if !taken {
+                break;
             }
+            len += 1;
         }
-        None
+        Some((start, len))
+    }
+
+    /// First free bit at or after `from`
+    fn find_free(&self, block_device: &Arc<dyn BlockDevice>, from: usize) -> Option<usize> {
+        (from / BLOCK_BITS..self.blocks).find_map(|block_pos| {
+            get_block_cache(block_pos + self.start_block_id, Arc::clone(block_device))
+                .lock()
+                .read(0, |bitmap_block: &BitmapBlock| {
+                    bitmap_block
+                        .iter()
+                        .enumerate()
+                        .find_map(|(bits64_pos, bits64)| {
+                            let base = block_pos * BLOCK_BITS + bits64_pos * 64;
+                            // the bits before `from` count as allocated
+                            let skipped = match from.saturating_sub(base) {
+                                0 => 0,
+                                n if n >= 64 => u64::MAX,
+                                n => (1u64 << n) - 1,
+                            };
+                            let free = !(*bits64 | skipped);
+                            (free != 0).then(|| base + free.trailing_zeros() as usize)
+                        })
+                })
+        })
     }
 
     pub fn dealloc(&self, block_device: &Arc<dyn BlockDevice>, bit: usize) {
@@ -56,6 +109,42 @@ impl Bitmap {
             });
     }
 
+    /// Whether `bit` is allocated
+    pub fn is_allocated(&self, block_device: &Arc<dyn BlockDevice>, bit: usize) -> bool {
+        let (block_pos, bits64_pos, inner_pos) = decomposition(bit);
+        get_block_cache(block_pos + self.start_block_id, Arc::clone(block_device))
+            .lock()
+            .read(0, |bitmap_block: &BitmapBlock| {
+                bitmap_block[bits64_pos] & (1u64 << inner_pos) > 0
+            })
+    }
+
+    /// Mark `bit` as allocated
+    pub fn set(&self, block_device: &Arc<dyn BlockDevice>, bit: usize) {
+        let (block_pos, bits64_pos, inner_pos) = decomposition(bit);
+        get_block_cache(block_pos + self.start_block_id, Arc::clone(block_device))
+            .lock()
+            .modify(0, |bitmap_block: &mut BitmapBlock| {
+                bitmap_block[bits64_pos] |= 1u64 << inner_pos;
+            });
+    }
+
+    /// Number of allocated bits
+    pub fn count_allocated(&self, block_device: &Arc<dyn BlockDevice>) -> usize {
+        (0..self.blocks)
+            .map(|block_id| {
+                get_block_cache(block_id + self.start_block_id, Arc::clone(block_device))
+                    .lock()
+                    .read(0, |bitmap_block: &BitmapBlock| {
+                        bitmap_block
+                            .iter()
+                            .map(|bits64| bits64.count_ones() as usize)
+                            .sum::<usize>()
+                    })
+            })
+            .sum()
+    }
+
     pub fn maximum(&self) -> usize {
         self.blocks * BLOCK_BITS
     }
@@ -63,6 +152,6 @@ impl Bitmap {
 
 fn decomposition(mut bit: usize) -> (usize, usize, usize) {
     let block_pos = bit / BLOCK_BITS;
-    bit = bit % BLOCK_BITS;
+    bit %= BLOCK_BITS;
     (block_pos, bit / 64, bit % 64)
 }
@@ -1,61 +1,197 @@
 use super::BLOCK_SZ;
 use crate::block_device::BlockDevice;
 extern crate alloc;
-use alloc::collections::VecDeque;
+use alloc::collections::{BTreeMap, BTreeSet};
 use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
 use lazy_static::lazy_static;
-use spin::Mutex;
+use spin::{Mutex, MutexGuard};
 
-const BLOCK_CACHE_SIZE: usize = 16;
+/// Blocks read ahead of a reader going through a file in order, at most
+/// half of the cache
+pub(crate) const READ_AHEAD_BLOCKS: usize = 32;
+/// Blocks cached unless [`EasyFileSystem::open`](crate::EasyFileSystem::open) says otherwise
+pub const BLOCK_CACHE_SIZE: usize = 16;
 pub struct BlockCache {
     cache: [u8; BLOCK_SZ],
     block_id: usize,
     block_device: Arc<dyn BlockDevice>,
     modified: bool,
+    // modified inside a transaction, written by the journal on commit
+    logged: bool,
 }
 
-// simple FIFO cache thing
+/// Caches are keyed by device as well, so that two devices never share blocks
+type CacheKey = (usize, usize);
+
+pub(crate) fn device_key(block_device: &Arc<dyn BlockDevice>) -> usize {
+    Arc::as_ptr(block_device) as *const () as usize
+}
+
+fn cache_key(block_id: usize, block_device: &Arc<dyn BlockDevice>) -> CacheKey {
+    (device_key(block_device), block_id)
+}
+
+/// A cached block and its reference bit
+struct CacheSlot {
+    key: CacheKey,
+    cache: Arc<Mutex<BlockCache>>,
+    /// used since the clock hand last went past it
+    referenced: bool,
+}
+
+/// CLOCK cache: on a miss, the hand sweeps the slots and evicts the first
+/// block neither in use nor referenced since its last turn, so that the
+/// blocks used over and over, such as the superblock and the bitmaps,
+/// stay while blocks read once go
 pub struct BlockCacheManager {
-    queue: VecDeque<(usize, Arc<Mutex<BlockCache>>)>,
+    slots: Vec<CacheSlot>,
+    /// position of each cached block in `slots`
+    positions: BTreeMap<CacheKey, usize>,
+    /// next slot looked at for eviction
+    hand: usize,
+    capacity: usize,
+    // logged blocks pushed out of the cache, kept until the transaction commits
+    evicted_logged: Vec<(CacheKey, Arc<Mutex<BlockCache>>)>,
+    /// block following the last one read from a file, where a sequential
+    /// reader goes next
+    next_read: Option<CacheKey>,
 }
 
 impl BlockCacheManager {
     pub fn new() -> Self {
         Self {
-            queue: VecDeque::new(),
+            slots: Vec::new(),
+            positions: BTreeMap::new(),
+            hand: 0,
+            capacity: BLOCK_CACHE_SIZE,
+            evicted_logged: Vec::new(),
+            next_read: None,
         }
     }
 
+    /// Set the number of blocks cached, at least one. Blocks over the
+    /// capacity go as the cache misses.
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity.max(1);
+    }
+
     pub fn get_block_cache(
         &mut self,
         block_id: usize,
         block_device: Arc<dyn BlockDevice>,
     ) -> Arc<Mutex<BlockCache>> {
-        if let Some(pair) = self.queue.iter().find(|pair| pair.0 == block_id) {
-            Arc::clone(&pair.1)
-        } else {
-            // substitute
-            if self.queue.len() == BLOCK_CACHE_SIZE {
-                // from front to tail
-                if let Some((idx, _)) = self
-                    .queue
-                    .iter()
-                    .enumerate()
-                    .find(|(_, pair)| Arc::strong_count(&pair.1) == 1)
-                {
-                    self.queue.drain(idx..=idx);
-                } else {
-                    panic!("Run out of BlockCache!");
-                }
-            }
-            // load block into mem and push back
-            let block_cache = Arc::new(Mutex::new(BlockCache::new(
+        let key = cache_key(block_id, &block_device);
+        if let Some(&position) = self.positions.get(&key) {
+            let slot = &mut self.slots[position];
+            slot.referenced = true;
+            return Arc::clone(&slot.cache);
+        }
+        // when every block is in use the cache grows past its capacity
+        // for a while, rather than failing
+        while self.slots.len() >= self.capacity && self.evict() {}
+        // load block into mem (or take it back from the logged ones)
+        let block_cache = match self.evicted_logged.iter().position(|pair| pair.0 == key) {
+            Some(idx) => self.evicted_logged.swap_remove(idx).1,
+            None => Arc::new(Mutex::new(BlockCache::new(
                 block_id,
                 Arc::clone(&block_device),
-            )));
-            self.queue.push_back((block_id, Arc::clone(&block_cache)));
-            block_cache
+            ))),
+        };
+        self.insert(key, Arc::clone(&block_cache));
+        block_cache
+    }
+
+    /// Add a block right behind the hand, the last one it comes to
+    fn insert(&mut self, key: CacheKey, cache: Arc<Mutex<BlockCache>>) {
+        self.slots.push(CacheSlot {
+            key,
+            cache,
+            referenced: true,
+        });
+        let last = self.slots.len() - 1;
+        if self.hand < last {
+            self.slots.swap(self.hand, last);
+            self.positions.insert(self.slots[last].key, last);
+            self.positions.insert(key, self.hand);
+            self.hand += 1;
+        } else {
+            self.positions.insert(key, last);
+            self.hand = 0;
+        }
+    }
+
+    fn contains(&self, key: &CacheKey) -> bool {
+        self.positions.contains_key(key) || self.evicted_logged.iter().any(|pair| pair.0 == *key)
+    }
+
+    /// Load the blocks of `block_ids` that are not cached, up to half of
+    /// the cache, reading each run of consecutive blocks in a single request
+    pub fn prefetch(&mut self, block_ids: &[usize], block_device: &Arc<dyn BlockDevice>) {
+        let missing: Vec<usize> = block_ids
+            .iter()
+            .copied()
+            .filter(|block_id| !self.contains(&cache_key(*block_id, block_device)))
+            .take(self.capacity / 2)
+            .collect();
+        for run in missing.chunk_by(|a, b| *b == *a + 1) {
+            let mut buf = vec![0u8; run.len() * BLOCK_SZ];
+            block_device.read_blocks(run[0], &mut buf);
+            for (block_id, data) in run.iter().zip(buf.chunks(BLOCK_SZ)) {
+                while self.slots.len() >= self.capacity && self.evict() {}
+                let mut cache = [0u8; BLOCK_SZ];
+                cache.copy_from_slice(data);
+                let block_cache = BlockCache {
+                    cache,
+                    block_id: *block_id,
+                    block_device: Arc::clone(block_device),
+                    modified: false,
+                    logged: false,
+                };
+                self.insert(
+                    cache_key(*block_id, block_device),
+                    Arc::new(Mutex::new(block_cache)),
+                );
+            }
+        }
+    }
+
+    /// Evict a block, writing it back if it was modified.
+    /// Return false if every block is in use.
+    fn evict(&mut self) -> bool {
+        // the first turn may only clear reference bits
+        for _ in 0..2 * self.slots.len() {
+            if self.hand >= self.slots.len() {
+                self.hand = 0;
+            }
+            let slot = &mut self.slots[self.hand];
+            if Arc::strong_count(&slot.cache) > 1 {
+                self.hand += 1;
+                continue;
+            }
+            if slot.referenced {
+                slot.referenced = false;
+                self.hand += 1;
+                continue;
+            }
+            // the last slot takes the place of the victim, under the hand
+            let victim = self.slots.swap_remove(self.hand);
+            self.positions.remove(&victim.key);
+            if let Some(moved) = self.slots.get(self.hand) {
+                self.positions.insert(moved.key, self.hand);
+            }
+            let logged = {
+                let mut cache = victim.cache.lock();
+                cache.sync();
+                cache.logged
+            };
+            if logged {
+                self.evicted_logged.push((victim.key, victim.cache));
+            }
+            return true;
         }
+        false
     }
 }
 
@@ -68,6 +204,7 @@ impl BlockCache {
             block_id,
             block_device,
             modified: false,
+            logged: false,
         }
     }
 
@@ -79,6 +216,15 @@ impl BlockCache {
         f(self.get_mut(offset))
     }
 
+    /// Modify the block without logging it to the running transaction,
+    /// used for file content which is not journaled
+    pub fn modify_unlogged<T, V>(&mut self, offset: usize, f: impl FnOnce(&mut T) -> V) -> V {
+        let logged = self.logged;
+        let v = self.modify(offset, f);
+        self.logged = logged;
+        v
+    }
+
     fn addr_of_offset(&self, offset: usize) -> usize {
         &self.cache[offset] as *const _ as usize
     }
@@ -100,12 +246,16 @@ impl BlockCache {
         let type_size = core::mem::size_of::<T>();
         assert!(offset + type_size <= BLOCK_SZ);
         self.modified = true;
+        if LOGGING.lock().contains(&device_key(&self.block_device)) {
+            self.logged = true;
+        }
         let addr = self.addr_of_offset(offset);
         unsafe { &mut *(addr as *mut T) }
     }
 
     pub fn sync(&mut self) {
-        if self.modified {
+        // logged blocks must not reach the disk before their transaction commits
+        if self.modified && !self.logged {
             self.modified = false;
             self.block_device.write_block(self.block_id, &self.cache)
         }
@@ -121,6 +271,10 @@ impl Drop for BlockCache {
 }
 
 lazy_static! {
+    /// Devices in a transaction, whose modified blocks are logged to it.
+    /// Each device has its own, as a transaction may wait for the disk
+    /// while another filesystem starts one.
+    static ref LOGGING: Mutex<BTreeSet<usize>> = Mutex::new(BTreeSet::new());
     pub static ref BLOCK_CACHE_MANAGER: Mutex<BlockCacheManager> =
         Mutex::new(BlockCacheManager::new());
 }
@@ -134,9 +288,136 @@ pub fn get_block_cache(
         .get_block_cache(block_id, block_device)
 }
 
+/// Write back every modified block, except the ones logged to the running
+/// transaction. Runs of consecutive blocks are written in a single request.
 pub fn block_cache_sync_all() {
     let manager = BLOCK_CACHE_MANAGER.lock();
-    for (_, cache) in manager.queue.iter() {
-        cache.lock().sync();
+    let mut dirty: Vec<(CacheKey, MutexGuard<BlockCache>)> = manager
+        .slots
+        .iter()
+        .map(|slot| (slot.key, slot.cache.lock()))
+        .filter(|(_, cache)| cache.modified && !cache.logged)
+        .collect();
+    write_back(&mut dirty);
+}
+
+/// Write back the modified blocks of `block_ids`, except the ones logged
+/// to the running transaction
+pub fn block_cache_sync(block_ids: &[u32], block_device: &Arc<dyn BlockDevice>) {
+    // a block is locked only once
+    let mut block_ids = block_ids.to_vec();
+    block_ids.sort_unstable();
+    block_ids.dedup();
+    let manager = BLOCK_CACHE_MANAGER.lock();
+    let mut dirty: Vec<(CacheKey, MutexGuard<BlockCache>)> = block_ids
+        .iter()
+        .filter_map(|block_id| {
+            let key = cache_key(*block_id as usize, block_device);
+            let position = *manager.positions.get(&key)?;
+            Some((key, manager.slots[position].cache.lock()))
+        })
+        .filter(|(_, cache)| cache.modified && !cache.logged)
+        .collect();
+    write_back(&mut dirty);
+}
+
+/// Write back locked blocks, a single request per run of consecutive blocks
+fn write_back(dirty: &mut [(CacheKey, MutexGuard<BlockCache>)]) {
+    dirty.sort_by_key(|(key, _)| *key);
+    for run in dirty.chunk_by_mut(|(a, _), (b, _)| a.0 == b.0 && b.1 == a.1 + 1) {
+        let buf: Vec<u8> = run.iter().flat_map(|(_, cache)| cache.cache).collect();
+        run[0].1.block_device.write_blocks(run[0].1.block_id, &buf);
+        for (_, cache) in run.iter_mut() {
+            cache.modified = false;
+        }
+    }
+}
+
+/// Whether block `block_id` of `block_device` is cached
+pub fn block_cache_contains(block_id: usize, block_device: &Arc<dyn BlockDevice>) -> bool {
+    BLOCK_CACHE_MANAGER
+        .lock()
+        .contains(&cache_key(block_id, block_device))
+}
+
+/// Load the blocks of `block_ids` that are not cached yet
+pub fn block_cache_prefetch(block_ids: &[usize], block_device: &Arc<dyn BlockDevice>) {
+    BLOCK_CACHE_MANAGER.lock().prefetch(block_ids, block_device);
+}
+
+/// Remember that block `block_id` comes next for a sequential reader,
+/// and return whether the previous read expected `first` to come next
+pub fn block_cache_read_next(
+    first: usize,
+    block_id: Option<usize>,
+    block_device: &Arc<dyn BlockDevice>,
+) -> bool {
+    let mut manager = BLOCK_CACHE_MANAGER.lock();
+    let sequential = manager.next_read == Some(cache_key(first, block_device));
+    manager.next_read = block_id.map(|block_id| cache_key(block_id, block_device));
+    sequential
+}
+
+/// Write back like [`block_cache_sync_all`] without waiting for a lock,
+/// skipping the blocks in use. Return false if some were skipped.
+pub fn block_cache_try_sync_all() -> bool {
+    let Some(manager) = BLOCK_CACHE_MANAGER.try_lock() else {
+        return false;
+    };
+    let caches: Vec<_> = manager
+        .slots
+        .iter()
+        .map(|slot| Arc::clone(&slot.cache))
+        .collect();
+    drop(manager);
+    let mut synced = true;
+    for cache in caches {
+        match cache.try_lock() {
+            Some(mut cache) => cache.sync(),
+            None => synced = false,
+        }
+    }
+    synced
+}
+
+/// Set the number of blocks cached, for every device
+pub fn block_cache_set_capacity(capacity: usize) {
+    BLOCK_CACHE_MANAGER.lock().set_capacity(capacity);
+}
+
+/// Start logging the modified blocks of `block_device` to a transaction
+pub fn block_cache_begin_logging(block_device: &Arc<dyn BlockDevice>) {
+    assert!(
+        LOGGING.lock().insert(device_key(block_device)),
+        "Nested transaction!"
+    );
+}
+
+/// Stop logging and take the blocks of `block_device` logged so far,
+/// the caller becomes responsible for writing them back
+pub fn block_cache_take_logged(
+    block_device: &Arc<dyn BlockDevice>,
+) -> Vec<(usize, [u8; BLOCK_SZ])> {
+    let device = device_key(block_device);
+    LOGGING.lock().remove(&device);
+    let mut manager = BLOCK_CACHE_MANAGER.lock();
+    let evicted = core::mem::take(&mut manager.evicted_logged);
+    let mut v = Vec::new();
+    let cached = manager.slots.iter().map(|slot| (&slot.key, &slot.cache));
+    for (key, cache) in cached.chain(evicted.iter().map(|pair| (&pair.0, &pair.1))) {
+        let mut cache = cache.lock();
+        if key.0 == device && cache.logged {
+            cache.logged = false;
+            cache.modified = false;
+            v.push((cache.block_id, cache.cache));
+        }
     }
+    // blocks of other devices are not ours to take
+    manager.evicted_logged = evicted
+        .into_iter()
+        .filter(|(_, cache)| cache.lock().logged)
+        .collect();
+    // the same transaction always makes the same journal
+    v.sort_unstable_by_key(|(block_id, _)| *block_id);
+    v
 }
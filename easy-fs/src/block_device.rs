@@ -1,7 +1,23 @@
+use crate::BLOCK_SZ;
 use core::any::Any;
 
 pub trait BlockDevice: Send + Sync + Any {
     fn read_block(&self, block_id: usize, buf: &mut [u8]);
     fn write_block(&self, block_id: usize, buf: &[u8]);
     fn handle_irq(&self);
+    /// Number of blocks of the device
+    fn num_blocks(&self) -> usize;
+    /// Read the consecutive blocks from `block_id` that fill `buf`.
+    /// Devices that take several blocks per request should override it.
+    fn read_blocks(&self, block_id: usize, buf: &mut [u8]) {
+        for (i, block) in buf.chunks_mut(BLOCK_SZ).enumerate() {
+            self.read_block(block_id + i, block);
+        }
+    }
+    /// Write `buf` to the consecutive blocks from `block_id`
+    fn write_blocks(&self, block_id: usize, buf: &[u8]) {
+        for (i, block) in buf.chunks(BLOCK_SZ).enumerate() {
+            self.write_block(block_id + i, block);
+        }
+    }
 }
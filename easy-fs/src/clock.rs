@@ -0,0 +1,14 @@
+use spin::Mutex;
+
+/// Source of the current time, in seconds since the Unix epoch
+static CLOCK: Mutex<fn() -> u32> = Mutex::new(|| 0);
+
+/// Install the function used to timestamp inodes.
+/// Until this is called every timestamp is 0.
+pub fn set_clock(clock: fn() -> u32) {
+    *CLOCK.lock() = clock;
+}
+
+pub(crate) fn now() -> u32 {
+    (CLOCK.lock())()
+}
@@ -0,0 +1,290 @@
+//! Hash index of large directories
+//!
+//! On images with [`FEATURE_DIR_INDEX`](crate::FEATURE_DIR_INDEX), every
+//! directory starts with an index entry. Once the directory holds
+//! [`INDEX_MIN_ENTRIES`] entries, the index entry points at a file made of
+//! a header and a power of two of slots, an open addressing hash table of
+//! the offsets of the entries. Smaller directories are searched linearly.
+use crate::{
+    block_cache::get_block_cache,
+    efs::EasyFileSystem,
+    layout::{DiskInode, DiskInodeType},
+};
+extern crate alloc;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+
+/// Entries a directory holds before it gets an index
+const INDEX_MIN_ENTRIES: usize = 32;
+/// Slots of the smallest index
+const INDEX_MIN_SLOTS: usize = 64;
+/// Slots of the largest index, which is written in a single transaction.
+/// Directories too large for it are searched linearly.
+const INDEX_MAX_SLOTS: usize = 8192;
+/// Size of the header, the number of entries and of slots in use
+const INDEX_HEADER_SZ: usize = 8;
+/// A slot never used, which ends a probe
+const SLOT_EMPTY: u32 = 0;
+/// A slot whose entry was removed, which a probe goes past
+const SLOT_REMOVED: u32 = u32::MAX;
+
+/// FNV-1a hash of a name
+fn hash(name: &str) -> u32 {
+    name.bytes().fold(0x811c_9dc5, |hash, byte| {
+        (hash ^ byte as u32).wrapping_mul(0x0100_0193)
+    })
+}
+
+/// Slots of an index file, 0 if its size is not a valid one
+fn slot_count(index: &DiskInode) -> usize {
+    let size = index.size as usize;
+    if size < INDEX_HEADER_SZ {
+        return 0;
+    }
+    let slots = (size - INDEX_HEADER_SZ) / 4;
+    if slots.is_power_of_two() && INDEX_HEADER_SZ + slots * 4 == size {
+        slots
+    } else {
+        0
+    }
+}
+
+impl EasyFileSystem {
+    /// A copy of inode `inode_id`, so that no block stays locked while
+    /// the content of the inode is walked
+    pub(crate) fn load_disk_inode(&self, inode_id: u32) -> DiskInode {
+        let (block_id, block_offset) = self.get_disk_inode_pos(inode_id);
+        get_block_cache(block_id as usize, self.block_device.clone())
+            .lock()
+            .read(block_offset, |disk_inode: &DiskInode| *disk_inode)
+    }
+
+    /// Write back an inode read by [`EasyFileSystem::load_disk_inode`]
+    pub(crate) fn store_disk_inode(&self, inode_id: u32, new_inode: &DiskInode) {
+        let (block_id, block_offset) = self.get_disk_inode_pos(inode_id);
+        get_block_cache(block_id as usize, self.block_device.clone())
+            .lock()
+            .modify(block_offset, |disk_inode: &mut DiskInode| {
+                *disk_inode = *new_inode
+            });
+    }
+
+    fn read_u32(&self, index: &DiskInode, offset: usize) -> u32 {
+        let mut buf = [0u8; 4];
+        index.read_at(offset, &mut buf, &self.block_device);
+        u32::from_le_bytes(buf)
+    }
+
+    fn write_u32(&self, index: &mut DiskInode, offset: usize, value: u32) {
+        index.write_logged_at(offset, &value.to_le_bytes(), &self.block_device);
+    }
+
+    fn read_slot(&self, index: &DiskInode, slot: usize) -> u32 {
+        self.read_u32(index, INDEX_HEADER_SZ + slot * 4)
+    }
+
+    /// Look for entry `name` of directory `dir_inode` in its `index`.
+    /// Return the slot and the inode of the entry, or else the slot
+    /// where it would be added if there is one.
+    fn probe(
+        &self,
+        index: &DiskInode,
+        dir_inode: &DiskInode,
+        name: &str,
+    ) -> Result<(usize, u32), Option<usize>> {
+        let slots = slot_count(index);
+        let mut slot = hash(name) as usize & slots.wrapping_sub(1);
+        let mut free = None;
+        for _ in 0..slots {
+            match self.read_slot(index, slot) {
+                SLOT_EMPTY => return Err(free.or(Some(slot))),
+                SLOT_REMOVED => {
+                    free.get_or_insert(slot);
+                }
+                value => {
+                    if let Some((entry_name, inode)) =
+                        dir_inode.read_dirent_at(value as usize - 1, &self.block_device)
+                    {
+                        if entry_name == name {
+                            return Ok((slot, inode));
+                        }
+                    }
+                }
+            }
+            slot = (slot + 1) & (slots - 1);
+        }
+        Err(free)
+    }
+
+    /// Find entry `name` of directory `dir`, through its index if it has one
+    pub(crate) fn lookup_entry(&self, dir: u32, name: &str) -> Option<u32> {
+        let dir_inode = self.load_disk_inode(dir);
+        if !dir_inode.is_dir() {
+            return None;
+        }
+        match dir_inode.index_dirent(&self.block_device) {
+            Some(index_id) if self.long_names && index_id != 0 => {
+                let index = self.load_disk_inode(index_id);
+                self.probe(&index, &dir_inode, name)
+                    .ok()
+                    .map(|(_, inode)| inode)
+            }
+            _ => dir_inode
+                .dirents(self.long_names, &self.block_device)
+                .into_iter()
+                .find(|(entry_name, _)| entry_name == name)
+                .map(|(_, inode)| inode),
+        }
+    }
+
+    /// Record in the index of directory `dir` its entry `name` just added
+    /// at `offset`, building the index if the directory has grown enough
+    pub(crate) fn index_insert(&mut self, dir: u32, name: &str, offset: usize) {
+        let dir_inode = self.load_disk_inode(dir);
+        let index_id = match dir_inode.index_dirent(&self.block_device) {
+            Some(0) => {
+                if dir_inode.dirents(true, &self.block_device).len() >= INDEX_MIN_ENTRIES {
+                    self.build_index(dir);
+                }
+                return;
+            }
+            Some(index_id) => index_id,
+            None => return,
+        };
+        let mut index = self.load_disk_inode(index_id);
+        let live = self.read_u32(&index, 0);
+        let used = self.read_u32(&index, 4);
+        // keep the load under three quarters
+        if (used as usize + 1) * 4 > slot_count(&index) * 3 {
+            self.build_index(dir);
+            return;
+        }
+        let Err(Some(slot)) = self.probe(&index, &dir_inode, name) else {
+            self.build_index(dir);
+            return;
+        };
+        if self.read_slot(&index, slot) == SLOT_EMPTY {
+            self.write_u32(&mut index, 4, used + 1);
+        }
+        self.write_u32(&mut index, 0, live + 1);
+        self.write_u32(&mut index, INDEX_HEADER_SZ + slot * 4, offset as u32 + 1);
+    }
+
+    /// Forget entry `name` of directory `dir` in its index,
+    /// before the entry is removed
+    pub(crate) fn index_remove(&mut self, dir: u32, name: &str) {
+        let dir_inode = self.load_disk_inode(dir);
+        let index_id = match dir_inode.index_dirent(&self.block_device) {
+            Some(index_id) if index_id != 0 => index_id,
+            _ => return,
+        };
+        let mut index = self.load_disk_inode(index_id);
+        if let Ok((slot, _)) = self.probe(&index, &dir_inode, name) {
+            let live = self.read_u32(&index, 0);
+            self.write_u32(&mut index, 0, live.saturating_sub(1));
+            self.write_u32(&mut index, INDEX_HEADER_SZ + slot * 4, SLOT_REMOVED);
+        }
+    }
+
+    /// Build the index of directory `dir` anew, or leave it without one
+    /// if it is too small or too large for an index
+    pub(crate) fn build_index(&mut self, dir: u32) {
+        self.drop_index(dir);
+        let mut dir_inode = self.load_disk_inode(dir);
+        if dir_inode.index_dirent(&self.block_device).is_none() {
+            return;
+        }
+        let entries: Vec<_> = dir_inode
+            .dir_slots(true, &self.block_device)
+            .into_iter()
+            .filter(|slot| slot.is_named())
+            .collect();
+        let slots = (entries.len() * 2).next_power_of_two().max(INDEX_MIN_SLOTS);
+        if entries.len() < INDEX_MIN_ENTRIES || slots > INDEX_MAX_SLOTS {
+            return;
+        }
+        let mut table = vec![SLOT_EMPTY; 2 + slots];
+        table[0] = entries.len() as u32;
+        table[1] = entries.len() as u32;
+        for entry in entries {
+            let mut slot = hash(&entry.name) as usize & (slots - 1);
+            while table[2 + slot] != SLOT_EMPTY {
+                slot = (slot + 1) & (slots - 1);
+            }
+            table[2 + slot] = entry.offset as u32 + 1;
+        }
+        let bytes: Vec<u8> = table.iter().flat_map(|value| value.to_le_bytes()).collect();
+        let block_device = Arc::clone(&self.block_device);
+        let index_id = self.alloc_inode();
+        let mut index = self.load_disk_inode(index_id);
+        index.initialize(DiskInodeType::File, self.extents);
+        index.increase_size(
+            bytes.len() as u32,
+            |goal, count| self.alloc_data_run(goal, count),
+            &block_device,
+        );
+        index.write_logged_at(0, &bytes, &self.block_device);
+        self.store_disk_inode(index_id, &index);
+        dir_inode.set_index_inode(index_id, &self.block_device);
+    }
+
+    /// Release the index of directory `dir`, if it has one
+    pub(crate) fn drop_index(&mut self, dir: u32) {
+        let mut dir_inode = self.load_disk_inode(dir);
+        let index_id = match dir_inode.index_dirent(&self.block_device) {
+            Some(index_id) if index_id != 0 => index_id,
+            _ => return,
+        };
+        let mut index = self.load_disk_inode(index_id);
+        for block_id in index.decrease_size(0, &self.block_device) {
+            self.dealloc_data(block_id);
+        }
+        self.store_disk_inode(index_id, &index);
+        self.dealloc_inode(index_id);
+        dir_inode.set_index_inode(0, &self.block_device);
+    }
+
+    /// Forget the index of directory `dir` without releasing it,
+    /// for an index whose blocks cannot be walked
+    pub(crate) fn detach_index(&mut self, dir: u32) {
+        let mut dir_inode = self.load_disk_inode(dir);
+        if dir_inode.index_dirent(&self.block_device).is_some() {
+            dir_inode.set_index_inode(0, &self.block_device);
+        }
+    }
+
+    /// Whether `index_id` indexes every entry of directory `dir`, and only them
+    pub(crate) fn is_index_valid(&self, dir: u32, index_id: u32) -> bool {
+        let dir_inode = self.load_disk_inode(dir);
+        let index = self.load_disk_inode(index_id);
+        let slots = slot_count(&index);
+        if !index.is_file() || slots < INDEX_MIN_SLOTS {
+            return false;
+        }
+        let entries = dir_inode.dirents(true, &self.block_device);
+        let live = self.read_u32(&index, 0) as usize;
+        let used = self.read_u32(&index, 4) as usize;
+        let mut in_use = 0;
+        let mut indexed = 0;
+        for slot in 0..slots {
+            match self.read_slot(&index, slot) {
+                SLOT_EMPTY => {}
+                SLOT_REMOVED => in_use += 1,
+                _ => {
+                    in_use += 1;
+                    indexed += 1;
+                }
+            }
+        }
+        live == entries.len()
+            && indexed == live
+            && used == in_use
+            && used < slots
+            && entries.iter().all(|(name, inode)| {
+                self.probe(&index, &dir_inode, name)
+                    .ok()
+                    .is_some_and(|(_, found)| found == *inode)
+            })
+    }
+}
@@ -4,9 +4,17 @@ use spin::Mutex;
 
 use crate::{
     bitmap::Bitmap,
-    block_cache::{block_cache_sync_all, get_block_cache},
+    block_cache::{
+        block_cache_set_capacity, block_cache_sync_all, block_cache_try_sync_all, get_block_cache,
+    },
     block_device::BlockDevice,
-    layout::{DiskInode, DiskInodeType, SuperBlock},
+    clock::now,
+    journal::Journal,
+    layout::{
+        DiskInode, DiskInodeType, SuperBlock, FEATURES_SUPPORTED, FEATURE_DIR_INDEX,
+        FEATURE_EXTENTS, FEATURE_LONG_NAMES, JOURNAL_BLOCKS, LONG_NAME_LENGTH_LIMIT,
+        NAME_LENGTH_LIMIT,
+    },
     vfs::Inode,
     BLOCK_SZ,
 };
@@ -16,26 +24,59 @@ pub struct EasyFileSystem {
     pub inode_bitmap: Bitmap,
     pub data_bitmap: Bitmap,
     inode_area_start_block: u32,
-    data_area_start_block: u32,
+    pub(crate) data_area_start_block: u32,
+    journal: Journal,
+    /// directories hold entries of variable length
+    pub(crate) long_names: bool,
+    /// directories start with an index entry
+    pub(crate) dir_index: bool,
+    /// new inodes map their blocks with extent trees
+    pub(crate) extents: bool,
+    /// seconds between background flushes, 0 for none
+    flush_interval: u32,
+    /// time of the last flush
+    last_flush: u32,
+}
+
+/// Usage of the filesystem, as reported by [`EasyFileSystem::stat`]
+#[derive(Debug, Clone, Copy)]
+pub struct FsStat {
+    /// size of the device in blocks
+    pub total_blocks: u32,
+    /// number of blocks in the data area
+    pub data_blocks: u32,
+    pub free_data_blocks: u32,
+    pub inodes: u32,
+    pub free_inodes: u32,
 }
 
 type DataBlock = [u8; BLOCK_SZ];
 /// An easy fs over a block device
 impl EasyFileSystem {
-    /// A data block of block size
+    /// Format a block device, with the `FEATURE_*` flags in `features`
     pub fn create(
         block_device: Arc<dyn BlockDevice>,
         total_blocks: u32,
         inode_bitmap_blocks: u32,
+        features: u32,
     ) -> Arc<Mutex<Self>> {
+        assert!(features & !FEATURES_SUPPORTED == 0, "Unknown EFS features!");
+        assert!(
+            features & FEATURE_DIR_INDEX == 0 || features & FEATURE_LONG_NAMES != 0,
+            "Directory index needs long names!"
+        );
         // calculate block size of areas & create bitmaps
         let inode_bitmap = Bitmap::new(1, inode_bitmap_blocks as usize);
         let inode_num = inode_bitmap.maximum();
         let inode_area_blocks =
-            ((inode_num * core::mem::size_of::<DiskInode>() + BLOCK_SZ - 1) / BLOCK_SZ) as u32;
+            (inode_num * core::mem::size_of::<DiskInode>()).div_ceil(BLOCK_SZ) as u32;
         let inode_total_blocks = inode_bitmap_blocks + inode_area_blocks;
-        let data_total_blocks = total_blocks - 1 - inode_total_blocks;
-        let data_bitmap_blocks = (data_total_blocks + 4096) / 4097;
+        assert!(
+            total_blocks > 1 + inode_total_blocks + JOURNAL_BLOCKS + 1,
+            "Device too small!"
+        );
+        let data_total_blocks = total_blocks - 1 - inode_total_blocks - JOURNAL_BLOCKS;
+        let data_bitmap_blocks = data_total_blocks.div_ceil(4097);
         let data_area_blocks = data_total_blocks - data_bitmap_blocks;
         let data_bitmap = Bitmap::new(
             (1 + inode_bitmap_blocks + inode_area_blocks) as usize,
@@ -47,6 +88,12 @@ impl EasyFileSystem {
             data_bitmap,
             inode_area_start_block: 1 + inode_bitmap_blocks,
             data_area_start_block: 1 + inode_total_blocks + data_bitmap_blocks,
+            journal: Journal::new(total_blocks - JOURNAL_BLOCKS, JOURNAL_BLOCKS),
+            long_names: features & FEATURE_LONG_NAMES != 0,
+            dir_index: features & FEATURE_DIR_INDEX != 0,
+            extents: features & FEATURE_EXTENTS != 0,
+            flush_interval: 0,
+            last_flush: 0,
         };
         // clear all blocks
         for i in 0..total_blocks {
@@ -68,7 +115,9 @@ impl EasyFileSystem {
                     inode_area_blocks,
                     data_bitmap_blocks,
                     data_area_blocks,
+                    JOURNAL_BLOCKS,
                 );
+                super_block.features = features;
             },
         );
         // write back immediately
@@ -78,18 +127,34 @@ impl EasyFileSystem {
         get_block_cache(root_inode_block_id as usize, Arc::clone(&block_device))
             .lock()
             .modify(root_inode_offset, |disk_inode: &mut DiskInode| {
-                disk_inode.initialize(DiskInodeType::Directory);
+                disk_inode.initialize(DiskInodeType::Directory, efs.extents);
             });
         block_cache_sync_all();
         Arc::new(Mutex::new(efs))
     }
-    /// Open a block device as a filesystem
-    pub fn open(block_device: Arc<dyn BlockDevice>) -> Arc<Mutex<Self>> {
-        // read SuperBlock
-        get_block_cache(0, Arc::clone(&block_device))
+    /// Whether the block device holds a filesystem that can be opened
+    pub fn detect(block_device: &Arc<dyn BlockDevice>) -> bool {
+        get_block_cache(0, Arc::clone(block_device))
             .lock()
             .read(0, |super_block: &SuperBlock| {
+                super_block.is_valid() && super_block.features & !FEATURES_SUPPORTED == 0
+            })
+    }
+    /// Open a block device as a filesystem, replaying its journal.
+    /// The block cache, shared by the filesystems open, is set to
+    /// `cache_blocks` blocks.
+    pub fn open(block_device: Arc<dyn BlockDevice>, cache_blocks: usize) -> Arc<Mutex<Self>> {
+        block_cache_set_capacity(cache_blocks);
+        // read SuperBlock
+        let efs = get_block_cache(0, Arc::clone(&block_device)).lock().read(
+            0,
+            |super_block: &SuperBlock| {
+                assert!(!super_block.is_v1(), "EFS image of an older layout!");
                 assert!(super_block.is_valid(), "Error loading EFS!");
+                assert!(
+                    super_block.features & !FEATURES_SUPPORTED == 0,
+                    "Unsupported EFS features!"
+                );
                 let inode_total_blocks =
                     super_block.inode_bitmap_blocks + super_block.inode_area_blocks;
                 let efs = Self {
@@ -101,9 +166,66 @@ impl EasyFileSystem {
                     ),
                     inode_area_start_block: 1 + super_block.inode_bitmap_blocks,
                     data_area_start_block: 1 + inode_total_blocks + super_block.data_bitmap_blocks,
+                    journal: Journal::new(
+                        super_block.total_blocks - super_block.journal_blocks,
+                        super_block.journal_blocks,
+                    ),
+                    long_names: super_block.has_long_names(),
+                    dir_index: super_block.has_long_names() && super_block.has_dir_index(),
+                    extents: super_block.has_extents(),
+                    flush_interval: 0,
+                    last_flush: 0,
                 };
                 Arc::new(Mutex::new(efs))
-            })
+            },
+        );
+        {
+            let efs = efs.lock();
+            efs.journal.replay(&efs.block_device);
+        }
+        efs
+    }
+    /// Start a transaction, the updates until [`EasyFileSystem::commit`]
+    /// reach the disk all together or not at all
+    pub fn begin(&mut self) {
+        self.journal.begin(&self.block_device);
+    }
+    /// Commit the running transaction
+    pub fn commit(&mut self) {
+        self.journal.commit(&self.block_device);
+    }
+    /// Write back every modified block, outside of a transaction
+    pub fn sync(&mut self) {
+        self.journal.commit(&self.block_device);
+        self.last_flush = now();
+    }
+    /// Have [`EasyFileSystem::flush_if_due`] write back modified blocks
+    /// every `seconds`, 0 to stop
+    pub fn set_flush_interval(&mut self, seconds: u32) {
+        self.flush_interval = seconds;
+    }
+    /// Background flush, for a timer: write back the modified blocks if the
+    /// flush interval has passed since the last flush. It never waits, the
+    /// flush is left to a later call while the filesystem or a block is in use.
+    pub fn flush_if_due(efs: &Arc<Mutex<Self>>) -> bool {
+        let Some(fs) = efs.try_lock() else {
+            return false;
+        };
+        let time = now();
+        if fs.flush_interval == 0 || time.wrapping_sub(fs.last_flush) < fs.flush_interval {
+            return false;
+        }
+        // the writes may give the CPU to a task using the filesystem, so they
+        // go without it. Blocks of a transaction started meanwhile are logged,
+        // and left to its commit.
+        drop(fs);
+        if !block_cache_try_sync_all() {
+            return false;
+        }
+        if let Some(mut fs) = efs.try_lock() {
+            fs.last_flush = time;
+        }
+        true
     }
     /// Get the root inode of the filesystem
     pub fn root_inode(efs: &Arc<Mutex<Self>>) -> Inode {
@@ -123,6 +245,13 @@ impl EasyFileSystem {
             (inode_id % inodes_per_block) as usize * inode_size,
         )
     }
+    /// Get inode id by its position in the inode area
+    pub fn get_inode_id(&self, block_id: u32, block_offset: usize) -> u32 {
+        let inode_size = core::mem::size_of::<DiskInode>();
+        let inodes_per_block = (BLOCK_SZ / inode_size) as u32;
+        (block_id - self.inode_area_start_block) * inodes_per_block
+            + (block_offset / inode_size) as u32
+    }
     /// Get data block by id
     pub fn get_data_block_id(&self, data_block_id: u32) -> u32 {
         self.data_area_start_block + data_block_id
@@ -132,22 +261,107 @@ impl EasyFileSystem {
         self.inode_bitmap.alloc(&self.block_device).unwrap() as u32
     }
 
-    /// Allocate a data block
-    pub fn alloc_data(&mut self) -> u32 {
-        self.data_bitmap.alloc(&self.block_device).unwrap() as u32 + self.data_area_start_block
+    /// Allocate up to `count` consecutive data blocks, cleared to zero, from
+    /// the first free block at or after block `goal`. Return the first block
+    /// and the number of blocks allocated.
+    pub fn alloc_data_run(&mut self, goal: u32, count: u32) -> (u32, u32) {
+        let goal = goal.saturating_sub(self.data_area_start_block) as usize;
+        let (bit, len) = self
+            .data_bitmap
+            .alloc_run(&self.block_device, goal, count as usize)
+            .unwrap();
+        let start = bit as u32 + self.data_area_start_block;
+        // nothing refers to the blocks before the transaction commits,
+        // so clearing them needs no logging
+        for block_id in start..start + len as u32 {
+            get_block_cache(block_id as usize, Arc::clone(&self.block_device))
+                .lock()
+                .modify_unlogged(0, |data_block: &mut DataBlock| data_block.fill(0));
+        }
+        (start, len as u32)
     }
     /// Deallocate a data block
     pub fn dealloc_data(&mut self, block_id: u32) {
-        get_block_cache(block_id as usize, Arc::clone(&self.block_device))
-            .lock()
-            .modify(0, |data_block: &mut DataBlock| {
-                data_block.iter_mut().for_each(|p| {
-                    *p = 0;
-                })
-            });
         self.data_bitmap.dealloc(
             &self.block_device,
             (block_id - self.data_area_start_block) as usize,
         )
     }
+    /// Deallocate an inode
+    pub fn dealloc_inode(&mut self, inode_id: u32) {
+        self.inode_bitmap
+            .dealloc(&self.block_device, inode_id as usize)
+    }
+    /// Get the usage of the filesystem
+    pub fn stat(&self) -> FsStat {
+        let (total_blocks, data_blocks) = get_block_cache(0, Arc::clone(&self.block_device))
+            .lock()
+            .read(0, |super_block: &SuperBlock| {
+                (super_block.total_blocks, super_block.data_area_blocks)
+            });
+        let inodes = self.inode_bitmap.maximum() as u32;
+        FsStat {
+            total_blocks,
+            data_blocks,
+            free_data_blocks: data_blocks
+                - self.data_bitmap.count_allocated(&self.block_device) as u32,
+            inodes,
+            free_inodes: inodes - self.inode_bitmap.count_allocated(&self.block_device) as u32,
+        }
+    }
+
+    /// Longest name of a directory entry, in bytes
+    pub fn name_length_limit(&self) -> usize {
+        if self.long_names {
+            LONG_NAME_LENGTH_LIMIT
+        } else {
+            NAME_LENGTH_LIMIT
+        }
+    }
+
+    /// Remove the entries of directory `dir` matched by `f`,
+    /// which is given their name and inode number
+    pub(crate) fn remove_entries(&mut self, dir: u32, f: impl Fn(&str, u32) -> bool) {
+        let block_device = Arc::clone(&self.block_device);
+        let mut disk_inode = self.load_disk_inode(dir);
+        for (name, inode) in disk_inode.dirents(self.long_names, &block_device) {
+            if f(&name, inode) {
+                self.index_remove(dir, &name);
+            }
+        }
+        let new_size = disk_inode.remove_dirents(self.long_names, &block_device, f);
+        for block_id in disk_inode.decrease_size(new_size, &block_device) {
+            self.dealloc_data(block_id);
+        }
+        self.store_disk_inode(dir, &disk_inode);
+    }
+
+    /// Add an entry to directory `dir`
+    pub(crate) fn append_entry(&mut self, dir: u32, name: &str, inode: u32) {
+        let block_device = Arc::clone(&self.block_device);
+        let (long_names, dir_index) = (self.long_names, self.dir_index);
+        let mut disk_inode = self.load_disk_inode(dir);
+        let mut grow = |disk_inode: &mut DiskInode, new_size: u32| {
+            if new_size > disk_inode.size {
+                disk_inode.increase_size(
+                    new_size,
+                    |goal, count| self.alloc_data_run(goal, count),
+                    &block_device,
+                );
+            }
+        };
+        // the index entry comes first, the index is built once the directory is large
+        if dir_index && disk_inode.size == 0 {
+            grow(&mut disk_inode, DiskInode::dirent_end(0, "", true));
+            disk_inode.add_index_dirent(&block_device);
+        }
+        let offset = disk_inode.dirent_slot(name, long_names, &block_device);
+        grow(
+            &mut disk_inode,
+            DiskInode::dirent_end(offset, name, long_names),
+        );
+        disk_inode.add_dirent(offset, name, inode, long_names, &block_device);
+        self.store_disk_inode(dir, &disk_inode);
+        self.index_insert(dir, name, offset);
+    }
 }
@@ -0,0 +1,1161 @@
+//! ext2, read and write
+//!
+//! Revisions 0 and 1 are supported, with the `filetype`, `sparse_super` and
+//! `large_file` features; `mke2fs -t ext2` makes nothing else that matters
+//! to a driver. The volume goes through the same block cache as easy-fs, in
+//! sectors of [`BLOCK_SZ`] bytes whatever its block size. Nothing is
+//! journaled, a volume written to when the machine went down is for
+//! `e2fsck` to check.
+use super::block_cache::{
+    block_cache_prefetch, block_cache_set_capacity, block_cache_sync_all, block_cache_try_sync_all,
+    device_key, get_block_cache,
+};
+use super::clock::now;
+use super::vfs::Handles;
+use super::{BlockDevice, DiskInodeType, InodeStat, BLOCK_SZ};
+extern crate alloc;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+const SUPER_BLOCK_OFFSET: usize = 1024;
+const EXT2_MAGIC: u16 = 0xef53;
+const ROOT_INO: u32 = 2;
+/// Inode size and first inode not reserved, in revision 0
+const GOOD_OLD_INODE_SIZE: usize = 128;
+const GOOD_OLD_FIRST_INO: u32 = 11;
+
+/// Directory entries hold the type of their inode
+const INCOMPAT_FILETYPE: u32 = 0x2;
+/// Backups of the superblock are only in some groups, which changes
+/// nothing here
+const RO_COMPAT_SPARSE_SUPER: u32 = 0x1;
+/// Files may be 2 GiB or more
+const RO_COMPAT_LARGE_FILE: u32 = 0x2;
+const INCOMPAT_SUPPORTED: u32 = INCOMPAT_FILETYPE;
+const RO_COMPAT_SUPPORTED: u32 = RO_COMPAT_SPARSE_SUPER | RO_COMPAT_LARGE_FILE;
+
+// fields of the superblock
+const S_INODES_COUNT: usize = 0;
+const S_BLOCKS_COUNT: usize = 4;
+const S_FREE_BLOCKS_COUNT: usize = 12;
+const S_FREE_INODES_COUNT: usize = 16;
+const S_FIRST_DATA_BLOCK: usize = 20;
+const S_LOG_BLOCK_SIZE: usize = 24;
+const S_BLOCKS_PER_GROUP: usize = 32;
+const S_INODES_PER_GROUP: usize = 40;
+const S_MAGIC: usize = 56;
+const S_REV_LEVEL: usize = 76;
+const S_FIRST_INO: usize = 84;
+const S_INODE_SIZE: usize = 88;
+const S_FEATURE_INCOMPAT: usize = 96;
+const S_FEATURE_RO_COMPAT: usize = 100;
+
+// fields of a group descriptor
+const GROUP_DESC_SIZE: usize = 32;
+const BG_BLOCK_BITMAP: usize = 0;
+const BG_INODE_BITMAP: usize = 4;
+const BG_INODE_TABLE: usize = 8;
+const BG_FREE_BLOCKS_COUNT: usize = 12;
+const BG_FREE_INODES_COUNT: usize = 14;
+const BG_USED_DIRS_COUNT: usize = 16;
+
+// fields of an inode
+const I_MODE: usize = 0;
+const I_SIZE: usize = 4;
+const I_ATIME: usize = 8;
+const I_CTIME: usize = 12;
+const I_MTIME: usize = 16;
+const I_DTIME: usize = 20;
+const I_LINKS_COUNT: usize = 26;
+/// blocks used, in sectors of 512 bytes
+const I_BLOCKS: usize = 28;
+const I_FLAGS: usize = 32;
+const I_BLOCK: usize = 40;
+const I_FILE_ACL: usize = 104;
+const I_SIZE_HIGH: usize = 108;
+
+const S_IFMT: u16 = 0xf000;
+const S_IFREG: u16 = 0x8000;
+const S_IFDIR: u16 = 0x4000;
+const S_IFLNK: u16 = 0xa000;
+/// The directory holds an htree index, which changes to it would leave stale
+const INDEX_FL: u32 = 0x1000;
+/// Blocks pointed to by the inode itself
+const DIRECT_BLOCKS: usize = 12;
+/// Slots of the single, double and triple indirect blocks, with the levels
+/// of indirect blocks under each
+const INDIRECT_TREES: [(usize, u32); 3] = [(12, 1), (13, 2), (14, 3)];
+
+/// Types of the inodes of directory entries
+const FT_REG_FILE: u8 = 1;
+const FT_DIR: u8 = 2;
+const NAME_LENGTH_LIMIT: usize = 255;
+
+/// Read `buf.len()` bytes of the device at byte `offset`
+fn read_bytes(block_device: &Arc<dyn BlockDevice>, offset: usize, buf: &mut [u8]) {
+    let mut pos = offset;
+    while pos < offset + buf.len() {
+        let start = pos % BLOCK_SZ;
+        let len = (BLOCK_SZ - start).min(offset + buf.len() - pos);
+        get_block_cache(pos / BLOCK_SZ, Arc::clone(block_device))
+            .lock()
+            .read(0, |data: &[u8; BLOCK_SZ]| {
+                buf[pos - offset..pos - offset + len].copy_from_slice(&data[start..start + len])
+            });
+        pos += len;
+    }
+}
+
+/// Write `buf` to the device at byte `offset`. Nothing of ext2 is logged to
+/// the transactions of easy-fs, which may be running on another device.
+fn write_bytes(block_device: &Arc<dyn BlockDevice>, offset: usize, buf: &[u8]) {
+    let mut pos = offset;
+    while pos < offset + buf.len() {
+        let start = pos % BLOCK_SZ;
+        let len = (BLOCK_SZ - start).min(offset + buf.len() - pos);
+        get_block_cache(pos / BLOCK_SZ, Arc::clone(block_device))
+            .lock()
+            .modify_unlogged(0, |data: &mut [u8; BLOCK_SZ]| {
+                data[start..start + len].copy_from_slice(&buf[pos - offset..pos - offset + len])
+            });
+        pos += len;
+    }
+}
+
+fn le16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn le32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+/// Space taken by a directory entry whose name is `name_len` bytes
+fn rec_size(name_len: usize) -> usize {
+    (8 + name_len).next_multiple_of(4)
+}
+
+/// The first 128 bytes of an inode, which all revisions share
+#[derive(Clone)]
+struct RawInode([u8; GOOD_OLD_INODE_SIZE]);
+
+impl RawInode {
+    fn get16(&self, offset: usize) -> u16 {
+        le16(&self.0, offset)
+    }
+
+    fn get32(&self, offset: usize) -> u32 {
+        le32(&self.0, offset)
+    }
+
+    fn set16(&mut self, offset: usize, value: u16) {
+        self.0[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn set32(&mut self, offset: usize, value: u32) {
+        self.0[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn file_type(&self) -> u16 {
+        self.get16(I_MODE) & S_IFMT
+    }
+
+    fn is_dir(&self) -> bool {
+        self.file_type() == S_IFDIR
+    }
+
+    /// Whether it is a regular file, the only files that are written
+    fn is_file(&self) -> bool {
+        self.file_type() == S_IFREG
+    }
+
+    /// Whether it is a symbolic link whose target is held in place of the
+    /// block pointers
+    fn is_fast_symlink(&self, block_size: usize) -> bool {
+        let acl_sectors = if self.get32(I_FILE_ACL) != 0 {
+            block_size / 512
+        } else {
+            0
+        };
+        self.file_type() == S_IFLNK && self.get32(I_BLOCKS) as usize == acl_sectors
+    }
+
+    fn size(&self) -> u64 {
+        let high = if self.is_file() {
+            self.get32(I_SIZE_HIGH)
+        } else {
+            0
+        };
+        (high as u64) << 32 | self.get32(I_SIZE) as u64
+    }
+
+    fn set_size(&mut self, size: u64) {
+        self.set32(I_SIZE, size as u32);
+        if self.is_file() {
+            self.set32(I_SIZE_HIGH, (size >> 32) as u32);
+        }
+    }
+
+    fn block(&self, slot: usize) -> u32 {
+        self.get32(I_BLOCK + slot * 4)
+    }
+
+    fn set_block(&mut self, slot: usize, block: u32) {
+        self.set32(I_BLOCK + slot * 4, block);
+    }
+
+    /// Count `blocks` more blocks of `block_size` bytes as used, or fewer
+    fn add_blocks(&mut self, blocks: isize, block_size: usize) {
+        let sectors = self.get32(I_BLOCKS) as isize + blocks * (block_size / 512) as isize;
+        self.set32(I_BLOCKS, sectors as u32);
+    }
+
+    fn links(&self) -> u16 {
+        self.get16(I_LINKS_COUNT)
+    }
+
+    fn set_links(&mut self, links: u16) {
+        self.set16(I_LINKS_COUNT, links);
+    }
+
+    fn touch(&mut self) {
+        let time = now();
+        self.set32(I_MTIME, time);
+        self.set32(I_CTIME, time);
+    }
+}
+
+/// A record of a directory, free ones included
+struct DirRecord {
+    /// position in the directory
+    pos: usize,
+    /// 0 for a free record
+    ino: u32,
+    rec_len: usize,
+    name: String,
+}
+
+impl DirRecord {
+    /// Space the entry needs, none if it is free
+    fn used(&self) -> usize {
+        if self.ino == 0 {
+            0
+        } else {
+            rec_size(self.name.len())
+        }
+    }
+}
+
+pub struct Ext2FileSystem {
+    block_device: Arc<dyn BlockDevice>,
+    block_size: usize,
+    blocks_count: u32,
+    first_data_block: u32,
+    blocks_per_group: u32,
+    inodes_per_group: u32,
+    inode_size: usize,
+    first_ino: u32,
+    groups: u32,
+    /// whether directory entries hold the type of their inode
+    filetype: bool,
+    /// whether files keep the upper bits of their size, from revision 1
+    large_sizes: bool,
+    /// seconds between background flushes, 0 to never flush
+    flush_interval: u32,
+    /// time of the last flush
+    last_flush: u32,
+}
+
+impl Ext2FileSystem {
+    /// Read the layout of a volume from its superblock, `None` if it is not
+    /// an ext2 volume this driver can write to
+    fn parse(block_device: Arc<dyn BlockDevice>) -> Option<Self> {
+        let mut sb = [0u8; 1024];
+        read_bytes(&block_device, SUPER_BLOCK_OFFSET, &mut sb);
+        if le16(&sb, S_MAGIC) != EXT2_MAGIC || le32(&sb, S_LOG_BLOCK_SIZE) > 6 {
+            return None;
+        }
+        let block_size = 1024 << le32(&sb, S_LOG_BLOCK_SIZE);
+        let rev = le32(&sb, S_REV_LEVEL);
+        let (inode_size, first_ino, incompat, ro_compat) = match rev {
+            0 => (GOOD_OLD_INODE_SIZE, GOOD_OLD_FIRST_INO, 0, 0),
+            1 => (
+                le16(&sb, S_INODE_SIZE) as usize,
+                le32(&sb, S_FIRST_INO),
+                le32(&sb, S_FEATURE_INCOMPAT),
+                le32(&sb, S_FEATURE_RO_COMPAT),
+            ),
+            _ => return None,
+        };
+        let blocks_count = le32(&sb, S_BLOCKS_COUNT);
+        let first_data_block = le32(&sb, S_FIRST_DATA_BLOCK);
+        let blocks_per_group = le32(&sb, S_BLOCKS_PER_GROUP);
+        let inodes_per_group = le32(&sb, S_INODES_PER_GROUP);
+        let bits_per_block = block_size as u32 * 8;
+        if incompat & !INCOMPAT_SUPPORTED != 0
+            || ro_compat & !RO_COMPAT_SUPPORTED != 0
+            || inode_size < GOOD_OLD_INODE_SIZE
+            || !inode_size.is_power_of_two()
+            || inode_size > block_size
+            || !(1..=bits_per_block).contains(&blocks_per_group)
+            || !(1..=bits_per_block).contains(&inodes_per_group)
+            || blocks_count <= first_data_block
+        {
+            return None;
+        }
+        let groups = (blocks_count - first_data_block).div_ceil(blocks_per_group);
+        if (groups as u64 * inodes_per_group as u64) < le32(&sb, S_INODES_COUNT) as u64 {
+            return None;
+        }
+        Some(Self {
+            block_device,
+            block_size,
+            blocks_count,
+            first_data_block,
+            blocks_per_group,
+            inodes_per_group,
+            inode_size,
+            first_ino,
+            groups,
+            filetype: incompat & INCOMPAT_FILETYPE != 0,
+            large_sizes: rev >= 1,
+            flush_interval: 0,
+            last_flush: 0,
+        })
+    }
+
+    /// Whether the block device holds an ext2 volume this driver writes
+    pub fn detect(block_device: &Arc<dyn BlockDevice>) -> bool {
+        Self::parse(Arc::clone(block_device)).is_some()
+    }
+
+    /// Open a block device as a filesystem. The block cache, shared by the
+    /// filesystems open, is set to `cache_blocks` blocks.
+    pub fn open(block_device: Arc<dyn BlockDevice>, cache_blocks: usize) -> Arc<Mutex<Self>> {
+        block_cache_set_capacity(cache_blocks);
+        Arc::new(Mutex::new(
+            Self::parse(block_device).expect("Error loading ext2!"),
+        ))
+    }
+
+    pub fn root_inode(fs: &Arc<Mutex<Self>>) -> Ext2Inode {
+        let device = device_key(&fs.lock().block_device);
+        Ext2Inode::new(ROOT_INO, Arc::clone(fs), device)
+    }
+
+    /// Write back every modified block
+    pub fn sync(&mut self) {
+        block_cache_sync_all();
+        self.last_flush = now();
+    }
+
+    /// Have [`Ext2FileSystem::flush_if_due`] write back modified blocks
+    /// every `seconds`, 0 to stop
+    pub fn set_flush_interval(&mut self, seconds: u32) {
+        self.flush_interval = seconds;
+    }
+
+    /// Background flush, for a timer: write back the modified blocks if the
+    /// flush interval has passed since the last flush. It never waits, the
+    /// flush is left to a later call while the filesystem or a block is in use.
+    pub fn flush_if_due(fs: &Arc<Mutex<Self>>) -> bool {
+        let Some(guard) = fs.try_lock() else {
+            return false;
+        };
+        let time = now();
+        if guard.flush_interval == 0 || time.wrapping_sub(guard.last_flush) < guard.flush_interval {
+            return false;
+        }
+        // the writes may give the CPU to a task using the filesystem
+        drop(guard);
+        if !block_cache_try_sync_all() {
+            return false;
+        }
+        if let Some(mut guard) = fs.try_lock() {
+            guard.last_flush = time;
+        }
+        true
+    }
+
+    fn read_bytes(&self, offset: usize, buf: &mut [u8]) {
+        read_bytes(&self.block_device, offset, buf);
+    }
+
+    fn write_bytes(&self, offset: usize, buf: &[u8]) {
+        write_bytes(&self.block_device, offset, buf);
+    }
+
+    fn read_u32(&self, offset: usize) -> u32 {
+        let mut bytes = [0u8; 4];
+        self.read_bytes(offset, &mut bytes);
+        u32::from_le_bytes(bytes)
+    }
+
+    fn write_u32(&self, offset: usize, value: u32) {
+        self.write_bytes(offset, &value.to_le_bytes());
+    }
+
+    /// Add `delta` to the counter of 16 bits at `offset`
+    fn add_u16(&self, offset: usize, delta: i32) {
+        let mut bytes = [0u8; 2];
+        self.read_bytes(offset, &mut bytes);
+        let value = (u16::from_le_bytes(bytes) as i32 + delta) as u16;
+        self.write_bytes(offset, &value.to_le_bytes());
+    }
+
+    /// Add `delta` to the counter of 32 bits at `offset`
+    fn add_u32(&self, offset: usize, delta: i32) {
+        let value = (self.read_u32(offset) as i64 + delta as i64) as u32;
+        self.write_u32(offset, value);
+    }
+
+    /// Device offset of block `block`
+    fn block_offset(&self, block: u32) -> usize {
+        block as usize * self.block_size
+    }
+
+    /// Device offset of the descriptor of group `group`
+    fn group_desc(&self, group: u32) -> usize {
+        self.block_offset(self.first_data_block + 1) + group as usize * GROUP_DESC_SIZE
+    }
+
+    fn inode_group(&self, ino: u32) -> u32 {
+        (ino - 1) / self.inodes_per_group
+    }
+
+    fn inode_offset(&self, ino: u32) -> usize {
+        let group = self.inode_group(ino);
+        let table = self.read_u32(self.group_desc(group) + BG_INODE_TABLE);
+        self.block_offset(table) + ((ino - 1) % self.inodes_per_group) as usize * self.inode_size
+    }
+
+    fn load_inode(&self, ino: u32) -> RawInode {
+        let mut inode = RawInode([0; GOOD_OLD_INODE_SIZE]);
+        self.read_bytes(self.inode_offset(ino), &mut inode.0);
+        inode
+    }
+
+    fn store_inode(&self, ino: u32, inode: &RawInode) {
+        self.write_bytes(self.inode_offset(ino), &inode.0);
+    }
+
+    /// Set the first clear bit of the first `count` of bitmap block
+    /// `bitmap`, and return it
+    fn take_bit(&self, bitmap: u32, count: u32) -> Option<u32> {
+        let mut bytes = vec![0u8; count.div_ceil(8) as usize];
+        self.read_bytes(self.block_offset(bitmap), &mut bytes);
+        let bit = (0..count).find(|bit| bytes[*bit as usize / 8] & 1 << (bit % 8) == 0)?;
+        let byte = bytes[bit as usize / 8] | 1 << (bit % 8);
+        self.write_bytes(self.block_offset(bitmap) + bit as usize / 8, &[byte]);
+        Some(bit)
+    }
+
+    fn clear_bit(&self, bitmap: u32, bit: u32) {
+        let offset = self.block_offset(bitmap) + bit as usize / 8;
+        let mut byte = [0u8];
+        self.read_bytes(offset, &mut byte);
+        self.write_bytes(offset, &[byte[0] & !(1 << (bit % 8))]);
+    }
+
+    /// Allocate a zeroed block, from group `goal` on, `None` if the volume
+    /// is full
+    fn alloc_block(&self, goal: u32) -> Option<u32> {
+        for i in 0..self.groups {
+            let group = (goal + i) % self.groups;
+            let desc = self.group_desc(group);
+            let mut free = [0u8; 2];
+            self.read_bytes(desc + BG_FREE_BLOCKS_COUNT, &mut free);
+            if u16::from_le_bytes(free) == 0 {
+                continue;
+            }
+            let first = self.first_data_block + group * self.blocks_per_group;
+            let count = self.blocks_per_group.min(self.blocks_count - first);
+            let Some(bit) = self.take_bit(self.read_u32(desc + BG_BLOCK_BITMAP), count) else {
+                continue;
+            };
+            self.add_u16(desc + BG_FREE_BLOCKS_COUNT, -1);
+            self.add_u32(SUPER_BLOCK_OFFSET + S_FREE_BLOCKS_COUNT, -1);
+            let block = first + bit;
+            self.write_bytes(self.block_offset(block), &vec![0u8; self.block_size]);
+            return Some(block);
+        }
+        None
+    }
+
+    fn free_block(&self, block: u32) {
+        let index = block - self.first_data_block;
+        let desc = self.group_desc(index / self.blocks_per_group);
+        self.clear_bit(
+            self.read_u32(desc + BG_BLOCK_BITMAP),
+            index % self.blocks_per_group,
+        );
+        self.add_u16(desc + BG_FREE_BLOCKS_COUNT, 1);
+        self.add_u32(SUPER_BLOCK_OFFSET + S_FREE_BLOCKS_COUNT, 1);
+    }
+
+    /// Allocate a zeroed inode, from group `goal` on, `None` if there is
+    /// none left
+    fn alloc_inode(&self, goal: u32, dir: bool) -> Option<u32> {
+        for i in 0..self.groups {
+            let group = (goal + i) % self.groups;
+            let desc = self.group_desc(group);
+            let mut free = [0u8; 2];
+            self.read_bytes(desc + BG_FREE_INODES_COUNT, &mut free);
+            if u16::from_le_bytes(free) == 0 {
+                continue;
+            }
+            let bitmap = self.read_u32(desc + BG_INODE_BITMAP);
+            let Some(bit) = self.take_bit(bitmap, self.inodes_per_group) else {
+                continue;
+            };
+            let ino = group * self.inodes_per_group + bit + 1;
+            if ino < self.first_ino {
+                // reserved, and should have been marked so
+                continue;
+            }
+            self.add_u16(desc + BG_FREE_INODES_COUNT, -1);
+            self.add_u32(SUPER_BLOCK_OFFSET + S_FREE_INODES_COUNT, -1);
+            if dir {
+                self.add_u16(desc + BG_USED_DIRS_COUNT, 1);
+            }
+            self.write_bytes(self.inode_offset(ino), &vec![0u8; self.inode_size]);
+            return Some(ino);
+        }
+        None
+    }
+
+    fn free_inode(&self, ino: u32, dir: bool) {
+        let group = self.inode_group(ino);
+        let desc = self.group_desc(group);
+        self.clear_bit(
+            self.read_u32(desc + BG_INODE_BITMAP),
+            (ino - 1) % self.inodes_per_group,
+        );
+        self.add_u16(desc + BG_FREE_INODES_COUNT, 1);
+        self.add_u32(SUPER_BLOCK_OFFSET + S_FREE_INODES_COUNT, 1);
+        if dir {
+            self.add_u16(desc + BG_USED_DIRS_COUNT, -1);
+        }
+    }
+
+    /// Block pointers held by an indirect block
+    fn pointers(&self) -> usize {
+        self.block_size / 4
+    }
+
+    /// Block of the volume holding block `index` of a file, 0 for a hole.
+    /// With a `goal` group, the blocks missing on the way are allocated
+    /// there or further. `None` if the volume is full or `index` is past
+    /// the largest file.
+    fn map_block(&self, inode: &mut RawInode, index: usize, goal: Option<u32>) -> Option<u32> {
+        let pointers = self.pointers();
+        let (slot, depth, index) = if index < DIRECT_BLOCKS {
+            (index, 0, 0)
+        } else {
+            let mut index = index - DIRECT_BLOCKS;
+            let mut tree = None;
+            for (slot, depth) in INDIRECT_TREES {
+                if index < pointers.pow(depth) {
+                    tree = Some((slot, depth, index));
+                    break;
+                }
+                index -= pointers.pow(depth);
+            }
+            tree?
+        };
+        let mut block = inode.block(slot);
+        if block == 0 {
+            let Some(goal) = goal else {
+                return Some(0);
+            };
+            block = self.alloc_block(goal)?;
+            inode.set_block(slot, block);
+            inode.add_blocks(1, self.block_size);
+        }
+        for level in (0..depth).rev() {
+            let entry = self.block_offset(block) + index / pointers.pow(level) % pointers * 4;
+            let mut next = self.read_u32(entry);
+            if next == 0 {
+                let Some(goal) = goal else {
+                    return Some(0);
+                };
+                next = self.alloc_block(goal)?;
+                self.write_u32(entry, next);
+                inode.add_blocks(1, self.block_size);
+            }
+            block = next;
+        }
+        Some(block)
+    }
+
+    /// Free the blocks under `block`, which has `depth` levels of indirect
+    /// blocks, from block `first` of the tree on. Return the number of
+    /// blocks freed, `block` included if the whole tree went.
+    fn free_tree(&self, block: u32, depth: u32, first: usize) -> usize {
+        if depth == 0 {
+            self.free_block(block);
+            return 1;
+        }
+        let span = self.pointers().pow(depth - 1);
+        let mut freed = 0;
+        for i in first / span..self.pointers() {
+            let entry = self.block_offset(block) + i * 4;
+            let child = self.read_u32(entry);
+            if child == 0 {
+                continue;
+            }
+            let child_first = if i == first / span { first % span } else { 0 };
+            freed += self.free_tree(child, depth - 1, child_first);
+            if child_first == 0 {
+                self.write_u32(entry, 0);
+            }
+        }
+        if first == 0 {
+            self.free_block(block);
+            freed += 1;
+        }
+        freed
+    }
+
+    /// Set the size of a file, freeing the blocks past the new end
+    fn truncate(&self, inode: &mut RawInode, size: u64) {
+        if size < inode.size() {
+            let kept = (size as usize).div_ceil(self.block_size);
+            let mut freed = 0;
+            for slot in kept.min(DIRECT_BLOCKS)..DIRECT_BLOCKS {
+                let block = inode.block(slot);
+                if block != 0 {
+                    self.free_block(block);
+                    inode.set_block(slot, 0);
+                    freed += 1;
+                }
+            }
+            let mut start = DIRECT_BLOCKS;
+            for (slot, depth) in INDIRECT_TREES {
+                let span = self.pointers().pow(depth);
+                let block = inode.block(slot);
+                if block != 0 && kept < start + span {
+                    let first = kept.saturating_sub(start);
+                    freed += self.free_tree(block, depth, first);
+                    if first == 0 {
+                        inode.set_block(slot, 0);
+                    }
+                }
+                start += span;
+            }
+            inode.add_blocks(-(freed as isize), self.block_size);
+            // the end of the last block is read as zeros if the file grows again
+            let tail = size as usize % self.block_size;
+            if tail != 0 {
+                let block = self.map_block(inode, size as usize / self.block_size, None);
+                if let Some(block) = block.filter(|block| *block != 0) {
+                    self.write_bytes(
+                        self.block_offset(block) + tail,
+                        &vec![0u8; self.block_size - tail],
+                    );
+                }
+            }
+        }
+        inode.set_size(size);
+        inode.touch();
+    }
+
+    fn read(&self, inode: &RawInode, offset: usize, buf: &mut [u8]) -> usize {
+        let end = (inode.size() as usize).min(offset.saturating_add(buf.len()));
+        if offset >= end {
+            return 0;
+        }
+        if inode.is_fast_symlink(self.block_size) {
+            buf[..end - offset].copy_from_slice(&inode.0[I_BLOCK + offset..I_BLOCK + end]);
+            return end - offset;
+        }
+        let mut inode = inode.clone();
+        let first = offset / self.block_size;
+        let blocks: Vec<u32> = (first..end.div_ceil(self.block_size))
+            .map(|index| self.map_block(&mut inode, index, None).unwrap_or(0))
+            .collect();
+        // the sectors in reach are fetched together
+        let sectors_per_block = self.block_size / BLOCK_SZ;
+        let sectors: Vec<usize> = blocks
+            .iter()
+            .filter(|block| **block != 0)
+            .flat_map(|block| {
+                let start = *block as usize * sectors_per_block;
+                start..start + sectors_per_block
+            })
+            .collect();
+        block_cache_prefetch(&sectors, &self.block_device);
+        let mut pos = offset;
+        for block in blocks {
+            let start = pos % self.block_size;
+            let len = (self.block_size - start).min(end - pos);
+            let dst = &mut buf[pos - offset..pos - offset + len];
+            match block {
+                0 => dst.fill(0),
+                block => self.read_bytes(self.block_offset(block) + start, dst),
+            }
+            pos += len;
+        }
+        end - offset
+    }
+
+    /// Write to a file of inode `ino`, short if the volume is full
+    fn write(&self, ino: u32, inode: &mut RawInode, offset: usize, buf: &[u8]) -> usize {
+        let limit = if self.large_sizes {
+            usize::MAX
+        } else {
+            u32::MAX as usize
+        };
+        let end = limit.min(offset.saturating_add(buf.len()));
+        let goal = self.inode_group(ino);
+        let mut pos = offset;
+        while pos < end {
+            let Some(block) = self.map_block(inode, pos / self.block_size, Some(goal)) else {
+                break;
+            };
+            let start = pos % self.block_size;
+            let len = (self.block_size - start).min(end - pos);
+            self.write_bytes(
+                self.block_offset(block) + start,
+                &buf[pos - offset..pos - offset + len],
+            );
+            pos += len;
+        }
+        if pos as u64 > inode.size() {
+            inode.set_size(pos as u64);
+            if pos > i32::MAX as usize {
+                let offset = SUPER_BLOCK_OFFSET + S_FEATURE_RO_COMPAT;
+                let features = self.read_u32(offset);
+                self.write_u32(offset, features | RO_COMPAT_LARGE_FILE);
+            }
+        }
+        if pos > offset {
+            inode.touch();
+        }
+        pos.saturating_sub(offset)
+    }
+
+    /// Records of a directory, the free ones included. A block whose
+    /// records do not add up is skipped.
+    fn records(&self, dir: &RawInode) -> Vec<DirRecord> {
+        let mut data = vec![0u8; dir.size() as usize];
+        let len = self.read(dir, 0, &mut data);
+        data.truncate(len);
+        let mut records = Vec::new();
+        for (i, block) in data.chunks(self.block_size).enumerate() {
+            let mut pos = 0;
+            while pos + 8 <= block.len() {
+                let rec_len = le16(block, pos + 4) as usize;
+                let name_len = block[pos + 6] as usize;
+                if rec_len < 8
+                    || !rec_len.is_multiple_of(4)
+                    || pos + rec_len > block.len()
+                    || 8 + name_len > rec_len
+                {
+                    break;
+                }
+                records.push(DirRecord {
+                    pos: i * self.block_size + pos,
+                    ino: le32(block, pos),
+                    rec_len,
+                    name: String::from_utf8_lossy(&block[pos + 8..pos + 8 + name_len]).into_owned(),
+                });
+                pos += rec_len;
+            }
+        }
+        records
+    }
+
+    fn find(&self, dir: &RawInode, name: &str) -> Option<u32> {
+        self.records(dir)
+            .into_iter()
+            .find(|record| record.ino != 0 && record.name == name)
+            .map(|record| record.ino)
+    }
+
+    /// Write a record at position `pos` of a directory
+    fn write_record(
+        &self,
+        dir: &RawInode,
+        pos: usize,
+        ino: u32,
+        rec_len: usize,
+        name: &str,
+        file_type: u8,
+    ) {
+        let mut record = vec![0u8; rec_size(name.len())];
+        record[..4].copy_from_slice(&ino.to_le_bytes());
+        record[4..6].copy_from_slice(&(rec_len as u16).to_le_bytes());
+        record[6] = name.len() as u8;
+        record[7] = if self.filetype { file_type } else { 0 };
+        record[8..8 + name.len()].copy_from_slice(name.as_bytes());
+        let block = self
+            .map_block(&mut dir.clone(), pos / self.block_size, None)
+            .unwrap();
+        self.write_bytes(self.block_offset(block) + pos % self.block_size, &record);
+    }
+
+    /// Add entry `name` for inode `ino` to directory `dir_ino`, in the first
+    /// record with room for it or in a new block. False if the volume is full.
+    fn add_entry(
+        &self,
+        dir_ino: u32,
+        dir: &mut RawInode,
+        name: &str,
+        ino: u32,
+        file_type: u8,
+    ) -> bool {
+        let need = rec_size(name.len());
+        let free = self
+            .records(dir)
+            .into_iter()
+            .find(|record| record.rec_len - record.used() >= need);
+        match free {
+            Some(record) => {
+                let used = record.used();
+                if used > 0 {
+                    // the record keeps the space it uses, the rest is the new one's
+                    let block = self
+                        .map_block(dir, record.pos / self.block_size, None)
+                        .unwrap();
+                    let offset = self.block_offset(block) + record.pos % self.block_size + 4;
+                    self.write_bytes(offset, &(used as u16).to_le_bytes());
+                }
+                self.write_record(
+                    dir,
+                    record.pos + used,
+                    ino,
+                    record.rec_len - used,
+                    name,
+                    file_type,
+                );
+            }
+            None => {
+                let size = dir.size() as usize;
+                if self
+                    .map_block(dir, size / self.block_size, Some(self.inode_group(dir_ino)))
+                    .is_none()
+                {
+                    return false;
+                }
+                dir.set_size((size + self.block_size) as u64);
+                self.write_record(dir, size, ino, self.block_size, name, file_type);
+            }
+        }
+        dir.set32(I_FLAGS, dir.get32(I_FLAGS) & !INDEX_FL);
+        dir.touch();
+        true
+    }
+
+    /// Remove entry `name` from a directory, merging its record into the
+    /// one before
+    fn remove_entry(&self, dir: &mut RawInode, name: &str) {
+        let records = self.records(dir);
+        let Some(i) = records
+            .iter()
+            .position(|record| record.ino != 0 && record.name == name)
+        else {
+            return;
+        };
+        let record = &records[i];
+        let block = self
+            .map_block(dir, record.pos / self.block_size, None)
+            .unwrap();
+        let block_offset = self.block_offset(block);
+        if record.pos.is_multiple_of(self.block_size) {
+            // the first record of a block stays, free
+            self.write_u32(block_offset, 0);
+        } else {
+            let prev = &records[i - 1];
+            let rec_len = (prev.rec_len + record.rec_len) as u16;
+            self.write_bytes(
+                block_offset + prev.pos % self.block_size + 4,
+                &rec_len.to_le_bytes(),
+            );
+        }
+        dir.set32(I_FLAGS, dir.get32(I_FLAGS) & !INDEX_FL);
+        dir.touch();
+    }
+
+    /// Create entry `name` in directory `dir_ino`, a directory if `dir`
+    fn create(&self, dir_ino: u32, name: &str, dir: bool) -> Option<u32> {
+        let mut parent = self.load_inode(dir_ino);
+        if !parent.is_dir()
+            || name.is_empty()
+            || name.len() > NAME_LENGTH_LIMIT
+            || name.contains('/')
+            || self.find(&parent, name).is_some()
+        {
+            return None;
+        }
+        let ino = self.alloc_inode(self.inode_group(dir_ino), dir)?;
+        let mut inode = RawInode([0; GOOD_OLD_INODE_SIZE]);
+        let time = now();
+        inode.set32(I_ATIME, time);
+        inode.touch();
+        if dir {
+            inode.set16(I_MODE, S_IFDIR | 0o755);
+            inode.set_links(2);
+            if self
+                .map_block(&mut inode, 0, Some(self.inode_group(ino)))
+                .is_none()
+            {
+                self.free_inode(ino, dir);
+                return None;
+            }
+            inode.set_size(self.block_size as u64);
+            self.write_record(&inode, 0, ino, 12, ".", FT_DIR);
+            self.write_record(&inode, 12, dir_ino, self.block_size - 12, "..", FT_DIR);
+        } else {
+            inode.set16(I_MODE, S_IFREG | 0o644);
+            inode.set_links(1);
+        }
+        let file_type = if dir { FT_DIR } else { FT_REG_FILE };
+        if !self.add_entry(dir_ino, &mut parent, name, ino, file_type) {
+            self.truncate(&mut inode, 0);
+            self.free_inode(ino, dir);
+            return None;
+        }
+        if dir {
+            parent.set_links(parent.links() + 1);
+        }
+        self.store_inode(ino, &inode);
+        self.store_inode(dir_ino, &parent);
+        Some(ino)
+    }
+
+    /// Remove entry `name` from directory `dir_ino`. Directories must be
+    /// empty. An inode no entry refers to any more is left to
+    /// [`Ext2FileSystem::release`].
+    fn unlink(&self, dir_ino: u32, name: &str) -> bool {
+        let mut parent = self.load_inode(dir_ino);
+        if !parent.is_dir() || name == "." || name == ".." {
+            return false;
+        }
+        let Some(ino) = self.find(&parent, name) else {
+            return false;
+        };
+        let mut inode = self.load_inode(ino);
+        let dir = inode.is_dir();
+        if dir
+            && self
+                .records(&inode)
+                .iter()
+                .any(|record| record.ino != 0 && record.name != "." && record.name != "..")
+        {
+            return false;
+        }
+        self.remove_entry(&mut parent, name);
+        if dir {
+            // its ".." referred to the parent
+            parent.set_links(parent.links() - 1);
+            inode.set_links(0);
+        } else {
+            inode.set_links(inode.links().saturating_sub(1));
+        }
+        self.store_inode(ino, &inode);
+        self.store_inode(dir_ino, &parent);
+        true
+    }
+
+    /// Free inode `ino` and its blocks, once unlinked and no longer in use
+    fn release(&self, ino: u32) {
+        let mut inode = self.load_inode(ino);
+        if !inode.is_fast_symlink(self.block_size) {
+            self.truncate(&mut inode, 0);
+        }
+        inode.set32(I_DTIME, now());
+        self.free_inode(ino, inode.is_dir());
+        self.store_inode(ino, &inode);
+    }
+}
+
+lazy_static! {
+    /// Handles of the inodes in use, by device and inode number. Like those
+    /// of easy-fs, an inode whose last link is gone is only freed with its
+    /// last handle.
+    static ref HANDLES: Mutex<BTreeMap<(usize, u32), Handles>> = Mutex::new(BTreeMap::new());
+}
+
+pub struct Ext2Inode {
+    ino: u32,
+    fs: Arc<Mutex<Ext2FileSystem>>,
+    /// key of the block device, for [`HANDLES`]
+    device: usize,
+}
+
+impl Ext2Inode {
+    fn new(ino: u32, fs: Arc<Mutex<Ext2FileSystem>>, device: usize) -> Self {
+        HANDLES.lock().entry((device, ino)).or_default().count += 1;
+        Self { ino, fs, device }
+    }
+
+    fn child(&self, ino: u32) -> Arc<Ext2Inode> {
+        Arc::new(Self::new(ino, Arc::clone(&self.fs), self.device))
+    }
+
+    /// Whether the last link is gone, and the inode only kept for its handles
+    fn is_unlinked(&self) -> bool {
+        HANDLES
+            .lock()
+            .get(&(self.device, self.ino))
+            .is_some_and(|handles| handles.unlinked)
+    }
+
+    fn inode(&self) -> RawInode {
+        self.fs.lock().load_inode(self.ino)
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.inode().is_dir()
+    }
+
+    /// Whether it is a regular file
+    pub fn is_file(&self) -> bool {
+        self.inode().is_file()
+    }
+
+    /// Size in bytes
+    pub fn size(&self) -> u64 {
+        self.inode().size()
+    }
+
+    pub fn stat(&self) -> InodeStat {
+        let inode = self.inode();
+        InodeStat {
+            ino: self.ino,
+            type_: if inode.is_dir() {
+                DiskInodeType::Directory
+            } else {
+                DiskInodeType::File
+            },
+            size: inode.size().min(u32::MAX as u64) as u32,
+            nlink: inode.links() as u32,
+            blocks: inode.get32(I_BLOCKS),
+            atime: inode.get32(I_ATIME),
+            mtime: inode.get32(I_MTIME),
+            ctime: inode.get32(I_CTIME),
+        }
+    }
+
+    pub fn read_at(&self, offset: usize, buf: &mut [u8]) -> usize {
+        let fs = self.fs.lock();
+        fs.read(&fs.load_inode(self.ino), offset, buf)
+    }
+
+    /// Write to a regular file, growing it if needed. Short if the volume
+    /// is full, nothing for other inodes.
+    pub fn write_at(&self, offset: usize, buf: &[u8]) -> usize {
+        let fs = self.fs.lock();
+        let mut inode = fs.load_inode(self.ino);
+        if !inode.is_file() {
+            return 0;
+        }
+        let len = fs.write(self.ino, &mut inode, offset, buf);
+        fs.store_inode(self.ino, &inode);
+        len
+    }
+
+    /// Grow or shrink a regular file to `size` bytes, false for other
+    /// inodes or sizes past the largest file
+    pub fn truncate(&self, size: u64) -> bool {
+        let fs = self.fs.lock();
+        let mut inode = fs.load_inode(self.ino);
+        if !inode.is_file() || !fs.large_sizes && size > u32::MAX as u64 {
+            return false;
+        }
+        fs.truncate(&mut inode, size);
+        fs.store_inode(self.ino, &inode);
+        true
+    }
+
+    /// Write back every modified block, those of the inode included
+    pub fn sync(&self) {
+        self.fs.lock().sync();
+    }
+
+    pub fn find(&self, name: &str) -> Option<Arc<Ext2Inode>> {
+        let fs = self.fs.lock();
+        let dir = fs.load_inode(self.ino);
+        if !dir.is_dir() {
+            return None;
+        }
+        let ino = fs.find(&dir, name)?;
+        Some(self.child(ino))
+    }
+
+    /// Names of the entries of the directory, "." and ".." included
+    pub fn ls(&self) -> Vec<String> {
+        let fs = self.fs.lock();
+        let dir = fs.load_inode(self.ino);
+        if !dir.is_dir() {
+            return Vec::new();
+        }
+        fs.records(&dir)
+            .into_iter()
+            .filter(|record| record.ino != 0)
+            .map(|record| record.name)
+            .collect()
+    }
+
+    /// Create a file in the directory
+    pub fn create(&self, name: &str) -> Option<Arc<Ext2Inode>> {
+        // entries of a removed directory would never be freed
+        if self.is_unlinked() {
+            return None;
+        }
+        let ino = self.fs.lock().create(self.ino, name, false)?;
+        Some(self.child(ino))
+    }
+
+    /// Create a directory in the directory
+    pub fn create_dir(&self, name: &str) -> Option<Arc<Ext2Inode>> {
+        if self.is_unlinked() {
+            return None;
+        }
+        let ino = self.fs.lock().create(self.ino, name, true)?;
+        Some(self.child(ino))
+    }
+
+    /// Remove entry `name` from the directory. When it was the last link,
+    /// its inode and blocks are released with the last handle on it, at
+    /// once unless the file is open. Directories must be empty.
+    pub fn unlink(&self, name: &str) -> bool {
+        let Some(inode) = self.find(name) else {
+            return false;
+        };
+        if !self.fs.lock().unlink(self.ino, name) {
+            return false;
+        }
+        if inode.inode().links() == 0 {
+            HANDLES
+                .lock()
+                .get_mut(&(inode.device, inode.ino))
+                .unwrap()
+                .unlinked = true;
+        }
+        true
+    }
+}
+
+impl Drop for Ext2Inode {
+    fn drop(&mut self) {
+        let key = (self.device, self.ino);
+        let mut handles = HANDLES.lock();
+        let entry = handles.get_mut(&key).unwrap();
+        entry.count -= 1;
+        if entry.count > 0 {
+            return;
+        }
+        let unlinked = handles.remove(&key).unwrap().unlinked;
+        drop(handles);
+        if unlinked {
+            self.fs.lock().release(self.ino);
+        }
+    }
+}
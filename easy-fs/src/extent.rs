@@ -0,0 +1,388 @@
+//! Extent trees, the block map of inodes on images with
+//! [`FEATURE_EXTENTS`](crate::FEATURE_EXTENTS)
+//!
+//! The root node lives in the inode in place of its direct blocks, the
+//! other nodes take a block each. A leaf maps runs of consecutive blocks of
+//! the file, an index node the first block of the file under each child.
+//! Files only grow and shrink at their end, so the tree only ever changes
+//! along its rightmost path.
+use crate::{block_cache::get_block_cache, block_device::BlockDevice, layout::DiskInode, BLOCK_SZ};
+extern crate alloc;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+
+/// Entries of the root node, held in the direct blocks of the inode
+const ROOT_ENTRIES: usize = 8;
+/// Entries of a node taking a block
+const NODE_ENTRIES: usize = (BLOCK_SZ - 4) / core::mem::size_of::<Extent>();
+/// Deepest tree, far more than [`MAX_FILE_SIZE`](crate::MAX_FILE_SIZE) needs
+const MAX_DEPTH: u16 = 4;
+
+/// Entry of a node
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct Extent {
+    /// first block of the file it maps
+    logical: u32,
+    /// number of blocks it maps, unused in index nodes
+    len: u32,
+    /// first block on the device, or the child node in index nodes
+    physical: u32,
+}
+
+#[repr(C)]
+struct ExtentNode<const N: usize> {
+    count: u16,
+    /// 0 for a leaf
+    depth: u16,
+    entries: [Extent; N],
+}
+
+type RootNode = ExtentNode<ROOT_ENTRIES>;
+type NodeBlock = ExtentNode<NODE_ENTRIES>;
+
+const _: () = assert!(core::mem::size_of::<RootNode>() <= 25 * 4);
+const _: () = assert!(core::mem::size_of::<NodeBlock>() <= BLOCK_SZ);
+
+/// A node copied out of its block, so that no block stays locked while the
+/// tree is walked
+struct Node {
+    depth: u16,
+    entries: Vec<Extent>,
+}
+
+impl<const N: usize> ExtentNode<N> {
+    /// `None` if the node holds more entries than it can
+    fn load(&self) -> Option<Node> {
+        let count = self.count as usize;
+        (count <= N).then(|| Node {
+            depth: self.depth,
+            entries: self.entries[..count].to_vec(),
+        })
+    }
+
+    fn store(&mut self, node: &Node) {
+        let count = node.entries.len();
+        assert!(count <= N, "Extent node overflow!");
+        self.count = count as u16;
+        self.depth = node.depth;
+        self.entries[..count].copy_from_slice(&node.entries);
+        self.entries[count..].fill(Extent::default());
+    }
+}
+
+fn read_node(block_id: u32, block_device: &Arc<dyn BlockDevice>) -> Option<Node> {
+    get_block_cache(block_id as usize, Arc::clone(block_device))
+        .lock()
+        .read(0, |node: &NodeBlock| node.load())
+}
+
+fn write_node(block_id: u32, node: &Node, block_device: &Arc<dyn BlockDevice>) {
+    get_block_cache(block_id as usize, Arc::clone(block_device))
+        .lock()
+        .modify(0, |node_block: &mut NodeBlock| node_block.store(node));
+}
+
+/// Release the blocks of the file from `new_blocks` on under `node`,
+/// along with the nodes left empty
+fn truncate_node(
+    node: &mut Node,
+    new_blocks: u32,
+    block_device: &Arc<dyn BlockDevice>,
+    released: &mut Vec<u32>,
+) {
+    if node.depth == 0 {
+        while let Some(last) = node.entries.last_mut() {
+            if last.logical + last.len <= new_blocks {
+                break;
+            }
+            let keep = new_blocks.saturating_sub(last.logical);
+            released.extend(last.physical + keep..last.physical + last.len);
+            if keep > 0 {
+                last.len = keep;
+                break;
+            }
+            node.entries.pop();
+        }
+        return;
+    }
+    while let Some(last) = node.entries.last().copied() {
+        let mut child = read_node(last.physical, block_device).unwrap();
+        truncate_node(&mut child, new_blocks, block_device, released);
+        if last.logical < new_blocks {
+            write_node(last.physical, &child, block_device);
+            break;
+        }
+        released.push(last.physical);
+        node.entries.pop();
+    }
+}
+
+impl DiskInode {
+    fn root(&self) -> Node {
+        let root = unsafe { &*(self.direct.as_ptr() as *const RootNode) };
+        root.load().unwrap()
+    }
+
+    fn set_root(&mut self, node: &Node) {
+        let root = unsafe { &mut *(self.direct.as_mut_ptr() as *mut RootNode) };
+        root.store(node);
+    }
+
+    /// Write back a node of the tree, `None` for the root
+    fn store_node(
+        &mut self,
+        node_id: Option<u32>,
+        node: &Node,
+        block_device: &Arc<dyn BlockDevice>,
+    ) {
+        match node_id {
+            Some(block_id) => write_node(block_id, node, block_device),
+            None => self.set_root(node),
+        }
+    }
+
+    /// Nodes from the root down to the rightmost leaf, with their blocks
+    fn rightmost_path(&self, block_device: &Arc<dyn BlockDevice>) -> Vec<(Option<u32>, Node)> {
+        let mut path = vec![(None, self.root())];
+        while let Some(child) = path
+            .last()
+            .filter(|(_, node)| node.depth > 0)
+            .map(|(_, node)| node.entries.last().unwrap().physical)
+        {
+            path.push((Some(child), read_node(child, block_device).unwrap()));
+        }
+        path
+    }
+
+    /// Block on the device of block `inner_id` of the file
+    pub(crate) fn extent_block_id(
+        &self,
+        inner_id: u32,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> u32 {
+        let mut node = self.root();
+        loop {
+            let i = node
+                .entries
+                .partition_point(|entry| entry.logical <= inner_id);
+            let entry = node.entries[i - 1];
+            if node.depth == 0 {
+                assert!(inner_id - entry.logical < entry.len, "Block not mapped!");
+                return entry.physical + (inner_id - entry.logical);
+            }
+            node = read_node(entry.physical, block_device).unwrap();
+        }
+    }
+
+    /// Block on the device following the last block of the file, 0 if it is empty
+    pub(crate) fn extent_end(&self, block_device: &Arc<dyn BlockDevice>) -> u32 {
+        let path = self.rightmost_path(block_device);
+        let (_, leaf) = path.last().unwrap();
+        leaf.entries
+            .last()
+            .map_or(0, |extent| extent.physical + extent.len)
+    }
+
+    /// Map the `len` blocks from `physical` after the last block of the file,
+    /// `logical`. Nodes are taken from `alloc` when the tree grows.
+    pub(crate) fn extent_append(
+        &mut self,
+        logical: u32,
+        physical: u32,
+        len: u32,
+        alloc: &mut impl FnMut(u32, u32) -> (u32, u32),
+        block_device: &Arc<dyn BlockDevice>,
+    ) {
+        let mut path = self.rightmost_path(block_device);
+        let (leaf_id, leaf) = path.last_mut().unwrap();
+        if let Some(last) = leaf.entries.last_mut() {
+            // the blocks follow the last extent, which just gets longer
+            if last.logical + last.len == logical && last.physical + last.len == physical {
+                last.len += len;
+                self.store_node(*leaf_id, leaf, block_device);
+                return;
+            }
+        }
+        let mut entry = Extent {
+            logical,
+            len,
+            physical,
+        };
+        for (node_id, node) in path.iter_mut().rev() {
+            let capacity = if node_id.is_some() {
+                NODE_ENTRIES
+            } else {
+                ROOT_ENTRIES
+            };
+            if node.entries.len() < capacity {
+                node.entries.push(entry);
+                self.store_node(*node_id, node, block_device);
+                return;
+            }
+            if node_id.is_none() {
+                break;
+            }
+            // a full node gets a right sibling holding the entry
+            let (sibling, _) = alloc(0, 1);
+            let sibling_node = Node {
+                depth: node.depth,
+                entries: vec![entry],
+            };
+            write_node(sibling, &sibling_node, block_device);
+            entry = Extent {
+                logical,
+                len: 0,
+                physical: sibling,
+            };
+        }
+        // the root is full too, its entries move down to a node of their
+        // own, which has room for the entry
+        let (_, root) = &mut path[0];
+        root.entries.push(entry);
+        let (child, _) = alloc(0, 1);
+        write_node(child, root, block_device);
+        let root = Node {
+            depth: root.depth + 1,
+            entries: vec![Extent {
+                logical: 0,
+                len: 0,
+                physical: child,
+            }],
+        };
+        self.set_root(&root);
+    }
+
+    /// Unmap the blocks of the file from `new_blocks` on, and return them
+    /// along with the nodes released
+    pub(crate) fn extent_truncate(
+        &mut self,
+        new_blocks: u32,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> Vec<u32> {
+        let mut released = Vec::new();
+        let mut root = self.root();
+        truncate_node(&mut root, new_blocks, block_device, &mut released);
+        // the tree gets shallower once the root can hold the entries of its only child
+        while root.depth > 0 {
+            if root.entries.is_empty() {
+                root.depth = 0;
+                break;
+            }
+            if root.entries.len() > 1 {
+                break;
+            }
+            let child_id = root.entries[0].physical;
+            let child = read_node(child_id, block_device).unwrap();
+            if child.entries.len() > ROOT_ENTRIES {
+                break;
+            }
+            released.push(child_id);
+            root = child;
+        }
+        self.set_root(&root);
+        released
+    }
+
+    /// Number of nodes taking a block
+    pub(crate) fn extent_nodes(&self, block_device: &Arc<dyn BlockDevice>) -> u32 {
+        fn count(node: &Node, block_device: &Arc<dyn BlockDevice>) -> u32 {
+            if node.depth == 0 {
+                return 0;
+            }
+            node.entries
+                .iter()
+                .map(|entry| {
+                    1 + count(
+                        &read_node(entry.physical, block_device).unwrap(),
+                        block_device,
+                    )
+                })
+                .sum()
+        }
+        count(&self.root(), block_device)
+    }
+
+    /// Collect the blocks of the file and the nodes, in the same way as
+    /// [`DiskInode::blocks`]
+    pub(crate) fn extent_blocks(
+        &self,
+        block_device: &Arc<dyn BlockDevice>,
+        valid: impl Fn(u32) -> bool,
+    ) -> (Vec<u32>, Option<u32>) {
+        fn walk(
+            node: &Node,
+            block_device: &Arc<dyn BlockDevice>,
+            valid: &impl Fn(u32) -> bool,
+            v: &mut Vec<u32>,
+        ) -> Result<(), u32> {
+            for entry in node.entries.iter() {
+                if node.depth == 0 {
+                    for block_id in entry.physical..entry.physical + entry.len {
+                        if !valid(block_id) {
+                            return Err(block_id);
+                        }
+                        v.push(block_id);
+                    }
+                } else {
+                    v.push(entry.physical);
+                    let child = read_node(entry.physical, block_device).unwrap();
+                    walk(&child, block_device, valid, v)?;
+                }
+            }
+            Ok(())
+        }
+        let mut v = Vec::new();
+        let result = walk(&self.root(), block_device, &valid, &mut v);
+        (v, result.err())
+    }
+
+    /// Whether the tree is well formed and maps each block of the file once,
+    /// following only the nodes accepted by `valid`
+    pub(crate) fn is_extent_tree_valid(
+        &self,
+        block_device: &Arc<dyn BlockDevice>,
+        valid: impl Fn(u32) -> bool,
+    ) -> bool {
+        /// Check the subtree of `node`, the next block of the file to map
+        /// being `next`
+        fn check(
+            node: &Node,
+            block_device: &Arc<dyn BlockDevice>,
+            valid: &impl Fn(u32) -> bool,
+            next: &mut u32,
+        ) -> bool {
+            node.depth <= MAX_DEPTH
+                && node.entries.iter().all(|entry| {
+                    if entry.logical != *next {
+                        return false;
+                    }
+                    if node.depth == 0 {
+                        *next = match entry.logical.checked_add(entry.len) {
+                            Some(next) if entry.len > 0 => next,
+                            _ => return false,
+                        };
+                        return entry.physical.checked_add(entry.len).is_some();
+                    }
+                    if !valid(entry.physical) {
+                        return false;
+                    }
+                    match read_node(entry.physical, block_device) {
+                        Some(child)
+                            if child.depth + 1 == node.depth && !child.entries.is_empty() =>
+                        {
+                            check(&child, block_device, valid, next)
+                        }
+                        _ => false,
+                    }
+                })
+        }
+        let root = unsafe { &*(self.direct.as_ptr() as *const RootNode) };
+        let Some(root) = root.load() else {
+            return false;
+        };
+        let mut next = 0;
+        check(&root, block_device, &valid, &mut next) && next == self.data_blocks()
+    }
+}
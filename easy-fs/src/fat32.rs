@@ -0,0 +1,460 @@
+//! FAT32, read only
+//!
+//! Volumes are read through the same block cache as easy-fs, so sectors
+//! must be [`BLOCK_SZ`] bytes. Long names are used when their checksum
+//! matches the short entry that follows them, and names are compared
+//! ignoring ASCII case as on other systems.
+use super::block_cache::{block_cache_prefetch, get_block_cache};
+use super::{BlockDevice, DiskInodeType, InodeStat, BLOCK_SZ};
+extern crate alloc;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+/// Size of a directory entry
+const DIRENT_SZ: usize = 32;
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+/// Attributes of the entries holding parts of a long name
+const ATTR_LONG_NAME: u8 = 0x0f;
+/// Flags of the short name being lower case, in the reserved byte
+const LOWER_CASE_BASE: u8 = 0x08;
+const LOWER_CASE_EXT: u8 = 0x10;
+/// Ordinal flag of the entry holding the last part of a long name
+const LAST_LONG_ENTRY: u8 = 0x40;
+/// Characters of a long name held by each entry
+const LONG_NAME_CHARS: usize = 13;
+/// Offsets of the characters of a long name entry
+const LONG_NAME_OFFSETS: [usize; LONG_NAME_CHARS] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+/// Inode number of the root directory, which has no entry
+const ROOT_INO: u32 = 1;
+
+fn le16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn le32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+/// Seconds since the Unix epoch of a FAT date and time, taken as UTC.
+/// Dates after 2106 are beyond a u32 and read as its largest value.
+fn unix_time(date: u16, time: u16) -> u32 {
+    if date == 0 {
+        return 0;
+    }
+    let year = 1980 + (date >> 9) as u32;
+    let month = ((date >> 5) & 0xf).clamp(1, 12) as u32;
+    let day = (date & 0x1f).max(1) as u32;
+    // count years from March, so that leap days come last
+    let (year, month) = if month <= 2 {
+        (year - 1, month + 9)
+    } else {
+        (year, month - 3)
+    };
+    let days =
+        365 * year + year / 4 - year / 100 + year / 400 + (153 * month + 2) / 5 + day - 1 - 719_468;
+    let seconds =
+        (time >> 11) as u32 * 3600 + ((time >> 5) & 0x3f) as u32 * 60 + (time & 0x1f) as u32 * 2;
+    u32::try_from(days as u64 * 86400 + seconds as u64).unwrap_or(u32::MAX)
+}
+
+/// Checksum of a short name, repeated in the entries of its long name
+fn checksum(short_name: &[u8]) -> u8 {
+    short_name
+        .iter()
+        .fold(0u8, |sum, &byte| sum.rotate_right(1).wrapping_add(byte))
+}
+
+/// Name of a short entry, like "README.TXT"
+fn short_name(raw: &[u8]) -> String {
+    let part = |bytes: &[u8], lower: bool| {
+        let mut s: String = bytes
+            .iter()
+            .enumerate()
+            // 0x05 stands for 0xe5 in the first byte, which marks free entries
+            .map(|(i, &byte)| if i == 0 && byte == 0x05 { 0xe5 } else { byte })
+            .map(char::from)
+            .collect();
+        s.truncate(s.trim_end_matches(' ').len());
+        if lower {
+            s.make_ascii_lowercase();
+        }
+        s
+    };
+    let mut name = part(&raw[..8], raw[12] & LOWER_CASE_BASE != 0);
+    let ext = part(&raw[8..11], raw[12] & LOWER_CASE_EXT != 0);
+    if !ext.is_empty() {
+        name.push('.');
+        name.push_str(&ext);
+    }
+    name
+}
+
+/// A long name being put together from its entries, which come last
+/// part first
+struct LongName {
+    chars: Vec<u16>,
+    /// ordinal of the entry expected next, 0 once complete
+    next: u8,
+    checksum: u8,
+}
+
+impl LongName {
+    /// Start from the entry holding the last part
+    fn new(raw: &[u8]) -> Option<Self> {
+        let parts = raw[0] & !LAST_LONG_ENTRY;
+        if raw[0] & LAST_LONG_ENTRY == 0 || parts == 0 || parts > 20 {
+            return None;
+        }
+        let mut long_name = Self {
+            chars: vec![0xffff; parts as usize * LONG_NAME_CHARS],
+            next: parts,
+            checksum: raw[13],
+        };
+        long_name.push(raw).then_some(long_name)
+    }
+
+    /// Add the entry expected next, false if it is not
+    fn push(&mut self, raw: &[u8]) -> bool {
+        if raw[0] & !LAST_LONG_ENTRY != self.next || raw[13] != self.checksum {
+            return false;
+        }
+        self.next -= 1;
+        let start = self.next as usize * LONG_NAME_CHARS;
+        for (i, offset) in LONG_NAME_OFFSETS.iter().enumerate() {
+            self.chars[start + i] = le16(raw, *offset);
+        }
+        true
+    }
+
+    /// The name, if it is complete and belongs to short name `short_name`
+    fn name(&self, short_name: &[u8]) -> Option<String> {
+        if self.next != 0 || self.checksum != checksum(short_name) {
+            return None;
+        }
+        let chars = self.chars.iter().copied().take_while(|c| *c != 0);
+        Some(
+            char::decode_utf16(chars)
+                .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+                .collect(),
+        )
+    }
+}
+
+/// An entry of a directory
+struct DirEntry {
+    name: String,
+    ino: u32,
+    attr: u8,
+    first_cluster: u32,
+    size: u32,
+    atime: u32,
+    mtime: u32,
+    ctime: u32,
+}
+
+impl DirEntry {
+    fn new(name: String, ino: u32, raw: &[u8]) -> Self {
+        Self {
+            name,
+            ino,
+            attr: raw[11],
+            first_cluster: (le16(raw, 20) as u32) << 16 | le16(raw, 26) as u32,
+            size: le32(raw, 28),
+            atime: unix_time(le16(raw, 18), 0),
+            mtime: unix_time(le16(raw, 24), le16(raw, 22)),
+            ctime: unix_time(le16(raw, 16), le16(raw, 14)),
+        }
+    }
+}
+
+pub struct Fat32FileSystem {
+    block_device: Arc<dyn BlockDevice>,
+    sectors_per_cluster: usize,
+    /// first sector of the first FAT
+    fat_start: usize,
+    /// first sector of cluster 2, the first one
+    data_start: usize,
+    /// number of clusters, numbered from 2
+    clusters: u32,
+    root_cluster: u32,
+}
+
+impl Fat32FileSystem {
+    /// Read the layout of a volume from its boot sector, `None` if it is
+    /// not a FAT32 volume with sectors of [`BLOCK_SZ`] bytes
+    fn parse(block_device: Arc<dyn BlockDevice>) -> Option<Self> {
+        let sector = get_block_cache(0, Arc::clone(&block_device))
+            .lock()
+            .read(0, |sector: &[u8; BLOCK_SZ]| *sector);
+        let sectors_per_cluster = sector[13] as usize;
+        let reserved_sectors = le16(&sector, 14) as usize;
+        let fats = sector[16] as usize;
+        let total_sectors = match le16(&sector, 19) {
+            0 => le32(&sector, 32),
+            sectors => sectors as u32,
+        } as usize;
+        let fat_size = le32(&sector, 36) as usize;
+        // FAT12 and FAT16 have a root directory out of the clusters, and
+        // the size of their FAT in a field of 16 bits
+        if sector[510..] != [0x55, 0xaa]
+            || le16(&sector, 11) as usize != BLOCK_SZ
+            || !sectors_per_cluster.is_power_of_two()
+            || reserved_sectors == 0
+            || fats == 0
+            || le16(&sector, 17) != 0
+            || le16(&sector, 22) != 0
+            || fat_size == 0
+        {
+            return None;
+        }
+        let data_start = reserved_sectors + fats * fat_size;
+        let clusters = (total_sectors.checked_sub(data_start)? / sectors_per_cluster)
+            .min(fat_size * BLOCK_SZ / 4 - 2) as u32;
+        let fs = Self {
+            block_device,
+            sectors_per_cluster,
+            fat_start: reserved_sectors,
+            data_start,
+            clusters,
+            root_cluster: le32(&sector, 44),
+        };
+        fs.is_data_cluster(fs.root_cluster).then_some(fs)
+    }
+
+    /// Whether the block device holds a FAT32 volume this driver reads
+    pub fn detect(block_device: &Arc<dyn BlockDevice>) -> bool {
+        Self::parse(Arc::clone(block_device)).is_some()
+    }
+
+    pub fn open(block_device: Arc<dyn BlockDevice>) -> Arc<Self> {
+        Arc::new(Self::parse(block_device).expect("Error loading FAT32!"))
+    }
+
+    pub fn root_inode(fs: &Arc<Self>) -> Fat32Inode {
+        Fat32Inode::new(
+            Arc::clone(fs),
+            DirEntry {
+                name: String::new(),
+                ino: ROOT_INO,
+                attr: ATTR_DIRECTORY,
+                first_cluster: fs.root_cluster,
+                size: 0,
+                atime: 0,
+                mtime: 0,
+                ctime: 0,
+            },
+        )
+    }
+
+    fn cluster_size(&self) -> usize {
+        self.sectors_per_cluster * BLOCK_SZ
+    }
+
+    fn is_data_cluster(&self, cluster: u32) -> bool {
+        (2..self.clusters + 2).contains(&cluster)
+    }
+
+    fn cluster_sector(&self, cluster: u32) -> usize {
+        self.data_start + (cluster as usize - 2) * self.sectors_per_cluster
+    }
+
+    /// Cluster following `cluster` in its chain, `None` at the end
+    fn next_cluster(&self, cluster: u32) -> Option<u32> {
+        let offset = cluster as usize * 4;
+        let next = get_block_cache(
+            self.fat_start + offset / BLOCK_SZ,
+            Arc::clone(&self.block_device),
+        )
+        .lock()
+        .read(offset % BLOCK_SZ, |entry: &u32| u32::from_le(*entry))
+            & 0x0fff_ffff;
+        // the end of the chain, a bad cluster or a broken chain
+        self.is_data_cluster(next).then_some(next)
+    }
+}
+
+pub struct Fat32Inode {
+    fs: Arc<Fat32FileSystem>,
+    /// number of its short entry counting from the start of the volume,
+    /// the root has none
+    ino: u32,
+    is_dir: bool,
+    size: u32,
+    first_cluster: u32,
+    atime: u32,
+    mtime: u32,
+    ctime: u32,
+    /// clusters of the file found so far, in order
+    chain: Mutex<Vec<u32>>,
+}
+
+impl Fat32Inode {
+    fn new(fs: Arc<Fat32FileSystem>, entry: DirEntry) -> Self {
+        let mut inode = Self {
+            fs,
+            ino: entry.ino,
+            is_dir: entry.attr & ATTR_DIRECTORY != 0,
+            size: entry.size,
+            first_cluster: entry.first_cluster,
+            atime: entry.atime,
+            mtime: entry.mtime,
+            ctime: entry.ctime,
+            chain: Mutex::new(Vec::new()),
+        };
+        if inode.is_dir {
+            // directories have no size, they end with their chain
+            let mut clusters = 0;
+            while inode.cluster(clusters).is_some() {
+                clusters += 1;
+            }
+            inode.size = (clusters * inode.fs.cluster_size()).min(u32::MAX as usize) as u32;
+        }
+        inode
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.is_dir
+    }
+
+    pub fn is_file(&self) -> bool {
+        !self.is_dir
+    }
+
+    /// Size in bytes
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    pub fn stat(&self) -> InodeStat {
+        let sectors =
+            (self.size as usize).div_ceil(self.fs.cluster_size()) * self.fs.sectors_per_cluster;
+        InodeStat {
+            ino: self.ino,
+            type_: if self.is_dir {
+                DiskInodeType::Directory
+            } else {
+                DiskInodeType::File
+            },
+            size: self.size,
+            nlink: if self.is_dir { 2 } else { 1 },
+            blocks: sectors as u32,
+            atime: self.atime,
+            mtime: self.mtime,
+            ctime: self.ctime,
+        }
+    }
+
+    /// Cluster `index` of the file, `None` past the end of its chain
+    fn cluster(&self, index: usize) -> Option<u32> {
+        let mut chain = self.chain.lock();
+        while chain.len() <= index {
+            // a chain longer than the volume loops
+            if chain.len() >= self.fs.clusters as usize {
+                return None;
+            }
+            let next = match chain.last() {
+                None if self.fs.is_data_cluster(self.first_cluster) => self.first_cluster,
+                None => return None,
+                Some(&last) => self.fs.next_cluster(last)?,
+            };
+            chain.push(next);
+        }
+        Some(chain[index])
+    }
+
+    /// Sector holding byte `offset` of the file
+    fn sector(&self, offset: usize) -> Option<usize> {
+        let cluster = self.cluster(offset / self.fs.cluster_size())?;
+        Some(self.fs.cluster_sector(cluster) + offset % self.fs.cluster_size() / BLOCK_SZ)
+    }
+
+    pub fn read_at(&self, offset: usize, buf: &mut [u8]) -> usize {
+        let end = (self.size as usize).min(offset.saturating_add(buf.len()));
+        if offset >= end {
+            return 0;
+        }
+        // the sectors in reach are fetched together
+        let sectors: Vec<usize> = (offset / BLOCK_SZ..end.div_ceil(BLOCK_SZ))
+            .map_while(|block| self.sector(block * BLOCK_SZ))
+            .collect();
+        block_cache_prefetch(&sectors, &self.fs.block_device);
+        let mut pos = offset;
+        for sector in sectors {
+            let start = pos % BLOCK_SZ;
+            let len = (BLOCK_SZ - start).min(end - pos);
+            get_block_cache(sector, Arc::clone(&self.fs.block_device))
+                .lock()
+                .read(0, |data: &[u8; BLOCK_SZ]| {
+                    buf[pos - offset..pos - offset + len].copy_from_slice(&data[start..start + len])
+                });
+            pos += len;
+        }
+        pos - offset
+    }
+
+    /// Entries of the directory, "." and ".." excluded
+    fn dirents(&self) -> Vec<DirEntry> {
+        if !self.is_dir {
+            return Vec::new();
+        }
+        let mut data = vec![0u8; self.size as usize];
+        let len = self.read_at(0, &mut data);
+        data.truncate(len);
+        let mut entries = Vec::new();
+        let mut long_name: Option<LongName> = None;
+        for (i, raw) in data.chunks_exact(DIRENT_SZ).enumerate() {
+            match raw[0] {
+                // no entry past this one
+                0 => break,
+                0xe5 => {
+                    long_name = None;
+                    continue;
+                }
+                _ => {}
+            }
+            if raw[11] & 0x3f == ATTR_LONG_NAME {
+                // a part out of order starts over
+                long_name = match long_name.take() {
+                    Some(mut long_name) if raw[0] & LAST_LONG_ENTRY == 0 => {
+                        long_name.push(raw).then_some(long_name)
+                    }
+                    _ => LongName::new(raw),
+                };
+                continue;
+            }
+            let long_name = long_name.take();
+            if raw[11] & ATTR_VOLUME_ID != 0 {
+                continue;
+            }
+            let name = long_name
+                .and_then(|long_name| long_name.name(&raw[..11]))
+                .unwrap_or_else(|| short_name(raw));
+            if name == "." || name == ".." {
+                continue;
+            }
+            let offset = i * DIRENT_SZ;
+            let ino = self.sector(offset).unwrap() * (BLOCK_SZ / DIRENT_SZ)
+                + offset % BLOCK_SZ / DIRENT_SZ;
+            entries.push(DirEntry::new(name, ino as u32, raw));
+        }
+        entries
+    }
+
+    /// Find entry `name` of the directory, ignoring ASCII case
+    pub fn find(&self, name: &str) -> Option<Arc<Fat32Inode>> {
+        let entry = self
+            .dirents()
+            .into_iter()
+            .find(|entry| entry.name.eq_ignore_ascii_case(name))?;
+        Some(Arc::new(Self::new(Arc::clone(&self.fs), entry)))
+    }
+
+    /// Names of the entries of the directory, "." and ".." excluded
+    pub fn ls(&self) -> Vec<String> {
+        self.dirents().into_iter().map(|entry| entry.name).collect()
+    }
+}
@@ -0,0 +1,404 @@
+use crate::{
+    block_cache::get_block_cache,
+    efs::EasyFileSystem,
+    layout::{DiskInode, SuperBlock},
+    BLOCK_SZ,
+};
+extern crate alloc;
+use alloc::collections::{BTreeMap, BTreeSet, VecDeque};
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt;
+use spin::Mutex;
+
+/// An inconsistency found by [`EasyFileSystem::check`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Problem {
+    /// The areas in the superblock do not add up to the device
+    BadSuperBlock,
+    /// An inode refers to a block outside the data area
+    BadBlockPointer { inode: u32, block: u32 },
+    /// An inode is larger than its index can address,
+    /// or a directory does not hold a whole number of entries
+    BadSize { inode: u32, size: u32 },
+    /// The extent tree of an inode is malformed, does not map its whole
+    /// size, or the inode does not use the block map of the filesystem
+    BadExtentTree { inode: u32 },
+    /// A block is used twice, by the same inode or by two of them
+    DoublyOwnedBlock { block: u32, inodes: [u32; 2] },
+    /// A block is used by an inode but free in the data bitmap
+    UnmarkedBlock { block: u32, inode: u32 },
+    /// A block is allocated in the data bitmap but used by no inode
+    LeakedBlock { block: u32 },
+    /// A reachable inode is free in the inode bitmap
+    UnmarkedInode { inode: u32 },
+    /// An inode is allocated in the inode bitmap but no entry refers to it
+    LeakedInode { inode: u32, size: u32 },
+    /// A directory entry refers to a free or out of range inode
+    DanglingEntry { dir: u32, name: String, inode: u32 },
+    /// A `.` or `..` entry is missing, repeated or refers to the wrong directory
+    BadDotEntry {
+        dir: u32,
+        name: &'static str,
+        expected: u32,
+    },
+    /// The index of a directory does not match its entries. An index that
+    /// is not `owned` by the directory alone is left alone on repair.
+    BadIndex { dir: u32, index: u32, owned: bool },
+}
+
+impl Problem {
+    /// Whether [`EasyFileSystem::repair`] fixes the problem
+    pub fn is_repairable(&self) -> bool {
+        match self {
+            Problem::UnmarkedBlock { .. }
+            | Problem::LeakedBlock { .. }
+            | Problem::UnmarkedInode { .. }
+            | Problem::DanglingEntry { .. }
+            | Problem::BadDotEntry { .. }
+            | Problem::BadIndex { .. } => true,
+            // the content of an orphan is kept for a human to look at
+            Problem::LeakedInode { size, .. } => *size == 0,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Problem::BadSuperBlock => write!(f, "superblock areas do not match the device"),
+            Problem::BadBlockPointer { inode, block } => {
+                write!(
+                    f,
+                    "inode {} refers to block {} outside the data area",
+                    inode, block
+                )
+            }
+            Problem::BadSize { inode, size } => {
+                write!(f, "inode {} has a bad size {}", inode, size)
+            }
+            Problem::BadExtentTree { inode } => {
+                write!(f, "inode {} has a bad extent tree", inode)
+            }
+            Problem::DoublyOwnedBlock { block, inodes } => write!(
+                f,
+                "block {} is owned by inode {} and inode {}",
+                block, inodes[0], inodes[1]
+            ),
+            Problem::UnmarkedBlock { block, inode } => write!(
+                f,
+                "block {} of inode {} is free in the data bitmap",
+                block, inode
+            ),
+            Problem::LeakedBlock { block } => write!(f, "block {} is allocated but unused", block),
+            Problem::UnmarkedInode { inode } => {
+                write!(f, "inode {} is free in the inode bitmap", inode)
+            }
+            Problem::LeakedInode { inode, size } => write!(
+                f,
+                "inode {} of size {} is allocated but unreachable",
+                inode, size
+            ),
+            Problem::DanglingEntry { dir, name, inode } => write!(
+                f,
+                "entry {:?} of directory {} refers to free inode {}",
+                name, dir, inode
+            ),
+            Problem::BadDotEntry {
+                dir,
+                name,
+                expected,
+            } => write!(
+                f,
+                "directory {} needs a single {:?} entry to inode {}",
+                dir, name, expected
+            ),
+            Problem::BadIndex { dir, index, .. } => write!(
+                f,
+                "index {} of directory {} does not match its entries",
+                index, dir
+            ),
+        }
+    }
+}
+
+impl EasyFileSystem {
+    /// Walk the bitmaps and every inode reachable from the root, and
+    /// return the inconsistencies found
+    pub fn check(efs: &Arc<Mutex<Self>>) -> Vec<Problem> {
+        efs.lock().find_problems()
+    }
+
+    /// Fix the repairable `problems` found by [`EasyFileSystem::check`]
+    pub fn repair(efs: &Arc<Mutex<Self>>, problems: &[Problem]) {
+        let mut fs = efs.lock();
+        // blocks behind a bad pointer or size belong to an inode we could
+        // not walk, so they may look leaked
+        let owners_known = !problems.iter().any(|problem| {
+            matches!(
+                problem,
+                Problem::BadBlockPointer { .. }
+                    | Problem::BadSize { .. }
+                    | Problem::BadExtentTree { .. }
+            )
+        });
+        let block_device = Arc::clone(&fs.block_device);
+        let data_start = fs.data_area_start_block;
+        // bitmaps first, so that entries are appended with free blocks only
+        for problem in problems.iter().filter(|problem| problem.is_repairable()) {
+            fs.begin();
+            match problem {
+                Problem::UnmarkedBlock { block, .. } => fs
+                    .data_bitmap
+                    .set(&block_device, (block - data_start) as usize),
+                Problem::LeakedBlock { block } if owners_known => fs.dealloc_data(*block),
+                Problem::UnmarkedInode { inode } => {
+                    fs.inode_bitmap.set(&block_device, *inode as usize)
+                }
+                Problem::LeakedInode { inode, .. } if owners_known => {
+                    fs.inode_bitmap.dealloc(&block_device, *inode as usize)
+                }
+                _ => {}
+            }
+            fs.commit();
+        }
+        for problem in problems {
+            fs.begin();
+            match problem {
+                // a bad index comes before the entries of its directory,
+                // which are then repaired through the new index
+                Problem::BadIndex { dir, owned, .. } => {
+                    if !owned || !owners_known {
+                        fs.detach_index(*dir);
+                    }
+                    fs.build_index(*dir);
+                }
+                Problem::DanglingEntry { dir, name, inode } => fs
+                    .remove_entries(*dir, |dirent_name, dirent_inode| {
+                        dirent_name == name && dirent_inode == *inode
+                    }),
+                Problem::BadDotEntry {
+                    dir,
+                    name,
+                    expected,
+                } => {
+                    fs.remove_entries(*dir, |dirent_name, _| dirent_name == *name);
+                    fs.append_entry(*dir, name, *expected);
+                }
+                _ => {}
+            }
+            fs.commit();
+        }
+    }
+
+    fn find_problems(&self) -> Vec<Problem> {
+        let block_device = &self.block_device;
+        let mut problems = Vec::new();
+        let (total_blocks, inode_area_blocks, data_bitmap_blocks, data_area_blocks, areas) =
+            get_block_cache(0, Arc::clone(block_device)).lock().read(
+                0,
+                |super_block: &SuperBlock| {
+                    (
+                        super_block.total_blocks,
+                        super_block.inode_area_blocks,
+                        super_block.data_bitmap_blocks,
+                        super_block.data_area_blocks,
+                        1 + super_block.inode_bitmap_blocks
+                            + super_block.inode_area_blocks
+                            + super_block.data_bitmap_blocks
+                            + super_block.data_area_blocks
+                            + super_block.journal_blocks,
+                    )
+                },
+            );
+        let inode_count = self.inode_bitmap.maximum();
+        if areas != total_blocks
+            || inode_count * core::mem::size_of::<DiskInode>()
+                > (inode_area_blocks as usize) * BLOCK_SZ
+            || (data_area_blocks as usize) > (data_bitmap_blocks as usize) * BLOCK_SZ * 8
+        {
+            problems.push(Problem::BadSuperBlock);
+            return problems;
+        }
+        let data_start = self.data_area_start_block;
+        let long_names = self.long_names;
+        let extents = self.extents;
+        let data_end = data_start + data_area_blocks;
+        let in_data_area = |block_id: u32| block_id >= data_start && block_id < data_end;
+
+        // owner of every block in use
+        let mut owners: BTreeMap<u32, u32> = BTreeMap::new();
+        // record the blocks of an inode, false if they could not all be walked
+        let mut take_blocks = |inode_id: u32, problems: &mut Vec<Problem>| -> bool {
+            let (block_id, block_offset) = self.get_disk_inode_pos(inode_id);
+            let walked = get_block_cache(block_id as usize, Arc::clone(block_device))
+                .lock()
+                .read(block_offset, |disk_inode: &DiskInode| {
+                    if !disk_inode.is_size_valid() {
+                        Err(Problem::BadSize {
+                            inode: inode_id,
+                            size: disk_inode.size,
+                        })
+                    } else if disk_inode.has_extents() != extents
+                        || (extents && !disk_inode.is_extent_tree_valid(block_device, in_data_area))
+                    {
+                        Err(Problem::BadExtentTree { inode: inode_id })
+                    } else if disk_inode.is_dir()
+                        && !disk_inode.is_dir_size_valid(long_names, block_device)
+                    {
+                        Err(Problem::BadSize {
+                            inode: inode_id,
+                            size: disk_inode.size,
+                        })
+                    } else {
+                        Ok(disk_inode.blocks(block_device, in_data_area))
+                    }
+                });
+            let (blocks, bad_block) = match walked {
+                Ok(walked) => walked,
+                Err(problem) => {
+                    problems.push(problem);
+                    return false;
+                }
+            };
+            for block in blocks {
+                if let Some(owner) = owners.insert(block, inode_id) {
+                    problems.push(Problem::DoublyOwnedBlock {
+                        block,
+                        inodes: [owner, inode_id],
+                    });
+                } else if !self
+                    .data_bitmap
+                    .is_allocated(block_device, (block - data_start) as usize)
+                {
+                    problems.push(Problem::UnmarkedBlock {
+                        block,
+                        inode: inode_id,
+                    });
+                }
+            }
+            if let Some(block) = bad_block {
+                problems.push(Problem::BadBlockPointer {
+                    inode: inode_id,
+                    block,
+                });
+                return false;
+            }
+            true
+        };
+
+        // walk the tree from the root, with the parent of each directory
+        let mut reached: BTreeSet<u32> = BTreeSet::new();
+        let mut queue: VecDeque<(u32, u32)> = VecDeque::new();
+        reached.insert(0);
+        queue.push_back((0, 0));
+        if !self.inode_bitmap.is_allocated(block_device, 0) {
+            problems.push(Problem::UnmarkedInode { inode: 0 });
+        }
+        while let Some((inode_id, parent)) = queue.pop_front() {
+            if !take_blocks(inode_id, &mut problems) {
+                continue;
+            }
+            let (block_id, block_offset) = self.get_disk_inode_pos(inode_id);
+            let entries = get_block_cache(block_id as usize, Arc::clone(block_device))
+                .lock()
+                .read(block_offset, |disk_inode: &DiskInode| {
+                    if disk_inode.is_file() {
+                        return None;
+                    }
+                    Some(disk_inode.dirents(long_names, block_device))
+                });
+            let Some(entries) = entries else {
+                continue;
+            };
+            // the index belongs to its directory
+            let index = self
+                .load_disk_inode(inode_id)
+                .index_dirent(block_device)
+                .filter(|index| long_names && *index != 0);
+            if let Some(index) = index {
+                if index as usize >= inode_count || !reached.insert(index) {
+                    problems.push(Problem::BadIndex {
+                        dir: inode_id,
+                        index,
+                        owned: false,
+                    });
+                } else {
+                    if !self.inode_bitmap.is_allocated(block_device, index as usize) {
+                        problems.push(Problem::UnmarkedInode { inode: index });
+                    }
+                    if !take_blocks(index, &mut problems) || !self.is_index_valid(inode_id, index) {
+                        problems.push(Problem::BadIndex {
+                            dir: inode_id,
+                            index,
+                            owned: true,
+                        });
+                    }
+                }
+            }
+            for (name, inode) in entries
+                .iter()
+                .filter(|(name, _)| name != "." && name != "..")
+            {
+                if *inode as usize >= inode_count
+                    || !self
+                        .inode_bitmap
+                        .is_allocated(block_device, *inode as usize)
+                {
+                    problems.push(Problem::DanglingEntry {
+                        dir: inode_id,
+                        name: name.clone(),
+                        inode: *inode,
+                    });
+                } else if reached.insert(*inode) {
+                    queue.push_back((*inode, inode_id));
+                }
+            }
+            for (name, expected) in [(".", inode_id), ("..", parent)] {
+                let links: Vec<u32> = entries
+                    .iter()
+                    .filter(|(entry_name, _)| entry_name == name)
+                    .map(|(_, inode)| *inode)
+                    .collect();
+                // the root is made without links
+                if links != [expected] && !(inode_id == 0 && links.is_empty()) {
+                    problems.push(Problem::BadDotEntry {
+                        dir: inode_id,
+                        name,
+                        expected,
+                    });
+                }
+            }
+        }
+
+        // orphans keep their blocks, they are not leaked unless the orphan is dropped
+        let orphans: Vec<u32> = (0..inode_count as u32)
+            .filter(|inode| {
+                !reached.contains(inode)
+                    && self
+                        .inode_bitmap
+                        .is_allocated(block_device, *inode as usize)
+            })
+            .collect();
+        for inode in orphans {
+            let (block_id, block_offset) = self.get_disk_inode_pos(inode);
+            let size = get_block_cache(block_id as usize, Arc::clone(block_device))
+                .lock()
+                .read(block_offset, |disk_inode: &DiskInode| disk_inode.size);
+            problems.push(Problem::LeakedInode { inode, size });
+            take_blocks(inode, &mut problems);
+        }
+        for block in data_start..data_end {
+            if !owners.contains_key(&block)
+                && self
+                    .data_bitmap
+                    .is_allocated(block_device, (block - data_start) as usize)
+            {
+                problems.push(Problem::LeakedBlock { block });
+            }
+        }
+        problems
+    }
+}
@@ -0,0 +1,89 @@
+use crate::{
+    block_cache::{
+        block_cache_begin_logging, block_cache_sync_all, block_cache_take_logged, get_block_cache,
+    },
+    block_device::BlockDevice,
+    layout::JournalHeader,
+    BLOCK_SZ,
+};
+extern crate alloc;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+
+type DataBlock = [u8; BLOCK_SZ];
+
+/// Write-ahead log of the metadata blocks (bitmaps, inodes, index blocks
+/// and directory content) modified by a transaction
+pub struct Journal {
+    start_block: u32,
+    blocks: u32,
+}
+
+impl Journal {
+    /// A journal of `blocks` blocks from `start_block`
+    pub fn new(start_block: u32, blocks: u32) -> Self {
+        Self {
+            start_block,
+            blocks,
+        }
+    }
+    /// Start a transaction, metadata blocks of `block_device` modified from
+    /// now on are logged
+    pub fn begin(&self, block_device: &Arc<dyn BlockDevice>) {
+        block_cache_begin_logging(block_device);
+    }
+    /// Make the running transaction durable and write its blocks back
+    pub fn commit(&self, block_device: &Arc<dyn BlockDevice>) {
+        let logged = block_cache_take_logged(block_device);
+        // file content goes first, so committed metadata never refers to stale data
+        block_cache_sync_all();
+        if logged.is_empty() {
+            return;
+        }
+        if self.blocks > 0 {
+            let slots: Vec<u8> = logged.iter().flat_map(|(_, data)| *data).collect();
+            block_device.write_blocks(self.start_block as usize + 1, &slots);
+            let block_ids: Vec<u32> = logged.iter().map(|(id, _)| *id as u32).collect();
+            // commit point
+            block_device.write_block(
+                self.start_block as usize,
+                JournalHeader::new(&block_ids).as_bytes(),
+            );
+        }
+        for (block_id, data) in logged.iter() {
+            block_device.write_block(*block_id, data);
+        }
+        if self.blocks > 0 {
+            block_device.write_block(
+                self.start_block as usize,
+                JournalHeader::new(&[]).as_bytes(),
+            );
+        }
+    }
+    /// Write back the transaction committed before a crash, if any
+    pub fn replay(&self, block_device: &Arc<dyn BlockDevice>) {
+        if self.blocks == 0 {
+            return;
+        }
+        let mut header = JournalHeader::new(&[]);
+        block_device.read_block(self.start_block as usize, header.as_bytes_mut());
+        if !header.is_committed() {
+            return;
+        }
+        let block_ids = &header.blocks[..header.count as usize];
+        let mut slots = vec![0u8; block_ids.len() * BLOCK_SZ];
+        block_device.read_blocks(self.start_block as usize + 1, &mut slots);
+        for (block_id, data) in block_ids.iter().zip(slots.chunks(BLOCK_SZ)) {
+            // through the cache, so that no stale copy of the block survives
+            get_block_cache(*block_id as usize, Arc::clone(block_device))
+                .lock()
+                .modify(0, |block: &mut DataBlock| block.copy_from_slice(data));
+        }
+        block_cache_sync_all();
+        block_device.write_block(
+            self.start_block as usize,
+            JournalHeader::new(&[]).as_bytes(),
+        );
+    }
+}
@@ -1,9 +1,26 @@
-use crate::{block_cache::get_block_cache, block_device::BlockDevice, BLOCK_SZ};
+use crate::{
+    block_cache::{
+        block_cache_contains, block_cache_prefetch, block_cache_read_next, get_block_cache,
+        READ_AHEAD_BLOCKS,
+    },
+    block_device::BlockDevice,
+    clock::now,
+    BLOCK_SZ,
+};
 extern crate alloc;
+use alloc::string::String;
 use alloc::sync::Arc;
 use alloc::vec::Vec;
-const EFS_MAGIC: u32 = 0x3b800001;
-const INODE_DIRECT_COUNT: usize = 28;
+/// Magic of the layout with inode timestamps and 25 direct blocks
+const EFS_MAGIC: u32 = 0x3b800003;
+/// Magic of the layout before, with 28 direct blocks, which is not read
+const EFS_MAGIC_V1: u32 = 0x3b800001;
+const JOURNAL_MAGIC: u32 = 0x3b800002;
+/// Number of blocks a single transaction may log
+pub const JOURNAL_CAPACITY: usize = BLOCK_SZ / 4 - 2;
+/// Journal header followed by one slot per logged block
+pub const JOURNAL_BLOCKS: u32 = 1 + JOURNAL_CAPACITY as u32;
+const INODE_DIRECT_COUNT: usize = 25;
 
 #[repr(C)]
 pub struct SuperBlock {
@@ -13,6 +30,10 @@ pub struct SuperBlock {
     pub inode_area_blocks: u32,
     pub data_bitmap_blocks: u32,
     pub data_area_blocks: u32,
+    // the journal follows the data area
+    pub journal_blocks: u32,
+    // FEATURE_* flags, images with ones outside FEATURES_SUPPORTED are not opened
+    pub features: u32,
 }
 
 impl SuperBlock {
@@ -23,6 +44,7 @@ impl SuperBlock {
         inode_area_blocks: u32,
         data_bitmap_blocks: u32,
         data_area_blocks: u32,
+        journal_blocks: u32,
     ) {
         *self = Self {
             magic: EFS_MAGIC,
@@ -31,25 +53,96 @@ impl SuperBlock {
             inode_area_blocks,
             data_bitmap_blocks,
             data_area_blocks,
+            journal_blocks,
+            features: 0,
         }
     }
 
     pub fn is_valid(&self) -> bool {
         self.magic == EFS_MAGIC
     }
+
+    /// Whether the block holds an image of the layout before inode
+    /// timestamps
+    pub fn is_v1(&self) -> bool {
+        self.magic == EFS_MAGIC_V1
+    }
+
+    /// Whether directories hold [`LongDirEntry`] entries
+    pub fn has_long_names(&self) -> bool {
+        self.features & FEATURE_LONG_NAMES != 0
+    }
+
+    /// Whether directories start with an index entry
+    pub fn has_dir_index(&self) -> bool {
+        self.features & FEATURE_DIR_INDEX != 0
+    }
+
+    /// Whether inodes map their blocks with extent trees
+    pub fn has_extents(&self) -> bool {
+        self.features & FEATURE_EXTENTS != 0
+    }
 }
 
+/// First block of the journal. A transaction is committed once the header
+/// listing its blocks is written, and the journal is emptied after the
+/// blocks reach their home location.
 #[repr(C)]
+pub struct JournalHeader {
+    magic: u32,
+    pub count: u32,
+    pub blocks: [u32; JOURNAL_CAPACITY],
+}
+
+impl JournalHeader {
+    /// Create a header of a transaction logging `blocks`
+    pub fn new(blocks: &[u32]) -> Self {
+        assert!(blocks.len() <= JOURNAL_CAPACITY, "Transaction too large!");
+        let mut header = Self {
+            magic: JOURNAL_MAGIC,
+            count: blocks.len() as u32,
+            blocks: [0; JOURNAL_CAPACITY],
+        };
+        header.blocks[..blocks.len()].copy_from_slice(blocks);
+        header
+    }
+    /// Whether a committed transaction is waiting to be written back
+    pub fn is_committed(&self) -> bool {
+        self.magic == JOURNAL_MAGIC && self.count > 0
+    }
+    /// Serialize into bytes
+    pub fn as_bytes(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self as *const _ as usize as *const u8, BLOCK_SZ) }
+    }
+    /// Serialize into mutable bytes
+    pub fn as_bytes_mut(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self as *mut _ as usize as *mut u8, BLOCK_SZ) }
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
 pub struct DiskInode {
     pub size: u32,
     // indexes of data blocks storing file content/directory content
     pub direct: [u32; INODE_DIRECT_COUNT],
     pub indirect1: u32,
     pub indirect2: u32,
+    // last access, last content modification and last status change,
+    // in seconds since the Unix epoch
+    pub atime: u32,
+    pub mtime: u32,
+    pub ctime: u32,
     type_: DiskInodeType,
+    // INODE_* flags, in what used to be padding
+    flags: u8,
 }
 
-#[derive(PartialEq, Copy, Clone)]
+/// Inode flag: the direct blocks hold the root of an extent tree, and the
+/// indirect blocks are unused. See [`crate::extent`].
+const INODE_EXTENTS: u8 = 1;
+
+#[derive(PartialEq, Copy, Clone, Debug)]
 pub enum DiskInodeType {
     File,
     Directory,
@@ -59,17 +152,42 @@ const DIRECT_BOUND: usize = INODE_DIRECT_COUNT;
 const INODE_INDIRECT1_COUNT: usize = BLOCK_SZ / 4;
 const INDIRECT1_BOUND: usize = DIRECT_BOUND + INODE_INDIRECT1_COUNT;
 const INODE_INDIRECT2_COUNT: usize = INODE_INDIRECT1_COUNT * INODE_INDIRECT1_COUNT;
+/// Largest size of a file, in bytes
+pub const MAX_FILE_SIZE: usize =
+    (INODE_DIRECT_COUNT + INODE_INDIRECT1_COUNT + INODE_INDIRECT2_COUNT) * BLOCK_SZ;
+
+/// Data and index blocks a file may take to grow from `old_size` to `new_size` bytes
+pub fn blocks_needed(old_size: usize, new_size: usize) -> usize {
+    let data = new_size
+        .div_ceil(BLOCK_SZ)
+        .saturating_sub(old_size.div_ceil(BLOCK_SZ));
+    if data == 0 {
+        return 0;
+    }
+    // one index block per BLOCK_SZ / 4 data blocks, plus the top level ones
+    data + data.div_ceil(BLOCK_SZ / 4) + 2
+}
 type IndirectBlock = [u32; BLOCK_SZ / 4];
 type DataBlock = [u8; BLOCK_SZ];
 
 impl DiskInode {
-    pub fn initialize(&mut self, type_: DiskInodeType) {
+    /// Initialize an empty inode, mapping its blocks with an extent tree if `extents`
+    pub fn initialize(&mut self, type_: DiskInodeType, extents: bool) {
         self.size = 0;
         self.direct.iter_mut().for_each(|v| *v = 0);
         // 1 and 2 block are allocated only when needed.
         self.indirect1 = 0;
         self.indirect2 = 0;
+        let time = now();
+        self.atime = time;
+        self.mtime = time;
+        self.ctime = time;
         self.type_ = type_;
+        self.flags = if extents { INODE_EXTENTS } else { 0 };
+    }
+
+    pub fn type_(&self) -> DiskInodeType {
+        self.type_
     }
 
     pub fn is_dir(&self) -> bool {
@@ -80,7 +198,15 @@ impl DiskInode {
         self.type_ == DiskInodeType::File
     }
 
+    /// Whether the blocks are mapped by an extent tree
+    pub fn has_extents(&self) -> bool {
+        self.flags & INODE_EXTENTS != 0
+    }
+
     pub fn get_block_id(&self, inner_id: u32, block_device: &Arc<dyn BlockDevice>) -> u32 {
+        if self.has_extents() {
+            return self.extent_block_id(inner_id, block_device);
+        }
         let inner_id = inner_id as usize;
 
         if inner_id < INODE_DIRECT_COUNT {
@@ -113,12 +239,12 @@ impl DiskInode {
     }
 
     fn _data_blocks(size: u32) -> u32 {
-        (size + BLOCK_SZ as u32 - 1) / BLOCK_SZ as u32
+        size.div_ceil(BLOCK_SZ as u32)
     }
 
     pub fn total_blocks(size: u32) -> u32 {
         let data_blocks = Self::_data_blocks(size) as usize;
-        let mut total = data_blocks as usize;
+        let mut total = data_blocks;
         // indirect1
         if data_blocks > INODE_DIRECT_COUNT {
             total += 1;
@@ -127,22 +253,119 @@ impl DiskInode {
         if data_blocks > INDIRECT1_BOUND {
             total += 1;
             // sub indirect1
-            total +=
-                (data_blocks - INDIRECT1_BOUND + INODE_INDIRECT1_COUNT - 1) / INODE_INDIRECT1_COUNT;
+            total += (data_blocks - INDIRECT1_BOUND).div_ceil(INODE_INDIRECT1_COUNT);
         }
         total as u32
     }
+    /// Blocks held by the inode, index blocks included
+    pub fn allocated_blocks(&self, block_device: &Arc<dyn BlockDevice>) -> u32 {
+        if self.has_extents() {
+            self.data_blocks() + self.extent_nodes(block_device)
+        } else {
+            Self::total_blocks(self.size)
+        }
+    }
+    /// Whether the size can be addressed by the direct and indirect blocks
+    pub fn is_size_valid(&self) -> bool {
+        self.size as usize <= MAX_FILE_SIZE
+    }
+    /// Collect the blocks of the inode, index blocks included. Block ids
+    /// rejected by `valid` are not followed: the blocks collected so far
+    /// are returned along with the first rejected id.
+    pub fn blocks(
+        &self,
+        block_device: &Arc<dyn BlockDevice>,
+        valid: impl Fn(u32) -> bool,
+    ) -> (Vec<u32>, Option<u32>) {
+        assert!(self.is_size_valid());
+        if self.has_extents() {
+            return self.extent_blocks(block_device, valid);
+        }
+        let mut v: Vec<u32> = Vec::new();
+        let mut push = |block_id: u32| {
+            if !valid(block_id) {
+                return Err(block_id);
+            }
+            v.push(block_id);
+            Ok(())
+        };
+        let data_blocks = self.data_blocks() as usize;
+        let result = (|| {
+            for block_id in self.direct.iter().take(data_blocks) {
+                push(*block_id)?;
+            }
+            if data_blocks > DIRECT_BOUND {
+                push(self.indirect1)?;
+                let entries: Vec<u32> =
+                    get_block_cache(self.indirect1 as usize, Arc::clone(block_device))
+                        .lock()
+                        .read(0, |indirect1: &IndirectBlock| {
+                            let count = (data_blocks - DIRECT_BOUND).min(INODE_INDIRECT1_COUNT);
+                            indirect1[..count].to_vec()
+                        });
+                for block_id in entries {
+                    push(block_id)?;
+                }
+            }
+            if data_blocks > INDIRECT1_BOUND {
+                push(self.indirect2)?;
+                let rest = data_blocks - INDIRECT1_BOUND;
+                let lows: Vec<u32> =
+                    get_block_cache(self.indirect2 as usize, Arc::clone(block_device))
+                        .lock()
+                        .read(0, |indirect2: &IndirectBlock| {
+                            indirect2[..rest.div_ceil(INODE_INDIRECT1_COUNT)].to_vec()
+                        });
+                for (a, low) in lows.into_iter().enumerate() {
+                    push(low)?;
+                    let count = (rest - a * INODE_INDIRECT1_COUNT).min(INODE_INDIRECT1_COUNT);
+                    let entries: Vec<u32> = get_block_cache(low as usize, Arc::clone(block_device))
+                        .lock()
+                        .read(0, |indirect1: &IndirectBlock| indirect1[..count].to_vec());
+                    for block_id in entries {
+                        push(block_id)?;
+                    }
+                }
+            }
+            Ok(())
+        })();
+        (v, result.err())
+    }
     pub fn blocks_num_needed(&self, new_size: u32) -> u32 {
         assert!(new_size >= self.size);
         Self::total_blocks(new_size) - Self::total_blocks(self.size)
     }
 
+    /// Grow to `new_size` bytes. The blocks come from `alloc`, which is given
+    /// a block to allocate from and a number of blocks, and returns the first
+    /// of the consecutive blocks it allocated and how many there are.
     pub fn increase_size(
         &mut self,
         new_size: u32,
-        new_blocks: Vec<u32>,
+        mut alloc: impl FnMut(u32, u32) -> (u32, u32),
         block_device: &Arc<dyn BlockDevice>,
     ) {
+        if self.has_extents() {
+            assert!(new_size >= self.size);
+            let total_blocks = Self::_data_blocks(new_size);
+            let mut current_blocks = self.data_blocks();
+            // go on from the last block, so that the file stays in a single extent
+            let mut goal = self.extent_end(block_device);
+            while current_blocks < total_blocks {
+                let (start, len) = alloc(goal, total_blocks - current_blocks);
+                self.extent_append(current_blocks, start, len, &mut alloc, block_device);
+                current_blocks += len;
+                goal = start + len;
+            }
+            self.size = new_size;
+            return;
+        }
+        let needed = self.blocks_num_needed(new_size) as usize;
+        let mut new_blocks: Vec<u32> = Vec::with_capacity(needed);
+        while new_blocks.len() < needed {
+            let (start, len) = alloc(0, (needed - new_blocks.len()) as u32);
+            new_blocks.extend(start..start + len);
+        }
         let mut current_blocks = self.data_blocks();
         self.size = new_size;
         let mut total_blocks = self.data_blocks();
@@ -210,77 +433,100 @@ impl DiskInode {
             });
     }
 
-    /// Clear size to zero and return blocks that should be deallocated.
-    /// We will clear the block contents to zero later.
-    pub fn clear_size(&mut self, block_device: &Arc<dyn BlockDevice>) -> Vec<u32> {
+    /// Shrink size to `new_size` and return blocks that should be deallocated.
+    /// The tail of the last kept block is zeroed so that growing the file
+    /// again reads zeros, and the released index entries are reset.
+    pub fn decrease_size(
+        &mut self,
+        new_size: u32,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> Vec<u32> {
+        assert!(new_size <= self.size);
         let mut v: Vec<u32> = Vec::new();
-        let mut data_blocks = self.data_blocks() as usize;
-        self.size = 0;
-        let mut current_blocks = 0usize;
+        let old_blocks = self.data_blocks() as usize;
+        let new_blocks = Self::_data_blocks(new_size) as usize;
+        // zero the tail of the last kept block
+        let tail = new_size as usize % BLOCK_SZ;
+        if tail != 0 {
+            let block_id = self.get_block_id(new_blocks as u32 - 1, block_device);
+            let block_cache = get_block_cache(block_id as usize, Arc::clone(block_device));
+            let mut block_cache = block_cache.lock();
+            let zero = |data_block: &mut DataBlock| data_block[tail..].fill(0);
+            if self.is_dir() {
+                block_cache.modify(0, zero);
+            } else {
+                block_cache.modify_unlogged(0, zero);
+            }
+        }
+        self.size = new_size;
+        if self.has_extents() {
+            return self.extent_truncate(new_blocks as u32, block_device);
+        }
         // direct
-        while current_blocks < data_blocks.min(INODE_DIRECT_COUNT) {
-            v.push(self.direct[current_blocks]);
-            self.direct[current_blocks] = 0;
-            current_blocks += 1;
+        for i in new_blocks.min(INODE_DIRECT_COUNT)..old_blocks.min(INODE_DIRECT_COUNT) {
+            v.push(self.direct[i]);
+            self.direct[i] = 0;
         }
-        // indirect1 block
-        if data_blocks > INODE_DIRECT_COUNT {
-            v.push(self.indirect1);
-            data_blocks -= INODE_DIRECT_COUNT;
-            current_blocks = 0;
-        } else {
+        if old_blocks <= DIRECT_BOUND {
             return v;
         }
         // indirect1
+        let lo = new_blocks.saturating_sub(DIRECT_BOUND);
+        let hi = (old_blocks - DIRECT_BOUND).min(INODE_INDIRECT1_COUNT);
         get_block_cache(self.indirect1 as usize, Arc::clone(block_device))
             .lock()
             .modify(0, |indirect1: &mut IndirectBlock| {
-                while current_blocks < data_blocks.min(INODE_INDIRECT1_COUNT) {
-                    v.push(indirect1[current_blocks]);
-                    //indirect1[current_blocks] = 0;
-                    current_blocks += 1;
+                for entry in indirect1.iter_mut().take(hi).skip(lo) {
+                    v.push(*entry);
+                    *entry = 0;
                 }
             });
-        self.indirect1 = 0;
-        // indirect2 block
-        if data_blocks > INODE_INDIRECT1_COUNT {
-            v.push(self.indirect2);
-            data_blocks -= INODE_INDIRECT1_COUNT;
-        } else {
+        if new_blocks <= DIRECT_BOUND {
+            v.push(self.indirect1);
+            self.indirect1 = 0;
+        }
+        if old_blocks <= INDIRECT1_BOUND {
             return v;
         }
-        // indirect2
-        assert!(data_blocks <= INODE_INDIRECT2_COUNT);
-        let a1 = data_blocks / INODE_INDIRECT1_COUNT;
-        let b1 = data_blocks % INODE_INDIRECT1_COUNT;
+        // indirect2: release [lo, hi) counted from the start of indirect2
+        let lo = new_blocks.saturating_sub(INDIRECT1_BOUND);
+        let hi = old_blocks - INDIRECT1_BOUND;
+        assert!(hi <= INODE_INDIRECT2_COUNT);
+        let a0 = lo / INODE_INDIRECT1_COUNT;
+        let a1 = (hi - 1) / INODE_INDIRECT1_COUNT;
         get_block_cache(self.indirect2 as usize, Arc::clone(block_device))
             .lock()
             .modify(0, |indirect2: &mut IndirectBlock| {
-                // full indirect1 blocks
-                for entry in indirect2.iter_mut().take(a1) {
-                    v.push(*entry);
-                    get_block_cache(*entry as usize, Arc::clone(block_device))
+                for (a, low) in indirect2.iter_mut().enumerate().take(a1 + 1).skip(a0) {
+                    let b0 = if a == a0 {
+                        lo % INODE_INDIRECT1_COUNT
+                    } else {
+                        0
+                    };
+                    let b1 = if a == a1 {
+                        (hi - 1) % INODE_INDIRECT1_COUNT + 1
+                    } else {
+                        INODE_INDIRECT1_COUNT
+                    };
+                    get_block_cache(*low as usize, Arc::clone(block_device))
                         .lock()
                         .modify(0, |indirect1: &mut IndirectBlock| {
-                            for entry in indirect1.iter() {
+                            for entry in indirect1.iter_mut().take(b1).skip(b0) {
                                 v.push(*entry);
+                                *entry = 0;
                             }
                         });
-                }
-                // last indirect1 block
-                if b1 > 0 {
-                    v.push(indirect2[a1]);
-                    get_block_cache(indirect2[a1] as usize, Arc::clone(block_device))
-                        .lock()
-                        .modify(0, |indirect1: &mut IndirectBlock| {
-                            for entry in indirect1.iter().take(b1) {
-                                v.push(*entry);
-                            }
-                        });
-                    //indirect2[a1] = 0;
+                    // the whole low-level indirect1 block is released
+                    if b0 == 0 {
+                        v.push(*low);
+                        *low = 0;
+                    }
                 }
             });
-        self.indirect2 = 0;
+        if lo == 0 {
+            v.push(self.indirect2);
+            self.indirect2 = 0;
+        }
         v
     }
 
@@ -297,6 +543,17 @@ impl DiskInode {
         }
         let mut start_block = start / BLOCK_SZ;
         let mut read_size = 0usize;
+        // a reader going through the file in order gets the blocks after its range too
+        let last_block = (end - 1) / BLOCK_SZ;
+        let data_blocks = self.data_blocks() as usize;
+        let next = (last_block + 1 < data_blocks)
+            .then(|| self.get_block_id(last_block as u32 + 1, block_device) as usize);
+        let first = self.get_block_id(start_block as u32, block_device) as usize;
+        let read_end = if block_cache_read_next(first, next, block_device) {
+            data_blocks
+        } else {
+            last_block + 1
+        };
         loop {
             // calculate end of current block
             let mut end_current_block = (start / BLOCK_SZ + 1) * BLOCK_SZ;
@@ -304,15 +561,21 @@ impl DiskInode {
             // read and update read size
             let block_read_size = end_current_block - start;
             let dst = &mut buf[read_size..read_size + block_read_size];
-            get_block_cache(
-                self.get_block_id(start_block as u32, block_device) as usize,
-                Arc::clone(block_device),
-            )
-            .lock()
-            .read(0, |data_block: &DataBlock| {
-                let src = &data_block[start % BLOCK_SZ..start % BLOCK_SZ + block_read_size];
-                dst.copy_from_slice(src);
-            });
+            let block_id = self.get_block_id(start_block as u32, block_device) as usize;
+            // on a miss, the blocks to come are loaded together
+            if !block_cache_contains(block_id, block_device) {
+                let block_ids: Vec<usize> = (start_block
+                    ..read_end.min(start_block + READ_AHEAD_BLOCKS))
+                    .map(|inner_id| self.get_block_id(inner_id as u32, block_device) as usize)
+                    .collect();
+                block_cache_prefetch(&block_ids, block_device);
+            }
+            get_block_cache(block_id, Arc::clone(block_device))
+                .lock()
+                .read(0, |data_block: &DataBlock| {
+                    let src = &data_block[start % BLOCK_SZ..start % BLOCK_SZ + block_read_size];
+                    dst.copy_from_slice(src);
+                });
             read_size += block_read_size;
             // move to next block
             if end_current_block == end {
@@ -329,6 +592,27 @@ impl DiskInode {
         offset: usize,
         buf: &[u8],
         block_device: &Arc<dyn BlockDevice>,
+    ) -> usize {
+        // directory content is journaled, file content is not
+        self.write_blocks(offset, buf, self.is_dir(), block_device)
+    }
+
+    /// Write like [`DiskInode::write_at`], journaling file content too
+    pub fn write_logged_at(
+        &mut self,
+        offset: usize,
+        buf: &[u8],
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> usize {
+        self.write_blocks(offset, buf, true, block_device)
+    }
+
+    fn write_blocks(
+        &mut self,
+        offset: usize,
+        buf: &[u8],
+        logged: bool,
+        block_device: &Arc<dyn BlockDevice>,
     ) -> usize {
         let mut start = offset;
         let end = (offset + buf.len()).min(self.size as usize);
@@ -341,16 +625,21 @@ impl DiskInode {
             end_current_block = end_current_block.min(end);
             // write and update write size
             let block_write_size = end_current_block - start;
-            get_block_cache(
+            let block_cache = get_block_cache(
                 self.get_block_id(start_block as u32, block_device) as usize,
                 Arc::clone(block_device),
-            )
-            .lock()
-            .modify(0, |data_block: &mut DataBlock| {
+            );
+            let mut block_cache = block_cache.lock();
+            let write = |data_block: &mut DataBlock| {
                 let src = &buf[write_size..write_size + block_write_size];
                 let dst = &mut data_block[start % BLOCK_SZ..start % BLOCK_SZ + block_write_size];
                 dst.copy_from_slice(src);
-            });
+            };
+            if logged {
+                block_cache.modify(0, write);
+            } else {
+                block_cache.modify_unlogged(0, write);
+            }
             write_size += block_write_size;
             // move to next block
             if end_current_block == end {
@@ -363,7 +652,20 @@ impl DiskInode {
     }
 }
 
-const NAME_LENGTH_LIMIT: usize = 27;
+/// Longest name of a directory entry, in bytes
+pub const NAME_LENGTH_LIMIT: usize = 27;
+/// Longest name of a directory entry on images with [`FEATURE_LONG_NAMES`], in bytes
+pub const LONG_NAME_LENGTH_LIMIT: usize = 255;
+/// Superblock feature: directories hold [`LongDirEntry`] entries instead of [`DirEntry`]
+pub const FEATURE_LONG_NAMES: u32 = 1;
+/// Superblock feature: large directories have a hash index, see [`DiskInode::index_dirent`].
+/// Requires [`FEATURE_LONG_NAMES`].
+pub const FEATURE_DIR_INDEX: u32 = 2;
+/// Superblock feature: inodes map their blocks with extent trees, see [`crate::extent`]
+pub const FEATURE_EXTENTS: u32 = 4;
+/// Superblock features known to this version
+pub const FEATURES_SUPPORTED: u32 = FEATURE_LONG_NAMES | FEATURE_DIR_INDEX | FEATURE_EXTENTS;
+
 /// A directory entry
 #[repr(C)]
 pub struct DirEntry {
@@ -408,3 +710,305 @@ impl DirEntry {
         self.inode_number
     }
 }
+
+/// Header of a directory entry of variable length, followed by the name.
+/// The entries of a directory are chained by `rec_len`, which also covers
+/// the space left free after the name; an entry with no name is free.
+#[repr(C)]
+struct LongDirEntry {
+    inode_number: u32,
+    rec_len: u32,
+    name_len: u8,
+    kind: u8,
+    _reserved: [u8; 2],
+}
+/// Size of the header of a directory entry of variable length
+const LONG_DIRENT_SZ: usize = 12;
+/// Kind of an entry naming a file or a directory
+const DIRENT_NAME: u8 = 0;
+/// Kind of the nameless first entry of a directory on images with
+/// [`FEATURE_DIR_INDEX`], its inode is the index of the directory or 0
+const DIRENT_INDEX: u8 = 1;
+
+impl LongDirEntry {
+    fn new(kind: u8, name_len: usize, inode_number: u32, rec_len: usize) -> Self {
+        Self {
+            inode_number,
+            rec_len: rec_len as u32,
+            name_len: name_len as u8,
+            kind,
+            _reserved: [0; 2],
+        }
+    }
+    /// Space taken by an entry with a name of `name_len` bytes
+    fn len(name_len: usize) -> usize {
+        (LONG_DIRENT_SZ + name_len).next_multiple_of(4)
+    }
+    fn as_bytes(&self) -> &[u8] {
+        unsafe {
+            core::slice::from_raw_parts(self as *const _ as usize as *const u8, LONG_DIRENT_SZ)
+        }
+    }
+    fn as_bytes_mut(&mut self) -> &mut [u8] {
+        unsafe {
+            core::slice::from_raw_parts_mut(self as *mut _ as usize as *mut u8, LONG_DIRENT_SZ)
+        }
+    }
+}
+
+/// An entry read from a directory
+pub(crate) struct DirSlot {
+    pub offset: usize,
+    /// space owned by the entry, its name and the free space after it
+    pub len: usize,
+    kind: u8,
+    pub name: String,
+    pub inode_number: u32,
+}
+
+impl DirSlot {
+    /// Whether the entry names a file or a directory
+    pub fn is_named(&self) -> bool {
+        self.kind == DIRENT_NAME && !self.name.is_empty()
+    }
+    /// Space used by the entry, free space excluded
+    fn used(&self) -> usize {
+        if self.kind == DIRENT_NAME && self.name.is_empty() {
+            0
+        } else {
+            LongDirEntry::len(self.name.len())
+        }
+    }
+    fn header(&self, rec_len: usize) -> LongDirEntry {
+        LongDirEntry::new(self.kind, self.name.len(), self.inode_number, rec_len)
+    }
+}
+
+/// Directory content, in either entry format
+impl DiskInode {
+    /// Entries of the directory in order, free ones included for the
+    /// variable length format. Reading stops at a malformed entry.
+    pub(crate) fn dir_slots(
+        &self,
+        long_names: bool,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> Vec<DirSlot> {
+        let size = self.size as usize;
+        let mut slots = Vec::new();
+        if !long_names {
+            let mut dirent = DirEntry::empty();
+            for i in 0..size / DIRENT_SZ {
+                self.read_at(i * DIRENT_SZ, dirent.as_bytes_mut(), block_device);
+                slots.push(DirSlot {
+                    offset: i * DIRENT_SZ,
+                    len: DIRENT_SZ,
+                    kind: DIRENT_NAME,
+                    name: String::from(dirent.name()),
+                    inode_number: dirent.inode_number(),
+                });
+            }
+            return slots;
+        }
+        let mut offset = 0;
+        while offset + LONG_DIRENT_SZ <= size {
+            let Some(slot) = self.long_dir_slot(offset, block_device) else {
+                break;
+            };
+            if !slot.len.is_multiple_of(4)
+                || slot.len < LongDirEntry::len(slot.name.len())
+                || offset + slot.len > size
+            {
+                break;
+            }
+            offset += slot.len;
+            slots.push(slot);
+        }
+        slots
+    }
+    /// The variable length entry at `offset`
+    fn long_dir_slot(&self, offset: usize, block_device: &Arc<dyn BlockDevice>) -> Option<DirSlot> {
+        let mut header = LongDirEntry::new(0, 0, 0, 0);
+        if self.read_at(offset, header.as_bytes_mut(), block_device) < LONG_DIRENT_SZ {
+            return None;
+        }
+        let mut name = [0u8; LONG_NAME_LENGTH_LIMIT];
+        let name = &mut name[..header.name_len as usize];
+        self.read_at(offset + LONG_DIRENT_SZ, name, block_device);
+        Some(DirSlot {
+            offset,
+            len: header.rec_len as usize,
+            kind: header.kind,
+            name: String::from_utf8_lossy(name).into_owned(),
+            inode_number: header.inode_number,
+        })
+    }
+    /// Name and inode number of the variable length entry at `offset`
+    pub fn read_dirent_at(
+        &self,
+        offset: usize,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> Option<(String, u32)> {
+        self.long_dir_slot(offset, block_device)
+            .filter(|slot| slot.is_named())
+            .map(|slot| (slot.name, slot.inode_number))
+    }
+    /// Whether the directory is a whole number of well formed entries
+    pub fn is_dir_size_valid(&self, long_names: bool, block_device: &Arc<dyn BlockDevice>) -> bool {
+        if !long_names {
+            return (self.size as usize).is_multiple_of(DIRENT_SZ);
+        }
+        let slots = self.dir_slots(true, block_device);
+        slots.last().map_or(0, |slot| slot.offset + slot.len) == self.size as usize
+    }
+    /// Names and inode numbers of the entries of the directory
+    pub fn dirents(
+        &self,
+        long_names: bool,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> Vec<(String, u32)> {
+        self.dir_slots(long_names, block_device)
+            .into_iter()
+            .filter(|slot| slot.is_named())
+            .map(|slot| (slot.name, slot.inode_number))
+            .collect()
+    }
+    /// Offset where an entry named `name` would be added. The directory
+    /// must be grown to [`DiskInode::dirent_end`] before adding it.
+    pub fn dirent_slot(
+        &self,
+        name: &str,
+        long_names: bool,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> usize {
+        if long_names {
+            let len = LongDirEntry::len(name.len());
+            // first fit in the free space of an entry
+            for slot in self.dir_slots(true, block_device) {
+                if slot.len - slot.used() >= len {
+                    return slot.offset + slot.used();
+                }
+            }
+        }
+        self.size as usize
+    }
+    /// End of an entry named `name` added at `offset`
+    pub fn dirent_end(offset: usize, name: &str, long_names: bool) -> u32 {
+        let len = if long_names {
+            LongDirEntry::len(name.len())
+        } else {
+            DIRENT_SZ
+        };
+        (offset + len) as u32
+    }
+    /// Add an entry at `offset`, found by [`DiskInode::dirent_slot`]
+    pub fn add_dirent(
+        &mut self,
+        offset: usize,
+        name: &str,
+        inode_number: u32,
+        long_names: bool,
+        block_device: &Arc<dyn BlockDevice>,
+    ) {
+        if !long_names {
+            let dirent = DirEntry::new(name, inode_number);
+            self.write_at(offset, dirent.as_bytes(), block_device);
+            return;
+        }
+        self.add_long_dirent(offset, DIRENT_NAME, name, inode_number, block_device);
+    }
+    fn add_long_dirent(
+        &mut self,
+        offset: usize,
+        kind: u8,
+        name: &str,
+        inode_number: u32,
+        block_device: &Arc<dyn BlockDevice>,
+    ) {
+        let mut len = LongDirEntry::len(name.len());
+        // the entry whose free space is taken gives it away
+        if let Some(slot) = self
+            .dir_slots(true, block_device)
+            .into_iter()
+            .find(|slot| slot.offset <= offset && offset < slot.offset + slot.len)
+        {
+            len = slot.offset + slot.len - offset;
+            if slot.offset < offset {
+                let header = slot.header(offset - slot.offset);
+                self.write_at(slot.offset, header.as_bytes(), block_device);
+            }
+        }
+        let header = LongDirEntry::new(kind, name.len(), inode_number, len);
+        self.write_at(offset, header.as_bytes(), block_device);
+        self.write_at(offset + LONG_DIRENT_SZ, name.as_bytes(), block_device);
+    }
+    /// Add the index entry of a new directory, which must have grown
+    /// to [`DiskInode::dirent_end`] of an empty name
+    pub fn add_index_dirent(&mut self, block_device: &Arc<dyn BlockDevice>) {
+        self.add_long_dirent(0, DIRENT_INDEX, "", 0, block_device);
+    }
+    /// The inode in the index entry of a directory, 0 if the index has
+    /// not been built. None if the directory has no index entry.
+    pub fn index_dirent(&self, block_device: &Arc<dyn BlockDevice>) -> Option<u32> {
+        self.long_dir_slot(0, block_device)
+            .filter(|slot| slot.kind == DIRENT_INDEX)
+            .map(|slot| slot.inode_number)
+    }
+    /// Point the index entry of a directory at `inode_number`, 0 for none
+    pub fn set_index_inode(&mut self, inode_number: u32, block_device: &Arc<dyn BlockDevice>) {
+        let slot = self.long_dir_slot(0, block_device).unwrap();
+        assert_eq!(slot.kind, DIRENT_INDEX);
+        let header = LongDirEntry::new(DIRENT_INDEX, 0, inode_number, slot.len);
+        self.write_at(0, header.as_bytes(), block_device);
+    }
+    /// Remove the entries matched by `f`, which is given their name and
+    /// inode number, and return the new size of the directory
+    pub fn remove_dirents(
+        &mut self,
+        long_names: bool,
+        block_device: &Arc<dyn BlockDevice>,
+        f: impl Fn(&str, u32) -> bool,
+    ) -> u32 {
+        if !long_names {
+            // move the last entry into each hole
+            let mut count = self.size as usize / DIRENT_SZ;
+            let mut dirent = DirEntry::empty();
+            for i in (0..count).rev() {
+                self.read_at(i * DIRENT_SZ, dirent.as_bytes_mut(), block_device);
+                if f(dirent.name(), dirent.inode_number()) {
+                    self.read_at((count - 1) * DIRENT_SZ, dirent.as_bytes_mut(), block_device);
+                    self.write_at(i * DIRENT_SZ, dirent.as_bytes(), block_device);
+                    count -= 1;
+                }
+            }
+            return (count * DIRENT_SZ) as u32;
+        }
+        // a removed entry joins the free space of the previous one
+        let mut kept: Vec<DirSlot> = Vec::new();
+        for slot in self.dir_slots(true, block_device) {
+            if !slot.is_named() || !f(&slot.name, slot.inode_number) {
+                kept.push(slot);
+                continue;
+            }
+            match kept.last_mut() {
+                Some(previous) => previous.len += slot.len,
+                None => kept.push(DirSlot {
+                    name: String::new(),
+                    ..slot
+                }),
+            }
+            let previous = kept.last().unwrap();
+            let header = previous.header(previous.len);
+            self.write_at(previous.offset, header.as_bytes(), block_device);
+        }
+        // the free space at the end is given back
+        let Some(last) = kept.last() else {
+            return 0;
+        };
+        let end = last.offset + last.used();
+        if last.used() > 0 && last.len > last.used() {
+            let header = last.header(last.used());
+            self.write_at(last.offset, header.as_bytes(), block_device);
+        }
+        end as u32
+    }
+}
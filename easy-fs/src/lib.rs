@@ -3,9 +3,27 @@ pub const BLOCK_SZ: usize = 512;
 mod bitmap;
 mod block_cache;
 mod block_device;
+mod clock;
+mod dir_index;
+mod ext2;
+mod extent;
+mod fat32;
+mod partition;
+pub use block_cache::BLOCK_CACHE_SIZE;
 pub use block_device::BlockDevice;
-pub use efs::EasyFileSystem;
-pub use vfs::Inode;
+pub use clock::set_clock;
+pub use efs::{EasyFileSystem, FsStat};
+pub use ext2::{Ext2FileSystem, Ext2Inode};
+pub use fat32::{Fat32FileSystem, Fat32Inode};
+pub use fsck::Problem;
+pub use layout::{
+    blocks_needed, DiskInodeType, FEATURE_DIR_INDEX, FEATURE_EXTENTS, FEATURE_LONG_NAMES,
+    LONG_NAME_LENGTH_LIMIT, MAX_FILE_SIZE, NAME_LENGTH_LIMIT,
+};
+pub use partition::{partitions, Partition};
+pub use vfs::{Inode, InodeStat};
 mod efs;
+mod fsck;
+mod journal;
 mod layout;
 mod vfs;
@@ -0,0 +1,260 @@
+//! Partition tables, MBR and GPT
+//!
+//! Each partition is a block device of its own, made of the blocks of the
+//! disk from its first one on. The tables are read straight from the disk,
+//! past the block cache.
+use super::{BlockDevice, BLOCK_SZ};
+extern crate alloc;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+
+const MBR_SIGNATURE: [u8; 2] = [0x55, 0xaa];
+const MBR_ENTRIES_OFFSET: usize = 446;
+const MBR_ENTRY_SIZE: usize = 16;
+/// Types of the partitions holding logical ones
+const MBR_EXTENDED_TYPES: [u8; 3] = [0x05, 0x0f, 0x85];
+/// Type of the partition an MBR covers a GPT disk with
+const MBR_PROTECTIVE_TYPE: u8 = 0xee;
+/// Logical partitions followed at most, the chain may loop
+const MAX_LOGICAL_PARTITIONS: usize = 64;
+
+const GPT_SIGNATURE: &[u8] = b"EFI PART";
+// fields of the GPT header
+const GPT_HEADER_SIZE: usize = 12;
+const GPT_HEADER_CRC: usize = 16;
+const GPT_ENTRIES_LBA: usize = 72;
+const GPT_ENTRY_COUNT: usize = 80;
+const GPT_ENTRY_SIZE: usize = 84;
+const GPT_ENTRIES_CRC: usize = 88;
+/// Size of the header fields of revision 1.0
+const GPT_MIN_HEADER_SIZE: usize = 92;
+// fields of a GPT entry, whose last block is inclusive
+const GPT_TYPE_GUID: usize = 0;
+const GPT_FIRST_LBA: usize = 32;
+const GPT_LAST_LBA: usize = 40;
+const GPT_MIN_ENTRY_SIZE: usize = 128;
+
+fn le32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn le64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+/// CRC-32 of IEEE 802.3, as GPT uses it
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in bytes {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+/// A partition of a disk
+pub struct Partition {
+    disk: Arc<dyn BlockDevice>,
+    /// number of the partition, from 1, logical ones of an MBR from 5
+    number: usize,
+    /// first block on the disk
+    start: usize,
+    blocks: usize,
+}
+
+impl Partition {
+    pub fn number(&self) -> usize {
+        self.number
+    }
+
+    /// First block of the partition on the disk
+    pub fn start(&self) -> usize {
+        self.start
+    }
+
+    /// Block of the disk for `blocks` blocks of the partition from `block_id`
+    fn disk_block(&self, block_id: usize, blocks: usize) -> usize {
+        assert!(
+            block_id + blocks <= self.blocks,
+            "Block {} out of partition {}!",
+            block_id + blocks - 1,
+            self.number
+        );
+        self.start + block_id
+    }
+}
+
+impl BlockDevice for Partition {
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) {
+        self.disk.read_block(self.disk_block(block_id, 1), buf);
+    }
+
+    fn write_block(&self, block_id: usize, buf: &[u8]) {
+        self.disk.write_block(self.disk_block(block_id, 1), buf);
+    }
+
+    /// Interrupts are the disk's to handle
+    fn handle_irq(&self) {}
+
+    fn num_blocks(&self) -> usize {
+        self.blocks
+    }
+
+    fn read_blocks(&self, block_id: usize, buf: &mut [u8]) {
+        let blocks = buf.len().div_ceil(BLOCK_SZ);
+        self.disk
+            .read_blocks(self.disk_block(block_id, blocks), buf);
+    }
+
+    fn write_blocks(&self, block_id: usize, buf: &[u8]) {
+        let blocks = buf.len().div_ceil(BLOCK_SZ);
+        self.disk
+            .write_blocks(self.disk_block(block_id, blocks), buf);
+    }
+}
+
+/// Partitions found on a disk as (number, first block, blocks)
+type Table = Vec<(usize, usize, usize)>;
+
+fn read_block(disk: &Arc<dyn BlockDevice>, block_id: usize) -> [u8; BLOCK_SZ] {
+    let mut block = [0u8; BLOCK_SZ];
+    disk.read_block(block_id, &mut block);
+    block
+}
+
+/// The 4 entries of an MBR or an EBR as (type, first block, blocks), `None`
+/// if the block holds none. The boot sector of a FAT volume has the same
+/// signature, but not entries that make sense.
+fn mbr_entries(block: &[u8; BLOCK_SZ]) -> Option<[(u8, usize, usize); 4]> {
+    if block[510..] != MBR_SIGNATURE {
+        return None;
+    }
+    let mut entries = [(0, 0, 0); 4];
+    for (i, entry) in entries.iter_mut().enumerate() {
+        let raw = &block[MBR_ENTRIES_OFFSET + i * MBR_ENTRY_SIZE..][..MBR_ENTRY_SIZE];
+        if raw[0] & 0x7f != 0 {
+            return None;
+        }
+        *entry = (raw[4], le32(raw, 8) as usize, le32(raw, 12) as usize);
+    }
+    Some(entries)
+}
+
+/// Primary partitions, then the logical ones in the extended partition
+fn mbr_table(disk: &Arc<dyn BlockDevice>, entries: [(u8, usize, usize); 4]) -> Table {
+    let fits = |start: usize, blocks: usize| {
+        start > 0 && blocks > 0 && start.saturating_add(blocks) <= disk.num_blocks()
+    };
+    let mut table = Table::new();
+    let mut extended = None;
+    for (i, (type_, start, blocks)) in entries.into_iter().enumerate() {
+        if type_ == 0 || !fits(start, blocks) {
+            continue;
+        }
+        if MBR_EXTENDED_TYPES.contains(&type_) {
+            extended.get_or_insert((start, blocks));
+        } else {
+            table.push((i + 1, start, blocks));
+        }
+    }
+    // each EBR holds a logical partition, after it, and a link to the
+    // next EBR, after the start of the extended partition
+    let Some((extended_start, extended_blocks)) = extended else {
+        return table;
+    };
+    let mut ebr = extended_start;
+    for number in 5..5 + MAX_LOGICAL_PARTITIONS {
+        let Some(entries) = mbr_entries(&read_block(disk, ebr)) else {
+            break;
+        };
+        let (type_, start, blocks) = entries[0];
+        if type_ != 0 && fits(ebr + start, blocks) {
+            table.push((number, ebr + start, blocks));
+        }
+        let (type_, next, _) = entries[1];
+        if !MBR_EXTENDED_TYPES.contains(&type_) || next == 0 || next >= extended_blocks {
+            break;
+        }
+        ebr = extended_start + next;
+    }
+    table
+}
+
+/// Partitions of the GPT whose header is at block `lba`, `None` if the
+/// header or the entries are damaged
+fn gpt_table(disk: &Arc<dyn BlockDevice>, lba: usize) -> Option<Table> {
+    let mut header = read_block(disk, lba);
+    let header_size = le32(&header, GPT_HEADER_SIZE) as usize;
+    if &header[..GPT_SIGNATURE.len()] != GPT_SIGNATURE
+        || !(GPT_MIN_HEADER_SIZE..=BLOCK_SZ).contains(&header_size)
+    {
+        return None;
+    }
+    let crc = le32(&header, GPT_HEADER_CRC);
+    header[GPT_HEADER_CRC..GPT_HEADER_CRC + 4].fill(0);
+    if crc32(&header[..header_size]) != crc {
+        return None;
+    }
+    let entries_lba = le64(&header, GPT_ENTRIES_LBA) as usize;
+    let count = le32(&header, GPT_ENTRY_COUNT) as usize;
+    let entry_size = le32(&header, GPT_ENTRY_SIZE) as usize;
+    let len = count.checked_mul(entry_size)?;
+    if entry_size < GPT_MIN_ENTRY_SIZE
+        || len.div_ceil(BLOCK_SZ) > disk.num_blocks().saturating_sub(entries_lba)
+    {
+        return None;
+    }
+    let mut entries = vec![0u8; len.next_multiple_of(BLOCK_SZ)];
+    disk.read_blocks(entries_lba, &mut entries);
+    if crc32(&entries[..len]) != le32(&header, GPT_ENTRIES_CRC) {
+        return None;
+    }
+    let table = entries[..len]
+        .chunks(entry_size)
+        .enumerate()
+        .filter(|(_, entry)| entry[GPT_TYPE_GUID..GPT_TYPE_GUID + 16] != [0; 16])
+        .filter_map(|(i, entry)| {
+            let first = le64(entry, GPT_FIRST_LBA) as usize;
+            let last = le64(entry, GPT_LAST_LBA) as usize;
+            (first > 0 && first <= last && last < disk.num_blocks()).then_some((
+                i + 1,
+                first,
+                last - first + 1,
+            ))
+        })
+        .collect();
+    Some(table)
+}
+
+/// Partitions of a disk, none if it has no partition table. A GPT is read
+/// from its backup at the end of the disk if the header at block 1 is
+/// damaged.
+pub fn partitions(disk: &Arc<dyn BlockDevice>) -> Vec<Arc<Partition>> {
+    if disk.num_blocks() < 2 {
+        return Vec::new();
+    }
+    let Some(entries) = mbr_entries(&read_block(disk, 0)) else {
+        return Vec::new();
+    };
+    let table = if entries.iter().any(|entry| entry.0 == MBR_PROTECTIVE_TYPE) {
+        gpt_table(disk, 1)
+            .or_else(|| gpt_table(disk, disk.num_blocks() - 1))
+            .unwrap_or_default()
+    } else {
+        mbr_table(disk, entries)
+    };
+    table
+        .into_iter()
+        .map(|(number, start, blocks)| {
+            Arc::new(Partition {
+                disk: Arc::clone(disk),
+                number,
+                start,
+                blocks,
+            })
+        })
+        .collect()
+}
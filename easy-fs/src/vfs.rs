@@ -1,18 +1,59 @@
 extern crate alloc;
+use alloc::collections::BTreeMap;
 use alloc::string::String;
 use alloc::string::ToString;
 use alloc::sync::Arc;
 use alloc::vec::Vec;
 
+use lazy_static::lazy_static;
 use spin::{Mutex, MutexGuard};
 
 use crate::{
-    block_cache::{block_cache_sync_all, get_block_cache},
+    block_cache::{block_cache_sync, device_key, get_block_cache},
     block_device::BlockDevice,
+    clock::now,
     efs::EasyFileSystem,
-    layout::{DirEntry, DiskInode, DiskInodeType, DIRENT_SZ},
+    layout::{blocks_needed, DiskInode, DiskInodeType, SuperBlock, MAX_FILE_SIZE},
+    BLOCK_SZ,
 };
 
+/// Resizing a file by more than this many bytes is split into several
+/// transactions, so that the index and bitmap blocks each of them
+/// modifies fit in the journal
+const RESIZE_STEP: u32 = (4096 * BLOCK_SZ) as u32;
+
+/// Metadata of an inode, as reported by [`Inode::stat`]
+#[derive(Debug, Clone, Copy)]
+pub struct InodeStat {
+    /// inode number
+    pub ino: u32,
+    pub type_: DiskInodeType,
+    /// size in bytes
+    pub size: u32,
+    /// number of directory entries referring to the inode
+    pub nlink: u32,
+    /// number of blocks used, index blocks included
+    pub blocks: u32,
+    pub atime: u32,
+    pub mtime: u32,
+    pub ctime: u32,
+}
+
+/// The [`Inode`]s of a disk inode, and whether it was unlinked
+#[derive(Default)]
+pub(crate) struct Handles {
+    pub(crate) count: usize,
+    pub(crate) unlinked: bool,
+}
+
+lazy_static! {
+    /// Handles of the disk inodes in use, by device and position. An
+    /// unlinked inode is only freed with its last handle, so that a file
+    /// stays usable while open and its id is not given to another one.
+    static ref HANDLES: Mutex<BTreeMap<(usize, usize, usize), Handles>> =
+        Mutex::new(BTreeMap::new());
+}
+
 pub struct Inode {
     block_id: usize,
     block_offset: usize,
@@ -37,12 +78,30 @@ impl Inode {
         fs: Arc<Mutex<EasyFileSystem>>,
         block_device: Arc<dyn BlockDevice>,
     ) -> Self {
-        Self {
+        let inode = Self {
             block_id: block_id as usize,
             block_offset,
             fs,
             block_device,
-        }
+        };
+        HANDLES.lock().entry(inode.key()).or_default().count += 1;
+        inode
+    }
+
+    fn key(&self) -> (usize, usize, usize) {
+        (
+            device_key(&self.block_device),
+            self.block_id,
+            self.block_offset,
+        )
+    }
+
+    /// Whether the inode was unlinked, and is only kept for its handles
+    fn is_unlinked(&self) -> bool {
+        HANDLES
+            .lock()
+            .get(&self.key())
+            .is_some_and(|handles| handles.unlinked)
     }
 
     fn read_disk_inode<V>(&self, f: impl FnOnce(&DiskInode) -> V) -> V {
@@ -57,6 +116,63 @@ impl Inode {
             .modify(self.block_offset, f)
     }
 
+    pub fn is_dir(&self) -> bool {
+        self.read_disk_inode(|disk_inode| disk_inode.is_dir())
+    }
+
+    pub fn is_file(&self) -> bool {
+        self.read_disk_inode(|disk_inode| disk_inode.is_file())
+    }
+
+    /// Size of current inode in bytes
+    pub fn size(&self) -> u32 {
+        self.read_disk_inode(|disk_inode| disk_inode.size)
+    }
+
+    /// Get the metadata of current inode
+    pub fn stat(&self) -> InodeStat {
+        let fs = self.fs.lock();
+        let ino = fs.get_inode_id(self.block_id as u32, self.block_offset);
+        // inode ids of the entries, "." and ".." excluded
+        let mut children: Vec<u32> = Vec::new();
+        let mut stat = self.read_disk_inode(|disk_inode| {
+            if disk_inode.is_dir() {
+                for (name, inode_id) in disk_inode.dirents(fs.long_names, &self.block_device) {
+                    if name != "." && name != ".." {
+                        children.push(inode_id);
+                    }
+                }
+            }
+            InodeStat {
+                ino,
+                type_: disk_inode.type_(),
+                size: disk_inode.size,
+                nlink: 1,
+                blocks: disk_inode.allocated_blocks(&self.block_device),
+                atime: disk_inode.atime,
+                mtime: disk_inode.mtime,
+                ctime: disk_inode.ctime,
+            }
+        });
+        if self.is_unlinked() {
+            stat.nlink = 0;
+        } else if stat.type_ == DiskInodeType::Directory {
+            // a directory is referred to by its parent, by its own "."
+            // and by the ".." of each subdirectory
+            let subdirs = children
+                .into_iter()
+                .filter(|inode_id| {
+                    let (block_id, block_offset) = fs.get_disk_inode_pos(*inode_id);
+                    get_block_cache(block_id as usize, Arc::clone(&self.block_device))
+                        .lock()
+                        .read(block_offset, |disk_inode: &DiskInode| disk_inode.is_dir())
+                })
+                .count();
+            stat.nlink = 2 + subdirs as u32;
+        }
+        stat
+    }
+
     pub fn find(&self, path: &str) -> Option<Arc<Inode>> {
         let fs = self.fs.lock();
         let mut block_id = self.block_id as u32;
@@ -69,20 +185,11 @@ impl Inode {
                 self.block_device.clone(),
             )));
         }
+        let mut inode_id = fs.get_inode_id(block_id, block_offset);
         for name in path.split("/").filter(|s| !s.is_empty()) {
-            let inode_id = get_block_cache(block_id as usize, self.block_device.clone())
-                .lock()
-                .read(block_offset, |disk_inode: &DiskInode| {
-                    if disk_inode.is_file() {
-                        return None;
-                    }
-                    self.find_inode_id(name, disk_inode)
-                });
-            if inode_id.is_none() {
-                return None;
-            }
-            (block_id, block_offset) = fs.get_disk_inode_pos(inode_id.unwrap());
+            inode_id = fs.lookup_entry(inode_id, name)?;
         }
+        (block_id, block_offset) = fs.get_disk_inode_pos(inode_id);
         Some(Arc::new(Self::new(
             block_id,
             block_offset,
@@ -91,7 +198,13 @@ impl Inode {
         )))
     }
     pub fn is_root(&self) -> bool {
-        self.block_id == 2 && self.block_offset == 0
+        // the root is the first inode, right after the superblock and the inode bitmap
+        let root_block_id = get_block_cache(0, Arc::clone(&self.block_device))
+            .lock()
+            .read(0, |super_block: &SuperBlock| {
+                1 + super_block.inode_bitmap_blocks as usize
+            });
+        self.block_id == root_block_id && self.block_offset == 0
     }
 
     pub fn get_current_inode_id(&self) -> Option<u32> {
@@ -121,22 +234,13 @@ impl Inode {
             }
         }
         let current_inode_id = self.get_current_inode_id().unwrap();
+        let long_names = self.long_names();
         self.get_parent().unwrap().read_disk_inode(|disk_inode| {
-            let file_count = (disk_inode.size as usize) / DIRENT_SZ;
-            let mut dirent = DirEntry::empty();
-            for i in 0..file_count {
-                assert_eq!(
-                    disk_inode.read_at(DIRENT_SZ * i, dirent.as_bytes_mut(), &self.block_device,),
-                    DIRENT_SZ,
-                );
-                if dirent.name() == "." {
-                    continue;
-                }
-                if dirent.inode_number() == current_inode_id {
-                    return Some(ToString::to_string(&dirent.name()));
-                }
-            }
-            None
+            disk_inode
+                .dirents(long_names, &self.block_device)
+                .into_iter()
+                .find(|(name, inode_id)| name != "." && *inode_id == current_inode_id)
+                .map(|(name, _)| name)
         })
     }
 
@@ -149,7 +253,7 @@ impl Inode {
         let parent = inode.get_parent().expect("parent should exist");
         let name = inode.get_name().expect("name should exist");
         self.helper_cwd(
-            if path == "" {
+            if path.is_empty() {
                 name
             } else {
                 alloc::format!("{}/{}", name, path)
@@ -169,18 +273,18 @@ impl Inode {
     fn find_inode_id(&self, name: &str, disk_inode: &DiskInode) -> Option<u32> {
         // assert it is a directory
         assert!(disk_inode.is_dir());
-        let file_count = (disk_inode.size as usize) / DIRENT_SZ;
-        let mut dirent = DirEntry::empty();
-        for i in 0..file_count {
-            assert_eq!(
-                disk_inode.read_at(DIRENT_SZ * i, dirent.as_bytes_mut(), &self.block_device,),
-                DIRENT_SZ,
-            );
-            if dirent.name() == name {
-                return Some(dirent.inode_number() as u32);
-            }
-        }
-        None
+        disk_inode
+            .dirents(self.long_names(), &self.block_device)
+            .into_iter()
+            .find(|(dirent_name, _)| dirent_name == name)
+            .map(|(_, inode_id)| inode_id)
+    }
+
+    /// Whether directories hold entries of variable length, as the superblock says
+    fn long_names(&self) -> bool {
+        get_block_cache(0, Arc::clone(&self.block_device))
+            .lock()
+            .read(0, |super_block: &SuperBlock| super_block.has_long_names())
     }
 
     pub fn get_parent(&self) -> Option<Arc<Inode>> {
@@ -191,11 +295,8 @@ impl Inode {
                 return None;
             }
             self.find_inode_id("..", disk_inode)
-        });
-        if parent_inode_id.is_none() {
-            return None;
-        }
-        let (block_id, block_offset) = fs.get_disk_inode_pos(parent_inode_id.unwrap());
+        })?;
+        let (block_id, block_offset) = fs.get_disk_inode_pos(parent_inode_id);
         Some(Arc::new(Self::new(
             block_id,
             block_offset,
@@ -206,17 +307,21 @@ impl Inode {
 
     fn create_inode(&self, name: &str, inode_type: DiskInodeType) -> Option<Arc<Inode>> {
         let mut fs = self.fs.lock();
-        if self
-            .modify_disk_inode(|root_inode| {
-                // assert it is a directory
-                assert!(root_inode.is_dir());
-                // has the file been created?
-                self.find_inode_id(name, root_inode)
-            })
-            .is_some()
-        {
+        if name.is_empty() || name.len() > fs.name_length_limit() || name.contains('/') {
             return None;
         }
+        // assert it is a directory
+        assert!(self.is_dir());
+        // entries of a removed directory would never be freed
+        if self.is_unlinked() {
+            return None;
+        }
+        // has the file been created?
+        let dir_id = fs.get_inode_id(self.block_id as u32, self.block_offset);
+        if fs.lookup_entry(dir_id, name).is_some() {
+            return None;
+        }
+        fs.begin();
         // create a new file
         // alloc a inode with an indirect block
         let new_inode_id = fs.alloc_inode();
@@ -225,29 +330,33 @@ impl Inode {
         get_block_cache(new_inode_block_id as usize, Arc::clone(&self.block_device))
             .lock()
             .modify(new_inode_block_offset, |new_inode: &mut DiskInode| {
-                new_inode.initialize(inode_type);
+                new_inode.initialize(inode_type, fs.extents);
             });
-        self.modify_disk_inode(|root_inode| {
-            // append file in the dirent
-            let file_count = (root_inode.size as usize) / DIRENT_SZ;
-            let new_size = (file_count + 1) * DIRENT_SZ;
-            // increase size
-            self.increase_size(new_size as u32, root_inode, &mut fs);
-            // write dirent
-            let dirent = DirEntry::new(name, new_inode_id);
-            root_inode.write_at(
-                file_count * DIRENT_SZ,
-                dirent.as_bytes(),
-                &self.block_device,
-            );
-        });
-        let (block_id, block_offset) = fs.get_disk_inode_pos(new_inode_id);
-        Some(Arc::new(Self::new(
-            block_id,
-            block_offset,
+        self.append_dirent(name, new_inode_id, &mut fs);
+        let inode = Arc::new(Self::new(
+            new_inode_block_id,
+            new_inode_block_offset,
             self.fs.clone(),
             self.block_device.clone(),
-        )))
+        ));
+        // a directory is complete with its links, in the same transaction
+        if inode_type == DiskInodeType::Directory {
+            inode.append_dirent(".", new_inode_id, &mut fs);
+            inode.append_dirent("..", self.get_current_inode_id().unwrap(), &mut fs);
+        }
+        fs.commit();
+        Some(inode)
+    }
+
+    /// Append an entry to current directory
+    fn append_dirent(&self, name: &str, inode_id: u32, fs: &mut MutexGuard<EasyFileSystem>) {
+        let dir_id = fs.get_inode_id(self.block_id as u32, self.block_offset);
+        fs.append_entry(dir_id, name, inode_id);
+        self.modify_disk_inode(|root_inode| {
+            let time = now();
+            root_inode.mtime = time;
+            root_inode.ctime = time;
+        });
     }
 
     /// Create a folder that has inode pointing to current folder
@@ -256,21 +365,9 @@ impl Inode {
             panic!("path should be . or ..");
         }
         let mut fs = self.fs.lock();
-
-        self.modify_disk_inode(|root_inode| {
-            // append file in the dirent
-            let file_count = (root_inode.size as usize) / DIRENT_SZ;
-            let new_size = (file_count + 1) * DIRENT_SZ;
-            // increase size
-            self.increase_size(new_size as u32, root_inode, &mut fs);
-            // write dirent
-            let dirent = DirEntry::new(path, inode);
-            root_inode.write_at(
-                file_count * DIRENT_SZ,
-                dirent.as_bytes(),
-                &self.block_device,
-            );
-        });
+        fs.begin();
+        self.append_dirent(path, inode, &mut fs);
+        fs.commit();
         let (block_id, block_offset) = fs.get_disk_inode_pos(inode);
         Some(Arc::new(Self::new(
             block_id,
@@ -293,13 +390,63 @@ impl Inode {
 
     /// Create a directory in current inode
     pub fn create_dir(&self, name: &str) -> Option<Arc<Inode>> {
-        let inode = self.create_inode(name, DiskInodeType::Directory);
-        if let Some(inode) = &inode {
-            let inode_id = self.read_disk_inode(|disk_inode| self.find_inode_id(name, disk_inode));
-            inode.create_dir_link(".", inode_id.unwrap());
-            inode.create_dir_link("..", self.get_current_inode_id().unwrap());
+        self.create_inode(name, DiskInodeType::Directory)
+    }
+
+    /// Remove entry `name` from current directory. Its inode and blocks
+    /// are released with the last handle on it, at once unless the file is
+    /// open. Directories must be empty. Return false if nothing was removed.
+    pub fn unlink(&self, name: &str) -> bool {
+        if name == "." || name == ".." || name.contains('/') {
+            return false;
         }
-        inode
+        let Some(inode) = self.find(name) else {
+            return false;
+        };
+        if inode.is_dir() && inode.ls().iter().any(|name| name != "." && name != "..") {
+            return false;
+        }
+        let mut fs = self.fs.lock();
+        let dir_id = fs.get_inode_id(self.block_id as u32, self.block_offset);
+        fs.begin();
+        fs.remove_entries(dir_id, |dirent_name, _| dirent_name == name);
+        self.modify_disk_inode(|disk_inode| {
+            let time = now();
+            disk_inode.mtime = time;
+            disk_inode.ctime = time;
+        });
+        fs.commit();
+        drop(fs);
+        HANDLES.lock().get_mut(&inode.key()).unwrap().unlinked = true;
+        true
+    }
+
+    /// Free an unlinked inode and its blocks, once its last handle is gone
+    fn release(&self) {
+        if self.is_file() {
+            // large files are released one transaction at a time
+            self.truncate(0);
+        }
+        let mut fs = self.fs.lock();
+        let inode_id = fs.get_inode_id(self.block_id as u32, self.block_offset);
+        fs.begin();
+        fs.drop_index(inode_id);
+        // what is left is "." and ".." of a directory
+        let data_blocks_dealloc =
+            self.modify_disk_inode(|disk_inode| disk_inode.decrease_size(0, &self.block_device));
+        for data_block in data_blocks_dealloc.into_iter() {
+            fs.dealloc_data(data_block);
+        }
+        fs.dealloc_inode(inode_id);
+        fs.commit();
+    }
+
+    /// Whether a file of `size` bytes can grow to `new_size` bytes. An
+    /// allocation that runs out of blocks halfway cannot be undone, so it
+    /// must not start.
+    fn has_room(size: u32, new_size: usize, fs: &EasyFileSystem) -> bool {
+        new_size <= MAX_FILE_SIZE
+            && blocks_needed(size as usize, new_size) <= fs.stat().free_data_blocks as usize
     }
 
     fn increase_size(
@@ -311,61 +458,137 @@ impl Inode {
         if new_size < disk_inode.size {
             return;
         }
-        let blocks_needed = disk_inode.blocks_num_needed(new_size);
-        let mut v: Vec<u32> = Vec::new();
-        for _ in 0..blocks_needed {
-            v.push(fs.alloc_data());
-        }
-        disk_inode.increase_size(new_size, v, &self.block_device);
+        disk_inode.increase_size(
+            new_size,
+            |goal, count| fs.alloc_data_run(goal, count),
+            &self.block_device,
+        );
     }
 
     pub fn ls(&self) -> Vec<String> {
-        let _fs = self.fs.lock();
+        let fs = self.fs.lock();
         self.read_disk_inode(|disk_inode| {
-            let mut v: Vec<String> = Vec::new();
             if disk_inode.is_file() {
-                return v;
-            }
-            let file_count = (disk_inode.size as usize) / DIRENT_SZ;
-            for i in 0..file_count {
-                let mut dirent = DirEntry::empty();
-                assert_eq!(
-                    disk_inode.read_at(i * DIRENT_SZ, dirent.as_bytes_mut(), &self.block_device,),
-                    DIRENT_SZ,
-                );
-                v.push(String::from(dirent.name()));
+                return Vec::new();
             }
-            v
+            disk_inode
+                .dirents(fs.long_names, &self.block_device)
+                .into_iter()
+                .map(|(name, _)| name)
+                .collect()
         })
     }
 
     pub fn read_at(&self, offset: usize, buf: &mut [u8]) -> usize {
         let _fs = self.fs.lock();
-        self.read_disk_inode(|disk_inode| disk_inode.read_at(offset, buf, &self.block_device))
+        // a lone atime update is a single block write, no transaction needed
+        self.modify_disk_inode(|disk_inode| {
+            disk_inode.atime = now();
+            disk_inode.read_at(offset, buf, &self.block_device)
+        })
     }
 
+    /// Write `buf` at `offset`, growing current file as needed. Nothing is
+    /// written if the file cannot grow that far.
     pub fn write_at(&self, offset: usize, buf: &[u8]) -> usize {
         let mut fs = self.fs.lock();
+        let size = self.size();
+        let end = offset.saturating_add(buf.len());
+        if end > size as usize {
+            if !Self::has_room(size, end, &fs) {
+                return 0;
+            }
+            self.resize(end as u32, &mut fs);
+        }
+        fs.begin();
         let size = self.modify_disk_inode(|disk_inode| {
             assert!(disk_inode.is_file());
-            self.increase_size((offset + buf.len()) as u32, disk_inode, &mut fs);
+            let time = now();
+            disk_inode.mtime = time;
+            disk_inode.ctime = time;
             disk_inode.write_at(offset, buf, &self.block_device)
         });
-        block_cache_sync_all();
+        fs.commit();
         size
     }
+    /// Write back the modified content of current file, along with its
+    /// inode unless `data_only`. The rest of the metadata is journaled.
+    pub fn sync(&self, data_only: bool) {
+        let _fs = self.fs.lock();
+        let disk_inode = self.read_disk_inode(|disk_inode| *disk_inode);
+        let (mut block_ids, _) = disk_inode.blocks(&self.block_device, |_| true);
+        if !data_only {
+            block_ids.push(self.block_id as u32);
+        }
+        block_cache_sync(&block_ids, &self.block_device);
+    }
     /// Clear the data in current inode
     pub fn clear(&self) {
+        self.truncate(0);
+    }
+    /// Grow or shrink current file to `new_size` bytes.
+    /// Grown space reads as zeros and blocks beyond the new size are released.
+    /// False, and the file is left as it is, if it cannot grow that far.
+    pub fn truncate(&self, new_size: u32) -> bool {
         let mut fs = self.fs.lock();
-        self.modify_disk_inode(|disk_inode| {
-            assert!(disk_inode.is_file());
-            let size = disk_inode.size;
-            let data_blocks_dealloc = disk_inode.clear_size(&self.block_device);
-            assert!(data_blocks_dealloc.len() == DiskInode::total_blocks(size) as usize);
-            for data_block in data_blocks_dealloc.into_iter() {
-                fs.dealloc_data(data_block);
+        let size = self.size();
+        if new_size > size && !Self::has_room(size, new_size as usize, &fs) {
+            return false;
+        }
+        self.resize(new_size, &mut fs);
+        true
+    }
+    /// Resize current file one transaction per [`RESIZE_STEP`] bytes
+    fn resize(&self, new_size: u32, fs: &mut MutexGuard<EasyFileSystem>) {
+        loop {
+            let size = self.size();
+            let step_size = if new_size >= size {
+                new_size.min(size.saturating_add(RESIZE_STEP))
+            } else {
+                new_size.max(size - RESIZE_STEP.min(size))
+            };
+            fs.begin();
+            self.modify_disk_inode(|disk_inode| {
+                assert!(disk_inode.is_file());
+                if step_size >= size {
+                    self.increase_size(step_size, disk_inode, fs);
+                } else {
+                    let blocks = disk_inode.allocated_blocks(&self.block_device);
+                    let data_blocks_dealloc =
+                        disk_inode.decrease_size(step_size, &self.block_device);
+                    assert!(
+                        data_blocks_dealloc.len()
+                            == (blocks - disk_inode.allocated_blocks(&self.block_device)) as usize
+                    );
+                    for data_block in data_blocks_dealloc.into_iter() {
+                        fs.dealloc_data(data_block);
+                    }
+                }
+                let time = now();
+                disk_inode.mtime = time;
+                disk_inode.ctime = time;
+            });
+            fs.commit();
+            if step_size == new_size {
+                break;
             }
-        });
-        block_cache_sync_all();
+        }
+    }
+}
+
+impl Drop for Inode {
+    fn drop(&mut self) {
+        let key = self.key();
+        let mut handles = HANDLES.lock();
+        let entry = handles.get_mut(&key).unwrap();
+        entry.count -= 1;
+        if entry.count > 0 {
+            return;
+        }
+        let unlinked = handles.remove(&key).unwrap().unlinked;
+        drop(handles);
+        if unlinked {
+            self.release();
+        }
     }
 }
@@ -1,5 +1,78 @@
+use std::env;
+use std::fs::{read, read_dir, write};
+use std::path::Path;
+
 static TARGET_PATH: &str = "../user/target/riscv64gc-unknown-none-elf/release/";
+
 fn main() {
     println!("cargo:rerun-if-changed=../user/src/");
     println!("cargo:rerun-if-changed={}", TARGET_PATH);
+    println!("cargo:rerun-if-env-changed=INITRAMFS");
+    // the kernel boots from the archive unless it is empty
+    let archive = match env::var("INITRAMFS") {
+        Ok(value) if !value.is_empty() && value != "0" => initramfs(),
+        _ => Vec::new(),
+    };
+    let out_dir = env::var("OUT_DIR").unwrap();
+    write(Path::new(&out_dir).join("initramfs.cpio"), archive).unwrap();
+}
+
+/// A newc cpio archive holding the user programs in /bin
+fn initramfs() -> Vec<u8> {
+    let mut apps: Vec<String> = read_dir("../user/src/bin")
+        .unwrap()
+        .map(|entry| {
+            let name = entry.unwrap().file_name().into_string().unwrap();
+            name.trim_end_matches(".rs").to_string()
+        })
+        .collect();
+    apps.sort();
+    let mut archive = Vec::new();
+    push_entry(&mut archive, 1, "bin", 0o040755, &[]);
+    for (i, app) in apps.iter().enumerate() {
+        let path = format!("{}{}", TARGET_PATH, app);
+        let data = read(&path)
+            .unwrap_or_else(|_| panic!("{} is missing, build the user programs first", path));
+        push_entry(
+            &mut archive,
+            i + 2,
+            &format!("bin/{}", app),
+            0o100755,
+            &data,
+        );
+    }
+    push_entry(&mut archive, 0, "TRAILER!!!", 0, &[]);
+    archive
+}
+
+/// Append an entry, whose header fields are hexadecimal and whose name and
+/// data are padded to 4 bytes
+fn push_entry(archive: &mut Vec<u8>, ino: usize, name: &str, mode: u32, data: &[u8]) {
+    let nlink = if mode & 0o040000 != 0 { 2 } else { 1 };
+    // ino, mode, uid, gid, nlink, mtime, filesize, devmajor, devminor,
+    // rdevmajor, rdevminor, namesize, check
+    let fields = [
+        ino,
+        mode as usize,
+        0,
+        0,
+        nlink,
+        0,
+        data.len(),
+        0,
+        0,
+        0,
+        0,
+        name.len() + 1,
+        0,
+    ];
+    archive.extend_from_slice(b"070701");
+    for field in fields {
+        archive.extend_from_slice(format!("{:08x}", field).as_bytes());
+    }
+    archive.extend_from_slice(name.as_bytes());
+    archive.push(0);
+    archive.resize(archive.len().next_multiple_of(4), 0);
+    archive.extend_from_slice(data);
+    archive.resize(archive.len().next_multiple_of(4), 0);
 }
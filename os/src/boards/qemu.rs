@@ -1,51 +1,160 @@
 extern crate alloc;
-use enum_iterator::all;
-use enum_iterator_derive::Sequence;
-use strum_macros::FromRepr;
+use alloc::collections::BTreeMap;
+use alloc::vec;
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
 
 use crate::drivers::{
-    block::BLOCK_DEVICE,
+    block::DISKS,
     chardev::{UartDevice, UART},
+    fdt::DeviceTree,
     plic::{IntrTargetPriority, PLIC},
 };
+use crate::sync::UPIntrFreeCell;
+use core::sync::atomic::{AtomicUsize, Ordering};
 
-#[allow(non_snake_case, non_upper_case_globals)]
-pub mod VirtAddrEnum {
-    pub const VIRTTEST: usize = 0x0010_0000;
-    pub const UART0: usize = 0x1000_0000;
-    pub const VIRTIO: usize = 0x1000_8000;
-    pub const PLIC: usize = 0x0C00_0000;
+pub type UartDeviceImpl = crate::drivers::chardev::NS16550a;
+
+/// Where the SBI firmware left the device tree
+static DTB: AtomicUsize = AtomicUsize::new(0);
+/// Hart the kernel boots on
+static BOOT_HART: AtomicUsize = AtomicUsize::new(0);
+
+/// The machine as the device tree describes it
+pub struct Machine {
+    pub harts: usize,
+    /// ticks of `time` per second
+    pub clock_freq: usize,
+    /// the memory the kernel is loaded in
+    pub memory_start: usize,
+    pub memory_end: usize,
+    /// registers and IRQ of the UART
+    pub uart: (usize, u32),
+    pub plic: usize,
+    pub rtc: usize,
+    /// registers and IRQ of each virtio-mmio slot. QEMU fills the slots
+    /// from the last one, so they come from the last one too.
+    pub virtio: Vec<(usize, u32)>,
+    /// regions of registers, mapped into the kernel space
+    pub mmio: Vec<(usize, usize)>,
 }
 
-pub const CLOCK_FREQ: usize = 12500000;
-pub const MEMORY_END: usize = 0x8800_0000;
-pub type UartDeviceImpl = crate::drivers::chardev::NS16550a<{ VirtAddrEnum::UART0 }>;
+impl Machine {
+    fn from_device_tree(tree: &DeviceTree) -> Self {
+        let cpus = tree.find("/cpus").expect("No /cpus in the device tree!");
+        let harts = tree
+            .children(cpus)
+            .filter(|node| node.has_string("device_type", "cpu"))
+            .count();
+        let clock_freq = cpus
+            .prop_u32("timebase-frequency")
+            .or_else(|| {
+                tree.children(cpus)
+                    .find_map(|cpu| cpu.prop_u32("timebase-frequency"))
+            })
+            .expect("No timebase frequency in the device tree!") as usize;
+        let (memory_start, memory_end) = tree
+            .nodes()
+            .filter(|node| node.has_string("device_type", "memory"))
+            .flat_map(|node| tree.reg(node))
+            // the kernel, and so its statics, lie in it
+            .find(|(base, size)| (*base..base + size).contains(&(&DTB as *const _ as usize)))
+            .map(|(base, size)| (base, base + size))
+            .expect("No memory holding the kernel in the device tree!");
+
+        let mut mmio = Vec::new();
+        // the registers of the first device compatible with `models`, and its IRQ
+        let mut device = |models: &[&str]| {
+            let node = tree.compatible(models).next()?;
+            let reg = *tree.reg(node).first()?;
+            mmio.push(reg);
+            Some((reg.0, node.prop_u32("interrupts")))
+        };
+        let (uart_base, uart_irq) = device(&["ns16550a"]).expect("No UART in the device tree!");
+        let uart = (
+            uart_base,
+            uart_irq.expect("No IRQ of the UART in the device tree!"),
+        );
+        let (plic, _) =
+            device(&["riscv,plic0", "sifive,plic-1.0.0"]).expect("No PLIC in the device tree!");
+        let (rtc, _) = device(&["google,goldfish-rtc"]).expect("No RTC in the device tree!");
+        let mut virtio: Vec<(usize, u32)> = tree
+            .compatible(&["virtio,mmio"])
+            .filter_map(|node| {
+                let reg = *tree.reg(node).first()?;
+                mmio.push(reg);
+                Some((reg.0, node.prop_u32("interrupts")?))
+            })
+            .collect();
+        virtio.sort_by(|a, b| b.cmp(a));
+        Self {
+            harts,
+            clock_freq,
+            memory_start,
+            memory_end,
+            uart,
+            plic,
+            rtc,
+            virtio,
+            mmio,
+        }
+    }
+}
+
+lazy_static! {
+    /// Read from the device tree the first time it is needed, before the
+    /// frame allocator hands out the frames it lies in
+    pub static ref MACHINE: Machine = {
+        let tree = unsafe { DeviceTree::parse(DTB.load(Ordering::Relaxed)) }
+            .expect("No device tree from the SBI firmware!");
+        Machine::from_device_tree(&tree)
+    };
+}
 
-pub const MMIO: &[(usize, usize)] = &[
-    (VirtAddrEnum::VIRTTEST, 0x00_2000), // VIRT_TEST/RTC  in virt machine
-    (VirtAddrEnum::VIRTIO, 0x00_1000),   // Virtio Block in virt machine
-    (VirtAddrEnum::UART0, 0x100),        // uart0 in virt machine
-    (VirtAddrEnum::PLIC, 0x210000),      // PLIC in virt machine
-];
+/// Keep what the SBI firmware passes to the kernel, the boot hart and the
+/// device tree
+pub fn init(hart_id: usize, dtb: usize) {
+    BOOT_HART.store(hart_id, Ordering::Relaxed);
+    DTB.store(dtb, Ordering::Relaxed);
+}
+
+/// Interrupt sources and their devices, the UART and the slots holding a
+/// disk
+fn irq_sources() -> Vec<(u32, &'static str)> {
+    let mut sources = vec![(MACHINE.uart.1, "uart")];
+    for (irq, _) in DISKS.iter() {
+        sources.push((*irq, "virtio-blk"));
+    }
+    sources.sort();
+    sources
+}
+
+lazy_static! {
+    /// Interrupts handled from each source
+    static ref IRQ_COUNTS: UPIntrFreeCell<BTreeMap<u32, usize>> =
+        unsafe { UPIntrFreeCell::new(BTreeMap::new()) };
+}
 
-#[derive(FromRepr, Sequence, Clone, Copy)]
-#[repr(u32)]
-pub enum IrqEnum {
-    BLOCK = 8,
-    UART = 10,
+/// Return (IRQ, device, interrupts handled) of each source
+pub fn irq_counts() -> Vec<(u32, &'static str, usize)> {
+    let counts = IRQ_COUNTS.exclusive_access();
+    irq_sources()
+        .into_iter()
+        .map(|(id, name)| (id, name, counts.get(&id).copied().unwrap_or(0)))
+        .collect()
 }
 
 pub fn device_init() {
     use riscv::register::sie;
-    let mut plic = unsafe { PLIC::new(VirtAddrEnum::PLIC) };
-    let hart_id: usize = 0;
+    let mut plic = unsafe { PLIC::new(MACHINE.plic) };
+    let hart_id = BOOT_HART.load(Ordering::Relaxed);
     let supervisor = IntrTargetPriority::Supervisor;
     let machine = IntrTargetPriority::Machine;
 
     plic.set_threshold(hart_id, supervisor, 0);
     plic.set_threshold(hart_id, machine, 1);
 
-    for intr_src_id in all::<IrqEnum>() {
+    for (intr_src_id, _) in irq_sources() {
         plic.enable(hart_id, supervisor, intr_src_id as usize);
         plic.set_priority(intr_src_id as usize, 1);
     }
@@ -55,12 +164,15 @@ pub fn device_init() {
 }
 
 pub fn irq_handler() {
-    let mut plic = unsafe { PLIC::new(VirtAddrEnum::PLIC) };
-    let irq_id = plic.claim(0, IntrTargetPriority::Supervisor);
-    match IrqEnum::from_repr(irq_id).expect(alloc::format!("Invalid IRQ {}", irq_id).as_str()) {
-        IrqEnum::BLOCK => BLOCK_DEVICE.handle_irq(),
-        IrqEnum::UART => UART.handle_irq(),
+    let mut plic = unsafe { PLIC::new(MACHINE.plic) };
+    let hart_id = BOOT_HART.load(Ordering::Relaxed);
+    let irq_id = plic.claim(hart_id, IntrTargetPriority::Supervisor);
+    *IRQ_COUNTS.exclusive_access().entry(irq_id).or_insert(0) += 1;
+    match DISKS.iter().find(|(irq, _)| *irq == irq_id) {
+        Some((_, disk)) => disk.handle_irq(),
+        None if irq_id == MACHINE.uart.1 => UART.handle_irq(),
+        None => panic!("Invalid IRQ {}", irq_id),
     }
-    plic.complete(0, IntrTargetPriority::Supervisor, irq_id);
+    plic.complete(hart_id, IntrTargetPriority::Supervisor, irq_id);
 }
 pub type BlockDeviceImpl = crate::drivers::block::VirtIOBlock;
@@ -1,10 +1,19 @@
 pub const USER_STACK_SIZE: usize = 4096 * 2;
 pub const KERNEL_STACK_SIZE: usize = 4096 * 2;
 pub const KERNEL_HEAP_SIZE: usize = 0x20_0000;
+/// Blocks of the filesystem cached in memory
+pub const BLOCK_CACHE_BLOCKS: usize = 256;
+/// Seconds between write backs of the file content modified in the block cache
+pub const FS_FLUSH_INTERVAL: u32 = 5;
+/// Frames the tmpfs mounted at /tmp may hold
+pub const TMPFS_PAGES: usize = 2048;
+/// Frames the root unpacked from an initramfs may hold
+pub const INITRAMFS_PAGES: usize = 8192;
+/// Block device the root is mounted from, like "vda2", set with `ROOT` at
+/// build time. Left empty, it is the first one holding a filesystem.
+pub const ROOT_DEVICE: Option<&str> = option_env!("ROOT");
 pub const PAGE_SIZE: usize = 0x1000;
 pub const PAGE_SIZE_BITS: usize = 0xc;
 
 pub const TRAMPOLINE: usize = usize::MAX - PAGE_SIZE + 1;
 pub const TRAP_CONTEXT: usize = TRAMPOLINE - PAGE_SIZE;
-
-pub use crate::board::{CLOCK_FREQ, MEMORY_END, MMIO};
@@ -1,11 +1,67 @@
 mod virtio_blk;
 pub use virtio_blk::VirtIOBlock;
 extern crate alloc;
-use crate::board::BlockDeviceImpl;
+use crate::board::{BlockDeviceImpl, MACHINE};
+use crate::drivers::bus::virtio::probe;
+use alloc::format;
+use alloc::string::String;
 use alloc::sync::Arc;
-use easy_fs::BlockDevice;
+use alloc::vec::Vec;
+use easy_fs::{partitions, BlockDevice};
 use lazy_static::*;
+use virtio_drivers::DeviceType;
 
 lazy_static! {
-    pub static ref BLOCK_DEVICE: Arc<dyn BlockDevice> = Arc::new(BlockDeviceImpl::new());
+    /// Disks found in the virtio-mmio slots, with their IRQs, in the order
+    /// QEMU was given them
+    pub static ref DISKS: Vec<(u32, Arc<dyn BlockDevice>)> = MACHINE
+        .virtio
+        .iter()
+        .filter(|(base, _)| matches!(probe(*base), Some(DeviceType::Block)))
+        .map(|(base, irq)| {
+            let disk: Arc<dyn BlockDevice> = Arc::new(BlockDeviceImpl::new(*base));
+            (*irq, disk)
+        })
+        .collect();
+    /// Each disk then its partitions, read once at boot, with the index
+    /// of the disk they lie on
+    static ref BLOCK_DEVICES: Vec<(String, Arc<dyn BlockDevice>, usize)> = {
+        let mut devices = Vec::new();
+        for (i, (_, disk)) in DISKS.iter().enumerate() {
+            let disk_name = format!("vd{}", (b'a' + i as u8) as char);
+            devices.push((disk_name.clone(), disk.clone(), i));
+            for partition in partitions(disk) {
+                let name = format!("{}{}", disk_name, partition.number());
+                devices.push((name, partition as Arc<dyn BlockDevice>, i));
+            }
+        }
+        devices
+    };
+}
+
+/// Block devices and their names, like "vda" or "vdb1"
+pub fn block_devices() -> Vec<(String, Arc<dyn BlockDevice>)> {
+    BLOCK_DEVICES
+        .iter()
+        .map(|(name, device, _)| (name.clone(), device.clone()))
+        .collect()
+}
+
+/// Whether `a` and `b` are the same block device
+pub fn same_device(a: &Arc<dyn BlockDevice>, b: &Arc<dyn BlockDevice>) -> bool {
+    Arc::as_ptr(a) as *const () == Arc::as_ptr(b) as *const ()
+}
+
+/// Whether two block devices share blocks, being the same one or a disk
+/// and one of its partitions
+pub fn overlap(a: &Arc<dyn BlockDevice>, b: &Arc<dyn BlockDevice>) -> bool {
+    let disk = |device: &Arc<dyn BlockDevice>| {
+        BLOCK_DEVICES
+            .iter()
+            .find(|(_, other, _)| same_device(other, device))
+            .map(|(_, _, disk)| *disk)
+    };
+    let is_disk =
+        |device: &Arc<dyn BlockDevice>| DISKS.iter().any(|(_, disk)| same_device(disk, device));
+    same_device(a, b) || disk(a).is_some() && disk(a) == disk(b) && (is_disk(a) || is_disk(b))
 }
@@ -1,18 +1,24 @@
 extern crate alloc;
 use super::BlockDevice;
-use crate::board::VirtAddrEnum;
 use crate::drivers::bus::virtio::VirtioHal;
 use crate::sync::{Condvar, UPIntrFreeCell};
 use crate::task::schedule;
 use crate::DEV_NON_BLOCKING_ACCESS;
 use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use easy_fs::BLOCK_SZ;
 use virtio_drivers::{BlkResp, RespStatus, VirtIOBlk, VirtIOHeader};
 
 pub struct VirtIOBlock {
     virtio_blk: UPIntrFreeCell<VirtIOBlk<'static, VirtioHal>>,
     condvars: BTreeMap<u16, Condvar>,
+    num_blocks: usize,
 }
 
+/// Offset of the device configuration in the virtio-mmio registers, the
+/// capacity in 512-byte sectors is its first field
+const VIRTIO_CONFIG_OFFSET: usize = 0x100;
+
 impl BlockDevice for VirtIOBlock {
     fn read_block(&self, block_id: usize, buf: &mut [u8]) {
         let nb = *DEV_NON_BLOCKING_ACCESS.exclusive_access();
@@ -56,6 +62,57 @@ impl BlockDevice for VirtIOBlock {
                 .expect("Error when writing VirtIOBlk");
         }
     }
+    fn read_blocks(&self, block_id: usize, buf: &mut [u8]) {
+        if !*DEV_NON_BLOCKING_ACCESS.exclusive_access() {
+            for (i, block) in buf.chunks_mut(BLOCK_SZ).enumerate() {
+                self.read_block(block_id + i, block);
+            }
+            return;
+        }
+        let batch = self.batch_size();
+        for (i, chunk) in buf.chunks_mut(BLOCK_SZ * batch).enumerate() {
+            let first = block_id + i * batch;
+            let mut resps = Self::new_resps(chunk.len());
+            let tokens: Vec<u16> = self.virtio_blk.exclusive_session(|blk| {
+                chunk
+                    .chunks_mut(BLOCK_SZ)
+                    .zip(resps.iter_mut())
+                    .enumerate()
+                    .map(|(j, (block, resp))| unsafe {
+                        blk.read_block_nb(first + j, block, resp).unwrap()
+                    })
+                    .collect()
+            });
+            self.wait_all(&tokens, &resps, "Error when reading VirtIOBlk");
+        }
+    }
+    fn write_blocks(&self, block_id: usize, buf: &[u8]) {
+        if !*DEV_NON_BLOCKING_ACCESS.exclusive_access() {
+            for (i, block) in buf.chunks(BLOCK_SZ).enumerate() {
+                self.write_block(block_id + i, block);
+            }
+            return;
+        }
+        let batch = self.batch_size();
+        for (i, chunk) in buf.chunks(BLOCK_SZ * batch).enumerate() {
+            let first = block_id + i * batch;
+            let mut resps = Self::new_resps(chunk.len());
+            let tokens: Vec<u16> = self.virtio_blk.exclusive_session(|blk| {
+                chunk
+                    .chunks(BLOCK_SZ)
+                    .zip(resps.iter_mut())
+                    .enumerate()
+                    .map(|(j, (block, resp))| unsafe {
+                        blk.write_block_nb(first + j, block, resp).unwrap()
+                    })
+                    .collect()
+            });
+            self.wait_all(&tokens, &resps, "Error when writing VirtIOBlk");
+        }
+    }
+    fn num_blocks(&self) -> usize {
+        self.num_blocks
+    }
     fn handle_irq(&self) {
         self.virtio_blk.exclusive_session(|blk| {
             while let Ok(token) = blk.pop_used() {
@@ -66,11 +123,37 @@ impl BlockDevice for VirtIOBlock {
 }
 
 impl VirtIOBlock {
-    pub fn new() -> Self {
+    /// Requests in flight at once, each of them takes three descriptors
+    fn batch_size(&self) -> usize {
+        (self.condvars.len() / 3).max(1)
+    }
+
+    fn new_resps(len: usize) -> Vec<BlkResp> {
+        (0..len.div_ceil(BLOCK_SZ))
+            .map(|_| BlkResp::default())
+            .collect()
+    }
+
+    /// Sleep until every request of a batch is done
+    fn wait_all(&self, tokens: &[u16], resps: &[BlkResp], error: &str) {
+        for (token, resp) in tokens.iter().zip(resps) {
+            // a request done before we wait has already been signaled
+            let task_cx_ptr = self.virtio_blk.exclusive_session(|_| {
+                (resp.status() == RespStatus::_NotReady)
+                    .then(|| self.condvars.get(token).unwrap().wait_no_sched())
+            });
+            if let Some(task_cx_ptr) = task_cx_ptr {
+                schedule(task_cx_ptr);
+            }
+            assert_eq!(resp.status(), RespStatus::Ok, "{}", error);
+        }
+    }
+
+    /// The disk in the virtio-mmio slot whose registers are at `base`
+    pub fn new(base: usize) -> Self {
         let virtio_blk = unsafe {
             UPIntrFreeCell::new(
-                VirtIOBlk::<VirtioHal>::new(&mut *(VirtAddrEnum::VIRTIO as *mut VirtIOHeader))
-                    .unwrap(),
+                VirtIOBlk::<VirtioHal>::new(&mut *(base as *mut VirtIOHeader)).unwrap(),
             )
         };
         let mut condvars = BTreeMap::new();
@@ -79,9 +162,12 @@ impl VirtIOBlock {
             let condvar = Condvar::new();
             condvars.insert(i, condvar);
         }
+        let num_blocks =
+            unsafe { ((base + VIRTIO_CONFIG_OFFSET) as *const u64).read_volatile() } as usize;
         Self {
             virtio_blk,
             condvars,
+            num_blocks,
         }
     }
 }
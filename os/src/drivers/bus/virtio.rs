@@ -6,7 +6,7 @@ use crate::memory::{
 use crate::sync::UPIntrFreeCell;
 use alloc::vec::Vec;
 use lazy_static::*;
-use virtio_drivers::Hal;
+use virtio_drivers::{DeviceType, Hal, VirtIOHeader};
 
 lazy_static! {
     static ref QUEUE_FRAMES: UPIntrFreeCell<Vec<FrameTracker>> =
@@ -47,3 +47,10 @@ impl Hal for VirtioHal {
             .0
     }
 }
+
+/// Type of the device in the virtio-mmio slot whose registers are at
+/// `base`, `None` if the slot is empty
+pub fn probe(base: usize) -> Option<DeviceType> {
+    let header = unsafe { &*(base as *const VirtIOHeader) };
+    header.verify().then(|| header.device_type())
+}
@@ -4,7 +4,7 @@ pub use ns16550a::NS16550a;
 extern crate alloc;
 use alloc::sync::Arc;
 
-use crate::board::UartDeviceImpl;
+use crate::board::{UartDeviceImpl, MACHINE};
 
 pub trait UartDevice {
     fn init(&self);
@@ -14,5 +14,5 @@ pub trait UartDevice {
 }
 
 lazy_static! {
-    pub static ref UART: Arc<UartDeviceImpl> = Arc::new(UartDeviceImpl::new());
+    pub static ref UART: Arc<UartDeviceImpl> = Arc::new(UartDeviceImpl::new(MACHINE.uart.0));
 }
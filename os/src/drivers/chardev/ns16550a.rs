@@ -130,15 +130,15 @@ struct NS16550aInner {
     read_buffer: VecDeque<u8>,
 }
 
-pub struct NS16550a<const BASE_ADDR: usize> {
+pub struct NS16550a {
     inner: UPIntrFreeCell<NS16550aInner>,
     condvar: Condvar,
 }
 
-impl<const BASE_ADDR: usize> NS16550a<BASE_ADDR> {
-    pub fn new() -> Self {
+impl NS16550a {
+    pub fn new(base_addr: usize) -> Self {
         let inner = NS16550aInner {
-            ns16550a: NS16550aRaw::new(BASE_ADDR),
+            ns16550a: NS16550aRaw::new(base_addr),
             read_buffer: VecDeque::new(),
         };
         Self {
@@ -154,7 +154,7 @@ impl<const BASE_ADDR: usize> NS16550a<BASE_ADDR> {
     }
 }
 
-impl<const BASE_ADDR: usize> UartDevice for NS16550a<BASE_ADDR> {
+impl UartDevice for NS16550a {
     fn init(&self) {
         let mut inner = self.inner.exclusive_access();
         info!("init uart");
@@ -0,0 +1,187 @@
+//! Flattened device tree, as the SBI firmware passes it in `a1`
+//!
+//! The tree is read once at boot into nodes owning their properties, since
+//! it lies in frames that are handed out afterwards.
+extern crate alloc;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+const FDT_MAGIC: u32 = 0xd00d_feed;
+// fields of the header
+const HEADER_TOTALSIZE: usize = 4;
+const HEADER_OFF_STRUCT: usize = 8;
+const HEADER_OFF_STRINGS: usize = 12;
+const HEADER_SIZE: usize = 40;
+// tokens of the structure block
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_NOP: u32 = 4;
+const FDT_END: u32 = 9;
+
+fn be32(bytes: &[u8], offset: usize) -> Option<u32> {
+    let field = bytes.get(offset..offset + 4)?;
+    Some(u32::from_be_bytes(field.try_into().unwrap()))
+}
+
+/// The string up to the first NUL
+fn c_str(bytes: &[u8]) -> Option<&str> {
+    let len = bytes.iter().position(|byte| *byte == 0)?;
+    core::str::from_utf8(&bytes[..len]).ok()
+}
+
+/// A value of cells, most significant first
+fn cells(bytes: &[u8]) -> usize {
+    bytes.chunks(4).fold(0, |value, cell| {
+        (value << 32) | be32(cell, 0).unwrap() as usize
+    })
+}
+
+/// A node of the tree
+pub struct Node {
+    /// name with its unit address, like "uart@10000000", empty for the root
+    pub name: String,
+    /// index of the parent node, `None` for the root
+    pub parent: Option<usize>,
+    props: Vec<(String, Vec<u8>)>,
+}
+
+impl Node {
+    pub fn prop(&self, name: &str) -> Option<&[u8]> {
+        self.props
+            .iter()
+            .find(|(prop, _)| prop == name)
+            .map(|(_, value)| value.as_slice())
+    }
+
+    /// The first cell of a property
+    pub fn prop_u32(&self, name: &str) -> Option<u32> {
+        be32(self.prop(name)?, 0)
+    }
+
+    /// Whether the property holds the string `value`, among others for a
+    /// list like `compatible`
+    pub fn has_string(&self, name: &str, value: &str) -> bool {
+        self.prop(name).is_some_and(|list| {
+            list.split(|byte| *byte == 0)
+                .any(|string| string == value.as_bytes())
+        })
+    }
+}
+
+pub struct DeviceTree {
+    /// parents come before their children
+    nodes: Vec<Node>,
+}
+
+impl DeviceTree {
+    /// Read the tree at `addr`, `None` if there is none or it is damaged
+    ///
+    /// # Safety
+    ///
+    /// `addr` must be 0 or readable for the size its header gives.
+    pub unsafe fn parse(addr: usize) -> Option<Self> {
+        if addr == 0 || addr % 4 != 0 {
+            return None;
+        }
+        let header = core::slice::from_raw_parts(addr as *const u8, HEADER_SIZE);
+        if be32(header, 0)? != FDT_MAGIC {
+            return None;
+        }
+        let size = be32(header, HEADER_TOTALSIZE)? as usize;
+        Self::from_bytes(core::slice::from_raw_parts(addr as *const u8, size))
+    }
+
+    fn from_bytes(blob: &[u8]) -> Option<Self> {
+        let strings = blob.get(be32(blob, HEADER_OFF_STRINGS)? as usize..)?;
+        let mut nodes: Vec<Node> = Vec::new();
+        let mut open = Vec::new();
+        let mut pos = be32(blob, HEADER_OFF_STRUCT)? as usize;
+        loop {
+            let token = be32(blob, pos)?;
+            pos += 4;
+            match token {
+                FDT_BEGIN_NODE => {
+                    let name = c_str(blob.get(pos..)?)?;
+                    pos = (pos + name.len() + 1).next_multiple_of(4);
+                    nodes.push(Node {
+                        name: String::from(name),
+                        parent: open.last().copied(),
+                        props: Vec::new(),
+                    });
+                    open.push(nodes.len() - 1);
+                }
+                FDT_END_NODE => {
+                    open.pop()?;
+                }
+                FDT_PROP => {
+                    let len = be32(blob, pos)? as usize;
+                    let name = c_str(strings.get(be32(blob, pos + 4)? as usize..)?)?;
+                    let value = blob.get(pos + 8..pos + 8 + len)?;
+                    let node = &mut nodes[*open.last()?];
+                    node.props.push((String::from(name), value.to_vec()));
+                    pos = (pos + 8 + len).next_multiple_of(4);
+                }
+                FDT_NOP => {}
+                FDT_END => break,
+                _ => return None,
+            }
+        }
+        Some(Self { nodes })
+    }
+
+    pub fn nodes(&self) -> impl Iterator<Item = &Node> {
+        self.nodes.iter()
+    }
+
+    /// The node at `path`, like "/cpus", whose components may leave out the
+    /// unit address
+    pub fn find(&self, path: &str) -> Option<&Node> {
+        let mut found = self.nodes.first()?;
+        let mut index = 0;
+        for component in path.split('/').filter(|name| !name.is_empty()) {
+            (index, found) = self.nodes.iter().enumerate().find(|(_, node)| {
+                node.parent == Some(index)
+                    && (node.name == component || node.name.split('@').next() == Some(component))
+            })?;
+        }
+        Some(found)
+    }
+
+    /// Nodes compatible with one of `models`
+    pub fn compatible<'a>(&'a self, models: &'a [&str]) -> impl Iterator<Item = &'a Node> {
+        self.nodes.iter().filter(|node| {
+            models
+                .iter()
+                .any(|model| node.has_string("compatible", model))
+        })
+    }
+
+    /// Children of `parent`
+    pub fn children<'a>(&'a self, parent: &'a Node) -> impl Iterator<Item = &'a Node> {
+        self.nodes.iter().filter(move |node| {
+            node.parent
+                .is_some_and(|index| core::ptr::eq(&self.nodes[index], parent))
+        })
+    }
+
+    /// The (address, size) regions of `reg`, whose cells the parent node
+    /// counts
+    pub fn reg(&self, node: &Node) -> Vec<(usize, usize)> {
+        let parent = node.parent.map(|index| &self.nodes[index]);
+        let count = |name, default| {
+            parent
+                .and_then(|parent| parent.prop_u32(name))
+                .unwrap_or(default) as usize
+                * 4
+        };
+        let (address_len, size_len) = (count("#address-cells", 2), count("#size-cells", 1));
+        match node.prop("reg") {
+            Some(reg) if address_len + size_len > 0 => reg
+                .chunks_exact(address_len + size_len)
+                .map(|region| (cells(&region[..address_len]), cells(&region[address_len..])))
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+}
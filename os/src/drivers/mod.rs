@@ -1,4 +1,5 @@
 pub mod block;
 pub mod bus;
 pub mod chardev;
+pub mod fdt;
 pub mod plic;
@@ -0,0 +1,240 @@
+//! devfs, the devices as files
+//!
+//! The directory holds the character devices and a file for each block
+//! device. Devices are files for the VFS, told apart by the mode of their
+//! metadata.
+extern crate alloc;
+use super::vfs::{Inode, InodeType, SuperBlock};
+use super::{Stat, StatMode};
+use crate::drivers::block::block_devices;
+use crate::drivers::chardev::{UartDevice, UART};
+use crate::sync::UPIntrFreeCell;
+use crate::timer::{get_rtc_time, get_time};
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use easy_fs::{BlockDevice, BLOCK_SZ};
+use lazy_static::lazy_static;
+
+pub struct DevFs;
+
+impl SuperBlock for DevFs {
+    fn root(&self) -> Arc<dyn Inode> {
+        Arc::new(DevDir)
+    }
+}
+
+/// Metadata shared by the inodes, whose times are not kept
+fn dev_stat(mode: StatMode, size: usize) -> Stat {
+    let time = get_rtc_time();
+    let nlink = if mode.file_type() == StatMode::DIR {
+        2
+    } else {
+        1
+    };
+    Stat {
+        ino: 0,
+        mode,
+        nlink,
+        size: size as u64,
+        blocks: 0,
+        atime: time,
+        mtime: time,
+        ctime: time,
+    }
+}
+
+#[derive(Clone, Copy)]
+enum CharDevice {
+    /// reads nothing, swallows writes
+    Null,
+    /// reads zeros, swallows writes
+    Zero,
+    /// the console on the UART
+    Tty,
+    /// reads pseudo-random bytes, swallows writes
+    Random,
+}
+
+const CHAR_DEVICES: &[(&str, CharDevice)] = &[
+    ("null", CharDevice::Null),
+    ("random", CharDevice::Random),
+    ("tty", CharDevice::Tty),
+    ("urandom", CharDevice::Random),
+    ("zero", CharDevice::Zero),
+];
+
+lazy_static! {
+    /// xorshift64* state, never zero
+    static ref RANDOM_STATE: UPIntrFreeCell<u64> =
+        unsafe { UPIntrFreeCell::new((get_rtc_time() ^ get_time() as u64) | 1) };
+}
+
+/// Fill `buf` with pseudo-random bytes. The generator is not fit for keys,
+/// there is no entropy but the time of the reads.
+fn fill_random(buf: &mut [u8]) {
+    let mut state = RANDOM_STATE.exclusive_access();
+    *state ^= (get_time() as u64).rotate_left(32);
+    if *state == 0 {
+        *state = 1;
+    }
+    for chunk in buf.chunks_mut(8) {
+        *state ^= *state >> 12;
+        *state ^= *state << 25;
+        *state ^= *state >> 27;
+        let bytes = state.wrapping_mul(0x2545_f491_4f6c_dd1d).to_le_bytes();
+        chunk.copy_from_slice(&bytes[..chunk.len()]);
+    }
+}
+
+impl Inode for CharDevice {
+    fn type_(&self) -> InodeType {
+        InodeType::File
+    }
+
+    fn stat(&self) -> Stat {
+        dev_stat(StatMode::CHAR, 0)
+    }
+
+    fn size(&self) -> usize {
+        0
+    }
+
+    fn read_at(&self, _offset: usize, buf: &mut [u8]) -> usize {
+        match self {
+            CharDevice::Null => 0,
+            CharDevice::Zero => {
+                buf.fill(0);
+                buf.len()
+            }
+            CharDevice::Tty => match buf.first_mut() {
+                // a byte at a time, as soon as one is typed
+                Some(byte) => {
+                    *byte = UART.read();
+                    1
+                }
+                None => 0,
+            },
+            CharDevice::Random => {
+                fill_random(buf);
+                buf.len()
+            }
+        }
+    }
+
+    fn write_at(&self, _offset: usize, buf: &[u8]) -> usize {
+        if let CharDevice::Tty = self {
+            for &byte in buf {
+                UART.write(byte);
+            }
+        }
+        buf.len()
+    }
+}
+
+/// File of a block device, read and written at any byte offset below its
+/// size. It goes straight to the device, past the block caches of a
+/// filesystem mounted from it.
+struct BlockDeviceFile {
+    device: Arc<dyn BlockDevice>,
+}
+
+impl BlockDeviceFile {
+    /// Blocks of the device overlapping `len` bytes at `offset`, clamped to
+    /// its size, with the range each covers in its block
+    fn blocks(&self, offset: usize, len: usize) -> Vec<(usize, usize, usize)> {
+        let end = self.size().min(offset.saturating_add(len));
+        let mut blocks = Vec::new();
+        let mut pos = offset;
+        while pos < end {
+            let start = pos % BLOCK_SZ;
+            let len = (BLOCK_SZ - start).min(end - pos);
+            blocks.push((pos / BLOCK_SZ, start, start + len));
+            pos += len;
+        }
+        blocks
+    }
+}
+
+impl Inode for BlockDeviceFile {
+    fn type_(&self) -> InodeType {
+        InodeType::File
+    }
+
+    fn stat(&self) -> Stat {
+        dev_stat(StatMode::BLOCK, self.size())
+    }
+
+    fn size(&self) -> usize {
+        self.device.num_blocks() * BLOCK_SZ
+    }
+
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> usize {
+        let mut block = [0u8; BLOCK_SZ];
+        let mut read_size = 0;
+        for (block_id, start, end) in self.blocks(offset, buf.len()) {
+            self.device.read_block(block_id, &mut block);
+            buf[read_size..read_size + end - start].copy_from_slice(&block[start..end]);
+            read_size += end - start;
+        }
+        read_size
+    }
+
+    fn write_at(&self, offset: usize, buf: &[u8]) -> usize {
+        let mut block = [0u8; BLOCK_SZ];
+        let mut write_size = 0;
+        for (block_id, start, end) in self.blocks(offset, buf.len()) {
+            if end - start < BLOCK_SZ {
+                self.device.read_block(block_id, &mut block);
+            }
+            block[start..end].copy_from_slice(&buf[write_size..write_size + end - start]);
+            self.device.write_block(block_id, &block);
+            write_size += end - start;
+        }
+        write_size
+    }
+
+    fn block_device(&self) -> Option<Arc<dyn BlockDevice>> {
+        Some(self.device.clone())
+    }
+}
+
+struct DevDir;
+
+impl Inode for DevDir {
+    fn type_(&self) -> InodeType {
+        InodeType::Directory
+    }
+
+    fn stat(&self) -> Stat {
+        dev_stat(StatMode::DIR, 0)
+    }
+
+    fn size(&self) -> usize {
+        0
+    }
+
+    fn lookup(&self, name: &str) -> Option<Arc<dyn Inode>> {
+        if let Some((_, device)) = CHAR_DEVICES.iter().find(|(file, _)| *file == name) {
+            return Some(Arc::new(*device));
+        }
+        let (_, device) = block_devices()
+            .into_iter()
+            .find(|(file, _)| *file == name)?;
+        Some(Arc::new(BlockDeviceFile { device }))
+    }
+
+    fn entries(&self) -> Vec<(String, InodeType)> {
+        [".", ".."]
+            .into_iter()
+            .map(|name| (String::from(name), InodeType::Directory))
+            .chain(
+                CHAR_DEVICES
+                    .iter()
+                    .map(|(name, _)| String::from(*name))
+                    .chain(block_devices().into_iter().map(|(name, _)| name))
+                    .map(|name| (name, InodeType::File)),
+            )
+            .collect()
+    }
+}
@@ -0,0 +1,136 @@
+//! easy-fs behind the [`vfs`](super::vfs) traits
+extern crate alloc;
+use super::vfs::{Inode, InodeType, SuperBlock};
+use super::{Stat, StatMode};
+use crate::config::{BLOCK_CACHE_BLOCKS, FS_FLUSH_INTERVAL};
+use crate::timer::get_rtc_time;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use easy_fs::{BlockDevice, DiskInodeType, EasyFileSystem, MAX_FILE_SIZE};
+use spin::Mutex;
+
+pub struct EasyFsSuperBlock {
+    efs: Arc<Mutex<EasyFileSystem>>,
+}
+
+impl EasyFsSuperBlock {
+    pub fn open(block_device: Arc<dyn BlockDevice>) -> Arc<Self> {
+        easy_fs::set_clock(|| get_rtc_time() as u32);
+        let efs = EasyFileSystem::open(block_device, BLOCK_CACHE_BLOCKS);
+        efs.lock().set_flush_interval(FS_FLUSH_INTERVAL);
+        Arc::new(Self { efs })
+    }
+}
+
+impl SuperBlock for EasyFsSuperBlock {
+    fn root(&self) -> Arc<dyn Inode> {
+        Arc::new(EasyFileSystem::root_inode(&self.efs))
+    }
+
+    fn sync(&self) {
+        self.efs.lock().sync();
+    }
+
+    fn flush_if_due(&self) {
+        EasyFileSystem::flush_if_due(&self.efs);
+    }
+
+    fn in_use(&self) -> bool {
+        Arc::strong_count(&self.efs) > 1
+    }
+}
+
+fn inode_type(inode: &easy_fs::Inode) -> InodeType {
+    if inode.is_dir() {
+        InodeType::Directory
+    } else {
+        InodeType::File
+    }
+}
+
+impl Inode for easy_fs::Inode {
+    fn type_(&self) -> InodeType {
+        inode_type(self)
+    }
+
+    fn stat(&self) -> Stat {
+        let stat = easy_fs::Inode::stat(self);
+        Stat {
+            ino: stat.ino as u64,
+            mode: match stat.type_ {
+                DiskInodeType::File => StatMode::FILE,
+                DiskInodeType::Directory => StatMode::DIR,
+            },
+            nlink: stat.nlink,
+            size: stat.size as u64,
+            blocks: stat.blocks as u64,
+            atime: stat.atime as u64,
+            mtime: stat.mtime as u64,
+            ctime: stat.ctime as u64,
+        }
+    }
+
+    fn size(&self) -> usize {
+        easy_fs::Inode::size(self) as usize
+    }
+
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> usize {
+        easy_fs::Inode::read_at(self, offset, buf)
+    }
+
+    fn write_at(&self, offset: usize, buf: &[u8]) -> usize {
+        if !self.is_file() {
+            return 0;
+        }
+        easy_fs::Inode::write_at(self, offset, buf)
+    }
+
+    fn truncate(&self, len: usize) -> bool {
+        if !self.is_file() || len > MAX_FILE_SIZE {
+            return false;
+        }
+        easy_fs::Inode::truncate(self, len as u32)
+    }
+
+    fn sync(&self, data_only: bool) -> bool {
+        easy_fs::Inode::sync(self, data_only);
+        true
+    }
+
+    fn lookup(&self, name: &str) -> Option<Arc<dyn Inode>> {
+        if !self.is_dir() {
+            return None;
+        }
+        let inode: Arc<dyn Inode> = self.find(name)?;
+        Some(inode)
+    }
+
+    fn create(&self, name: &str, type_: InodeType) -> Option<Arc<dyn Inode>> {
+        if !self.is_dir() {
+            return None;
+        }
+        let inode: Arc<dyn Inode> = match type_ {
+            InodeType::File => easy_fs::Inode::create(self, name)?,
+            InodeType::Directory => self.create_dir(name)?,
+        };
+        Some(inode)
+    }
+
+    fn unlink(&self, name: &str) -> bool {
+        easy_fs::Inode::unlink(self, name)
+    }
+
+    fn entries(&self) -> Vec<(String, InodeType)> {
+        self.ls()
+            .into_iter()
+            .map(|name| {
+                let type_ = match self.find(&name) {
+                    Some(child) => inode_type(&child),
+                    None => InodeType::File,
+                };
+                (name, type_)
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,126 @@
+//! ext2 behind the [`vfs`](super::vfs) traits
+extern crate alloc;
+use super::vfs::{Inode, InodeType, SuperBlock};
+use super::{Stat, StatMode};
+use crate::config::{BLOCK_CACHE_BLOCKS, FS_FLUSH_INTERVAL};
+use crate::timer::get_rtc_time;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use easy_fs::{BlockDevice, Ext2FileSystem, Ext2Inode};
+use spin::Mutex;
+
+pub struct Ext2SuperBlock {
+    fs: Arc<Mutex<Ext2FileSystem>>,
+}
+
+impl Ext2SuperBlock {
+    pub fn open(block_device: Arc<dyn BlockDevice>) -> Arc<Self> {
+        easy_fs::set_clock(|| get_rtc_time() as u32);
+        let fs = Ext2FileSystem::open(block_device, BLOCK_CACHE_BLOCKS);
+        fs.lock().set_flush_interval(FS_FLUSH_INTERVAL);
+        Arc::new(Self { fs })
+    }
+}
+
+impl SuperBlock for Ext2SuperBlock {
+    fn root(&self) -> Arc<dyn Inode> {
+        Arc::new(Ext2FileSystem::root_inode(&self.fs))
+    }
+
+    fn sync(&self) {
+        self.fs.lock().sync();
+    }
+
+    fn flush_if_due(&self) {
+        Ext2FileSystem::flush_if_due(&self.fs);
+    }
+
+    fn in_use(&self) -> bool {
+        Arc::strong_count(&self.fs) > 1
+    }
+}
+
+fn inode_type(inode: &Ext2Inode) -> InodeType {
+    if inode.is_dir() {
+        InodeType::Directory
+    } else {
+        InodeType::File
+    }
+}
+
+impl Inode for Ext2Inode {
+    fn type_(&self) -> InodeType {
+        inode_type(self)
+    }
+
+    fn stat(&self) -> Stat {
+        let stat = Ext2Inode::stat(self);
+        Stat {
+            ino: stat.ino as u64,
+            mode: if self.is_dir() {
+                StatMode::DIR
+            } else {
+                StatMode::FILE
+            },
+            nlink: stat.nlink,
+            // that of an InodeStat stops at 4 GiB
+            size: Ext2Inode::size(self),
+            blocks: stat.blocks as u64,
+            atime: stat.atime as u64,
+            mtime: stat.mtime as u64,
+            ctime: stat.ctime as u64,
+        }
+    }
+
+    fn size(&self) -> usize {
+        Ext2Inode::size(self) as usize
+    }
+
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> usize {
+        Ext2Inode::read_at(self, offset, buf)
+    }
+
+    fn write_at(&self, offset: usize, buf: &[u8]) -> usize {
+        Ext2Inode::write_at(self, offset, buf)
+    }
+
+    fn truncate(&self, len: usize) -> bool {
+        Ext2Inode::truncate(self, len as u64)
+    }
+
+    fn sync(&self, _data_only: bool) -> bool {
+        Ext2Inode::sync(self);
+        true
+    }
+
+    fn lookup(&self, name: &str) -> Option<Arc<dyn Inode>> {
+        let inode: Arc<dyn Inode> = self.find(name)?;
+        Some(inode)
+    }
+
+    fn create(&self, name: &str, type_: InodeType) -> Option<Arc<dyn Inode>> {
+        let inode: Arc<dyn Inode> = match type_ {
+            InodeType::File => Ext2Inode::create(self, name)?,
+            InodeType::Directory => self.create_dir(name)?,
+        };
+        Some(inode)
+    }
+
+    fn unlink(&self, name: &str) -> bool {
+        Ext2Inode::unlink(self, name)
+    }
+
+    fn entries(&self) -> Vec<(String, InodeType)> {
+        self.ls()
+            .into_iter()
+            .map(|name| {
+                let type_ = match self.find(&name) {
+                    Some(child) => inode_type(&child),
+                    None => InodeType::File,
+                };
+                (name, type_)
+            })
+            .collect()
+    }
+}
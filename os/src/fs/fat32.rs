@@ -0,0 +1,93 @@
+//! FAT32 behind the [`vfs`](super::vfs) traits, read only
+extern crate alloc;
+use super::vfs::{Inode, InodeType, SuperBlock};
+use super::{Stat, StatMode};
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use easy_fs::{BlockDevice, DiskInodeType, Fat32FileSystem, Fat32Inode};
+
+pub struct Fat32SuperBlock {
+    fs: Arc<Fat32FileSystem>,
+}
+
+impl Fat32SuperBlock {
+    pub fn open(block_device: Arc<dyn BlockDevice>) -> Arc<Self> {
+        Arc::new(Self {
+            fs: Fat32FileSystem::open(block_device),
+        })
+    }
+}
+
+impl SuperBlock for Fat32SuperBlock {
+    fn root(&self) -> Arc<dyn Inode> {
+        Arc::new(Fat32FileSystem::root_inode(&self.fs))
+    }
+
+    fn in_use(&self) -> bool {
+        Arc::strong_count(&self.fs) > 1
+    }
+}
+
+fn inode_type(inode: &Fat32Inode) -> InodeType {
+    if inode.is_dir() {
+        InodeType::Directory
+    } else {
+        InodeType::File
+    }
+}
+
+impl Inode for Fat32Inode {
+    fn type_(&self) -> InodeType {
+        inode_type(self)
+    }
+
+    fn stat(&self) -> Stat {
+        let stat = Fat32Inode::stat(self);
+        Stat {
+            ino: stat.ino as u64,
+            mode: match stat.type_ {
+                DiskInodeType::File => StatMode::FILE,
+                DiskInodeType::Directory => StatMode::DIR,
+            },
+            nlink: stat.nlink,
+            size: stat.size as u64,
+            blocks: stat.blocks as u64,
+            atime: stat.atime as u64,
+            mtime: stat.mtime as u64,
+            ctime: stat.ctime as u64,
+        }
+    }
+
+    fn size(&self) -> usize {
+        Fat32Inode::size(self) as usize
+    }
+
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> usize {
+        Fat32Inode::read_at(self, offset, buf)
+    }
+
+    fn lookup(&self, name: &str) -> Option<Arc<dyn Inode>> {
+        let inode: Arc<dyn Inode> = self.find(name)?;
+        Some(inode)
+    }
+
+    fn entries(&self) -> Vec<(String, InodeType)> {
+        if !self.is_dir() {
+            return Vec::new();
+        }
+        // FAT32 keeps no "." and ".." in the root, so they are not taken
+        // from the disk
+        [".", ".."]
+            .into_iter()
+            .map(|name| (String::from(name), InodeType::Directory))
+            .chain(self.ls().into_iter().map(|name| {
+                let type_ = match self.find(&name) {
+                    Some(child) => inode_type(&child),
+                    None => InodeType::File,
+                };
+                (name, type_)
+            }))
+            .collect()
+    }
+}
@@ -0,0 +1,110 @@
+//! initramfs, a root filesystem built into the kernel
+//!
+//! `build.rs` packs the user programs into a newc cpio archive when
+//! `INITRAMFS` is set. At boot the archive is unpacked into a tmpfs that
+//! becomes the root, so that no disk is needed.
+extern crate alloc;
+use super::tmpfs::TmpFs;
+use super::vfs::{Inode, InodeType, SuperBlock};
+use crate::config::INITRAMFS_PAGES;
+use alloc::sync::Arc;
+use log::warn;
+
+/// The archive, empty if the kernel was built without one
+static ARCHIVE: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/initramfs.cpio"));
+
+const NEWC_MAGIC: &[u8] = b"070701";
+/// The magic, then 13 fields of 8 hexadecimal digits
+const HEADER_SIZE: usize = 110;
+const FIELD_MODE: usize = 1;
+const FIELD_FILESIZE: usize = 6;
+const FIELD_NAMESIZE: usize = 11;
+const TRAILER: &str = "TRAILER!!!";
+
+const S_IFMT: u32 = 0o170000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+
+/// An entry of the archive
+struct Entry<'a> {
+    path: &'a str,
+    mode: u32,
+    data: &'a [u8],
+}
+
+/// Read the entry at `pos`, and return it with the position of the next
+/// one. `None` at the trailer or at anything that is not a newc entry.
+fn parse_entry(archive: &[u8], pos: usize) -> Option<(Entry<'_>, usize)> {
+    let header = archive.get(pos..pos + HEADER_SIZE)?;
+    if &header[..NEWC_MAGIC.len()] != NEWC_MAGIC {
+        return None;
+    }
+    let field = |i: usize| {
+        let start = NEWC_MAGIC.len() + i * 8;
+        let digits = core::str::from_utf8(&header[start..start + 8]).ok()?;
+        usize::from_str_radix(digits, 16).ok()
+    };
+    let name_start = pos + HEADER_SIZE;
+    // the name ends with a NUL
+    let name = archive.get(name_start..name_start + field(FIELD_NAMESIZE)?.checked_sub(1)?)?;
+    let path = core::str::from_utf8(name).ok()?;
+    if path == TRAILER {
+        return None;
+    }
+    let data_start = (name_start + name.len() + 1).next_multiple_of(4);
+    let data = archive.get(data_start..data_start + field(FIELD_FILESIZE)?)?;
+    let entry = Entry {
+        path,
+        mode: field(FIELD_MODE)? as u32,
+        data,
+    };
+    Some((entry, (data_start + data.len()).next_multiple_of(4)))
+}
+
+/// Add an entry under `root`, whose parent directories came before it
+fn unpack_entry(root: &Arc<dyn Inode>, entry: &Entry) -> bool {
+    let path = entry.path.trim_start_matches("./");
+    if path.is_empty() || path == "." {
+        return true;
+    }
+    let (dir, name) = match path.rsplit_once('/') {
+        Some((parent, name)) => {
+            let mut dir = root.clone();
+            for name in parent.split('/').filter(|name| !name.is_empty()) {
+                match dir.lookup(name) {
+                    Some(child) => dir = child,
+                    None => return false,
+                }
+            }
+            (dir, name)
+        }
+        None => (root.clone(), path),
+    };
+    match entry.mode & S_IFMT {
+        S_IFDIR => dir.lookup(name).is_some() || dir.create(name, InodeType::Directory).is_some(),
+        S_IFREG => match dir.create(name, InodeType::File) {
+            Some(file) => file.write_at(0, entry.data) == entry.data.len(),
+            None => false,
+        },
+        // links and devices have no place in a tmpfs
+        _ => true,
+    }
+}
+
+/// A root filesystem holding the archive built into the kernel, `None` if
+/// there is none
+pub fn unpack() -> Option<Arc<dyn SuperBlock>> {
+    if ARCHIVE.is_empty() {
+        return None;
+    }
+    let fs = TmpFs::new(INITRAMFS_PAGES);
+    let root = fs.root();
+    let mut pos = 0;
+    while let Some((entry, next)) = parse_entry(ARCHIVE, pos) {
+        if !unpack_entry(&root, &entry) {
+            warn!("initramfs: failed to unpack {}", entry.path);
+        }
+        pos = next;
+    }
+    Some(fs)
+}
@@ -1,103 +1,112 @@
 extern crate alloc;
 use alloc::ffi::CString;
+use alloc::string::String;
 use alloc::sync::Arc;
+use alloc::vec;
 use alloc::vec::Vec;
 use bitflags::bitflags;
-use easy_fs::{EasyFileSystem, Inode};
-use lazy_static::lazy_static;
 
-use crate::{
-    drivers::block::BLOCK_DEVICE, memory::UserBuffer, sync::UPIntrFreeCell, task::current_task,
-};
+use crate::{memory::UserBuffer, sync::UPIntrFreeCell, task::current_task};
 
-use super::{Dirent, DirentType, File};
-
-lazy_static! {
-    pub static ref ROOT_INODE: Arc<Inode> = {
-        let efs = EasyFileSystem::open(BLOCK_DEVICE.clone());
-        Arc::new(EasyFileSystem::root_inode(&efs))
-    };
-}
+use super::mount::{is_mount_point, lookup, root_dentry};
+use super::vfs::{Dentry, Inode, InodeType};
+use super::{Dirent, DirentType, File, SeekWhence, Stat};
 
 bitflags! {
     pub struct OpenFlags: u32 {
         const RDONLY = 0;
         const WRONLY = 1 << 0;
         const RDWR = 1 << 1;
+        /// fail if CREATE is set and the file already exists
+        const EXCL = 1 << 7;
         const CREATE = 1 << 9;
         const TRUNC = 1 << 10;
+        /// every write goes to the end of the file
+        const APPEND = 1 << 11;
+        /// fail if the path is not a directory
+        const DIRECTORY = 1 << 16;
+        /// close the file descriptor on exec
+        const CLOEXEC = 1 << 19;
     }
 }
 
 impl OpenFlags {
-    /// Do not check validity for simplicity
-    /// Return (readable, writable)
-    pub fn read_write(&self) -> (bool, bool) {
-        if self.is_empty() {
-            (true, false)
-        } else if self.contains(Self::WRONLY) {
-            (false, true)
-        } else {
-            (true, true)
+    /// Return (readable, writable), `None` if the access mode is invalid
+    pub fn read_write(&self) -> Option<(bool, bool)> {
+        match (self.contains(Self::WRONLY), self.contains(Self::RDWR)) {
+            (false, false) => Some((true, false)),
+            (true, false) => Some((false, true)),
+            (false, true) => Some((true, true)),
+            (true, true) => None,
         }
     }
 }
 
 pub struct OSInode {
-    #[allow(dead_code)]
     readable: bool,
-    #[allow(dead_code)]
     writable: bool,
+    append: bool,
+    /// the path the file was opened from
+    dentry: Arc<Dentry>,
     inner: UPIntrFreeCell<OSInodeInner>,
 }
 
 pub struct OSInodeInner {
     offset: usize,
-    inode: Arc<Inode>,
-}
-
-pub fn root_os_inode() -> Arc<OSInode> {
-    Arc::new(OSInode::new(true, true, ROOT_INODE.clone()))
+    inode: Arc<dyn Inode>,
 }
 
 impl OSInode {
-    pub fn new(readable: bool, writable: bool, inode: Arc<Inode>) -> Self {
+    pub fn new(readable: bool, writable: bool, dentry: Arc<Dentry>) -> Self {
+        let inode = dentry.inode();
         Self {
             readable,
             writable,
+            append: false,
+            dentry,
             inner: unsafe { UPIntrFreeCell::new(OSInodeInner { offset: 0, inode }) },
         }
     }
 
-    pub fn get_path(&self) -> CString {
-        let inner = self.inner.exclusive_access();
-        CString::new(inner.inode.cwd()).unwrap()
+    /// Read the whole file, the file offset is left untouched
+    pub fn read_all(&self) -> Vec<u8> {
+        let inode = self.inner.exclusive_access().inode.clone();
+        // a single read lets the file system fetch the blocks in batches
+        let mut v = vec![0u8; inode.size()];
+        let len = inode.read_at(0, &mut v);
+        v.truncate(len);
+        v
     }
+}
 
-    pub fn chdir(&self, path: &str) -> bool {
-        let mut inner = self.inner.exclusive_access();
-        if let Some(inode) = inner.inode.find(path) {
-            inner.inode = inode;
-            true
-        } else {
-            false
+fn read_inode_at(inode: &Arc<dyn Inode>, mut offset: usize, mut buf: UserBuffer) -> usize {
+    let mut total_read_size = 0usize;
+    for slice in buf.buffers.iter_mut() {
+        let read_size = inode.read_at(offset, slice);
+        offset += read_size;
+        total_read_size += read_size;
+        // the end of the file, or all a device had to give
+        if read_size < slice.len() {
+            break;
         }
     }
+    total_read_size
+}
 
-    pub fn read_all(&self) -> Vec<u8> {
-        let mut inner = self.inner.exclusive_access();
-        let mut buffer = [0u8; 512];
-        let mut v: Vec<u8> = Vec::new();
-        loop {
-            let len = inner.inode.read_at(inner.offset, &mut buffer);
-            if len == 0 {
-                break;
-            }
-            inner.offset += len;
-            v.extend_from_slice(&buffer[..len]);
+fn write_inode_at(inode: &Arc<dyn Inode>, mut offset: usize, buf: UserBuffer) -> usize {
+    let mut total_write_size = 0usize;
+    for slice in buf.buffers.iter() {
+        if slice.is_empty() {
+            continue;
+        }
+        let write_size = inode.write_at(offset, slice);
+        offset += write_size;
+        total_write_size += write_size;
+        if write_size < slice.len() {
+            break;
         }
-        v
     }
+    total_write_size
 }
 
 impl File for OSInode {
@@ -109,73 +118,166 @@ impl File for OSInode {
         self.writable
     }
 
-    fn read(&self, mut buf: UserBuffer) -> usize {
-        let mut inner = self.inner.exclusive_access();
-        let mut total_read_size = 0usize;
-        for slice in buf.buffers.iter_mut() {
-            let read_size = inner.inode.read_at(inner.offset, *slice);
-            if read_size == 0 {
-                break;
-            }
-            inner.offset += read_size;
-            total_read_size += read_size;
-        }
-        total_read_size
+    fn read(&self, buf: UserBuffer) -> usize {
+        // the file is released while reading, as a terminal waits for input
+        // and others sharing the file may use it meanwhile
+        let (inode, offset) = {
+            let inner = self.inner.exclusive_access();
+            (inner.inode.clone(), inner.offset)
+        };
+        let read_size = read_inode_at(&inode, offset, buf);
+        self.inner.exclusive_access().offset = offset + read_size;
+        read_size
     }
 
     fn write(&self, buf: UserBuffer) -> usize {
+        let (inode, offset) = {
+            let inner = self.inner.exclusive_access();
+            let offset = if self.append {
+                inner.inode.size()
+            } else {
+                inner.offset
+            };
+            (inner.inode.clone(), offset)
+        };
+        let write_size = write_inode_at(&inode, offset, buf);
+        self.inner.exclusive_access().offset = offset + write_size;
+        write_size
+    }
+
+    fn seek(&self, offset: isize, whence: SeekWhence) -> Option<usize> {
         let mut inner = self.inner.exclusive_access();
-        let mut total_write_size = 0usize;
-        for slice in buf.buffers.iter() {
-            let write_size = inner.inode.write_at(inner.offset, *slice);
-            assert_eq!(write_size, slice.len());
-            inner.offset += write_size;
-            total_write_size += write_size;
+        let base = match whence {
+            SeekWhence::Set => 0,
+            SeekWhence::Cur => inner.offset,
+            SeekWhence::End => inner.inode.size(),
+        };
+        let new_offset = (base as isize).checked_add(offset)?;
+        if new_offset < 0 {
+            return None;
         }
-        total_write_size
+        inner.offset = new_offset as usize;
+        Some(inner.offset)
+    }
+
+    fn pread(&self, buf: UserBuffer, offset: usize) -> Option<usize> {
+        let inode = self.inner.exclusive_access().inode.clone();
+        Some(read_inode_at(&inode, offset, buf))
+    }
+
+    fn pwrite(&self, buf: UserBuffer, offset: usize) -> Option<usize> {
+        let inode = self.inner.exclusive_access().inode.clone();
+        Some(write_inode_at(&inode, offset, buf))
+    }
+
+    fn truncate(&self, len: usize) -> bool {
+        let inode = self.inner.exclusive_access().inode.clone();
+        inode.truncate(len)
+    }
+
+    fn sync(&self, data_only: bool) -> bool {
+        let inode = self.inner.exclusive_access().inode.clone();
+        inode.sync(data_only)
     }
 
     fn getdents(&self) -> Vec<Dirent> {
-        let inner = self.inner.exclusive_access();
-        let vec = inner.inode.ls();
-        let mut v: Vec<Dirent> = Vec::new();
-        for name in vec {
-            v.push(Dirent::new(CString::new(name).unwrap(), DirentType::File));
+        let inode = self.inner.exclusive_access().inode.clone();
+        inode
+            .entries()
+            .into_iter()
+            .map(|(name, type_)| {
+                let type_ = match type_ {
+                    InodeType::Directory => DirentType::Directory,
+                    InodeType::File => DirentType::File,
+                };
+                Dirent::new(CString::new(name).unwrap(), type_)
+            })
+            .collect()
+    }
+
+    fn stat(&self) -> Stat {
+        self.inner.exclusive_access().inode.stat()
+    }
+
+    fn path(&self) -> Option<String> {
+        Some(self.dentry.path())
+    }
+}
+
+/// Dentry from which `path` is resolved: the working directory of the
+/// current task, the root when there is no task yet to load initproc
+fn lookup_base() -> Arc<Dentry> {
+    match current_task() {
+        Some(task) => task.get_cwd(),
+        None => root_dentry(),
+    }
+}
+
+/// Resolve `path` from the working directory of the current task
+pub fn lookup_path(path: &str) -> Option<Arc<Dentry>> {
+    lookup(&lookup_base(), path)
+}
+
+/// Directory holding the last component of `path`, and that component
+fn lookup_parent(path: &str) -> Option<(Arc<Dentry>, &str)> {
+    let path = path.trim_end_matches('/');
+    let (parent, name) = match path.rsplit_once('/') {
+        Some(("", name)) => (lookup_path("/")?, name),
+        Some((parent, name)) => (lookup_path(parent)?, name),
+        None => (lookup_base(), path),
+    };
+    if name.is_empty() || name == "." || name == ".." {
+        return None;
+    }
+    Some((parent, name))
+}
+
+/// Create directory `path`, false if it exists or its parent does not
+pub fn mkdir(path: &str) -> bool {
+    match lookup_parent(path) {
+        Some((parent, name)) => parent.inode().create(name, InodeType::Directory).is_some(),
+        None => false,
+    }
+}
+
+/// Remove `path`, which must be an empty directory if `dir` and must not
+/// be one otherwise. Mount points can not be removed.
+pub fn unlink(path: &str, dir: bool) -> bool {
+    let Some((parent, name)) = lookup_parent(path) else {
+        return false;
+    };
+    match lookup(&parent, name) {
+        Some(dentry) if dentry.inode().is_dir() == dir && !is_mount_point(&dentry) => {
+            parent.inode().unlink(name)
         }
-        v
+        _ => false,
     }
 }
 
-pub fn open_file(name: &str, flags: OpenFlags) -> Option<Arc<OSInode>> {
-    let (readable, writable) = flags.read_write();
-    // TODO: change ROOT_INODE to current process inode
-    let task = current_task();
-    let current_os_inode;
-    let current_inode;
-    // case where we want to first process
-    if task.is_none() {
-        current_inode = ROOT_INODE.clone();
-    } else {
-        current_os_inode = task.unwrap().get_cwd_inode();
-        current_inode = current_os_inode.inner.exclusive_access().inode.clone();
-    }
-
-    if flags.contains(OpenFlags::CREATE) {
-        if let Some(inode) = current_inode.find(name) {
-            inode.clear();
-            Some(Arc::new(OSInode::new(readable, writable, inode)))
-        } else {
-            // create file
-            current_inode
-                .create(name)
-                .map(|inode| Arc::new(OSInode::new(readable, writable, inode)))
+pub fn open_file(path: &str, flags: OpenFlags) -> Option<Arc<OSInode>> {
+    let (readable, writable) = flags.read_write()?;
+    let dentry = match lookup_path(path) {
+        Some(_) if flags.contains(OpenFlags::CREATE | OpenFlags::EXCL) => return None,
+        Some(dentry) => dentry,
+        None if flags.contains(OpenFlags::CREATE) => {
+            let (parent, name) = lookup_parent(path)?;
+            parent.child(name, parent.inode().create(name, InodeType::File)?)
         }
-    } else {
-        current_inode.find(name).map(|inode| {
-            if flags.contains(OpenFlags::TRUNC) {
-                inode.clear();
-            }
-            Arc::new(OSInode::new(readable, writable, inode))
-        })
+        None => return None,
+    };
+    let inode = dentry.inode();
+    if inode.is_dir() {
+        // directories can only be opened for reading
+        if writable || flags.contains(OpenFlags::TRUNC) {
+            return None;
+        }
+    } else if flags.contains(OpenFlags::DIRECTORY) {
+        return None;
+    }
+    if flags.contains(OpenFlags::TRUNC) && writable {
+        inode.truncate(0);
     }
+    let mut os_inode = OSInode::new(readable, writable, dentry);
+    os_inode.append = flags.contains(OpenFlags::APPEND);
+    Some(Arc::new(os_inode))
 }
@@ -1,18 +1,120 @@
+mod devfs;
+mod easyfs;
+mod ext2;
+mod fat32;
+mod initramfs;
 pub mod inode;
-pub mod stdio;
+pub mod mount;
+mod procfs;
+mod tmpfs;
+pub mod vfs;
 extern crate alloc;
 use crate::memory::UserBuffer;
 use alloc::ffi::CString;
+use alloc::string::String;
+use alloc::sync::Arc;
 use alloc::vec::Vec;
+use bitflags::bitflags;
+use strum_macros::FromRepr;
 
 pub trait File: Send + Sync {
-    #[allow(dead_code)]
     fn readable(&self) -> bool;
-    #[allow(dead_code)]
     fn writable(&self) -> bool;
     fn read(&self, buf: UserBuffer) -> usize;
     fn write(&self, buf: UserBuffer) -> usize;
     fn getdents(&self) -> Vec<Dirent>;
+    fn stat(&self) -> Stat;
+    /// Move the file offset and return the new one,
+    /// `None` if the file is not seekable or the offset would be negative
+    fn seek(&self, _offset: isize, _whence: SeekWhence) -> Option<usize> {
+        None
+    }
+    /// Read at `offset` without moving the file offset
+    fn pread(&self, _buf: UserBuffer, _offset: usize) -> Option<usize> {
+        None
+    }
+    /// Write at `offset` without moving the file offset
+    fn pwrite(&self, _buf: UserBuffer, _offset: usize) -> Option<usize> {
+        None
+    }
+    /// Set the file size to `len`, false if the file can not be resized
+    fn truncate(&self, _len: usize) -> bool {
+        false
+    }
+    /// Write back the content of the file, and its metadata unless
+    /// `data_only`. False if the file is not backed by a disk.
+    fn sync(&self, _data_only: bool) -> bool {
+        false
+    }
+    /// Path the file was opened from, `None` if it has none
+    fn path(&self) -> Option<String> {
+        None
+    }
+}
+
+/// An entry of the fd table of a process: the open file, shared by
+/// descriptors copied by fork, and the flags of this descriptor alone
+#[derive(Clone)]
+pub struct FileDescriptor {
+    pub file: Arc<dyn File + Send + Sync>,
+    /// closed by exec
+    pub cloexec: bool,
+}
+
+/// `whence` argument of `sys_lseek`
+#[derive(FromRepr, Clone, Copy)]
+#[repr(usize)]
+pub enum SeekWhence {
+    /// from the start of the file
+    Set = 0,
+    /// from the current offset
+    Cur = 1,
+    /// from the end of the file
+    End = 2,
+}
+
+/// File metadata, as copied to user space by `sys_fstat`
+#[repr(C)]
+pub struct Stat {
+    /// inode number
+    pub ino: u64,
+    /// file type
+    pub mode: StatMode,
+    /// number of hard links
+    pub nlink: u32,
+    /// size in bytes
+    pub size: u64,
+    /// number of 512-byte blocks allocated
+    pub blocks: u64,
+    /// last access, modification and status change times, in seconds
+    pub atime: u64,
+    pub mtime: u64,
+    pub ctime: u64,
+}
+
+bitflags! {
+    #[derive(Clone, Copy, PartialEq, Eq)]
+    pub struct StatMode: u32 {
+        const NULL = 0;
+        /// bits of the file type, whose values are the ones below
+        const S_IFMT = 0o170000;
+        /// character device
+        const CHAR = 0o020000;
+        /// directory
+        const DIR = 0o040000;
+        /// block device
+        const BLOCK = 0o060000;
+        /// ordinary regular file
+        const FILE = 0o100000;
+    }
+}
+
+impl StatMode {
+    /// The file type, to compare with CHAR, DIR, BLOCK or FILE. The types
+    /// share bits, so `contains` does not tell them apart.
+    pub fn file_type(&self) -> Self {
+        *self & Self::S_IFMT
+    }
 }
 
 #[repr(C)]
@@ -29,6 +131,5 @@ impl Dirent {
 
 pub enum DirentType {
     File,
-    #[allow(dead_code)]
     Directory,
 }
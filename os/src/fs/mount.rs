@@ -0,0 +1,359 @@
+//! Mount table and path resolution
+//!
+//! Mount points are kept as absolute paths. A path is resolved one
+//! component at a time, and each directory reached whose path is a mount
+//! point is replaced by the root of the filesystem mounted there.
+extern crate alloc;
+use super::devfs::DevFs;
+use super::easyfs::EasyFsSuperBlock;
+use super::ext2::Ext2SuperBlock;
+use super::fat32::Fat32SuperBlock;
+use super::initramfs;
+use super::inode::lookup_path;
+use super::procfs::ProcFs;
+use super::tmpfs::TmpFs;
+use super::vfs::{Dentry, InodeType, SuperBlock};
+use crate::config::{ROOT_DEVICE, TMPFS_PAGES};
+use crate::drivers::block::{block_devices, overlap, same_device};
+use crate::sync::UPIntrFreeCell;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use easy_fs::{BlockDevice, EasyFileSystem, Ext2FileSystem, Fat32FileSystem};
+use lazy_static::lazy_static;
+
+/// A filesystem mounted on a directory
+pub struct Mount {
+    /// absolute path of the mount point
+    pub path: String,
+    /// what the filesystem was mounted from, like "/dev/vda"
+    pub source: String,
+    pub fs_type: &'static str,
+    /// the block device the filesystem lies on, for those on a disk
+    pub device: Option<Arc<dyn BlockDevice>>,
+    pub sb: Arc<dyn SuperBlock>,
+}
+
+/// A type of filesystem `sys_mount` knows
+struct FsType {
+    name: &'static str,
+    open: Opener,
+}
+
+/// Open a block device, `None` if it holds another filesystem
+type DeviceOpener = fn(Arc<dyn BlockDevice>) -> Option<Arc<dyn SuperBlock>>;
+
+enum Opener {
+    /// from a block device, which is mounted once at most
+    Device(DeviceOpener),
+    /// from nothing, whatever the source
+    Virtual(fn() -> Arc<dyn SuperBlock>),
+}
+
+/// In the order the root is probed for
+const FS_TYPES: &[FsType] = &[
+    FsType {
+        name: "easyfs",
+        open: Opener::Device(open_easyfs),
+    },
+    FsType {
+        name: "ext2",
+        open: Opener::Device(open_ext2),
+    },
+    FsType {
+        name: "vfat",
+        open: Opener::Device(open_vfat),
+    },
+    FsType {
+        name: "tmpfs",
+        open: Opener::Virtual(|| TmpFs::new(TMPFS_PAGES)),
+    },
+    FsType {
+        name: "devfs",
+        open: Opener::Virtual(|| Arc::new(DevFs)),
+    },
+    FsType {
+        name: "proc",
+        open: Opener::Virtual(|| Arc::new(ProcFs)),
+    },
+];
+
+/// Block device of the file at `source`, like "/dev/vda"
+fn source_device(source: &str) -> Option<Arc<dyn BlockDevice>> {
+    lookup_path(source)?.inode().block_device()
+}
+
+fn open_easyfs(block_device: Arc<dyn BlockDevice>) -> Option<Arc<dyn SuperBlock>> {
+    if !EasyFileSystem::detect(&block_device) {
+        return None;
+    }
+    Some(EasyFsSuperBlock::open(block_device))
+}
+
+fn open_ext2(block_device: Arc<dyn BlockDevice>) -> Option<Arc<dyn SuperBlock>> {
+    if !Ext2FileSystem::detect(&block_device) {
+        return None;
+    }
+    Some(Ext2SuperBlock::open(block_device))
+}
+
+fn open_vfat(block_device: Arc<dyn BlockDevice>) -> Option<Arc<dyn SuperBlock>> {
+    if !Fat32FileSystem::detect(&block_device) {
+        return None;
+    }
+    Some(Fat32SuperBlock::open(block_device))
+}
+
+/// The type of filesystem found on a block device, and the filesystem
+fn probe(block_device: &Arc<dyn BlockDevice>) -> Option<(&'static str, Arc<dyn SuperBlock>)> {
+    FS_TYPES.iter().find_map(|fs| match fs.open {
+        Opener::Device(open) => Some((fs.name, open(block_device.clone())?)),
+        Opener::Virtual(_) => None,
+    })
+}
+
+/// The root, from the initramfs built into the kernel if there is one,
+/// else from the root device, or the first of the disk and its partitions
+/// holding a filesystem
+fn mount_root() -> Mount {
+    if let Some(sb) = initramfs::unpack() {
+        return Mount {
+            path: String::from("/"),
+            source: String::from("initramfs"),
+            fs_type: "tmpfs",
+            device: None,
+            sb,
+        };
+    }
+    let mut devices = block_devices();
+    assert!(!devices.is_empty(), "No boot disk, and no initramfs!");
+    if let Some(root) = ROOT_DEVICE.filter(|root| !root.is_empty()) {
+        devices.retain(|(name, _)| name == root);
+        assert!(!devices.is_empty(), "No root device {}!", root);
+    }
+    devices
+        .iter()
+        .find_map(|(name, block_device)| {
+            let (fs_type, sb) = probe(block_device)?;
+            Some(Mount {
+                path: String::from("/"),
+                source: format!("/dev/{}", name),
+                fs_type,
+                device: Some(block_device.clone()),
+                sb,
+            })
+        })
+        .expect("No filesystem found for the root!")
+}
+
+lazy_static! {
+    static ref MOUNTS: UPIntrFreeCell<Vec<Mount>> =
+        unsafe { UPIntrFreeCell::new(vec![mount_root()]) };
+    /// Filesystems unmounted while their files were still in use, which
+    /// keep their device until they are not
+    static ref DETACHED: UPIntrFreeCell<Vec<Mount>> =
+        unsafe { UPIntrFreeCell::new(Vec::new()) };
+}
+
+/// Filesystem mounted at `path`
+fn mounted_at(path: &str) -> Option<Arc<dyn SuperBlock>> {
+    MOUNTS
+        .exclusive_access()
+        .iter()
+        .find(|mount| mount.path == path)
+        .map(|mount| mount.sb.clone())
+}
+
+/// Filesystems mounted or still in use, so that the mount table is not
+/// held while they wait for their disks
+fn superblocks() -> Vec<Arc<dyn SuperBlock>> {
+    let mut superblocks: Vec<Arc<dyn SuperBlock>> = MOUNTS
+        .exclusive_access()
+        .iter()
+        .map(|mount| mount.sb.clone())
+        .collect();
+    superblocks.extend(
+        DETACHED
+            .exclusive_access()
+            .iter()
+            .map(|mount| mount.sb.clone()),
+    );
+    superblocks
+}
+
+/// Return (source, mount point, type) of each filesystem mounted
+pub fn mounts() -> Vec<(String, String, &'static str)> {
+    MOUNTS
+        .exclusive_access()
+        .iter()
+        .map(|mount| (mount.source.clone(), mount.path.clone(), mount.fs_type))
+        .collect()
+}
+
+/// Whether a filesystem is mounted at `dentry`
+pub fn is_mount_point(dentry: &Dentry) -> bool {
+    mounted_at(&dentry.path()).is_some()
+}
+
+pub fn root_dentry() -> Arc<Dentry> {
+    Dentry::root(mounted_at("/").unwrap().root())
+}
+
+/// Resolve `path` from `base`, or from the root if it is absolute
+pub fn lookup(base: &Arc<Dentry>, path: &str) -> Option<Arc<Dentry>> {
+    let mut dentry = if path.starts_with('/') {
+        root_dentry()
+    } else {
+        base.clone()
+    };
+    for name in path.split('/') {
+        dentry = match name {
+            "" | "." => continue,
+            ".." => dentry.parent(),
+            _ => {
+                let child = dentry.child(name, dentry.inode().lookup(name)?);
+                match mounted_at(&child.path()) {
+                    Some(sb) => dentry.child(name, sb.root()),
+                    None => child,
+                }
+            }
+        };
+    }
+    Some(dentry)
+}
+
+/// Mount the filesystem of type `fs_type` from `source` on directory
+/// `target`, false if it can not be mounted or `target` is a mount point
+pub fn mount(source: &str, target: &Arc<Dentry>, fs_type: &str) -> bool {
+    let Some(fs) = FS_TYPES.iter().find(|fs| fs.name == fs_type) else {
+        return false;
+    };
+    if !target.inode().is_dir() {
+        return false;
+    }
+    let path = target.path();
+    if MOUNTS
+        .exclusive_access()
+        .iter()
+        .any(|mount| mount.path == path)
+    {
+        return false;
+    }
+    let (sb, device) = match fs.open {
+        Opener::Device(open) => {
+            let Some(device) = source_device(source) else {
+                return false;
+            };
+            match device_superblock(&device, fs.name, open) {
+                Some(sb) => (sb, Some(device)),
+                None => return false,
+            }
+        }
+        Opener::Virtual(open) => (open(), None),
+    };
+    MOUNTS.exclusive_access().push(Mount {
+        path,
+        source: String::from(source),
+        fs_type: fs.name,
+        device,
+        sb,
+    });
+    true
+}
+
+/// The filesystem of type `fs_type` on `device`, `None` if it holds
+/// another one or shares blocks with a filesystem mounted or still in use.
+/// Two instances of a filesystem would write its device through separate
+/// allocators, so one unmounted while in use is mounted again instead.
+fn device_superblock(
+    device: &Arc<dyn BlockDevice>,
+    fs_type: &str,
+    open: DeviceOpener,
+) -> Option<Arc<dyn SuperBlock>> {
+    let overlaps = |mount: &Mount| mount.device.as_ref().is_some_and(|d| overlap(d, device));
+    if MOUNTS.exclusive_access().iter().any(overlaps) {
+        return None;
+    }
+    let mut detached = DETACHED.exclusive_access();
+    detached.retain(|mount| mount.sb.in_use());
+    if let Some(i) = detached.iter().position(overlaps) {
+        let mount = &detached[i];
+        if mount.fs_type != fs_type || !same_device(mount.device.as_ref().unwrap(), device) {
+            return None;
+        }
+        return Some(detached.remove(i).sb);
+    }
+    drop(detached);
+    open(device.clone())
+}
+
+/// Write back and detach the filesystem mounted at `target`, false if
+/// there is none, it is the root or others are mounted inside it. Files
+/// open in it keep working until they are closed.
+pub fn umount(target: &Arc<Dentry>) -> bool {
+    let path = target.path();
+    let sb = {
+        let mounts = MOUNTS.exclusive_access();
+        let inner = path.clone() + "/";
+        if path == "/" || mounts.iter().any(|mount| mount.path.starts_with(&inner)) {
+            return false;
+        }
+        match mounts.iter().find(|mount| mount.path == path) {
+            Some(mount) => mount.sb.clone(),
+            None => return false,
+        }
+    };
+    sb.sync();
+    let mut mounts = MOUNTS.exclusive_access();
+    let i = mounts.iter().position(|mount| mount.path == path).unwrap();
+    let mount = mounts.remove(i);
+    drop(mounts);
+    if mount.device.is_some() && mount.sb.in_use() {
+        DETACHED.exclusive_access().push(mount);
+    }
+    true
+}
+
+/// Mount a filesystem of type `fs_type` on directory `name` of the root,
+/// which is created if needed
+fn mount_on_root(name: &str, fs_type: &str) {
+    let root = root_dentry();
+    let target = match lookup(&root, name) {
+        Some(target) => target,
+        None => root.child(
+            name,
+            root.inode()
+                .create(name, InodeType::Directory)
+                .expect("Failed to create a mount point!"),
+        ),
+    };
+    assert!(
+        mount(fs_type, &target, fs_type),
+        "Failed to mount /{}!",
+        name
+    );
+}
+
+/// Mount the filesystems every boot needs under the root
+pub fn init() {
+    mount_on_root("dev", "devfs");
+    mount_on_root("tmp", "tmpfs");
+    mount_on_root("proc", "proc");
+}
+
+/// Write back the file content modified in the block caches once in a
+/// while, called on timer interrupts
+pub fn flush_if_due() {
+    for sb in superblocks() {
+        sb.flush_if_due();
+    }
+}
+
+/// Write back every modified block, before the machine goes down
+pub fn sync_all() {
+    for sb in superblocks() {
+        sb.sync();
+    }
+}
@@ -0,0 +1,277 @@
+//! procfs, the state of the kernel as text files
+//!
+//! The content of a file is generated when it is looked up, so that an
+//! open file reads a consistent snapshot.
+extern crate alloc;
+use super::vfs::{Inode, InodeType, SuperBlock};
+use super::{Stat, StatMode};
+use crate::board::irq_counts;
+use crate::config::PAGE_SIZE;
+use crate::fs::mount::mounts;
+use crate::memory::{frame_usage, heap_usage, MapPermission};
+use crate::task::{find_task, task_pids, TaskControlBlock, TaskStatus};
+use crate::timer::{get_rtc_time, get_time_ms};
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt::Write;
+
+pub struct ProcFs;
+
+impl SuperBlock for ProcFs {
+    fn root(&self) -> Arc<dyn Inode> {
+        Arc::new(ProcDir::Root)
+    }
+}
+
+/// A file at the root, and what generates its content
+type RootFile = (&'static str, fn() -> String);
+/// A file of the directory of each task, and what generates its content
+type TaskFile = (&'static str, fn(&TaskControlBlock) -> String);
+
+const ROOT_FILES: &[RootFile] = &[
+    ("interrupts", interrupts),
+    ("meminfo", meminfo),
+    ("mounts", mount_table),
+    ("uptime", uptime),
+];
+
+const TASK_FILES: &[TaskFile] = &[
+    ("cwd", cwd),
+    ("fd", fd_table),
+    ("maps", maps),
+    ("status", status),
+];
+
+fn interrupts() -> String {
+    let mut s = String::new();
+    for (irq, device, count) in irq_counts() {
+        writeln!(s, "{:>4}: {:>10} {}", irq, count, device).unwrap();
+    }
+    s
+}
+
+fn meminfo() -> String {
+    let (total_frames, free_frames) = frame_usage();
+    let (heap_total, heap_used) = heap_usage();
+    format!(
+        "MemTotal:  {:>8} kB\nMemFree:   {:>8} kB\nHeapTotal: {:>8} kB\nHeapUsed:  {:>8} kB\n",
+        total_frames * PAGE_SIZE / 1024,
+        free_frames * PAGE_SIZE / 1024,
+        heap_total / 1024,
+        heap_used / 1024,
+    )
+}
+
+fn mount_table() -> String {
+    let mut s = String::new();
+    for (source, path, fs_type) in mounts() {
+        writeln!(s, "{} {} {}", source, path, fs_type).unwrap();
+    }
+    s
+}
+
+fn uptime() -> String {
+    let ms = get_time_ms();
+    format!("{}.{:02}\n", ms / 1000, ms % 1000 / 10)
+}
+
+fn cwd(task: &TaskControlBlock) -> String {
+    task.get_cwd().path() + "\n"
+}
+
+fn fd_table(task: &TaskControlBlock) -> String {
+    // the files may wait for their disks, so the task is released first
+    let files: Vec<_> = task
+        .inner_exclusive_access()
+        .fd_table
+        .iter()
+        .enumerate()
+        .filter_map(|(fd, entry)| Some((fd, entry.as_ref()?.file.clone())))
+        .collect();
+    let mut s = String::new();
+    for (fd, file) in files {
+        let path = file.path().unwrap_or_else(|| {
+            let mode = file.stat().mode;
+            let kind = if mode.file_type() == StatMode::CHAR {
+                "char"
+            } else {
+                "anon"
+            };
+            format!("[{}]", kind)
+        });
+        writeln!(
+            s,
+            "{} {}{} {}",
+            fd,
+            if file.readable() { 'r' } else { '-' },
+            if file.writable() { 'w' } else { '-' },
+            path
+        )
+        .unwrap();
+    }
+    s
+}
+
+fn maps(task: &TaskControlBlock) -> String {
+    let mut s = String::new();
+    for (start, end, perm) in task.inner_exclusive_access().memory_set.areas() {
+        let flag = |bit, c| if perm.contains(bit) { c } else { '-' };
+        writeln!(
+            s,
+            "{:#011x}-{:#011x} {}{}{}{}",
+            start.0,
+            end.0,
+            flag(MapPermission::R, 'r'),
+            flag(MapPermission::W, 'w'),
+            flag(MapPermission::X, 'x'),
+            flag(MapPermission::U, 'u'),
+        )
+        .unwrap();
+    }
+    s
+}
+
+fn status(task: &TaskControlBlock) -> String {
+    let inner = task.inner_exclusive_access();
+    let state = match inner.task_status {
+        TaskStatus::Running => "R (running)",
+        TaskStatus::Ready => "R (runnable)",
+        TaskStatus::Blocked => "S (sleeping)",
+        TaskStatus::Zombie => "Z (zombie)",
+    };
+    let ppid = inner
+        .parent
+        .as_ref()
+        .and_then(|parent| parent.upgrade())
+        .map_or(0, |parent| parent.getpid());
+    format!(
+        "Pid:\t{}\nPPid:\t{}\nState:\t{}\nExitCode:\t{}\n",
+        task.getpid(),
+        ppid,
+        state,
+        inner.exit_code
+    )
+}
+
+/// Metadata shared by the inodes, which are never written
+fn proc_stat(mode: StatMode, size: usize) -> Stat {
+    let time = get_rtc_time();
+    let nlink = if mode.file_type() == StatMode::DIR {
+        2
+    } else {
+        1
+    };
+    Stat {
+        ino: 0,
+        mode,
+        nlink,
+        size: size as u64,
+        blocks: 0,
+        atime: time,
+        mtime: time,
+        ctime: time,
+    }
+}
+
+enum ProcDir {
+    Root,
+    /// directory of the task with the pid
+    Task(usize),
+}
+
+impl Inode for ProcDir {
+    fn type_(&self) -> InodeType {
+        InodeType::Directory
+    }
+
+    fn stat(&self) -> Stat {
+        proc_stat(StatMode::DIR, 0)
+    }
+
+    fn size(&self) -> usize {
+        0
+    }
+
+    fn lookup(&self, name: &str) -> Option<Arc<dyn Inode>> {
+        let inode: Arc<dyn Inode> = match self {
+            ProcDir::Root => {
+                if let Some((_, generate)) = ROOT_FILES.iter().find(|(file, _)| *file == name) {
+                    Arc::new(ProcFile::new(generate()))
+                } else {
+                    let pid = name.parse().ok()?;
+                    find_task(pid)?;
+                    Arc::new(ProcDir::Task(pid))
+                }
+            }
+            ProcDir::Task(pid) => {
+                let (_, generate) = TASK_FILES.iter().find(|(file, _)| *file == name)?;
+                let task = find_task(*pid)?;
+                Arc::new(ProcFile::new(generate(&task)))
+            }
+        };
+        Some(inode)
+    }
+
+    fn entries(&self) -> Vec<(String, InodeType)> {
+        let mut v: Vec<_> = [".", ".."]
+            .into_iter()
+            .map(|name| (String::from(name), InodeType::Directory))
+            .collect();
+        match self {
+            ProcDir::Root => {
+                v.extend(
+                    task_pids()
+                        .into_iter()
+                        .map(|pid| (pid.to_string(), InodeType::Directory)),
+                );
+                v.extend(
+                    ROOT_FILES
+                        .iter()
+                        .map(|(name, _)| (String::from(*name), InodeType::File)),
+                );
+            }
+            ProcDir::Task(_) => v.extend(
+                TASK_FILES
+                    .iter()
+                    .map(|(name, _)| (String::from(*name), InodeType::File)),
+            ),
+        }
+        v
+    }
+}
+
+struct ProcFile {
+    content: String,
+}
+
+impl ProcFile {
+    fn new(content: String) -> Self {
+        Self { content }
+    }
+}
+
+impl Inode for ProcFile {
+    fn type_(&self) -> InodeType {
+        InodeType::File
+    }
+
+    fn stat(&self) -> Stat {
+        proc_stat(StatMode::FILE, self.content.len())
+    }
+
+    fn size(&self) -> usize {
+        self.content.len()
+    }
+
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> usize {
+        let content = self.content.as_bytes();
+        if offset >= content.len() {
+            return 0;
+        }
+        let len = buf.len().min(content.len() - offset);
+        buf[..len].copy_from_slice(&content[offset..offset + len]);
+        len
+    }
+}
@@ -0,0 +1,287 @@
+//! tmpfs, a filesystem kept in memory
+//!
+//! File content lives in frames, allocated on the first write to each page
+//! so that holes cost nothing. A filesystem holds at most `max_pages`
+//! frames, writes past the limit come out short.
+extern crate alloc;
+use super::vfs::{Inode, InodeType, SuperBlock};
+use super::{Stat, StatMode};
+use crate::config::PAGE_SIZE;
+use crate::memory::{frame_alloc, FrameTracker};
+use crate::sync::UPIntrFreeCell;
+use crate::timer::get_rtc_time;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+/// State shared by the inodes of a filesystem
+struct Usage {
+    /// frames held by files
+    pages: AtomicUsize,
+    max_pages: usize,
+    next_ino: AtomicU64,
+}
+
+impl Usage {
+    /// A zeroed frame, `None` once the limit is reached or memory is out
+    fn alloc_page(&self) -> Option<FrameTracker> {
+        let frame = if self.pages.fetch_add(1, Ordering::Relaxed) < self.max_pages {
+            frame_alloc()
+        } else {
+            None
+        };
+        if frame.is_none() {
+            self.release(1);
+        }
+        frame
+    }
+
+    fn release(&self, pages: usize) {
+        self.pages.fetch_sub(pages, Ordering::Relaxed);
+    }
+}
+
+pub struct TmpFs {
+    root: Arc<TmpInode>,
+}
+
+impl TmpFs {
+    pub fn new(max_pages: usize) -> Arc<Self> {
+        let usage = Arc::new(Usage {
+            pages: AtomicUsize::new(0),
+            max_pages,
+            next_ino: AtomicU64::new(1),
+        });
+        Arc::new(Self {
+            root: TmpInode::new(InodeType::Directory, usage),
+        })
+    }
+}
+
+impl SuperBlock for TmpFs {
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+}
+
+pub struct TmpInode {
+    ino: u64,
+    type_: InodeType,
+    usage: Arc<Usage>,
+    inner: UPIntrFreeCell<TmpInodeInner>,
+}
+
+struct TmpInodeInner {
+    size: usize,
+    /// frames of the file, `None` for holes. Bytes past the size are zeros.
+    pages: Vec<Option<FrameTracker>>,
+    /// entries of the directory
+    children: BTreeMap<String, Arc<TmpInode>>,
+    atime: u64,
+    mtime: u64,
+    ctime: u64,
+}
+
+impl TmpInode {
+    fn new(type_: InodeType, usage: Arc<Usage>) -> Arc<Self> {
+        let time = get_rtc_time();
+        Arc::new(Self {
+            ino: usage.next_ino.fetch_add(1, Ordering::Relaxed),
+            type_,
+            usage,
+            inner: unsafe {
+                UPIntrFreeCell::new(TmpInodeInner {
+                    size: 0,
+                    pages: Vec::new(),
+                    children: BTreeMap::new(),
+                    atime: time,
+                    mtime: time,
+                    ctime: time,
+                })
+            },
+        })
+    }
+}
+
+impl TmpInodeInner {
+    fn allocated_pages(&self) -> usize {
+        self.pages.iter().filter(|page| page.is_some()).count()
+    }
+
+    fn touch(&mut self) {
+        let time = get_rtc_time();
+        self.mtime = time;
+        self.ctime = time;
+    }
+}
+
+impl Drop for TmpInode {
+    fn drop(&mut self) {
+        self.usage
+            .release(self.inner.exclusive_access().allocated_pages());
+    }
+}
+
+impl Inode for TmpInode {
+    fn type_(&self) -> InodeType {
+        self.type_
+    }
+
+    fn stat(&self) -> Stat {
+        let inner = self.inner.exclusive_access();
+        let (mode, nlink) = match self.type_ {
+            InodeType::File => (StatMode::FILE, 1),
+            InodeType::Directory => {
+                let subdirs = inner
+                    .children
+                    .values()
+                    .filter(|child| child.is_dir())
+                    .count();
+                (StatMode::DIR, 2 + subdirs as u32)
+            }
+        };
+        Stat {
+            ino: self.ino,
+            mode,
+            nlink,
+            size: inner.size as u64,
+            blocks: (inner.allocated_pages() * PAGE_SIZE / 512) as u64,
+            atime: inner.atime,
+            mtime: inner.mtime,
+            ctime: inner.ctime,
+        }
+    }
+
+    fn size(&self) -> usize {
+        self.inner.exclusive_access().size
+    }
+
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> usize {
+        let mut inner = self.inner.exclusive_access();
+        inner.atime = get_rtc_time();
+        let end = inner.size.min(offset.saturating_add(buf.len()));
+        let mut pos = offset;
+        while pos < end {
+            let in_page = pos % PAGE_SIZE;
+            let len = (PAGE_SIZE - in_page).min(end - pos);
+            let dst = &mut buf[pos - offset..pos - offset + len];
+            match inner.pages.get(pos / PAGE_SIZE) {
+                Some(Some(frame)) => {
+                    dst.copy_from_slice(&frame.ppn.get_bytes_array()[in_page..][..len])
+                }
+                _ => dst.fill(0),
+            }
+            pos += len;
+        }
+        end.saturating_sub(offset)
+    }
+
+    fn write_at(&self, offset: usize, buf: &[u8]) -> usize {
+        if self.type_ != InodeType::File {
+            return 0;
+        }
+        let mut inner = self.inner.exclusive_access();
+        let mut pos = offset;
+        while pos < offset + buf.len() {
+            let page = pos / PAGE_SIZE;
+            let in_page = pos % PAGE_SIZE;
+            let len = (PAGE_SIZE - in_page).min(offset + buf.len() - pos);
+            if !matches!(inner.pages.get(page), Some(Some(_))) {
+                let Some(frame) = self.usage.alloc_page() else {
+                    break;
+                };
+                if inner.pages.len() <= page {
+                    inner.pages.resize_with(page + 1, || None);
+                }
+                inner.pages[page] = Some(frame);
+            }
+            let frame = inner.pages[page].as_ref().unwrap();
+            frame.ppn.get_bytes_array()[in_page..][..len]
+                .copy_from_slice(&buf[pos - offset..pos - offset + len]);
+            pos += len;
+        }
+        if pos > offset {
+            inner.size = inner.size.max(pos);
+            inner.touch();
+        }
+        pos - offset
+    }
+
+    fn truncate(&self, len: usize) -> bool {
+        if self.type_ != InodeType::File {
+            return false;
+        }
+        let mut inner = self.inner.exclusive_access();
+        if len < inner.size {
+            let kept = len.div_ceil(PAGE_SIZE).min(inner.pages.len());
+            let released = inner
+                .pages
+                .drain(kept..)
+                .filter(|page| page.is_some())
+                .count();
+            self.usage.release(released);
+            // keep the bytes past the size zeroed
+            if let Some(Some(frame)) = inner.pages.get(len / PAGE_SIZE) {
+                frame.ppn.get_bytes_array()[len % PAGE_SIZE..].fill(0);
+            }
+        }
+        inner.size = len;
+        inner.touch();
+        true
+    }
+
+    fn sync(&self, _data_only: bool) -> bool {
+        // nothing to write back, the content is already where it belongs
+        true
+    }
+
+    fn lookup(&self, name: &str) -> Option<Arc<dyn Inode>> {
+        let child: Arc<dyn Inode> = self.inner.exclusive_access().children.get(name)?.clone();
+        Some(child)
+    }
+
+    fn create(&self, name: &str, type_: InodeType) -> Option<Arc<dyn Inode>> {
+        if self.type_ != InodeType::Directory {
+            return None;
+        }
+        let mut inner = self.inner.exclusive_access();
+        if inner.children.contains_key(name) {
+            return None;
+        }
+        let child = TmpInode::new(type_, self.usage.clone());
+        inner.children.insert(String::from(name), child.clone());
+        inner.touch();
+        Some(child)
+    }
+
+    fn unlink(&self, name: &str) -> bool {
+        let mut inner = self.inner.exclusive_access();
+        match inner.children.get(name) {
+            Some(child) if child.inner.exclusive_access().children.is_empty() => {}
+            _ => return false,
+        }
+        // the frames go once the inode is closed everywhere
+        inner.children.remove(name);
+        inner.touch();
+        true
+    }
+
+    fn entries(&self) -> Vec<(String, InodeType)> {
+        if self.type_ != InodeType::Directory {
+            return Vec::new();
+        }
+        let inner = self.inner.exclusive_access();
+        [".", ".."]
+            .into_iter()
+            .map(|name| (String::from(name), InodeType::Directory))
+            .chain(
+                inner
+                    .children
+                    .iter()
+                    .map(|(name, child)| (name.clone(), child.type_)),
+            )
+            .collect()
+    }
+}
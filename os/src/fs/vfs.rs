@@ -0,0 +1,144 @@
+//! Virtual filesystem layer
+//!
+//! Filesystems implement [`SuperBlock`] and [`Inode`], and know nothing of
+//! paths or mount points. Paths are resolved into [`Dentry`]s by
+//! [`mount::lookup`](super::mount::lookup), which steps into the filesystems
+//! mounted on the way.
+extern crate alloc;
+use super::Stat;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use easy_fs::BlockDevice;
+
+/// Type of an inode
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum InodeType {
+    File,
+    Directory,
+}
+
+/// A file or a directory of a filesystem
+///
+/// Directory operations fail on files and the other way round, through
+/// the default implementations or `None` / 0 / false results.
+pub trait Inode: Send + Sync {
+    fn type_(&self) -> InodeType;
+    /// Metadata, as reported by `sys_fstat`
+    fn stat(&self) -> Stat;
+    /// Size in bytes
+    fn size(&self) -> usize;
+    /// Read at `offset`, return the number of bytes read
+    fn read_at(&self, _offset: usize, _buf: &mut [u8]) -> usize {
+        0
+    }
+    /// Write at `offset`, growing the file if needed. Return the number of
+    /// bytes written, short when the filesystem is full.
+    fn write_at(&self, _offset: usize, _buf: &[u8]) -> usize {
+        0
+    }
+    /// Set the file size to `len`, false if the file can not be resized
+    fn truncate(&self, _len: usize) -> bool {
+        false
+    }
+    /// Write back the content of the file, and its metadata unless
+    /// `data_only`. False if the file is not backed by a disk.
+    fn sync(&self, _data_only: bool) -> bool {
+        false
+    }
+    /// Entry `name` of the directory, `name` being neither "." nor ".."
+    fn lookup(&self, _name: &str) -> Option<Arc<dyn Inode>> {
+        None
+    }
+    /// Create entry `name` in the directory, `None` if it already exists
+    fn create(&self, _name: &str, _type_: InodeType) -> Option<Arc<dyn Inode>> {
+        None
+    }
+    /// Remove entry `name` from the directory, directories must be empty
+    fn unlink(&self, _name: &str) -> bool {
+        false
+    }
+    /// Names and types of the entries of the directory
+    fn entries(&self) -> Vec<(String, InodeType)> {
+        Vec::new()
+    }
+    /// Device behind a block device file, what disk filesystems are
+    /// mounted from
+    fn block_device(&self) -> Option<Arc<dyn BlockDevice>> {
+        None
+    }
+
+    fn is_dir(&self) -> bool {
+        self.type_() == InodeType::Directory
+    }
+}
+
+/// A mounted filesystem
+pub trait SuperBlock: Send + Sync {
+    fn root(&self) -> Arc<dyn Inode>;
+    /// Write back every modified block
+    fn sync(&self) {}
+    /// Write back the file content modified once in a while, called on
+    /// timer interrupts so it must not wait for the disk to be free
+    fn flush_if_due(&self) {}
+    /// Whether inodes of the filesystem are still held, by open files or
+    /// working directories
+    fn in_use(&self) -> bool {
+        false
+    }
+}
+
+/// An inode reached through a path
+///
+/// Dentries keep the name and the parent the inode was reached through,
+/// so ".." and the path of the working directory cross mount points back
+/// the way they came.
+pub struct Dentry {
+    name: String,
+    parent: Option<Arc<Dentry>>,
+    inode: Arc<dyn Inode>,
+}
+
+impl Dentry {
+    /// Dentry of the root directory
+    pub fn root(inode: Arc<dyn Inode>) -> Arc<Self> {
+        Arc::new(Self {
+            name: String::new(),
+            parent: None,
+            inode,
+        })
+    }
+
+    /// Dentry of entry `name` of this directory, whose inode is `inode`
+    pub fn child(self: &Arc<Self>, name: &str, inode: Arc<dyn Inode>) -> Arc<Self> {
+        Arc::new(Self {
+            name: String::from(name),
+            parent: Some(self.clone()),
+            inode,
+        })
+    }
+
+    /// Parent directory, the root being its own parent
+    pub fn parent(self: &Arc<Self>) -> Arc<Self> {
+        self.parent.clone().unwrap_or_else(|| self.clone())
+    }
+
+    pub fn inode(&self) -> Arc<dyn Inode> {
+        self.inode.clone()
+    }
+
+    /// Absolute path
+    pub fn path(&self) -> String {
+        match &self.parent {
+            None => String::from("/"),
+            Some(parent) => {
+                let mut path = parent.path();
+                if !path.ends_with('/') {
+                    path.push('/');
+                }
+                path.push_str(&self.name);
+                path
+            }
+        }
+    }
+}
@@ -64,8 +64,9 @@ fn init_fpu() {
 }
 
 #[no_mangle]
-pub fn kmain() -> ! {
+pub fn kmain(hart_id: usize, dtb: usize) -> ! {
     clear_bss();
+    board::init(hart_id, dtb);
     init_fpu();
     logging::init();
 
@@ -73,7 +74,15 @@ pub fn kmain() -> ! {
     test_main();
 
     memory::init();
+    log::info!(
+        "{} harts, memory [{:#x}, {:#x}), timebase at {} Hz",
+        board::MACHINE.harts,
+        board::MACHINE.memory_start,
+        board::MACHINE.memory_end,
+        board::MACHINE.clock_freq
+    );
     UART.init();
+    fs::mount::init();
     task::add_initproc();
     trap::init();
     trap::enable_timer_interrupt();
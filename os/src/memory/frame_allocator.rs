@@ -1,7 +1,7 @@
 //! Implementation of [`FrameAllocator`] which
 //! controls all the frames in the operating system.
 
-use crate::config::MEMORY_END;
+use crate::board::MACHINE;
 use crate::memory::address::{PhysAddr, PhysPageNum};
 use crate::println;
 use crate::sync::UPIntrFreeCell;
@@ -47,6 +47,7 @@ trait FrameAllocator {
 
 /// an implementation for frame allocator
 pub struct StackFrameAllocator {
+    start: usize,
     current: usize,
     end: usize,
     recycled: Vec<usize>,
@@ -54,13 +55,22 @@ pub struct StackFrameAllocator {
 
 impl StackFrameAllocator {
     pub fn init(&mut self, l: PhysPageNum, r: PhysPageNum) {
+        self.start = l.0;
         self.current = l.0;
         self.end = r.0;
     }
+    /// Return (total, free) frames
+    pub fn usage(&self) -> (usize, usize) {
+        (
+            self.end - self.start,
+            self.end - self.current + self.recycled.len(),
+        )
+    }
 }
 impl FrameAllocator for StackFrameAllocator {
     fn new() -> Self {
         Self {
+            start: 0,
             current: 0,
             end: 0,
             recycled: Vec::new(),
@@ -106,14 +116,14 @@ lazy_static! {
         unsafe { UPIntrFreeCell::new(FrameAllocatorImpl::new()) };
 }
 
-/// initiate the frame allocator using `ekernel` and `MEMORY_END`
+/// initiate the frame allocator using `ekernel` and the end of memory
 pub fn init_frame_allocator() {
     extern "C" {
         fn ekernel();
     }
     FRAME_ALLOCATOR.exclusive_access().init(
         PhysAddr::from(ekernel as usize).ceil(),
-        PhysAddr::from(MEMORY_END).floor(),
+        PhysAddr::from(MACHINE.memory_end).floor(),
     );
 }
 
@@ -133,6 +143,11 @@ pub fn frame_alloc_more(pages: usize) -> Option<Vec<FrameTracker>> {
         .map(|v| v.into_iter().map(FrameTracker::new).collect())
 }
 
+/// Return (total, free) frames
+pub fn frame_usage() -> (usize, usize) {
+    FRAME_ALLOCATOR.exclusive_access().usage()
+}
+
 /// deallocate a frame
 pub fn frame_dealloc(ppn: PhysPageNum) {
     FRAME_ALLOCATOR.exclusive_access().dealloc(ppn);
@@ -24,6 +24,12 @@ pub fn init_heap() {
     }
 }
 
+/// Return (total, used) bytes of the heap
+pub fn heap_usage() -> (usize, usize) {
+    let heap = HEAP_ALLOCATOR.lock();
+    (heap.stats_total_bytes(), heap.stats_alloc_actual())
+}
+
 #[allow(unused)]
 pub fn heap_test() {
     use alloc::boxed::Box;
@@ -3,7 +3,8 @@ use super::{frame_alloc, FrameTracker};
 use super::{PTEFlags, PageTable, PageTableEntry};
 use super::{PhysAddr, PhysPageNum, VirtAddr, VirtPageNum};
 use super::{StepByOne, VPNRange};
-use crate::config::{MEMORY_END, MMIO, PAGE_SIZE, TRAMPOLINE, TRAP_CONTEXT, USER_STACK_SIZE};
+use crate::board::MACHINE;
+use crate::config::{PAGE_SIZE, TRAMPOLINE, TRAP_CONTEXT, USER_STACK_SIZE};
 use crate::println;
 use crate::sync::UPIntrFreeCell;
 use bitflags::bitflags;
@@ -56,6 +57,19 @@ impl MemorySet {
     pub fn token(&self) -> usize {
         self.page_table.token()
     }
+    /// Start, end and permission of each area
+    pub fn areas(&self) -> Vec<(VirtAddr, VirtAddr, MapPermission)> {
+        self.areas
+            .iter()
+            .map(|area| {
+                (
+                    area.vpn_range.get_start().into(),
+                    area.vpn_range.get_end().into(),
+                    area.map_perm.clone(),
+                )
+            })
+            .collect()
+    }
     /// Assume that no conflicts.
     pub fn insert_framed_area(
         &mut self,
@@ -152,14 +166,14 @@ impl MemorySet {
         memory_set.push(
             MapArea::new(
                 (ekernel as usize).into(),
-                MEMORY_END.into(),
+                MACHINE.memory_end.into(),
                 MapType::Identical,
                 MapPermission::R | MapPermission::W,
             ),
             None,
         );
         info!("mapping memory-mapped registers");
-        for pair in MMIO {
+        for pair in MACHINE.mmio.iter() {
             memory_set.push(
                 MapArea::new(
                     VirtAddr((*pair).0),
@@ -15,6 +15,14 @@ pub fn shutdown(failure: bool) -> ! {
     unreachable!()
 }
 
+pub fn reboot() -> ! {
+    use sbi_rt::{system_reset, ColdReboot, NoReason};
+
+    system_reset(ColdReboot, NoReason);
+
+    unreachable!()
+}
+
 pub fn set_timer(timer: usize) {
     sbi_rt::set_timer(timer as _);
 }
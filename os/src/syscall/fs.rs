@@ -1,11 +1,15 @@
 //! File and filesystem-related syscalls
 extern crate alloc;
-use crate::fs::inode::{open_file, OpenFlags};
-use crate::fs::{Dirent, DirentType};
-use crate::memory::{translated_byte_buffer, translated_str, UserBuffer};
+use crate::fs::inode::{lookup_path, mkdir, open_file, unlink, OpenFlags};
+use crate::fs::mount::{mount, sync_all, umount};
+use crate::fs::{Dirent, DirentType, File, FileDescriptor, SeekWhence, Stat};
+use crate::memory::{translated_byte_buffer, translated_refmut, translated_str, UserBuffer};
 use crate::task::{current_task, current_user_token};
 use alloc::vec::Vec;
 
+/// Special `dirfd` value: resolve the path from the current working directory
+pub const AT_FDCWD: isize = -100;
+
 pub fn sys_write(fd: usize, buf: *const u8, len: usize) -> isize {
     let token = current_user_token();
     let task = current_task().unwrap();
@@ -13,8 +17,11 @@ pub fn sys_write(fd: usize, buf: *const u8, len: usize) -> isize {
     if fd >= inner.fd_table.len() {
         return -1;
     }
-    if let Some(file) = &inner.fd_table[fd] {
+    if let Some(FileDescriptor { file, .. }) = &inner.fd_table[fd] {
         let file = file.clone();
+        if !file.writable() {
+            return -1;
+        }
         // release current task TCB manually to avoid multi-borrow
         drop(inner);
         file.write(UserBuffer::new(translated_byte_buffer(token, buf, len))) as isize
@@ -30,8 +37,11 @@ pub fn sys_read(fd: usize, buf: *const u8, len: usize) -> isize {
     if fd >= inner.fd_table.len() {
         return -1;
     }
-    if let Some(file) = &inner.fd_table[fd] {
+    if let Some(FileDescriptor { file, .. }) = &inner.fd_table[fd] {
         let file = file.clone();
+        if !file.readable() {
+            return -1;
+        }
         // release current task TCB manually to avoid multi-borrow
         drop(inner);
         file.read(UserBuffer::new(translated_byte_buffer(token, buf, len))) as isize
@@ -40,14 +50,119 @@ pub fn sys_read(fd: usize, buf: *const u8, len: usize) -> isize {
     }
 }
 
+pub fn sys_lseek(fd: usize, offset: isize, whence: usize) -> isize {
+    let task = current_task().unwrap();
+    let inner = task.inner_exclusive_access();
+    if fd >= inner.fd_table.len() {
+        return -1;
+    }
+    let whence = match SeekWhence::from_repr(whence) {
+        Some(whence) => whence,
+        None => return -1,
+    };
+    if let Some(FileDescriptor { file, .. }) = &inner.fd_table[fd] {
+        let file = file.clone();
+        drop(inner);
+        match file.seek(offset, whence) {
+            Some(offset) => offset as isize,
+            None => -1,
+        }
+    } else {
+        -1
+    }
+}
+
+pub fn sys_pread64(fd: usize, buf: *const u8, len: usize, offset: usize) -> isize {
+    let token = current_user_token();
+    let task = current_task().unwrap();
+    let inner = task.inner_exclusive_access();
+    if fd >= inner.fd_table.len() {
+        return -1;
+    }
+    if let Some(FileDescriptor { file, .. }) = &inner.fd_table[fd] {
+        let file = file.clone();
+        if !file.readable() {
+            return -1;
+        }
+        drop(inner);
+        match file.pread(
+            UserBuffer::new(translated_byte_buffer(token, buf, len)),
+            offset,
+        ) {
+            Some(size) => size as isize,
+            None => -1,
+        }
+    } else {
+        -1
+    }
+}
+
+pub fn sys_pwrite64(fd: usize, buf: *const u8, len: usize, offset: usize) -> isize {
+    let token = current_user_token();
+    let task = current_task().unwrap();
+    let inner = task.inner_exclusive_access();
+    if fd >= inner.fd_table.len() {
+        return -1;
+    }
+    if let Some(FileDescriptor { file, .. }) = &inner.fd_table[fd] {
+        let file = file.clone();
+        if !file.writable() {
+            return -1;
+        }
+        drop(inner);
+        match file.pwrite(
+            UserBuffer::new(translated_byte_buffer(token, buf, len)),
+            offset,
+        ) {
+            Some(size) => size as isize,
+            None => -1,
+        }
+    } else {
+        -1
+    }
+}
+
+pub fn sys_ftruncate(fd: usize, len: isize) -> isize {
+    if len < 0 {
+        return -1;
+    }
+    let task = current_task().unwrap();
+    let inner = task.inner_exclusive_access();
+    if fd >= inner.fd_table.len() {
+        return -1;
+    }
+    if let Some(FileDescriptor { file, .. }) = &inner.fd_table[fd] {
+        let file = file.clone();
+        if !file.writable() {
+            return -1;
+        }
+        drop(inner);
+        if file.truncate(len as usize) {
+            0
+        } else {
+            -1
+        }
+    } else {
+        -1
+    }
+}
+
 pub fn sys_open(path: *const u8, flags: u32) -> isize {
     let task = current_task().unwrap();
     let token = current_user_token();
     let path = translated_str(token, path);
-    if let Some(inode) = open_file(path.as_str(), OpenFlags::from_bits(flags).unwrap()) {
+    let flags = match OpenFlags::from_bits(flags) {
+        Some(flags) => flags,
+        None => return -1,
+    };
+    let cloexec = flags.contains(OpenFlags::CLOEXEC);
+    if let Some(inode) = open_file(path.as_str(), flags) {
         let mut inner = task.inner_exclusive_access();
         let fd = inner.alloc_fd();
-        inner.fd_table[fd] = Some(inode);
+        inner.fd_table[fd] = Some(FileDescriptor {
+            file: inode,
+            cloexec,
+        });
         fd as isize
     } else {
         -1
@@ -76,7 +191,7 @@ pub fn sys_getdents(fd: usize, buf: *mut u8, buflen: usize) -> isize {
     if fd >= inner.fd_table.len() {
         return -1;
     }
-    if let Some(file) = &inner.fd_table[fd] {
+    if let Some(FileDescriptor { file, .. }) = &inner.fd_table[fd] {
         let file = file.clone();
         drop(inner);
         let dirs: Vec<Dirent> = file.getdents();
@@ -104,3 +219,123 @@ pub fn sys_getdents(fd: usize, buf: *mut u8, buflen: usize) -> isize {
         -1
     }
 }
+
+pub fn sys_fstat(fd: usize, st: *mut Stat) -> isize {
+    let token = current_user_token();
+    let task = current_task().unwrap();
+    let inner = task.inner_exclusive_access();
+    if fd >= inner.fd_table.len() {
+        return -1;
+    }
+    if let Some(FileDescriptor { file, .. }) = &inner.fd_table[fd] {
+        let file = file.clone();
+        drop(inner);
+        *translated_refmut(token, st) = file.stat();
+        0
+    } else {
+        -1
+    }
+}
+
+pub fn sys_sync() -> isize {
+    sync_all();
+    0
+}
+
+pub fn sys_fsync(fd: usize) -> isize {
+    sync_fd(fd, false)
+}
+
+pub fn sys_fdatasync(fd: usize) -> isize {
+    sync_fd(fd, true)
+}
+
+fn sync_fd(fd: usize, data_only: bool) -> isize {
+    let task = current_task().unwrap();
+    let inner = task.inner_exclusive_access();
+    if fd >= inner.fd_table.len() {
+        return -1;
+    }
+    if let Some(FileDescriptor { file, .. }) = &inner.fd_table[fd] {
+        let file = file.clone();
+        drop(inner);
+        if file.sync(data_only) {
+            0
+        } else {
+            -1
+        }
+    } else {
+        -1
+    }
+}
+
+/// `flags` of `sys_unlinkat`: remove a directory rather than a file
+const AT_REMOVEDIR: u32 = 0x200;
+
+/// Only `AT_FDCWD` is supported as `dirfd`, and `mode` is ignored
+pub fn sys_mkdirat(dirfd: isize, path: *const u8, _mode: u32) -> isize {
+    if dirfd != AT_FDCWD {
+        return -1;
+    }
+    let path = translated_str(current_user_token(), path);
+    if mkdir(path.as_str()) {
+        0
+    } else {
+        -1
+    }
+}
+
+/// Only `AT_FDCWD` is supported as `dirfd`
+pub fn sys_unlinkat(dirfd: isize, path: *const u8, flags: u32) -> isize {
+    if dirfd != AT_FDCWD || flags & !AT_REMOVEDIR != 0 {
+        return -1;
+    }
+    let path = translated_str(current_user_token(), path);
+    if unlink(path.as_str(), flags & AT_REMOVEDIR != 0) {
+        0
+    } else {
+        -1
+    }
+}
+
+/// Only `AT_FDCWD` is supported as `dirfd` for now
+pub fn sys_fstatat(dirfd: isize, path: *const u8, st: *mut Stat) -> isize {
+    if dirfd != AT_FDCWD {
+        return -1;
+    }
+    let token = current_user_token();
+    let path = translated_str(token, path);
+    if let Some(inode) = open_file(path.as_str(), OpenFlags::RDONLY) {
+        *translated_refmut(token, st) = inode.stat();
+        0
+    } else {
+        -1
+    }
+}
+
+/// Mount the filesystem of type `fs_type` from `source` on directory
+/// `target`. No mount flags are supported.
+pub fn sys_mount(source: *const u8, target: *const u8, fs_type: *const u8, flags: u32) -> isize {
+    if flags != 0 {
+        return -1;
+    }
+    let token = current_user_token();
+    let source = translated_str(token, source);
+    let fs_type = translated_str(token, fs_type);
+    match lookup_path(translated_str(token, target).as_str()) {
+        Some(target) if mount(source.as_str(), &target, fs_type.as_str()) => 0,
+        _ => -1,
+    }
+}
+
+/// Unmount the filesystem mounted on `target`. No flags are supported.
+pub fn sys_umount2(target: *const u8, flags: u32) -> isize {
+    if flags != 0 {
+        return -1;
+    }
+    let token = current_user_token();
+    match lookup_path(translated_str(token, target).as_str()) {
+        Some(target) if umount(&target) => 0,
+        _ => -1,
+    }
+}
@@ -1,5 +1,7 @@
 use crate::fs::inode::{open_file, OpenFlags};
+use crate::fs::mount::sync_all;
 use crate::memory::{translated_byte_buffer, translated_ref, translated_refmut, translated_str};
+use crate::sbi::{reboot, shutdown};
 use crate::task::{
     add_task, current_task, current_user_token, exit_current_and_run_next,
     suspend_current_and_run_next,
@@ -49,6 +51,36 @@ pub fn sys_exit(exit_code: i32) -> ! {
     panic!("Unreachable in sys_exit!");
 }
 
+/// `magic` argument of `sys_reboot`
+const REBOOT_MAGIC1: u32 = 0xfee1_dead;
+/// accepted `magic2` arguments of `sys_reboot`
+const REBOOT_MAGIC2: [u32; 4] = [0x2812_1969, 0x0512_1996, 0x1604_1998, 0x2011_2000];
+/// `cmd` of `sys_reboot` restarting the machine
+const REBOOT_CMD_RESTART: u32 = 0x0123_4567;
+/// `cmd` of `sys_reboot` stopping the machine
+const REBOOT_CMD_HALT: u32 = 0xcdef_0123;
+/// `cmd` of `sys_reboot` powering the machine off
+const REBOOT_CMD_POWER_OFF: u32 = 0x4321_fedc;
+
+/// Write back the filesystem and restart or power off the machine,
+/// returns only on invalid arguments
+pub fn sys_reboot(magic: u32, magic2: u32, cmd: u32) -> isize {
+    if magic != REBOOT_MAGIC1 || !REBOOT_MAGIC2.contains(&magic2) {
+        return -1;
+    }
+    match cmd {
+        REBOOT_CMD_RESTART => {
+            sync_all();
+            reboot()
+        }
+        REBOOT_CMD_HALT | REBOOT_CMD_POWER_OFF => {
+            sync_all();
+            shutdown(false)
+        }
+        _ => -1,
+    }
+}
+
 pub fn sys_yield() -> isize {
     suspend_current_and_run_next();
     0
@@ -3,8 +3,9 @@ use super::task::TaskStatus;
 use super::TaskControlBlock;
 extern crate alloc;
 use crate::sync::UPIntrFreeCell;
-use alloc::collections::VecDeque;
-use alloc::sync::Arc;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
 use lazy_static::*;
 ///A array of `TaskControlBlock` that is thread-safe
 pub struct TaskManager {
@@ -34,6 +35,32 @@ lazy_static! {
     pub static ref TASK_MANAGER: UPIntrFreeCell<TaskManager> =
         unsafe { UPIntrFreeCell::new(TaskManager::new()) };
 }
+lazy_static! {
+    /// Every task alive, zombies included, by pid
+    static ref PID2TASK: UPIntrFreeCell<BTreeMap<usize, Weak<TaskControlBlock>>> =
+        unsafe { UPIntrFreeCell::new(BTreeMap::new()) };
+}
+
+/// Make `task` visible to [`find_task`], until its pid is released
+pub fn insert_into_pid2task(pid: usize, task: &Arc<TaskControlBlock>) {
+    PID2TASK
+        .exclusive_access()
+        .insert(pid, Arc::downgrade(task));
+}
+
+pub fn remove_from_pid2task(pid: usize) {
+    PID2TASK.exclusive_access().remove(&pid);
+}
+
+pub fn find_task(pid: usize) -> Option<Arc<TaskControlBlock>> {
+    PID2TASK.exclusive_access().get(&pid)?.upgrade()
+}
+
+/// Pids of every task alive
+pub fn task_pids() -> Vec<usize> {
+    PID2TASK.exclusive_access().keys().copied().collect()
+}
+
 ///Interface offered to add task
 pub fn add_task(task: Arc<TaskControlBlock>) {
     TASK_MANAGER.exclusive_access().add(task);
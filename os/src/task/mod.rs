@@ -23,20 +23,19 @@ mod switch;
 mod task;
 extern crate alloc;
 use crate::fs::inode::open_file;
-use crate::fs::inode::root_os_inode;
 use crate::fs::inode::OpenFlags;
+use crate::fs::mount::{root_dentry, sync_all};
 use crate::sbi::shutdown;
 use alloc::sync::Arc;
 pub use context::TaskContext;
 use lazy_static::*;
 use log::info;
-pub use manager::{add_task, fetch_task, wakeup_task};
+pub use manager::{add_task, fetch_task, find_task, task_pids, wakeup_task};
 pub use processor::{
     current_task, current_trap_cx, current_user_token, run_tasks, schedule, take_current_task,
 };
 use switch::__switch;
-pub use task::TaskControlBlock;
-use task::TaskStatus;
+pub use task::{TaskControlBlock, TaskStatus};
 /// Suspend the current 'Running' task and run the next task in task list.
 pub fn suspend_current_and_run_next() {
     // There must be an application running.
@@ -61,6 +60,10 @@ pub const IDLE_PID: usize = 0;
 
 /// Exit the current 'Running' task and run the next task in task list.
 pub fn exit_current_and_run_next(exit_code: i32) {
+    if current_task().unwrap().getpid() == IDLE_PID {
+        // write back the filesystem while the task can still wait for the disk
+        sync_all();
+    }
     // take from Processor
     let task = take_current_task().unwrap();
 
@@ -118,11 +121,13 @@ pub fn block_current_task() -> *mut TaskContext {
 
 lazy_static! {
     ///Globle process that init user shell
-    pub static ref INITPROC: Arc<TaskControlBlock> = Arc::new({
+    pub static ref INITPROC: Arc<TaskControlBlock> = {
         let inode = open_file("/bin/initproc", OpenFlags::RDONLY).unwrap();
         let v = inode.read_all();
-        TaskControlBlock::new(v.as_slice(), root_os_inode())
-    });
+        let task = Arc::new(TaskControlBlock::new(v.as_slice(), root_dentry()));
+        manager::insert_into_pid2task(task.getpid(), &task);
+        task
+    };
 }
 ///Add init process to the manager
 pub fn add_initproc() {
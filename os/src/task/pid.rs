@@ -1,4 +1,5 @@
 //!Implementation of [`PidAllocator`]
+use super::manager::remove_from_pid2task;
 use crate::config::{KERNEL_STACK_SIZE, PAGE_SIZE, TRAMPOLINE};
 use crate::memory::{MapPermission, VirtAddr, KERNEL_SPACE};
 use crate::sync::UPIntrFreeCell;
@@ -50,6 +51,7 @@ pub struct PidHandle(pub usize);
 impl Drop for PidHandle {
     fn drop(&mut self) {
         //println!("drop pid {}", self.0);
+        remove_from_pid2task(self.0);
         PID_ALLOCATOR.exclusive_access().dealloc(self.0);
     }
 }
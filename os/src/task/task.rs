@@ -1,23 +1,22 @@
+use super::manager::insert_into_pid2task;
 use super::pid::{pid_alloc, KernelStack, PidHandle};
 use super::TaskContext;
 use crate::config::TRAP_CONTEXT;
-use crate::fs::inode::OSInode;
-use crate::fs::File;
+use crate::fs::mount::lookup;
+use crate::fs::vfs::Dentry;
+use crate::fs::FileDescriptor;
 use crate::memory::{translated_refmut, MemorySet, PhysPageNum, VirtAddr, KERNEL_SPACE};
 use crate::sync::{UPIntrFreeCell, UPIntrRefMut};
 use crate::trap::{trap_handler, TrapContext};
 extern crate alloc;
-use crate::fs::stdio::{Stdin, Stdout};
 use alloc::string::String;
 use alloc::sync::{Arc, Weak};
-use alloc::vec;
 use alloc::vec::Vec;
 
 /// task control block structure
 pub struct TaskControlBlock {
     pub pid: PidHandle,
     pub kernel_stack: KernelStack,
-    pub cwd: Arc<OSInode>,
     inner: UPIntrFreeCell<TaskControlBlockInner>,
 }
 
@@ -30,7 +29,9 @@ pub struct TaskControlBlockInner {
     pub children: Vec<Arc<TaskControlBlock>>,
     pub exit_code: i32,
     pub base_size: usize,
-    pub fd_table: Vec<Option<Arc<dyn File + Send + Sync>>>,
+    pub fd_table: Vec<Option<FileDescriptor>>,
+    /// working directory
+    pub cwd: Arc<Dentry>,
 }
 
 impl TaskControlBlock {
@@ -41,18 +42,25 @@ impl TaskControlBlock {
         self.pid.0
     }
     pub fn getcwd(&self) -> alloc::ffi::CString {
-        self.cwd.get_path()
+        alloc::ffi::CString::new(self.get_cwd().path()).unwrap()
     }
 
-    pub fn get_cwd_inode(&self) -> Arc<OSInode> {
-        self.cwd.clone()
+    pub fn get_cwd(&self) -> Arc<Dentry> {
+        self.inner_exclusive_access().cwd.clone()
     }
 
+    /// Change the working directory, false if `path` is not a directory
     pub fn chdir(&self, path: &str) -> bool {
-        self.cwd.chdir(path)
+        match lookup(&self.get_cwd(), path) {
+            Some(dentry) if dentry.inode().is_dir() => {
+                self.inner_exclusive_access().cwd = dentry;
+                true
+            }
+            _ => false,
+        }
     }
 
-    pub fn new(elf_data: &[u8], cwd: Arc<OSInode>) -> Self {
+    pub fn new(elf_data: &[u8], cwd: Arc<Dentry>) -> Self {
         // memory_set with elf program headers/trampoline/trap context/user stack
         let (memory_set, user_sp, entry_point) = MemorySet::from_elf(elf_data);
         let trap_cx_ppn = memory_set
@@ -67,7 +75,6 @@ impl TaskControlBlock {
         let task_control_block = Self {
             pid: pid_handle,
             kernel_stack,
-            cwd,
             inner: unsafe {
                 UPIntrFreeCell::new(TaskControlBlockInner {
                     trap_cx_ppn,
@@ -78,11 +85,9 @@ impl TaskControlBlock {
                     parent: None,
                     children: Vec::new(),
                     exit_code: 0,
-                    fd_table: vec![
-                        Some(Arc::new(Stdin)),
-                        Some(Arc::new(Stdout)),
-                        Some(Arc::new(Stdout)),
-                    ],
+                    // initproc opens the standard streams itself
+                    fd_table: Vec::new(),
+                    cwd,
                 })
             },
         };
@@ -129,6 +134,12 @@ impl TaskControlBlock {
         let mut inner = self.inner_exclusive_access();
         // substitute memory_set
         inner.memory_set = memory_set;
+        // close file descriptors opened with CLOEXEC
+        for fd in inner.fd_table.iter_mut() {
+            if fd.as_ref().is_some_and(|fd| fd.cloexec) {
+                fd.take();
+            }
+        }
         // update trap_cx ppn
         inner.trap_cx_ppn = trap_cx_ppn;
         // initialize trap_cx
@@ -156,18 +167,9 @@ impl TaskControlBlock {
         let pid_handle = pid_alloc();
         let kernel_stack = KernelStack::new(&pid_handle);
         let kernel_stack_top = kernel_stack.get_top();
-        let mut new_fd_table: Vec<Option<Arc<dyn File + Send + Sync>>> = Vec::new();
-        for fd in parent_inner.fd_table.iter() {
-            if let Some(file) = fd {
-                new_fd_table.push(Some(file.clone()));
-            } else {
-                new_fd_table.push(None);
-            }
-        }
         let task_control_block = Arc::new(TaskControlBlock {
             pid: pid_handle,
             kernel_stack,
-            cwd: self.cwd.clone(),
             inner: unsafe {
                 UPIntrFreeCell::new(TaskControlBlockInner {
                     trap_cx_ppn,
@@ -178,12 +180,14 @@ impl TaskControlBlock {
                     parent: Some(Arc::downgrade(self)),
                     children: Vec::new(),
                     exit_code: 0,
-                    fd_table: new_fd_table,
+                    fd_table: parent_inner.fd_table.clone(),
+                    cwd: parent_inner.cwd.clone(),
                 })
             },
         });
         // add child
         parent_inner.children.push(task_control_block.clone());
+        insert_into_pid2task(task_control_block.getpid(), &task_control_block);
         // modify kernel_sp in trap_cx
         let trap_cx = task_control_block.inner_exclusive_access().get_trap_cx();
         trap_cx.kernel_sp = kernel_stack_top;
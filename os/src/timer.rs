@@ -1,11 +1,12 @@
 //! RISC-V timer-related functionality
 
-use crate::config::CLOCK_FREQ;
+use crate::board::MACHINE;
 use crate::sbi::set_timer;
 use riscv::register::time;
 
 const TICKS_PER_SEC: usize = 100;
 const MSEC_PER_SEC: usize = 1000;
+const NSEC_PER_SEC: u64 = 1_000_000_000;
 
 /// read the `mtime` register
 pub fn get_time() -> usize {
@@ -14,10 +15,18 @@ pub fn get_time() -> usize {
 
 /// get current time in milliseconds
 pub fn get_time_ms() -> usize {
-    time::read() / (CLOCK_FREQ / MSEC_PER_SEC)
+    time::read() / (MACHINE.clock_freq / MSEC_PER_SEC)
 }
 
 /// set the next timer interrupt
 pub fn set_next_trigger() {
-    set_timer(get_time() + CLOCK_FREQ / TICKS_PER_SEC);
+    set_timer(get_time() + MACHINE.clock_freq / TICKS_PER_SEC);
+}
+
+/// read the wall-clock time, in seconds since the Unix epoch, from the goldfish RTC
+pub fn get_rtc_time() -> u64 {
+    let rtc = MACHINE.rtc as *const u32;
+    // reading TIME_LOW latches TIME_HIGH, so it has to come first
+    let (low, high) = unsafe { (rtc.read_volatile(), rtc.add(1).read_volatile()) };
+    (((high as u64) << 32) | low as u64) / NSEC_PER_SEC
 }
@@ -4,10 +4,16 @@
 #[macro_use]
 extern crate user_lib;
 
-use user_lib::{exec, fork, wait, yield_};
+use user_lib::{exec, fork, open, wait, yield_, OpenFlags};
 
 #[no_mangle]
 fn main() -> i32 {
+    // stdin, stdout and stderr, inherited by every other process
+    for _ in 0..3 {
+        if open("/dev/tty\0", OpenFlags::RDWR) < 0 {
+            return -1;
+        }
+    }
     if fork() == 0 {
         exec("/bin/user_shell\0", &[core::ptr::null::<u8>()]);
     } else {
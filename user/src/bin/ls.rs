@@ -5,17 +5,68 @@
 extern crate user_lib;
 extern crate alloc;
 use alloc::ffi::CString;
+use alloc::format;
+use alloc::string::String;
 
-use user_lib::{close, getdents, open, OpenFlags};
+use user_lib::{close, getdents, open, stat, OpenFlags, Stat, StatMode};
 
-#[no_mangle]
-pub fn main(argc: usize, argv: &[&str]) -> i32 {
-    let path;
-    if argc == 1 {
-        path = ".";
+/// Format seconds since the Unix epoch as `YYYY-MM-DD hh:mm`
+fn format_time(secs: u64) -> String {
+    let days = (secs / 86400) as i64;
+    let rem = secs % 86400;
+    // civil date from day count, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60
+    )
+}
+
+fn print_long(dir: &str, name: &str) {
+    let path = if dir == "." {
+        format!("{}\0", name)
     } else {
-        path = argv[1];
+        format!("{}/{}\0", dir.trim_end_matches('/'), name)
+    };
+    let mut st = Stat::new();
+    if stat(path.as_str(), &mut st) == -1 {
+        println!("?          ? ? ?                {}", name);
+        return;
     }
+    let type_ = match st.mode.file_type() {
+        StatMode::BLOCK => 'b',
+        StatMode::DIR => 'd',
+        StatMode::CHAR => 'c',
+        _ => '-',
+    };
+    println!(
+        "{} {:>5} {:>3} {:>8} {} {}",
+        type_,
+        st.ino,
+        st.nlink,
+        st.size,
+        format_time(st.mtime),
+        name
+    );
+}
+
+#[no_mangle]
+pub fn main(argc: usize, argv: &[&str]) -> i32 {
+    let long = argc > 1 && argv[1] == "-l";
+    let first = if long { 2 } else { 1 };
+    let path = if argc > first { argv[first] } else { ".\0" };
     let fd = open(path, OpenFlags::RDONLY);
     assert_ne!(fd, -1);
     assert!(fd > 0);
@@ -23,15 +74,22 @@ pub fn main(argc: usize, argv: &[&str]) -> i32 {
     let nread = getdents(fd as usize, &mut buf);
     assert_ne!(nread, -1);
     let nread = nread as usize;
+    let dir = path.trim_end_matches('\0');
     let mut i = 0;
     while i < nread {
         // let t = buf[i];
         let null = buf[i + 1..].iter().position(|&x| x == 0).unwrap();
         let name = CString::new(&buf[i + 1..i + 1 + null]).unwrap();
-        print!("{} ", name.to_str().unwrap());
+        if long {
+            print_long(dir, name.to_str().unwrap());
+        } else {
+            print!("{} ", name.to_str().unwrap());
+        }
         i += null + 2;
     }
-    print!("\n");
+    if !long {
+        print!("\n");
+    }
     close(fd as usize);
     0
 }
@@ -0,0 +1,73 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+extern crate alloc;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use user_lib::{close, getdents, open, read, OpenFlags};
+
+/// Content of the file at `path`, `None` if it can not be opened
+fn read_file(path: &str) -> Option<String> {
+    let fd = open(path, OpenFlags::RDONLY);
+    if fd < 0 {
+        return None;
+    }
+    let fd = fd as usize;
+    let mut content = Vec::new();
+    let mut buf = [0u8; 256];
+    loop {
+        let size = read(fd, &mut buf);
+        if size <= 0 {
+            break;
+        }
+        content.extend_from_slice(&buf[..size as usize]);
+    }
+    close(fd);
+    String::from_utf8(content).ok()
+}
+
+/// Value of field `key` in a `/proc/<pid>/status` file
+fn field<'a>(status: &'a str, key: &str) -> &'a str {
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix(key)?.strip_prefix(":\t"))
+        .unwrap_or("?")
+}
+
+#[no_mangle]
+pub fn main() -> i32 {
+    let fd = open("/proc\0", OpenFlags::RDONLY | OpenFlags::DIRECTORY);
+    if fd < 0 {
+        println!("ps: /proc is not mounted");
+        return -1;
+    }
+    let mut buf = [0u8; 1024];
+    let nread = getdents(fd as usize, &mut buf);
+    close(fd as usize);
+    assert_ne!(nread, -1);
+    let nread = nread as usize;
+    println!("  PID  PPID STATE");
+    let mut i = 0;
+    while i < nread {
+        let null = buf[i + 1..].iter().position(|&x| x == 0).unwrap();
+        let name = core::str::from_utf8(&buf[i + 1..i + 1 + null]).unwrap();
+        i += null + 2;
+        if name.parse::<usize>().is_err() {
+            continue;
+        }
+        // the task may be gone by now
+        if let Some(status) = read_file(format!("/proc/{}/status\0", name).as_str()) {
+            println!(
+                "{:>5} {:>5} {}",
+                name,
+                field(&status, "PPid"),
+                field(&status, "State")
+            );
+        }
+    }
+    0
+}
@@ -0,0 +1,67 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+
+use user_lib::{close, lseek, open, read, stat, write, OpenFlags, Stat, StatMode, SEEK_SET};
+
+/// Read up to `buf.len()` bytes from the start of `path`
+fn read_from(path: &str, buf: &mut [u8]) -> isize {
+    let fd = open(path, OpenFlags::RDONLY);
+    assert!(fd > 0);
+    let len = read(fd as usize, buf);
+    close(fd as usize);
+    len
+}
+
+#[no_mangle]
+pub fn main() -> i32 {
+    let mut buf = [0xffu8; 64];
+    assert_eq!(read_from("/dev/null\0", &mut buf), 0);
+    assert_eq!(read_from("/dev/zero\0", &mut buf), 64);
+    assert!(buf.iter().all(|&byte| byte == 0));
+
+    let fd = open("/dev/null\0", OpenFlags::WRONLY);
+    assert!(fd > 0);
+    assert_eq!(write(fd as usize, b"gone"), 4);
+    close(fd as usize);
+
+    let mut other = [0u8; 64];
+    assert_eq!(read_from("/dev/urandom\0", &mut buf), 64);
+    assert_eq!(read_from("/dev/urandom\0", &mut other), 64);
+    assert_ne!(buf, other);
+
+    let mut st = Stat::new();
+    assert_eq!(stat("/dev/tty\0", &mut st), 0);
+    assert_eq!(st.mode.file_type(), StatMode::CHAR);
+    assert_eq!(stat("/dev/vda\0", &mut st), 0);
+    assert_eq!(st.mode.file_type(), StatMode::BLOCK);
+    assert!(st.size > 0 && st.size % 512 == 0);
+    // the device can be read at any offset, and no further than its end
+    assert_eq!(read_from("/dev/vda\0", &mut buf), 64);
+    let fd = open("/dev/vda\0", OpenFlags::RDONLY);
+    assert!(fd > 0);
+    assert_eq!(
+        lseek(fd as usize, st.size as isize - 10, SEEK_SET),
+        st.size as isize - 10
+    );
+    assert_eq!(read(fd as usize, &mut buf), 10);
+    close(fd as usize);
+
+    // a second disk, when one is attached, keeps what is written to it
+    let fd = open("/dev/vdb\0", OpenFlags::RDWR);
+    if fd > 0 {
+        let block: [u8; 512] = core::array::from_fn(|i| i as u8);
+        assert_eq!(lseek(fd as usize, 512, SEEK_SET), 512);
+        assert_eq!(write(fd as usize, &block), 512);
+        let mut back = [0u8; 512];
+        assert_eq!(lseek(fd as usize, 512, SEEK_SET), 512);
+        assert_eq!(read(fd as usize, &mut back), 512);
+        assert_eq!(block, back);
+        close(fd as usize);
+    }
+
+    println!("testdev passed!");
+    0
+}
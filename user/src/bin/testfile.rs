@@ -4,7 +4,7 @@
 #[macro_use]
 extern crate user_lib;
 
-use user_lib::{close, open, read, write, OpenFlags};
+use user_lib::{close, fsync, open, read, write, OpenFlags};
 
 #[no_mangle]
 pub fn main() -> i32 {
@@ -14,6 +14,7 @@ pub fn main() -> i32 {
     assert!(fd > 0);
     let fd = fd as usize;
     write(fd, test_str.as_bytes());
+    assert_eq!(fsync(fd), 0);
     close(fd);
 
     let fd = open(filea, OpenFlags::RDONLY);
@@ -0,0 +1,57 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+
+use user_lib::{close, lseek, open, read, write, OpenFlags, SEEK_END};
+
+#[no_mangle]
+pub fn main() -> i32 {
+    let filec = "/tmp/filec\0";
+    let fd = open(
+        filec,
+        OpenFlags::CREATE | OpenFlags::TRUNC | OpenFlags::WRONLY,
+    );
+    assert!(fd > 0);
+    let fd = fd as usize;
+    assert_eq!(write(fd, b"abc"), 3);
+    // write-only
+    let mut buffer = [0u8; 16];
+    assert_eq!(read(fd, &mut buffer), -1);
+    close(fd);
+
+    // CREATE alone keeps the content, EXCL refuses an existing file
+    let fd = open(filec, OpenFlags::CREATE | OpenFlags::RDONLY);
+    assert!(fd > 0);
+    let fd = fd as usize;
+    assert_eq!(lseek(fd, 0, SEEK_END), 3);
+    // read-only
+    assert_eq!(write(fd, b"def"), -1);
+    close(fd);
+    assert_eq!(open(filec, OpenFlags::CREATE | OpenFlags::EXCL), -1);
+
+    // APPEND
+    let fd = open(filec, OpenFlags::WRONLY | OpenFlags::APPEND);
+    assert!(fd > 0);
+    let fd = fd as usize;
+    assert_eq!(write(fd, b"def"), 3);
+    close(fd);
+    let fd = open(filec, OpenFlags::RDONLY) as usize;
+    assert_eq!(read(fd, &mut buffer), 6);
+    assert_eq!(&buffer[..6], b"abcdef");
+    close(fd);
+
+    // directories
+    assert_eq!(open(filec, OpenFlags::DIRECTORY), -1);
+    assert_eq!(open("/bin\0", OpenFlags::WRONLY), -1);
+    let fd = open("/bin\0", OpenFlags::DIRECTORY);
+    assert!(fd > 0);
+    close(fd as usize);
+
+    // invalid access mode
+    assert_eq!(open(filec, OpenFlags::WRONLY | OpenFlags::RDWR), -1);
+
+    println!("open_test passed!");
+    0
+}
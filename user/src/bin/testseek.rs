@@ -0,0 +1,51 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+
+use user_lib::{
+    close, lseek, open, pread, pwrite, read, write, OpenFlags, SEEK_CUR, SEEK_END, SEEK_SET,
+};
+
+#[no_mangle]
+pub fn main() -> i32 {
+    let fileb = "/tmp/fileb\0";
+    let fd = open(
+        fileb,
+        OpenFlags::CREATE | OpenFlags::TRUNC | OpenFlags::RDWR,
+    );
+    assert!(fd > 0);
+    let fd = fd as usize;
+    assert_eq!(write(fd, b"0123456789"), 10);
+
+    // lseek
+    assert_eq!(lseek(fd, 2, SEEK_SET), 2);
+    let mut buffer = [0u8; 3];
+    assert_eq!(read(fd, &mut buffer), 3);
+    assert_eq!(&buffer, b"234");
+    assert_eq!(lseek(fd, 1, SEEK_CUR), 6);
+    assert_eq!(lseek(fd, -1, SEEK_END), 9);
+    assert_eq!(read(fd, &mut buffer), 1);
+    assert_eq!(buffer[0], b'9');
+    assert_eq!(lseek(fd, -20, SEEK_CUR), -1);
+
+    // pread/pwrite leave the offset untouched
+    assert_eq!(lseek(fd, 0, SEEK_SET), 0);
+    assert_eq!(pwrite(fd, b"ab", 4), 2);
+    assert_eq!(pread(fd, &mut buffer, 3), 3);
+    assert_eq!(&buffer, b"3ab");
+    assert_eq!(lseek(fd, 0, SEEK_CUR), 0);
+
+    // writing past EOF leaves a gap of zeros
+    assert_eq!(lseek(fd, 1000, SEEK_SET), 1000);
+    assert_eq!(write(fd, b"end"), 3);
+    assert_eq!(lseek(fd, 0, SEEK_END), 1003);
+    let mut gap = [0xffu8; 64];
+    assert_eq!(pread(fd, &mut gap, 500), 64);
+    assert!(gap.iter().all(|b| *b == 0));
+    close(fd);
+
+    println!("seek_test passed!");
+    0
+}
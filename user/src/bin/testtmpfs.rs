@@ -0,0 +1,63 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+
+use user_lib::{close, mkdir, open, rmdir, stat, unlink, write, OpenFlags, Stat, StatMode};
+
+/// Write to `path` until the filesystem is full, return the bytes written
+fn fill(path: &str) -> usize {
+    let fd = open(path, OpenFlags::CREATE | OpenFlags::WRONLY);
+    assert!(fd > 0);
+    let fd = fd as usize;
+    let data = [b'x'; 4096];
+    let mut total = 0;
+    loop {
+        let written = write(fd, &data);
+        assert!(written >= 0);
+        total += written as usize;
+        if (written as usize) < data.len() {
+            break;
+        }
+    }
+    close(fd);
+    total
+}
+
+#[no_mangle]
+pub fn main() -> i32 {
+    assert_eq!(mkdir("/tmp/dir\0"), 0);
+    assert_eq!(mkdir("/tmp/dir\0"), -1);
+    let mut st = Stat::new();
+    assert_eq!(stat("/tmp/dir\0", &mut st), 0);
+    assert_eq!(st.mode.file_type(), StatMode::DIR);
+
+    let fd = open("/tmp/dir/file\0", OpenFlags::CREATE | OpenFlags::WRONLY);
+    assert!(fd > 0);
+    assert_eq!(write(fd as usize, b"hello"), 5);
+    close(fd as usize);
+    assert_eq!(stat("/tmp/dir/../dir/file\0", &mut st), 0);
+    assert_eq!(st.size, 5);
+
+    // files and directories are not removed by each other's calls,
+    // and directories must be empty
+    assert_eq!(rmdir("/tmp/dir/file\0"), -1);
+    assert_eq!(unlink("/tmp/dir\0"), -1);
+    assert_eq!(rmdir("/tmp/dir\0"), -1);
+    assert_eq!(unlink("/tmp/dir/file\0"), 0);
+    assert_eq!(stat("/tmp/dir/file\0", &mut st), -1);
+    assert_eq!(rmdir("/tmp/dir\0"), 0);
+    // mount points stay
+    assert_eq!(rmdir("/tmp\0"), -1);
+
+    // the space of a removed file can be used again
+    let size = fill("/tmp/big\0");
+    assert!(size > 0);
+    assert_eq!(unlink("/tmp/big\0"), 0);
+    assert_eq!(fill("/tmp/big\0"), size);
+    assert_eq!(unlink("/tmp/big\0"), 0);
+
+    println!("tmpfs_test passed!");
+    0
+}
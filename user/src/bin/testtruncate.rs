@@ -0,0 +1,51 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+
+use user_lib::{close, fstat, ftruncate, open, pread, write, OpenFlags, Stat};
+
+fn file_size(fd: usize) -> u64 {
+    let mut st = Stat::new();
+    assert_eq!(fstat(fd, &mut st), 0);
+    st.size
+}
+
+#[no_mangle]
+pub fn main() -> i32 {
+    let filed = "/tmp/filed\0";
+    let fd = open(
+        filed,
+        OpenFlags::CREATE | OpenFlags::TRUNC | OpenFlags::RDWR,
+    );
+    assert!(fd > 0);
+    let fd = fd as usize;
+    let data = [b'x'; 4096];
+    for _ in 0..32 {
+        assert_eq!(write(fd, &data), 4096);
+    }
+    assert_eq!(file_size(fd), 32 * 4096);
+
+    // shrink, then grow again: the regrown range reads as zeros
+    assert_eq!(ftruncate(fd, 100), 0);
+    assert_eq!(file_size(fd), 100);
+    assert_eq!(ftruncate(fd, 3000), 0);
+    assert_eq!(file_size(fd), 3000);
+    let mut buffer = [0xffu8; 200];
+    assert_eq!(pread(fd, &mut buffer, 0), 200);
+    assert!(buffer[..100].iter().all(|b| *b == b'x'));
+    assert!(buffer[100..].iter().all(|b| *b == 0));
+    assert_eq!(ftruncate(fd, 0), 0);
+    assert_eq!(file_size(fd), 0);
+    close(fd);
+
+    // read-only descriptors can not be truncated
+    let fd = open(filed, OpenFlags::RDONLY);
+    assert!(fd > 0);
+    assert_eq!(ftruncate(fd as usize, 10), -1);
+    close(fd as usize);
+
+    println!("truncate_test passed!");
+    0
+}
@@ -67,8 +67,99 @@ bitflags! {
         const RDONLY = 0;
         const WRONLY = 1 << 0;
         const RDWR = 1 << 1;
+        /// fail if CREATE is set and the file already exists
+        const EXCL = 1 << 7;
         const CREATE = 1 << 9;
         const TRUNC = 1 << 10;
+        /// every write goes to the end of the file
+        const APPEND = 1 << 11;
+        /// fail if the path is not a directory
+        const DIRECTORY = 1 << 16;
+        /// close the file descriptor on exec
+        const CLOEXEC = 1 << 19;
+    }
+}
+
+/// `whence` values of [`lseek`]
+pub const SEEK_SET: usize = 0;
+pub const SEEK_CUR: usize = 1;
+pub const SEEK_END: usize = 2;
+
+/// Special `dirfd` value: resolve the path from the current working directory
+pub const AT_FDCWD: isize = -100;
+/// `flags` of `sys_unlinkat`: remove a directory rather than a file
+pub const AT_REMOVEDIR: u32 = 0x200;
+
+/// `magic` arguments of [`reboot`]
+pub const REBOOT_MAGIC1: u32 = 0xfee1_dead;
+pub const REBOOT_MAGIC2: u32 = 0x2812_1969;
+/// `cmd` values of [`reboot`]
+pub const REBOOT_CMD_RESTART: u32 = 0x0123_4567;
+pub const REBOOT_CMD_HALT: u32 = 0xcdef_0123;
+pub const REBOOT_CMD_POWER_OFF: u32 = 0x4321_fedc;
+
+#[repr(C)]
+#[derive(Debug)]
+pub struct Stat {
+    /// inode number
+    pub ino: u64,
+    /// file type
+    pub mode: StatMode,
+    /// number of hard links
+    pub nlink: u32,
+    /// size in bytes
+    pub size: u64,
+    /// number of 512-byte blocks allocated
+    pub blocks: u64,
+    /// last access, modification and status change times, in seconds
+    pub atime: u64,
+    pub mtime: u64,
+    pub ctime: u64,
+}
+
+impl Stat {
+    pub fn new() -> Self {
+        Stat {
+            ino: 0,
+            mode: StatMode::NULL,
+            nlink: 0,
+            size: 0,
+            blocks: 0,
+            atime: 0,
+            mtime: 0,
+            ctime: 0,
+        }
+    }
+}
+
+impl Default for Stat {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+bitflags! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct StatMode: u32 {
+        const NULL = 0;
+        /// bits of the file type, whose values are the ones below
+        const S_IFMT = 0o170000;
+        /// character device
+        const CHAR = 0o020000;
+        /// directory
+        const DIR = 0o040000;
+        /// block device
+        const BLOCK = 0o060000;
+        /// ordinary regular file
+        const FILE = 0o100000;
+    }
+}
+
+impl StatMode {
+    /// The file type, to compare with CHAR, DIR, BLOCK or FILE. The types
+    /// share bits, so `contains` does not tell them apart.
+    pub fn file_type(&self) -> Self {
+        *self & Self::S_IFMT
     }
 }
 
@@ -85,6 +176,59 @@ pub fn getdents(fd: usize, buf: *mut [u8]) -> isize {
     sys_getdents(fd, buf, buf.len())
 }
 
+pub fn lseek(fd: usize, offset: isize, whence: usize) -> isize {
+    sys_lseek(fd, offset, whence)
+}
+
+pub fn pread(fd: usize, buf: &mut [u8], offset: usize) -> isize {
+    sys_pread64(fd, buf, offset)
+}
+
+pub fn pwrite(fd: usize, buf: &[u8], offset: usize) -> isize {
+    sys_pwrite64(fd, buf, offset)
+}
+pub fn ftruncate(fd: usize, len: usize) -> isize {
+    sys_ftruncate(fd, len)
+}
+
+pub fn fstat(fd: usize, st: &mut Stat) -> isize {
+    sys_fstat(fd, st)
+}
+
+pub fn stat(path: &str, st: &mut Stat) -> isize {
+    sys_fstatat(AT_FDCWD, path, st)
+}
+
+pub fn mkdir(path: &str) -> isize {
+    sys_mkdirat(AT_FDCWD, path, 0o755)
+}
+
+pub fn unlink(path: &str) -> isize {
+    sys_unlinkat(AT_FDCWD, path, 0)
+}
+
+/// Remove an empty directory
+pub fn rmdir(path: &str) -> isize {
+    sys_unlinkat(AT_FDCWD, path, AT_REMOVEDIR)
+}
+
+pub fn sync() -> isize {
+    sys_sync()
+}
+
+pub fn fsync(fd: usize) -> isize {
+    sys_fsync(fd)
+}
+
+pub fn fdatasync(fd: usize) -> isize {
+    sys_fdatasync(fd)
+}
+
+/// Write back the filesystem and run `cmd`, returns only on failure
+pub fn reboot(cmd: u32) -> isize {
+    sys_reboot(REBOOT_MAGIC1, REBOOT_MAGIC2, cmd)
+}
+
 pub fn read(fd: usize, buf: &mut [u8]) -> isize {
     sys_read(fd, buf)
 }
@@ -118,6 +262,15 @@ pub fn chdir(path: &str) -> isize {
     sys_chdir(path)
 }
 
+/// Mount the filesystem of type `fs_type` from `source` on directory `target`
+pub fn mount(source: &str, target: &str, fs_type: &str) -> isize {
+    sys_mount(source, target, fs_type, 0)
+}
+
+pub fn umount(target: &str) -> isize {
+    sys_umount2(target, 0)
+}
+
 pub fn wait(exit_code: &mut i32) -> isize {
     loop {
         match sys_waitpid(-1, exit_code as *mut _) {